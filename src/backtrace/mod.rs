@@ -0,0 +1,301 @@
+//! Backtraces at any step.
+//!
+//! Frames entered while recording come from the CALL history: for each call
+//! depth, the last call made before the step that reached it and has not
+//! returned yet. Frames set up before recording started (attached
+//! processes, sliced traces) are then recovered by following the
+//! frame-pointer chain through whatever stack memory the trace knows.
+
+use crate::storage::{IndexKey, InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+
+/// Frame-pointer frames followed at most, in case the chain loops.
+const MAX_FP_FRAMES: usize = 64;
+
+/// How a frame was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrameSource {
+    /// A recorded call that had not returned yet.
+    Call,
+    /// A saved frame pointer / return address pair on the stack.
+    FramePointer,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StackFrame {
+    /// 0 for the innermost frame.
+    pub level: usize,
+    /// Current PC in frame 0, the call site in recorded callers and the
+    /// return address in frame-pointer frames.
+    pub pc: u64,
+    /// Symbol of `pc`, "name" or "name+0x1c".
+    pub function: Option<String>,
+    /// Step of the CALL that entered this frame, None when it was entered
+    /// before the trace started.
+    pub call_step: Option<u64>,
+    /// Stack addresses `sp_low..sp_high` the frame occupies. The top is
+    /// unknown for the outermost frame.
+    pub sp_low: u64,
+    pub sp_high: Option<u64>,
+    pub source: FrameSource,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Backtrace {
+    pub step: u64,
+    /// Innermost frame first.
+    pub frames: Vec<StackFrame>,
+}
+
+impl Backtrace {
+    /// The call stack as it was when `step` was about to execute, or None if
+    /// the step was not recorded.
+    pub fn at(db: &TraceDb, symbols: &SymbolTable, step: u64) -> Option<Self> {
+        let entry = db.get(step)?;
+        let x86 = db.meta().arch == "x86_64";
+        let (sp_reg, fp_reg) = if x86 { ("rsp", "rbp") } else { ("sp", "fp") };
+        let reg = |step: u64, name: &str| -> Option<u64> {
+            let regs: serde_json::Value = serde_json::from_str(&db.get(step)?.regs).ok()?;
+            regs[name].as_u64()
+        };
+
+        // Calls made before `step` that had not returned by then, innermost
+        // first. A CALL or RETURN at `step` itself has not executed yet, so
+        // start from the depth the previous step left. The open call at each
+        // depth is the last one reaching it before the next inner open call;
+        // any later call to that depth would have had to return first.
+        let mut open = Vec::new();
+        {
+            let index = db.index();
+            let key = IndexKey::Kind(InsnKind::Call);
+            let mut depth = step.checked_sub(1).and_then(|s| db.depth(s)).unwrap_or(0);
+            let mut before = step;
+            while depth > 0 {
+                let mut calls = std::iter::successors(index.prev_before(key, before), |&c| {
+                    index.prev_before(key, c)
+                });
+                // None: the frame was entered before the trace started.
+                let Some(call) = calls.find(|&c| db.depth(c) == Some(depth)) else {
+                    break;
+                };
+                open.push(call);
+                before = call;
+                depth -= 1;
+            }
+        }
+
+        // Innermost first: each frame runs from the SP where it stands now
+        // (at `step`, or at the call it made) up to its caller's SP at the
+        // call that entered it.
+        let mut frames = Vec::new();
+        let mut pc = entry.pc;
+        let mut now = step;
+        for (level, &call) in open.iter().enumerate() {
+            frames.push(StackFrame {
+                level,
+                pc,
+                function: symbols.describe(pc),
+                call_step: Some(call),
+                sp_low: reg(now, sp_reg).unwrap_or(0),
+                sp_high: reg(call, sp_reg),
+                source: FrameSource::Call,
+            });
+            pc = db.get(call).map_or(0, |e| e.pc);
+            now = call;
+        }
+        frames.push(StackFrame {
+            level: open.len(),
+            pc,
+            function: symbols.describe(pc),
+            call_step: None,
+            sp_low: reg(now, sp_reg).unwrap_or(0),
+            sp_high: None,
+            source: FrameSource::Call,
+        });
+
+        // Frame records are [saved fp, return address] at fp on both arm64
+        // (x29, x30) and x86_64 (rbp, return address pushed by the call).
+        let mut fp = reg(now, fp_reg).unwrap_or(0);
+        for _ in 0..MAX_FP_FRAMES {
+            // A frame pointer used as a general register can hold anything,
+            // including addresses too close to the top to hold a record.
+            let Some(frame_top) = fp.checked_add(16) else {
+                break;
+            };
+            let record = match fp {
+                0 => None,
+                _ => db
+                    .read_memory(fp, 8, step)
+                    .zip(db.read_memory(fp + 8, 8, step)),
+            };
+            let Some((saved_fp, ret)) = record.filter(|&(_, ret)| ret != 0) else {
+                break;
+            };
+            let outer = frames.last_mut().expect("at least one frame");
+            if outer.sp_low > frame_top {
+                // The frame pointer is stale (not set up yet, or used as a
+                // general register) and points below the stack.
+                break;
+            }
+            outer.sp_high = Some(frame_top);
+            frames.push(StackFrame {
+                level: frames.len(),
+                pc: ret,
+                function: symbols.describe(ret),
+                call_step: None,
+                sp_low: frame_top,
+                sp_high: None,
+                source: FrameSource::FramePointer,
+            });
+            // The stack grows down, so callers' frames are always higher.
+            if saved_fp <= fp {
+                break;
+            }
+            fp = saved_fp;
+        }
+
+        Some(Self { step, frames })
+    }
+
+    pub fn print(&self) {
+        println!("Backtrace at step {}:", self.step);
+        for f in &self.frames {
+            let called = match (f.call_step, f.source) {
+                (Some(step), _) => format!("called at step {}", step),
+                (None, FrameSource::FramePointer) => "from frame pointer".to_string(),
+                (None, FrameSource::Call) => "before the trace".to_string(),
+            };
+            let stack = match f.sp_high {
+                Some(high) => format!("0x{:x}..0x{:x}", f.sp_low, high),
+                None => format!("0x{:x}..", f.sp_low),
+            };
+            println!(
+                "  #{:<3} 0x{:<14x} {:<32} {:<24} stack {}",
+                f.level,
+                f.pc,
+                f.function.as_deref().unwrap_or("??"),
+                called,
+                stack
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, insn: &str, sp: u64, fp: u64) -> TraceEntry {
+        TraceEntry {
+            regs: serde_json::json!({ "sp": sp, "fp": fp }).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    const CALL: &str = "bl ; CALL";
+    const RET: &str = "ret ; RETURN";
+
+    /// main calls work, which returns; then main calls work again, which
+    /// calls leaf.
+    fn trace() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        for (e, depth) in [
+            (entry(0, 0x1000, "nop", 0x8000, 0), 0),
+            (entry(1, 0x1004, CALL, 0x8000, 0), 1),
+            (entry(2, 0x2000, RET, 0x8000, 0), 0),
+            (entry(3, 0x1008, CALL, 0x8000, 0), 1),
+            (entry(4, 0x2000, "sub sp, sp, #0x20", 0x8000, 0), 1),
+            (entry(5, 0x2004, CALL, 0x7fe0, 0), 2),
+            (entry(6, 0x3000, "nop", 0x7fe0, 0), 2),
+        ] {
+            db.insert(TraceEntry { depth, ..e }).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![
+            sym(0x1000, "main"),
+            sym(0x2000, "work"),
+            sym(0x3000, "leaf"),
+        ])
+    }
+
+    #[test]
+    fn frames_from_open_calls() {
+        let bt = Backtrace::at(&trace(), &symbols(), 6).unwrap();
+        let frames: Vec<(u64, Option<&str>, Option<u64>)> = bt
+            .frames
+            .iter()
+            .map(|f| (f.pc, f.function.as_deref(), f.call_step))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (0x3000, Some("leaf"), Some(5)),
+                (0x2004, Some("work+0x4"), Some(3)),
+                (0x1008, Some("main+0x8"), None),
+            ]
+        );
+        assert_eq!(
+            (bt.frames[1].sp_low, bt.frames[1].sp_high),
+            (0x7fe0, Some(0x8000))
+        );
+        assert_eq!(bt.frames[2].sp_high, None);
+    }
+
+    #[test]
+    fn call_and_return_steps_belong_to_the_caller_and_callee() {
+        let db = trace();
+        // The CALL at step 1 has not executed yet; the RETURN at 2 has not
+        // left work yet.
+        assert_eq!(Backtrace::at(&db, &symbols(), 1).unwrap().frames.len(), 1);
+        assert_eq!(Backtrace::at(&db, &symbols(), 2).unwrap().frames.len(), 2);
+        assert_eq!(Backtrace::at(&db, &symbols(), 3).unwrap().frames.len(), 1);
+        assert!(Backtrace::at(&db, &symbols(), 99).is_none());
+    }
+
+    #[test]
+    fn frame_pointer_chain_beyond_the_trace() {
+        let db = TraceDb::new(":memory:").unwrap();
+        // Attached inside a function whose frame record at 0x7f00 points to
+        // a caller record at 0x7f40, which ends the chain.
+        let word =
+            |addr: u64, value: u64| (0..8).map(move |i| (addr + i, (value >> (8 * i)) as u8));
+        db.set_meta(TraceMeta {
+            initial_memory: word(0x7f00, 0x7f40)
+                .chain(word(0x7f08, 0x1010))
+                .chain(word(0x7f40, 0))
+                .chain(word(0x7f48, 0))
+                .collect(),
+            ..Default::default()
+        });
+        db.insert(entry(0, 0x2010, "nop", 0x7ee0, 0x7f00)).unwrap();
+
+        let bt = Backtrace::at(&db, &symbols(), 0).unwrap();
+        assert_eq!(bt.frames.len(), 2);
+        assert_eq!(bt.frames[0].function.as_deref(), Some("work+0x10"));
+        assert_eq!(bt.frames[0].sp_high, Some(0x7f10));
+        assert_eq!(bt.frames[1].function.as_deref(), Some("main+0x10"));
+        assert_eq!(bt.frames[1].source, FrameSource::FramePointer);
+        assert_eq!(bt.frames[1].sp_low, 0x7f10);
+    }
+
+    #[test]
+    fn frame_pointer_near_the_top_ends_the_chain() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(entry(0, 0x2010, "nop", 0x7ee0, u64::MAX - 4))
+            .unwrap();
+        let bt = Backtrace::at(&db, &symbols(), 0).unwrap();
+        assert_eq!(bt.frames.len(), 1);
+    }
+}
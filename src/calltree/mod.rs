@@ -0,0 +1,318 @@
+//! Call tree reconstruction: which function called which, for how long, and
+//! with what arguments.
+//!
+//! Built from the CALL / RETURN entries of the trace index. Each call is
+//! paired with the next unmatched return, so a trace that starts or stops
+//! inside a function still produces a consistent tree: returns with no call
+//! in range are ignored, and calls that never return run to the end.
+
+use crate::storage::{IndexKey, InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+
+/// Argument registers in calling-convention order, and the return register.
+const ARM64_ABI: (&[&str], &str) = (&["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"], "x0");
+const X86_64_ABI: (&[&str], &str) = (&["rdi", "rsi", "rdx", "rcx", "r8", "r9"], "rax");
+
+/// One function invocation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CallNode {
+    pub id: usize,
+    /// Node of the calling function, None for calls made at the top level.
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    /// Nesting level in the tree, 0 for roots.
+    pub depth: usize,
+    /// Address the call entered (the PC of the step after the CALL).
+    pub target: Option<u64>,
+    /// Symbol of `target`, "name" or "name+0x1c".
+    pub symbol: Option<String>,
+    /// Step of the CALL instruction.
+    pub call_step: u64,
+    /// Step of the matching RETURN, None if it never returned.
+    pub return_step: Option<u64>,
+    /// Steps from the CALL through the RETURN, including callees.
+    pub inclusive: u64,
+    /// `inclusive` minus the steps spent in callees.
+    pub exclusive: u64,
+    /// Argument registers on entry (x0-x7 on arm64, rdi, rsi, rdx, rcx, r8,
+    /// r9 on x86_64).
+    pub args: Vec<u64>,
+    /// Return register (x0 / rax) after the RETURN.
+    pub ret: Option<u64>,
+}
+
+impl CallNode {
+    /// Symbol, or the hex target address when there is none.
+    pub fn name(&self) -> String {
+        match (&self.symbol, self.target) {
+            (Some(symbol), _) => symbol.clone(),
+            (None, Some(target)) => format!("0x{:x}", target),
+            (None, None) => "(unknown)".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct CallTree {
+    /// Every call, in call order; a node's id is its index.
+    pub nodes: Vec<CallNode>,
+    pub roots: Vec<usize>,
+}
+
+impl CallTree {
+    /// Build the tree for the whole trace.
+    pub fn build(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        match db.step_bounds() {
+            Some(range) => Self::build_range(db, symbols, range),
+            None => Self::default(),
+        }
+    }
+
+    /// Build the tree of the calls made inside `start..=end`; empty when
+    /// `start > end`.
+    pub fn build_range(db: &TraceDb, symbols: &SymbolTable, (start, end): (u64, u64)) -> Self {
+        if start > end {
+            return Self::default();
+        }
+        let (calls, returns) = {
+            let index = db.index();
+            let in_range = |kind| -> Vec<u64> {
+                let steps = index.steps(IndexKey::Kind(kind));
+                let from = steps.partition_point(|&s| s < start);
+                let to = steps.partition_point(|&s| s <= end);
+                steps[from..to].to_vec()
+            };
+            (in_range(InsnKind::Call), in_range(InsnKind::Return))
+        };
+        let (arg_regs, ret_reg) = if db.meta().arch == "x86_64" {
+            X86_64_ABI
+        } else {
+            ARM64_ABI
+        };
+        let regs_at = |step: u64| -> Option<serde_json::Value> {
+            db.get(step)
+                .map(|e| serde_json::from_str(&e.regs).unwrap_or_default())
+        };
+
+        let mut tree = Self::default();
+        // Calls still waiting for their return.
+        let mut open: Vec<usize> = Vec::new();
+        let (mut ci, mut ri) = (0, 0);
+        loop {
+            match (calls.get(ci), returns.get(ri)) {
+                (Some(&call), ret) if ret.is_none_or(|&r| call < r) => {
+                    ci += 1;
+                    let id = tree.nodes.len();
+                    let parent = open.last().copied();
+                    match parent {
+                        Some(p) => tree.nodes[p].children.push(id),
+                        None => tree.roots.push(id),
+                    }
+                    let entry = db.get(call + 1);
+                    let regs = regs_at(call + 1).unwrap_or_default();
+                    tree.nodes.push(CallNode {
+                        id,
+                        parent,
+                        children: Vec::new(),
+                        depth: open.len(),
+                        target: entry.as_ref().map(|e| e.pc),
+                        symbol: entry.and_then(|e| symbols.describe(e.pc)),
+                        call_step: call,
+                        return_step: None,
+                        inclusive: 0,
+                        exclusive: 0,
+                        args: arg_regs
+                            .iter()
+                            .map(|r| regs[r].as_u64().unwrap_or(0))
+                            .collect(),
+                        ret: None,
+                    });
+                    open.push(id);
+                }
+                (_, Some(&ret)) => {
+                    ri += 1;
+                    if let Some(id) = open.pop() {
+                        let node = &mut tree.nodes[id];
+                        node.return_step = Some(ret);
+                        node.ret = regs_at(ret + 1).and_then(|r| r[ret_reg].as_u64());
+                    }
+                }
+                _ => break,
+            }
+        }
+
+        // Children always come after their parent, so walking backwards sees
+        // every callee's total before its caller needs it.
+        for id in (0..tree.nodes.len()).rev() {
+            let node = &tree.nodes[id];
+            let inclusive = node.return_step.unwrap_or(end) - node.call_step + 1;
+            let in_callees: u64 = node.children.iter().map(|&c| tree.nodes[c].inclusive).sum();
+            let node = &mut tree.nodes[id];
+            node.inclusive = inclusive;
+            node.exclusive = inclusive.saturating_sub(in_callees);
+        }
+        tree
+    }
+
+    /// Print the tree indented by call depth, down to `max_depth` levels,
+    /// showing the first `args` argument registers of each call.
+    pub fn print(&self, max_depth: Option<usize>, args: usize) {
+        let mut stack: Vec<usize> = self.roots.iter().rev().copied().collect();
+        while let Some(id) = stack.pop() {
+            let node = &self.nodes[id];
+            if max_depth.is_some_and(|max| node.depth >= max) {
+                continue;
+            }
+            let shown: Vec<String> = node
+                .args
+                .iter()
+                .take(args)
+                .map(|a| format!("0x{:x}", a))
+                .collect();
+            let ret = match (node.return_step, node.ret) {
+                (Some(_), Some(v)) => format!(" = 0x{:x}", v),
+                (Some(_), None) => String::new(),
+                (None, _) => " (no return)".to_string(),
+            };
+            let steps = match node.return_step {
+                Some(r) => format!("{}..{}", node.call_step, r),
+                None => format!("{}..", node.call_step),
+            };
+            println!(
+                "{}{}({}){}  [steps {}, {} total, {} self]",
+                "  ".repeat(node.depth),
+                node.name(),
+                shown.join(", "),
+                ret,
+                steps,
+                node.inclusive,
+                node.exclusive
+            );
+            stack.extend(node.children.iter().rev());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, insn: &str, x0: u64) -> TraceEntry {
+        TraceEntry {
+            regs: serde_json::json!({"x0": x0, "x1": x0 + 1}).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    /// main calls f(1) at step 1; f calls g(2) at step 3 and h at step 6,
+    /// which never returns.
+    fn trace() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        for e in [
+            entry(0, 0x1000, "mov x0, #1", 0),
+            entry(1, 0x1004, "bl #0x2000 ; CALL [depth:1]", 1),
+            entry(2, 0x2000, "mov x0, #2", 1),
+            entry(3, 0x2004, "bl #0x3000 ; CALL [depth:2]", 2),
+            entry(4, 0x3000, "ret ; RETURN [depth:1]", 2),
+            entry(5, 0x2008, "ret ; RETURN [depth:0]", 9),
+            entry(6, 0x1008, "bl #0x4000 ; CALL [depth:1]", 7),
+            entry(7, 0x4000, "nop", 7),
+            entry(8, 0x4004, "nop", 7),
+        ] {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![
+            sym(0x1000, "main"),
+            sym(0x2000, "f"),
+            sym(0x3000, "g"),
+        ])
+    }
+
+    #[test]
+    fn nesting_and_step_counts() {
+        let tree = CallTree::build(&trace(), &symbols());
+        assert_eq!(tree.roots, vec![0, 2]);
+        let names: Vec<String> = tree.nodes.iter().map(CallNode::name).collect();
+        assert_eq!(names, vec!["f", "g", "0x4000"]);
+
+        let f = &tree.nodes[0];
+        assert_eq!(f.children, vec![1]);
+        assert_eq!((f.call_step, f.return_step), (1, Some(5)));
+        assert_eq!((f.inclusive, f.exclusive), (5, 3));
+
+        let g = &tree.nodes[1];
+        assert_eq!((g.parent, g.depth), (Some(0), 1));
+        assert_eq!((g.inclusive, g.exclusive), (2, 2));
+
+        let h = &tree.nodes[2];
+        assert_eq!(h.return_step, None);
+        assert_eq!(h.inclusive, 3, "unreturned call runs to the last step");
+    }
+
+    #[test]
+    fn arguments_and_return_values() {
+        let tree = CallTree::build(&trace(), &symbols());
+        let f = &tree.nodes[0];
+        assert_eq!(&f.args[..2], &[1, 2]);
+        assert_eq!(f.args.len(), 8);
+        // x0 at the step after f's RETURN.
+        assert_eq!(f.ret, Some(7));
+        assert_eq!(tree.nodes[2].ret, None);
+    }
+
+    #[test]
+    fn x86_64_uses_system_v_registers() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "x86_64".into(),
+            ..Default::default()
+        });
+        let regs = serde_json::json!({"rdi": 3, "rsi": 4, "rax": 0});
+        for (step, insn) in [
+            (0, "call 0x2000 ; CALL [depth:1]"),
+            (1, "ret ; RETURN [depth:0]"),
+            (2, "nop"),
+        ] {
+            db.insert(TraceEntry {
+                regs: regs.to_string(),
+                ..entry(step, 0x1000 + step, insn, 0)
+            })
+            .unwrap();
+        }
+        let tree = CallTree::build(&db, &SymbolTable::default());
+        assert_eq!(tree.nodes[0].args, vec![3, 4, 0, 0, 0, 0]);
+        assert_eq!(tree.nodes[0].ret, Some(0));
+    }
+
+    #[test]
+    fn range_ignores_earlier_returns() {
+        let tree = CallTree::build_range(&trace(), &symbols(), (2, 8));
+        assert_eq!(tree.roots, vec![0, 1]);
+        assert_eq!(tree.nodes[0].name(), "g");
+        assert_eq!(tree.nodes[1].call_step, 6);
+    }
+
+    #[test]
+    fn inverted_range_has_no_calls() {
+        let tree = CallTree::build_range(&trace(), &symbols(), (8, 2));
+        assert_eq!(tree, CallTree::default());
+    }
+
+    #[test]
+    fn empty_trace_has_no_calls() {
+        let db = TraceDb::new(":memory:").unwrap();
+        assert_eq!(CallTree::build(&db, &symbols()), CallTree::default());
+    }
+}
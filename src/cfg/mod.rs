@@ -0,0 +1,481 @@
+//! Control-flow graph recovery: the basic blocks the trace executed and the
+//! edges taken between them.
+//!
+//! Blocks are recovered dynamically. Every address control arrived at other
+//! than by falling through from the previous instruction starts a block, as
+//! does the first step and every instruction after a branch, call or return.
+//! Calls are not followed into the callee: the calling block gets a `Call`
+//! edge to wherever execution resumed after the matching return, so each
+//! function's graph stays self-contained.
+
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Dot,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown cfg format '{}' (expected dot or json)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Insn {
+    pub addr: u64,
+    /// Disassembly, without the tracer's annotations.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BasicBlock {
+    pub start: u64,
+    /// Address just past the last instruction.
+    pub end: u64,
+    /// Function containing `start`, when the binary has a symbol for it.
+    pub function: Option<String>,
+    pub insns: Vec<Insn>,
+    /// Times the block was entered.
+    pub count: u64,
+    /// Kind of the last instruction.
+    pub terminator: InsnKind,
+    /// For blocks ending in a branch: times it jumped, and times it fell
+    /// through to the next instruction.
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EdgeKind {
+    /// Execution continued at the next instruction.
+    Fallthrough,
+    /// A branch (or anything else) moved execution elsewhere.
+    Taken,
+    /// From a call site to where execution resumed after the callee returned.
+    Call,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Edge {
+    /// Start addresses of the two blocks.
+    pub from: u64,
+    pub to: u64,
+    pub kind: EdgeKind,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Cfg {
+    /// Sorted by start address.
+    pub blocks: Vec<BasicBlock>,
+    /// Sorted by (from, to).
+    pub edges: Vec<Edge>,
+}
+
+/// An executed instruction.
+struct Seen {
+    len: u64,
+    text: String,
+    kind: InsnKind,
+    count: u64,
+}
+
+impl Cfg {
+    pub fn build(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        let mut insns: BTreeMap<u64, Seen> = BTreeMap::new();
+        let mut leaders: BTreeSet<u64> = BTreeSet::new();
+        // (pc, next pc) -> count, with calls stepped over: call site ->
+        // return site. Sequential pairs that end up inside one block are
+        // dropped once the blocks are known.
+        let mut transitions: HashMap<(u64, u64), (EdgeKind, u64)> = HashMap::new();
+        // Call sites still waiting for their return.
+        let mut calls: Vec<u64> = Vec::new();
+        // (step, pc, length, kind) of the previous instruction.
+        let mut prev: Option<(u64, u64, u64, InsnKind)> = None;
+
+        db.for_each_in(0, u64::MAX, |e| {
+            let len = e.insn_bytes.len().max(1) as u64;
+            insns
+                .entry(e.pc)
+                .or_insert_with(|| Seen {
+                    len,
+                    text: insn_text(&e.insn_text),
+                    kind: e.kind,
+                    count: 0,
+                })
+                .count += 1;
+
+            match prev {
+                Some((step, pc, len, kind)) if step + 1 == e.step => {
+                    let falls_through = e.pc == pc + len;
+                    let transition = match kind {
+                        InsnKind::Call => {
+                            calls.push(pc);
+                            None
+                        }
+                        InsnKind::Return => calls.pop().map(|site| (site, EdgeKind::Call)),
+                        _ if falls_through => Some((pc, EdgeKind::Fallthrough)),
+                        _ => Some((pc, EdgeKind::Taken)),
+                    };
+                    if !falls_through || ends_block(kind) {
+                        leaders.insert(e.pc);
+                    }
+                    if let Some((from, edge)) = transition {
+                        transitions.entry((from, e.pc)).or_insert((edge, 0)).1 += 1;
+                    }
+                }
+                _ => {
+                    leaders.insert(e.pc);
+                }
+            }
+            prev = Some((e.step, e.pc, len, e.kind));
+        });
+
+        let mut cfg = Self::default();
+        let mut next_pc = None;
+        for (&addr, insn) in &insns {
+            let continues = next_pc == Some(addr) && !leaders.contains(&addr);
+            if !continues {
+                let (function, _) = symbols.lookup(addr).unzip();
+                cfg.blocks.push(BasicBlock {
+                    start: addr,
+                    end: addr,
+                    function: function.map(str::to_string),
+                    insns: Vec::new(),
+                    count: insn.count,
+                    terminator: insn.kind,
+                    taken: 0,
+                    not_taken: 0,
+                });
+            }
+            let block = cfg.blocks.last_mut().unwrap();
+            block.end = addr + insn.len;
+            block.terminator = insn.kind;
+            block.insns.push(Insn {
+                addr,
+                text: insn.text.clone(),
+            });
+            next_pc = (!ends_block(insn.kind)).then_some(addr + insn.len);
+        }
+
+        // Sequential transitions inside a block are not edges.
+        for ((from, to), (kind, count)) in transitions {
+            if cfg.block_at(to).is_some_and(|b| b.start == to) {
+                let from = cfg.block_at(from).map_or(from, |b| b.start);
+                cfg.edges.push(Edge {
+                    from,
+                    to,
+                    kind,
+                    count,
+                });
+            }
+        }
+        cfg.edges.sort_by_key(|e| (e.from, e.to));
+        for edge in &cfg.edges {
+            let i = cfg.blocks.partition_point(|b| b.start < edge.from);
+            let block = &mut cfg.blocks[i];
+            if block.terminator == InsnKind::Branch {
+                match edge.kind {
+                    EdgeKind::Taken => block.taken += edge.count,
+                    EdgeKind::Fallthrough => block.not_taken += edge.count,
+                    EdgeKind::Call => {}
+                }
+            }
+        }
+        cfg
+    }
+
+    /// Block containing `addr`.
+    pub fn block_at(&self, addr: u64) -> Option<&BasicBlock> {
+        let pos = self.blocks.partition_point(|b| b.start <= addr);
+        let block = &self.blocks[pos.checked_sub(1)?];
+        (addr < block.end).then_some(block)
+    }
+
+    /// Names of the functions with executed blocks, hottest first.
+    pub fn functions(&self) -> Vec<String> {
+        let mut steps: HashMap<&str, u64> = HashMap::new();
+        for b in &self.blocks {
+            if let Some(f) = &b.function {
+                *steps.entry(f).or_default() += b.count * b.insns.len() as u64;
+            }
+        }
+        let mut functions: Vec<(&str, u64)> = steps.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        functions.into_iter().map(|(f, _)| f.to_string()).collect()
+    }
+
+    /// The blocks of `function` and the edges between them.
+    pub fn function(&self, function: &str) -> Self {
+        let blocks: Vec<BasicBlock> = self
+            .blocks
+            .iter()
+            .filter(|b| b.function.as_deref() == Some(function))
+            .cloned()
+            .collect();
+        let starts: BTreeSet<u64> = blocks.iter().map(|b| b.start).collect();
+        let edges = self
+            .edges
+            .iter()
+            .filter(|e| starts.contains(&e.from) && starts.contains(&e.to))
+            .cloned()
+            .collect();
+        Self { blocks, edges }
+    }
+
+    pub fn write(&self, format: Format, out: &mut impl Write) -> Result<(), String> {
+        let text = match format {
+            Format::Dot => self.dot(),
+            Format::Json => serde_json::to_string_pretty(self).map_err(|e| e.to_string())? + "\n",
+        };
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+
+    /// Graphviz source with one cluster per function. Edges are labelled
+    /// with their counts and drawn thicker (and pulled straighter by the
+    /// layout) the hotter they are.
+    fn dot(&self) -> String {
+        use std::fmt::Write;
+
+        let mut dot = String::new();
+        let _ = writeln!(dot, "digraph cfg {{");
+        let _ = writeln!(
+            dot,
+            "  node [shape=box, fontname=\"monospace\", fontsize=10];"
+        );
+        let _ = writeln!(dot, "  edge [fontname=\"monospace\", fontsize=9];");
+
+        let mut clusters: BTreeMap<Option<&str>, Vec<&BasicBlock>> = BTreeMap::new();
+        for b in &self.blocks {
+            clusters.entry(b.function.as_deref()).or_default().push(b);
+        }
+        for (n, (function, blocks)) in clusters.iter().enumerate() {
+            let indent = match function {
+                Some(f) => {
+                    let _ = writeln!(dot, "  subgraph cluster_{} {{", n);
+                    let _ = writeln!(dot, "    label=\"{}\";", escape(f));
+                    "    "
+                }
+                None => "  ",
+            };
+            for b in blocks {
+                let _ = writeln!(
+                    dot,
+                    "{}b{:x} [label=\"{}\"];",
+                    indent,
+                    b.start,
+                    escape_label(&block_label(b))
+                );
+            }
+            if function.is_some() {
+                let _ = writeln!(dot, "  }}");
+            }
+        }
+
+        let max = self.edges.iter().map(|e| e.count).max().unwrap_or(1);
+        for e in &self.edges {
+            let heat = (e.count as f64).ln_1p() / (max as f64).ln_1p();
+            let color = match e.kind {
+                EdgeKind::Taken => "darkgreen",
+                EdgeKind::Fallthrough => "black",
+                EdgeKind::Call => "gray40",
+            };
+            let style = if e.kind == EdgeKind::Call {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            let _ = writeln!(
+                dot,
+                "  b{:x} -> b{:x} [label=\"{}\", penwidth={:.1}, weight={}, color={}{}];",
+                e.from,
+                e.to,
+                e.count,
+                1.0 + 4.0 * heat,
+                e.count,
+                color,
+                style
+            );
+        }
+        let _ = writeln!(dot, "}}");
+        dot
+    }
+}
+
+fn ends_block(kind: InsnKind) -> bool {
+    matches!(kind, InsnKind::Branch | InsnKind::Call | InsnKind::Return)
+}
+
+/// Strip the tracer's "; CALL [depth:N]" style annotations.
+fn insn_text(text: &str) -> String {
+    text.split(" ; ").next().unwrap_or(text).trim().to_string()
+}
+
+fn block_label(b: &BasicBlock) -> String {
+    let mut label = match (&b.function, b.insns.first()) {
+        (Some(f), Some(first)) => format!("{} 0x{:x}", f, first.addr),
+        _ => format!("0x{:x}", b.start),
+    };
+    label += &format!("  ({}x)", b.count);
+    if b.terminator == InsnKind::Branch {
+        label += &format!("  taken {} / not taken {}", b.taken, b.not_taken);
+    }
+    label.push('\n');
+    for insn in &b.insns {
+        label += &format!("0x{:x}  {}\n", insn.addr, insn.text);
+    }
+    label
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Escape for a DOT label, with every line left-aligned.
+fn escape_label(s: &str) -> String {
+    escape(s).replace('\n', "\\l")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::entry;
+    use crate::symbols::Symbol;
+
+    /// main: a loop at 0x1004..0x100c that runs three times, then calls
+    /// helper and returns.
+    fn trace() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        let steps = [
+            (0x1000, "mov x0, #3"),
+            (0x1004, "sub x0, x0, #1"),
+            (0x1008, "cbnz x0, #0x1004"),
+            (0x1004, "sub x0, x0, #1"),
+            (0x1008, "cbnz x0, #0x1004"),
+            (0x1004, "sub x0, x0, #1"),
+            (0x1008, "cbnz x0, #0x1004"),
+            (0x100c, "bl #0x2000 ; CALL [depth:1]"),
+            (0x2000, "nop"),
+            (0x2004, "ret ; RETURN [depth:0]"),
+            (0x1010, "ret ; RETURN [depth:-1]"),
+        ];
+        for (step, (pc, insn)) in steps.into_iter().enumerate() {
+            db.insert(entry(step as u64, pc, insn)).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![
+            Symbol {
+                addr: 0x1000,
+                size: 0x100,
+                name: "main".to_string(),
+            },
+            Symbol {
+                addr: 0x2000,
+                size: 0x10,
+                name: "helper".to_string(),
+            },
+        ])
+    }
+
+    fn starts(cfg: &Cfg) -> Vec<u64> {
+        cfg.blocks.iter().map(|b| b.start).collect()
+    }
+
+    #[test]
+    fn splits_blocks_at_branch_targets_and_after_control_flow() {
+        let cfg = Cfg::build(&trace(), &symbols());
+        assert_eq!(starts(&cfg), vec![0x1000, 0x1004, 0x100c, 0x1010, 0x2000]);
+
+        let head = &cfg.blocks[0];
+        assert_eq!((head.end, head.count), (0x1004, 1));
+        let body = &cfg.blocks[1];
+        assert_eq!(body.insns.len(), 2);
+        assert_eq!(body.count, 3);
+        assert_eq!(body.terminator, InsnKind::Branch);
+        assert_eq!((body.taken, body.not_taken), (2, 1));
+        assert_eq!(cfg.blocks[2].insns[0].text, "bl #0x2000");
+        assert_eq!(cfg.blocks[4].function.as_deref(), Some("helper"));
+    }
+
+    #[test]
+    fn edges_count_transitions_and_step_over_calls() {
+        let cfg = Cfg::build(&trace(), &symbols());
+        let edges: Vec<(u64, u64, EdgeKind, u64)> = cfg
+            .edges
+            .iter()
+            .map(|e| (e.from, e.to, e.kind, e.count))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                (0x1000, 0x1004, EdgeKind::Fallthrough, 1),
+                (0x1004, 0x1004, EdgeKind::Taken, 2),
+                (0x1004, 0x100c, EdgeKind::Fallthrough, 1),
+                (0x100c, 0x1010, EdgeKind::Call, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn function_graph_keeps_its_own_blocks() {
+        let cfg = Cfg::build(&trace(), &symbols());
+        assert_eq!(cfg.functions(), vec!["main", "helper"]);
+        let main = cfg.function("main");
+        assert_eq!(starts(&main), vec![0x1000, 0x1004, 0x100c, 0x1010]);
+        assert_eq!(main.edges.len(), 4);
+        assert_eq!(cfg.function("helper").edges, vec![]);
+        assert_eq!(cfg.function("missing"), Cfg::default());
+    }
+
+    #[test]
+    fn dot_weights_hot_edges() {
+        let cfg = Cfg::build(&trace(), &symbols()).function("main");
+        let mut out = Vec::new();
+        cfg.write(Format::Dot, &mut out).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("label=\"main\""));
+        assert!(dot.contains("taken 2 / not taken 1"));
+        assert!(
+            dot.contains("b1004 -> b1004 [label=\"2\", penwidth=5.0, weight=2, color=darkgreen];")
+        );
+        assert!(dot.contains(
+            "b100c -> b1010 [label=\"1\", penwidth=3.5, weight=1, color=gray40, style=dashed];"
+        ));
+    }
+
+    #[test]
+    fn json_lists_blocks_and_edges() {
+        let cfg = Cfg::build(&trace(), &symbols());
+        let mut out = Vec::new();
+        cfg.write(Format::Json, &mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["blocks"][1]["start"], 0x1004);
+        assert_eq!(json["blocks"][1]["taken"], 2);
+        assert_eq!(json["edges"][3]["kind"], "call");
+    }
+
+    #[test]
+    fn empty_trace_has_no_blocks() {
+        let db = TraceDb::new(":memory:").unwrap();
+        assert_eq!(Cfg::build(&db, &symbols()), Cfg::default());
+    }
+}
@@ -0,0 +1,267 @@
+//! Calling-convention violations.
+//!
+//! Every call is paired with the return that ends it. Across the pair the
+//! callee-saved registers and SP must come back unchanged, and SP must be
+//! 16-byte aligned at the call itself on both arm64 (AAPCS64) and x86_64
+//! (System V and Apple). The registers the caller sees are the snapshot of
+//! the step after the return, so a return that is the last recorded step
+//! cannot be checked.
+
+use super::{Finding, FindingKind};
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+
+const X86_CALLEE_SAVED: &[&str] = &["rbx", "rbp", "r12", "r13", "r14", "r15"];
+const ARM64_CALLEE_SAVED: &[&str] = &[
+    "x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26", "x27", "x28", "fp",
+];
+
+/// A call that has not returned yet.
+struct Call {
+    step: u64,
+    pc: u64,
+    sp: u64,
+    /// Callee-saved register values when the call was made.
+    saved: Vec<(&'static str, u64)>,
+}
+
+/// A return whose effect is seen at the next step.
+struct Return {
+    step: u64,
+    pc: u64,
+    insn: String,
+    call: Call,
+}
+
+pub(super) fn check(db: &TraceDb, symbols: &SymbolTable) -> Result<Vec<Finding>, String> {
+    let Some((first, last)) = db.step_bounds() else {
+        return Ok(Vec::new());
+    };
+    let x86 = db.meta().arch == "x86_64";
+    let sp_reg = if x86 { "rsp" } else { "sp" };
+    let callee_saved = if x86 {
+        X86_CALLEE_SAVED
+    } else {
+        ARM64_CALLEE_SAVED
+    };
+    let name = |pc: u64| {
+        symbols
+            .describe(pc)
+            .unwrap_or_else(|| format!("0x{:x}", pc))
+    };
+
+    let mut calls: Vec<Call> = Vec::new();
+    let mut returned: Option<Return> = None;
+    let mut findings = Vec::new();
+
+    db.try_for_each_in(first, last, |e| -> Result<(), String> {
+        let regs: serde_json::Value = serde_json::from_str(&e.regs).unwrap_or_default();
+        let Some(sp) = regs[sp_reg].as_u64() else {
+            return Ok(());
+        };
+
+        if let Some(ret) = returned.take() {
+            let call = &ret.call;
+            let callee = symbols
+                .lookup(ret.pc)
+                .map_or_else(|| name(ret.pc), |(name, _)| name.to_string());
+            let mut report = |kind, detail| {
+                findings.push(Finding {
+                    kind,
+                    step: ret.step,
+                    pc: ret.pc,
+                    insn: ret.insn.clone(),
+                    function: symbols.describe(ret.pc),
+                    detail,
+                })
+            };
+            for &(reg, before) in &call.saved {
+                let Some(after) = regs[reg].as_u64().filter(|&after| after != before) else {
+                    continue;
+                };
+                report(
+                    FindingKind::CalleeSavedClobbered,
+                    format!(
+                        "{} was 0x{:x} at the call from {} at step {}, 0x{:x} after {} returned",
+                        reg,
+                        before,
+                        name(call.pc),
+                        call.step,
+                        after,
+                        callee
+                    ),
+                );
+            }
+            if sp != call.sp {
+                let (sign, diff) = if sp > call.sp {
+                    ('+', sp - call.sp)
+                } else {
+                    ('-', call.sp - sp)
+                };
+                report(
+                    FindingKind::StackImbalance,
+                    format!(
+                        "{} was 0x{:x} at the call from {} at step {}, 0x{:x} ({}0x{:x}) after {} returned",
+                        sp_reg,
+                        call.sp,
+                        name(call.pc),
+                        call.step,
+                        sp,
+                        sign,
+                        diff,
+                        callee
+                    ),
+                );
+            }
+        }
+
+        match e.kind {
+            InsnKind::Call => {
+                if sp % 16 != 0 {
+                    findings.push(Finding {
+                        kind: FindingKind::MisalignedCall,
+                        step: e.step,
+                        pc: e.pc,
+                        insn: e.insn_text.clone(),
+                        function: symbols.describe(e.pc),
+                        detail: format!(
+                            "{} is 0x{:x} at the call, {} bytes off a 16-byte boundary",
+                            sp_reg,
+                            sp,
+                            sp % 16
+                        ),
+                    });
+                }
+                calls.push(Call {
+                    step: e.step,
+                    pc: e.pc,
+                    sp,
+                    saved: callee_saved
+                        .iter()
+                        .filter_map(|&reg| Some((reg, regs[reg].as_u64()?)))
+                        .collect(),
+                });
+            }
+            // Returns from frames entered before the trace started have
+            // nothing to compare against.
+            InsnKind::Return => {
+                returned = calls.pop().map(|call| Return {
+                    step: e.step,
+                    pc: e.pc,
+                    insn: e.insn_text.clone(),
+                    call,
+                });
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, insn: &str, regs: serde_json::Value) -> TraceEntry {
+        TraceEntry {
+            regs: regs.to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    fn db(arch: &str, entries: Vec<TraceEntry>) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: arch.to_string(),
+            ..Default::default()
+        });
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![sym(0x1000, "main"), sym(0x2000, "work")])
+    }
+
+    /// main calls work and gets back the given registers after the return.
+    fn arm64_trace(sp: u64, after: serde_json::Value) -> Vec<TraceEntry> {
+        let regs = |sp: u64| serde_json::json!({ "sp": sp, "fp": 0x8040, "x19": 1, "x20": 2 });
+        vec![
+            entry(0, 0x1000, "bl #0x2000 ; CALL", regs(sp)),
+            entry(1, 0x2000, "sub sp, sp, #0x20", regs(sp)),
+            entry(2, 0x2004, "ret ; RETURN", regs(sp - 0x20)),
+            entry(3, 0x1004, "nop", after),
+        ]
+    }
+
+    #[test]
+    fn balanced_call_has_no_findings() {
+        let after = serde_json::json!({ "sp": 0x8000, "fp": 0x8040, "x19": 1, "x20": 2 });
+        let findings = check(&db("arm64", arm64_trace(0x8000, after)), &symbols()).unwrap();
+        assert!(findings.is_empty(), "{:?}", findings);
+    }
+
+    #[test]
+    fn clobbered_register_and_unbalanced_stack() {
+        let after = serde_json::json!({ "sp": 0x7fe0, "fp": 0x8040, "x19": 1, "x20": 9 });
+        let findings = check(&db("arm64", arm64_trace(0x8000, after)), &symbols()).unwrap();
+        let got: Vec<(FindingKind, u64, &str)> = findings
+            .iter()
+            .map(|f| (f.kind, f.step, f.detail.as_str()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (
+                    FindingKind::CalleeSavedClobbered,
+                    2,
+                    "x20 was 0x2 at the call from main at step 0, 0x9 after work returned"
+                ),
+                (
+                    FindingKind::StackImbalance,
+                    2,
+                    "sp was 0x8000 at the call from main at step 0, 0x7fe0 (-0x20) after work returned"
+                ),
+            ]
+        );
+        assert_eq!(findings[0].function.as_deref(), Some("work+0x4"));
+    }
+
+    #[test]
+    fn misaligned_call() {
+        let after = serde_json::json!({ "sp": 0x8008, "fp": 0x8040, "x19": 1, "x20": 2 });
+        let findings = check(&db("arm64", arm64_trace(0x8008, after)), &symbols()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!(findings[0].kind, FindingKind::MisalignedCall);
+        assert_eq!(findings[0].step, 0);
+        assert_eq!(
+            findings[0].detail,
+            "sp is 0x8008 at the call, 8 bytes off a 16-byte boundary"
+        );
+    }
+
+    #[test]
+    fn x86_callee_saved_registers() {
+        let regs = |rsp: u64, rbx: u64, rax: u64| serde_json::json!({ "rsp": rsp, "rbp": 0x8040, "rbx": rbx, "rax": rax });
+        let entries = vec![
+            entry(0, 0x1000, "call 0x2000 ; CALL", regs(0x8000, 1, 0)),
+            // Caller-saved rax may change; rbx may not.
+            entry(1, 0x2000, "ret ; RETURN", regs(0x7ff8, 1, 0)),
+            entry(2, 0x1005, "nop", regs(0x8000, 5, 3)),
+        ];
+        let findings = check(&db("x86_64", entries), &symbols()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!(findings[0].kind, FindingKind::CalleeSavedClobbered);
+        assert!(findings[0].detail.starts_with("rbx was 0x1"));
+    }
+}
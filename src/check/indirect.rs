@@ -0,0 +1,66 @@
+//! Indirect calls and jumps to code that doesn't look loaded: a target on
+//! the stack, or on a page nothing but an indirect transfer ever entered.
+//! The targets come from `stats::IndirectTargets`; each suspicious target
+//! of a site is reported once, at the first transfer there.
+
+use super::{Finding, FindingKind};
+use crate::stats::IndirectTargets;
+use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
+
+pub(super) fn check(db: &TraceDb, symbols: &SymbolTable) -> Result<Vec<Finding>, String> {
+    let targets = IndirectTargets::build(db, symbols);
+    Ok(targets
+        .suspicious()
+        .filter_map(|(site, target)| {
+            Some(Finding {
+                kind: FindingKind::SuspiciousIndirectTarget,
+                step: target.first_step,
+                pc: site.addr,
+                insn: site.insn.clone(),
+                function: site.function.clone(),
+                detail: format!(
+                    "went to {}, {} ({} time{})",
+                    target
+                        .function
+                        .clone()
+                        .unwrap_or_else(|| format!("0x{:x}", target.addr)),
+                    target.suspicious?.label(),
+                    target.count,
+                    if target.count == 1 { "" } else { "s" }
+                ),
+            })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+
+    fn entry(step: u64, pc: u64, insn: &str) -> TraceEntry {
+        TraceEntry {
+            regs: serde_json::json!({ "sp": 0x8000 }).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    #[test]
+    fn call_into_the_stack() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".to_string(),
+            ..Default::default()
+        });
+        db.insert(entry(0, 0x1000, "nop")).unwrap();
+        db.insert(entry(1, 0x1004, "blr x8 ; CALL")).unwrap();
+        db.insert(entry(2, 0x7ff0, "nop")).unwrap();
+        let findings = check(&db, &SymbolTable::default()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        let f = &findings[0];
+        assert_eq!(f.kind, FindingKind::SuspiciousIndirectTarget);
+        assert_eq!((f.step, f.pc, f.insn.as_str()), (1, 0x1004, "blr x8"));
+        assert_eq!(f.detail, "went to 0x7ff0, on the stack (1 time)");
+    }
+}
@@ -0,0 +1,126 @@
+//! Bug-finding passes over a recorded trace: things that went wrong while
+//! the program ran, found after the fact.
+
+mod abi;
+mod indirect;
+mod stack;
+mod uninit;
+
+use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+
+/// An analysis `tdb check` can run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// Saved return addresses and frame pointers overwritten before their
+    /// function returned, and returns that land somewhere unexpected.
+    Stack,
+    /// Loads from stack bytes that were never written since their frame
+    /// was allocated.
+    Uninit,
+    /// Callee-saved registers and SP not restored across a call, and calls
+    /// made with a misaligned stack.
+    Abi,
+    /// Indirect calls and jumps to the stack or to code that was never
+    /// loaded.
+    Indirect,
+}
+
+impl Check {
+    pub const ALL: &[Check] = &[Check::Stack, Check::Uninit, Check::Abi, Check::Indirect];
+}
+
+impl std::str::FromStr for Check {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "stack" => Ok(Check::Stack),
+            "uninit" => Ok(Check::Uninit),
+            "abi" => Ok(Check::Abi),
+            "indirect" => Ok(Check::Indirect),
+            _ => Err(format!(
+                "Unknown check '{}' (expected stack, uninit, abi, indirect)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FindingKind {
+    ReturnAddressOverwrite,
+    FramePointerOverwrite,
+    ReturnMismatch,
+    UninitializedRead,
+    CalleeSavedClobbered,
+    StackImbalance,
+    MisalignedCall,
+    SuspiciousIndirectTarget,
+}
+
+impl FindingKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            FindingKind::ReturnAddressOverwrite => "return address overwritten",
+            FindingKind::FramePointerOverwrite => "saved frame pointer overwritten",
+            FindingKind::ReturnMismatch => "return to unexpected address",
+            FindingKind::UninitializedRead => "read of uninitialized stack",
+            FindingKind::CalleeSavedClobbered => "callee-saved register not restored",
+            FindingKind::StackImbalance => "stack pointer not restored",
+            FindingKind::MisalignedCall => "call with misaligned stack",
+            FindingKind::SuspiciousIndirectTarget => "suspicious indirect target",
+        }
+    }
+}
+
+/// One problem, pinned to the instruction that caused it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub step: u64,
+    pub pc: u64,
+    pub insn: String,
+    /// Function containing the offending instruction.
+    pub function: Option<String>,
+    pub detail: String,
+}
+
+/// Run `checks` over the whole trace. Findings come back in step order.
+pub fn run(db: &TraceDb, symbols: &SymbolTable, checks: &[Check]) -> Result<Vec<Finding>, String> {
+    let mut findings = Vec::new();
+    for check in checks {
+        match check {
+            Check::Stack => findings.extend(stack::check(db, symbols)?),
+            Check::Uninit => findings.extend(uninit::check(db, symbols)?),
+            Check::Abi => findings.extend(abi::check(db, symbols)?),
+            Check::Indirect => findings.extend(indirect::check(db, symbols)?),
+        }
+    }
+    findings.sort_by_key(|f| f.step);
+    Ok(findings)
+}
+
+pub fn print(findings: &[Finding]) {
+    if findings.is_empty() {
+        println!("No problems found");
+        return;
+    }
+    for f in findings {
+        println!(
+            "step {:<8} 0x{:<14x} {:<28} {}",
+            f.step,
+            f.pc,
+            f.function.as_deref().unwrap_or("??"),
+            f.insn
+        );
+        println!("    {}: {}", f.kind.label(), f.detail);
+    }
+    println!(
+        "\n{} problem{} found",
+        findings.len(),
+        if findings.len() == 1 { "" } else { "s" }
+    );
+}
@@ -0,0 +1,472 @@
+//! Saved return addresses and frame pointers.
+//!
+//! Every call leaves its return address on the stack: pushed by the call on
+//! x86_64, stored by the callee's prologue on arm64, usually next to the
+//! caller's frame pointer. Those slots are found by value in the recorded
+//! stack memory and watched until the call returns. A recorded change to
+//! one of them in between is an overflow; a return that lands anywhere but
+//! the instruction after its call means a corrupted slot was used.
+
+use super::{Finding, FindingKind};
+use crate::deps::Decoder;
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
+/// How far before the step where an overwrite was seen to look for the
+/// store that made it. Stack bytes are sampled when SP moves or a store is
+/// about to run, so the writer is usually just a few steps earlier.
+const MAX_WRITER_DISTANCE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Slot {
+    ReturnAddress,
+    FramePointer,
+}
+
+struct Watched {
+    addr: u64,
+    slot: Slot,
+    value: u64,
+    /// Step from which the slot held `value`.
+    since: u64,
+}
+
+/// An open call.
+struct Frame {
+    call_step: u64,
+    function: Option<String>,
+    return_addr: u64,
+    caller_fp: u64,
+    /// SP at the call: whatever the callee saves lies below it.
+    sp: u64,
+    slots: Vec<Watched>,
+    /// The frame record is only looked for until the callee makes a call of
+    /// its own.
+    searching: bool,
+}
+
+impl Frame {
+    fn has(&self, slot: Slot) -> bool {
+        self.slots.iter().any(|w| w.slot == slot)
+    }
+}
+
+/// A watched slot that changed, before its writer is known.
+struct Overwrite {
+    seen: u64,
+    since: u64,
+    addr: u64,
+    slot: Slot,
+    old: u64,
+    new: u64,
+    victim: String,
+    call_step: u64,
+}
+
+pub(super) fn check(db: &TraceDb, symbols: &SymbolTable) -> Result<Vec<Finding>, String> {
+    let Some((first, last)) = db.step_bounds() else {
+        return Ok(Vec::new());
+    };
+    let meta = db.meta();
+    let x86 = meta.arch == "x86_64";
+    let (sp_reg, fp_reg) = if x86 { ("rsp", "rbp") } else { ("sp", "fp") };
+    let name = |pc: u64| {
+        symbols
+            .describe(pc)
+            .unwrap_or_else(|| format!("0x{:x}", pc))
+    };
+
+    let mut memory: HashMap<u64, u8> = meta.initial_memory.clone().into_iter().collect();
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut overwrites: Vec<Overwrite> = Vec::new();
+    let mut findings = Vec::new();
+    // The last RETURN, checked against where execution went next.
+    let mut returned: Option<(u64, u64, String, Frame)> = None;
+
+    db.try_for_each_in(first, last, |e| -> Result<(), String> {
+        let regs = OnceCell::new();
+        let reg = |name: &str| {
+            regs.get_or_init(|| {
+                serde_json::from_str::<serde_json::Value>(&e.regs).unwrap_or_default()
+            })[name]
+                .as_u64()
+        };
+
+        if let Some(frame) = frames.last_mut().filter(|f| f.function.is_none()) {
+            frame.function = Some(name(e.pc));
+        }
+        if let Some((step, pc, insn, frame)) = returned.take() {
+            if e.pc != frame.return_addr {
+                findings.push(Finding {
+                    kind: FindingKind::ReturnMismatch,
+                    step,
+                    pc,
+                    insn,
+                    function: frame.function,
+                    detail: format!(
+                        "returned to {}, expected 0x{:x} after the call at step {}",
+                        name(e.pc),
+                        frame.return_addr,
+                        frame.call_step
+                    ),
+                });
+            }
+        }
+
+        if !e.mem_changes.is_empty() {
+            // Frames the stack was unwound past (longjmp, exceptions) are gone.
+            if let Some(sp) = reg(sp_reg) {
+                while frames.last().is_some_and(|f| f.sp < sp) {
+                    frames.pop();
+                }
+            }
+            for c in &e.mem_changes {
+                memory.insert(c.addr, c.new_val);
+            }
+            for f in &mut frames {
+                f.slots.retain(|w| {
+                    let hit = e.mem_changes.iter().any(|c| {
+                        (w.addr..w.addr + 8).contains(&c.addr)
+                            && c.new_val != byte(w.value, c.addr - w.addr)
+                    });
+                    if hit {
+                        overwrites.push(Overwrite {
+                            seen: e.step,
+                            since: w.since,
+                            addr: w.addr,
+                            slot: w.slot,
+                            old: w.value,
+                            new: (0..8).rev().fold(0, |acc, i| {
+                                let b = memory.get(&(w.addr + i)).copied();
+                                acc << 8 | b.unwrap_or(byte(w.value, i)) as u64
+                            }),
+                            victim: f.function.clone().unwrap_or_default(),
+                            call_step: f.call_step,
+                        });
+                        // Reported once; whatever it holds now is not the
+                        // caller's any more.
+                        return false;
+                    }
+                    true
+                });
+            }
+        }
+
+        if let Some(f) = frames.last_mut().filter(|f| f.searching) {
+            let watch = |f: &mut Frame, addr, slot, value| {
+                f.slots.push(Watched {
+                    addr,
+                    slot,
+                    value,
+                    since: e.step,
+                });
+            };
+            // x86_64 calls push the return address right below the caller's
+            // SP, with or without a frame pointer.
+            if x86
+                && !f.has(Slot::ReturnAddress)
+                && holds(&memory, f.sp.wrapping_sub(8), f.return_addr)
+            {
+                watch(f, f.sp.wrapping_sub(8), Slot::ReturnAddress, f.return_addr);
+            }
+            // Once the prologue has set up the frame pointer, it points at a
+            // frame record: [saved fp, return address].
+            if let Some(fp) = reg(fp_reg).filter(|&fp| fp != f.caller_fp && fp < f.sp) {
+                if !f.has(Slot::ReturnAddress) && holds(&memory, fp + 8, f.return_addr) {
+                    watch(f, fp + 8, Slot::ReturnAddress, f.return_addr);
+                }
+                if f.caller_fp != 0 && !f.has(Slot::FramePointer) && holds(&memory, fp, f.caller_fp)
+                {
+                    watch(f, fp, Slot::FramePointer, f.caller_fp);
+                }
+            }
+            f.searching = !(f.has(Slot::ReturnAddress) && f.has(Slot::FramePointer));
+        }
+
+        match e.kind {
+            InsnKind::Call => {
+                if let Some(parent) = frames.last_mut() {
+                    parent.searching = false;
+                }
+                frames.push(Frame {
+                    call_step: e.step,
+                    function: None,
+                    return_addr: e.pc + e.insn_bytes.len() as u64,
+                    caller_fp: reg(fp_reg).unwrap_or(0),
+                    sp: reg(sp_reg).unwrap_or(0),
+                    slots: Vec::new(),
+                    searching: true,
+                });
+            }
+            InsnKind::Return => {
+                if let Some(frame) = frames.pop() {
+                    returned = Some((e.step, e.pc, e.insn_text.clone(), frame));
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    if overwrites.is_empty() {
+        return Ok(findings);
+    }
+    let decoder = Decoder::new(&meta.arch)?;
+    for o in overwrites {
+        // The latest store to the slot before the change was seen.
+        let lowest = o.since.max(o.seen.saturating_sub(MAX_WRITER_DISTANCE));
+        let writer = (lowest..o.seen).rev().filter_map(|s| db.get(s)).find(|w| {
+            decoder
+                .decode(w)
+                .stores
+                .iter()
+                .any(|&(addr, len)| addr < o.addr + 8 && o.addr < addr + len)
+        });
+        let found = writer.is_some();
+        let Some(w) = writer.or_else(|| db.get(o.seen)) else {
+            continue;
+        };
+        let (kind, what) = match o.slot {
+            Slot::ReturnAddress => (FindingKind::ReturnAddressOverwrite, "return address"),
+            Slot::FramePointer => (FindingKind::FramePointerOverwrite, "saved frame pointer"),
+        };
+        findings.push(Finding {
+            kind,
+            step: w.step,
+            pc: w.pc,
+            insn: w.insn_text.clone(),
+            function: symbols.describe(w.pc),
+            detail: format!(
+                "{} of {} (called at step {}) at 0x{:x} changed from 0x{:x} to 0x{:x}{}",
+                what,
+                o.victim,
+                o.call_step,
+                o.addr,
+                o.old,
+                o.new,
+                if found {
+                    ""
+                } else {
+                    " (store not found; seen here)"
+                }
+            ),
+        });
+    }
+    Ok(findings)
+}
+
+/// Whether the 8 bytes at `addr` hold `value`, little-endian. Only bytes
+/// that ever changed are recorded, so the others (typically the zero top
+/// bytes of a pointer) are assumed to match, as long as one byte is known.
+fn holds(memory: &HashMap<u64, u8>, addr: u64, value: u64) -> bool {
+    let mut known = false;
+    for i in 0..8 {
+        if let Some(&b) = memory.get(&(addr + i)) {
+            if b != byte(value, i) {
+                return false;
+            }
+            known = true;
+        }
+    }
+    known
+}
+
+fn byte(value: u64, i: u64) -> u8 {
+    (value >> (8 * i)) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemChange, TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, bytes: &[u8], insn: &str, regs: serde_json::Value) -> TraceEntry {
+        TraceEntry {
+            insn_bytes: bytes.to_vec(),
+            regs: regs.to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    /// Byte changes storing `new` over `old` at `addr`.
+    fn store(addr: u64, old: u64, new: u64) -> Vec<MemChange> {
+        (0..8)
+            .map(|i| MemChange {
+                addr: addr + i,
+                old_val: (old >> (8 * i)) as u8,
+                new_val: (new >> (8 * i)) as u8,
+            })
+            .filter(|c| c.old_val != c.new_val)
+            .collect()
+    }
+
+    fn db(arch: &str, entries: Vec<TraceEntry>) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: arch.to_string(),
+            ..Default::default()
+        });
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![sym(0x1000, "main"), sym(0x2000, "work")])
+    }
+
+    /// main calls work, whose prologue saves fp and lr at 0x7ff0; work then
+    /// stores `x1` to `x0` and returns.
+    fn arm64_trace(x0: u64, x1: u64) -> Vec<TraceEntry> {
+        let regs = |sp: u64, fp: u64| serde_json::json!({ "sp": sp, "fp": fp, "x0": x0, "x1": x1 });
+        let mut e = vec![
+            entry(
+                0,
+                0x1000,
+                &[0x00, 0x04, 0x00, 0x94],
+                "bl #0x2000 ; CALL",
+                regs(0x8000, 0x8040),
+            ),
+            entry(
+                1,
+                0x2000,
+                &[0xfd, 0x7b, 0xbf, 0xa9],
+                "stp x29, x30, [sp, #-0x10]!",
+                regs(0x8000, 0x8040),
+            ),
+            entry(
+                2,
+                0x2004,
+                &[0xfd, 0x03, 0x00, 0x91],
+                "mov x29, sp",
+                regs(0x7ff0, 0x8040),
+            ),
+            entry(
+                3,
+                0x2008,
+                &[0x01, 0x00, 0x00, 0xf9],
+                "str x1, [x0]",
+                regs(0x7ff0, 0x7ff0),
+            ),
+            entry(
+                4,
+                0x200c,
+                &[0xc0, 0x03, 0x5f, 0xd6],
+                "ret ; RETURN",
+                regs(0x7ff0, 0x7ff0),
+            ),
+        ];
+        e[2].mem_changes = [store(0x7ff0, 0, 0x8040), store(0x7ff8, 0, 0x1004)].concat();
+        e
+    }
+
+    #[test]
+    fn clean_call_has_no_findings() {
+        let mut entries = arm64_trace(0x7fe0, 7);
+        entries[4].mem_changes = store(0x7fe0, 0, 7);
+        entries.push(entry(
+            5,
+            0x1004,
+            &[0x1f, 0x20, 0x03, 0xd5],
+            "nop",
+            serde_json::json!({}),
+        ));
+        let findings = check(&db("arm64", entries), &symbols()).unwrap();
+        assert!(findings.is_empty(), "{:?}", findings);
+    }
+
+    #[test]
+    fn arm64_return_address_overwrite() {
+        let smash = 0x4141414141414141;
+        let mut entries = arm64_trace(0x7ff8, smash);
+        entries[4].mem_changes = store(0x7ff8, 0x1004, smash);
+        entries.push(entry(
+            5,
+            smash,
+            &[0x1f, 0x20, 0x03, 0xd5],
+            "nop",
+            serde_json::json!({}),
+        ));
+        let findings = check(&db("arm64", entries), &symbols()).unwrap();
+
+        assert_eq!(findings.len(), 2, "{:?}", findings);
+        let overwrite = findings
+            .iter()
+            .find(|f| f.kind == FindingKind::ReturnAddressOverwrite)
+            .unwrap();
+        // Blamed on the store, not on the step where the change was seen.
+        assert_eq!(overwrite.step, 3);
+        assert_eq!(overwrite.function.as_deref(), Some("work+0x8"));
+        assert!(overwrite.detail.contains("0x4141414141414141"));
+        assert!(overwrite.detail.contains("of work (called at step 0)"));
+
+        let ret = findings
+            .iter()
+            .find(|f| f.kind == FindingKind::ReturnMismatch)
+            .unwrap();
+        assert_eq!(ret.step, 4);
+        assert!(ret.detail.contains("expected 0x1004"));
+    }
+
+    #[test]
+    fn arm64_frame_pointer_overwrite() {
+        let mut entries = arm64_trace(0x7ff0, 0);
+        entries[4].mem_changes = store(0x7ff0, 0x8040, 0);
+        entries.push(entry(
+            5,
+            0x1004,
+            &[0x1f, 0x20, 0x03, 0xd5],
+            "nop",
+            serde_json::json!({}),
+        ));
+        let findings = check(&db("arm64", entries), &symbols()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert_eq!(findings[0].kind, FindingKind::FramePointerOverwrite);
+        assert_eq!(findings[0].step, 3);
+    }
+
+    #[test]
+    fn x86_return_address_pushed_by_call() {
+        let regs = |rsp: u64| serde_json::json!({ "rsp": rsp, "rbp": 0, "rax": 0xdead });
+        let mut entries = vec![
+            entry(
+                0,
+                0x1000,
+                &[0xe8, 0xfb, 0x0f, 0x00, 0x00],
+                "call 0x2000 ; CALL",
+                regs(0x8000),
+            ),
+            entry(
+                1,
+                0x2000,
+                &[0x48, 0x89, 0x04, 0x24],
+                "mov qword ptr [rsp], rax",
+                regs(0x7ff8),
+            ),
+            entry(2, 0x2004, &[0xc3], "ret ; RETURN", regs(0x7ff8)),
+            entry(3, 0xdead, &[0x90], "nop", regs(0x8000)),
+        ];
+        entries[1].mem_changes = store(0x7ff8, 0, 0x1005);
+        entries[2].mem_changes = store(0x7ff8, 0x1005, 0xdead);
+        let findings = check(&db("x86_64", entries), &symbols()).unwrap();
+        let mut kinds: Vec<(FindingKind, u64)> =
+            findings.iter().map(|f| (f.kind, f.step)).collect();
+        kinds.sort_by_key(|&(_, step)| step);
+        assert_eq!(
+            kinds,
+            vec![
+                (FindingKind::ReturnAddressOverwrite, 1),
+                (FindingKind::ReturnMismatch, 2)
+            ]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! Reads of stack memory that was never written.
+//!
+//! Lowering SP allocates stack bytes; they hold whatever an earlier frame
+//! left there until a store writes them. Loads are decoded from the trace
+//! with the registers recorded at each step, so every access is checked,
+//! not just the sampled stack bytes. The first load of each never-written
+//! byte is reported; the byte then counts as written so a loop reading it
+//! is only reported once.
+
+use super::{Finding, FindingKind};
+use crate::deps::Decoder;
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use std::collections::BTreeMap;
+
+/// Larger SP drops (stack probes, big alloca) are not tracked byte by byte.
+const MAX_ALLOCATION: u64 = 1 << 20;
+
+/// Where never-written bytes came from.
+#[derive(Debug, Clone)]
+struct Allocation {
+    step: u64,
+    function: String,
+    /// SP before the call that entered the allocating function (DWARF's
+    /// canonical frame address), if that call was recorded.
+    cfa: Option<u64>,
+}
+
+/// A step whose effects are applied at the next one.
+struct Pending {
+    step: u64,
+    /// SP before the step ran.
+    sp: u64,
+    function: String,
+    stores: Vec<(u64, u64)>,
+}
+
+pub(super) fn check(db: &TraceDb, symbols: &SymbolTable) -> Result<Vec<Finding>, String> {
+    let Some((first, last)) = db.step_bounds() else {
+        return Ok(Vec::new());
+    };
+    let arch = db.meta().arch;
+    let x86 = arch == "x86_64";
+    let sp_reg = if x86 { "rsp" } else { "sp" };
+    let decoder = Decoder::new(&arch)?;
+    let name = |pc: u64| {
+        symbols
+            .lookup(pc)
+            .map_or_else(|| format!("0x{:x}", pc), |(name, _)| name.to_string())
+    };
+
+    // Allocated bytes not written yet, and what allocated them.
+    let mut unwritten: BTreeMap<u64, Allocation> = BTreeMap::new();
+    // SP before each open call.
+    let mut cfas: Vec<u64> = Vec::new();
+    // Stores of the previous step, applied once the next step shows where
+    // SP ended up, so `stp x29, x30, [sp, #-16]!` and `push` allocate and
+    // write in one go.
+    let mut pending: Option<Pending> = None;
+    let mut findings = Vec::new();
+
+    db.try_for_each_in(first, last, |e| -> Result<(), String> {
+        let regs: serde_json::Value = serde_json::from_str(&e.regs).unwrap_or_default();
+        let Some(sp) = regs[sp_reg].as_u64() else {
+            return Ok(());
+        };
+        if let Some(prev) = pending.take() {
+            if sp < prev.sp && prev.sp - sp <= MAX_ALLOCATION {
+                let alloc = Allocation {
+                    step: prev.step,
+                    function: prev.function,
+                    cfa: cfas.last().copied(),
+                };
+                for addr in sp..prev.sp {
+                    unwritten.insert(addr, alloc.clone());
+                }
+            }
+            // Below SP is free again.
+            unwritten = unwritten.split_off(&sp);
+            for (addr, len) in prev.stores {
+                for a in addr..addr.saturating_add(len) {
+                    unwritten.remove(&a);
+                }
+            }
+        }
+
+        let access = decoder.decode(e);
+        for &(addr, len) in &access.loads {
+            let end = addr.saturating_add(len);
+            let bytes: Vec<u64> = unwritten.range(addr..end).map(|(&a, _)| a).collect();
+            let Some(&first_byte) = bytes.first() else {
+                continue;
+            };
+            let alloc = unwritten[&first_byte].clone();
+            for a in &bytes {
+                unwritten.remove(a);
+            }
+            // ldp x0, x1, [sp]: the first register gets the low bytes.
+            let dests: Vec<&String> = access
+                .writes
+                .iter()
+                .filter(|r| !matches!(r.as_str(), "sp" | "rsp" | "cpsr" | "rflags"))
+                .collect();
+            let into = match dests.len() {
+                0 => String::new(),
+                n => {
+                    let width = (len / n as u64).max(1);
+                    let reg = dests[(((first_byte - addr) / width) as usize).min(n - 1)];
+                    format!(" into {}", reg)
+                }
+            };
+            let place = match alloc.cfa {
+                Some(cfa) if cfa >= first_byte => format!("CFA-0x{:x}", cfa - first_byte),
+                _ => format!("sp+0x{:x}", first_byte - sp),
+            };
+            findings.push(Finding {
+                kind: FindingKind::UninitializedRead,
+                step: e.step,
+                pc: e.pc,
+                insn: e.insn_text.clone(),
+                function: symbols.describe(e.pc),
+                detail: format!(
+                    "{} of {} bytes at 0x{:x} ({}) loaded{}, never written since {} allocated them at step {}",
+                    bytes.len(),
+                    len,
+                    first_byte,
+                    place,
+                    into,
+                    alloc.function,
+                    alloc.step
+                ),
+            });
+        }
+
+        match e.kind {
+            InsnKind::Call => cfas.push(sp),
+            InsnKind::Return => {
+                cfas.pop();
+            }
+            _ => {}
+        }
+        pending = Some(Pending {
+            step: e.step,
+            sp,
+            function: name(e.pc),
+            stores: access.stores,
+        });
+        Ok(())
+    })?;
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, bytes: &[u8], insn: &str, sp: u64) -> TraceEntry {
+        TraceEntry {
+            insn_bytes: bytes.to_vec(),
+            regs: serde_json::json!({ "sp": sp, "fp": 0, "x0": 0, "x1": 0 }).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    fn db(entries: Vec<TraceEntry>) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".to_string(),
+            ..Default::default()
+        });
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![
+            Symbol {
+                addr: 0x1000,
+                size: 0x100,
+                name: "main".to_string(),
+            },
+            Symbol {
+                addr: 0x2000,
+                size: 0x100,
+                name: "work".to_string(),
+            },
+        ])
+    }
+
+    const BL: &[u8] = &[0x00, 0x04, 0x00, 0x94]; // bl #0x2000 (from 0x1000)
+    const SUB: &[u8] = &[0xff, 0x83, 0x00, 0xd1]; // sub sp, sp, #0x20
+    const STR_X1: &[u8] = &[0xe1, 0x07, 0x00, 0xf9]; // str x1, [sp, #8]
+    const LDR_X0_8: &[u8] = &[0xe0, 0x07, 0x40, 0xf9]; // ldr x0, [sp, #8]
+    const LDR_X0_16: &[u8] = &[0xe0, 0x0b, 0x40, 0xf9]; // ldr x0, [sp, #0x10]
+    const LDP: &[u8] = &[0xe0, 0x07, 0x41, 0xa9]; // ldp x0, x1, [sp, #0x10]
+
+    /// main calls work, which allocates 0x20 bytes and writes sp+8 only.
+    fn prologue() -> Vec<TraceEntry> {
+        vec![
+            entry(0, 0x1000, BL, "bl #0x2000 ; CALL", 0x8000),
+            entry(1, 0x2000, SUB, "sub sp, sp, #0x20", 0x8000),
+            entry(2, 0x2004, STR_X1, "str x1, [sp, #8]", 0x7fe0),
+        ]
+    }
+
+    #[test]
+    fn written_bytes_are_clean() {
+        let mut entries = prologue();
+        entries.push(entry(3, 0x2008, LDR_X0_8, "ldr x0, [sp, #8]", 0x7fe0));
+        let findings = check(&db(entries), &symbols()).unwrap();
+        assert!(findings.is_empty(), "{:?}", findings);
+    }
+
+    #[test]
+    fn read_of_unwritten_slot() {
+        let mut entries = prologue();
+        entries.push(entry(3, 0x2008, LDR_X0_16, "ldr x0, [sp, #0x10]", 0x7fe0));
+        entries.push(entry(4, 0x200c, LDR_X0_16, "ldr x0, [sp, #0x10]", 0x7fe0));
+        let findings = check(&db(entries), &symbols()).unwrap();
+        // Reported once, at the first read.
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        let f = &findings[0];
+        assert_eq!(f.kind, FindingKind::UninitializedRead);
+        assert_eq!(f.step, 3);
+        assert_eq!(f.function.as_deref(), Some("work+0x8"));
+        assert_eq!(
+            f.detail,
+            "8 of 8 bytes at 0x7ff0 (CFA-0x10) loaded into x0, never written since work allocated them at step 1"
+        );
+    }
+
+    #[test]
+    fn pair_load_names_the_register() {
+        let mut entries = prologue();
+        entries[2] = entry(
+            2,
+            0x2004,
+            &[0xe1, 0x0b, 0x00, 0xf9],
+            "str x1, [sp, #0x10]",
+            0x7fe0,
+        );
+        entries.push(entry(3, 0x2008, LDP, "ldp x0, x1, [sp, #0x10]", 0x7fe0));
+        let findings = check(&db(entries), &symbols()).unwrap();
+        assert_eq!(findings.len(), 1, "{:?}", findings);
+        assert!(findings[0]
+            .detail
+            .starts_with("8 of 16 bytes at 0x7ff8 (CFA-0x8) loaded into x1"));
+    }
+
+    #[test]
+    fn freed_stack_is_forgotten() {
+        let mut entries = prologue();
+        // add sp, sp, #0x20 then a read below SP is out of scope.
+        entries.push(entry(
+            3,
+            0x2008,
+            &[0xff, 0x83, 0x00, 0x91],
+            "add sp, sp, #0x20",
+            0x7fe0,
+        ));
+        entries.push(entry(4, 0x200c, LDR_X0_16, "ldr x0, [sp, #0x10]", 0x8000));
+        let findings = check(&db(entries), &symbols()).unwrap();
+        // sp+0x10 is now 0x8010, above everything work allocated.
+        assert!(findings.is_empty(), "{:?}", findings);
+    }
+}
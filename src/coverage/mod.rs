@@ -0,0 +1,416 @@
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::{LineTable, SymbolTable};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Drcov,
+    Lcov,
+    Text,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "drcov" => Ok(Format::Drcov),
+            "lcov" => Ok(Format::Lcov),
+            "text" => Ok(Format::Text),
+            _ => Err(format!(
+                "Unknown coverage format '{}' (expected drcov, lcov or text)",
+                s
+            )),
+        }
+    }
+}
+
+/// A straight-line run of instructions entered at `start`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub size: u64,
+    pub hits: u64,
+}
+
+/// Executed code of the traced binary, merged over one or more traces.
+///
+/// Addresses are stored as offsets from the start of the binary's image so
+/// traces recorded at different ASLR slides line up.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Executions per instruction.
+    pub insns: BTreeMap<u64, u64>,
+    /// Basic blocks by start offset.
+    pub blocks: BTreeMap<u64, Block>,
+    pub traces: usize,
+    /// Steps that ran outside the binary (dyld, system libraries).
+    pub outside: u64,
+    module: Option<String>,
+}
+
+/// Basic block being followed while walking a trace.
+struct OpenBlock {
+    start: u64,
+    next_pc: u64,
+    last_step: u64,
+    ended: bool,
+}
+
+impl Coverage {
+    /// Add the steps of `db`, whose binary is described by `symbols`.
+    pub fn add_trace(&mut self, db: &TraceDb, symbols: &SymbolTable) -> Result<(), String> {
+        match &self.module {
+            Some(path) if path != symbols.path() => {
+                return Err(format!(
+                    "Cannot merge coverage of {} with {}",
+                    symbols.path(),
+                    path
+                ));
+            }
+            _ => self.module = Some(symbols.path().to_string()),
+        }
+        // Without an image range every PC counts, relative to address 0.
+        let (base, end) = symbols.image_range().unwrap_or((0, u64::MAX));
+
+        let mut open: Option<OpenBlock> = None;
+        db.for_each_in(0, u64::MAX, |e| {
+            if !(base..end).contains(&e.pc) {
+                self.outside += 1;
+                if let Some(b) = open.take() {
+                    self.close_block(b, base);
+                }
+                return;
+            }
+            *self.insns.entry(e.pc - base).or_default() += 1;
+
+            let continues = open
+                .as_ref()
+                .is_some_and(|b| !b.ended && b.last_step + 1 == e.step && b.next_pc == e.pc);
+            if !continues {
+                if let Some(b) = open.take() {
+                    self.close_block(b, base);
+                }
+            }
+            let block = open.get_or_insert(OpenBlock {
+                start: e.pc,
+                next_pc: e.pc,
+                last_step: e.step,
+                ended: false,
+            });
+            block.next_pc = e.pc + e.insn_bytes.len().max(1) as u64;
+            block.last_step = e.step;
+            block.ended = matches!(e.kind, InsnKind::Branch | InsnKind::Call | InsnKind::Return);
+        });
+        if let Some(b) = open {
+            self.close_block(b, base);
+        }
+        self.traces += 1;
+        Ok(())
+    }
+
+    fn close_block(&mut self, b: OpenBlock, base: u64) {
+        let block = self
+            .blocks
+            .entry(b.start - base)
+            .or_insert(Block { size: 0, hits: 0 });
+        block.size = block.size.max(b.next_pc - b.start);
+        block.hits += 1;
+    }
+
+    /// Write the coverage in `format`. `symbols` locates the binary;
+    /// `lines` is only needed for lcov.
+    pub fn write(
+        &self,
+        format: Format,
+        symbols: &SymbolTable,
+        lines: Option<&LineTable>,
+        out: &mut impl Write,
+    ) -> Result<(), String> {
+        let text = match format {
+            Format::Drcov => self.drcov(symbols)?,
+            Format::Lcov => self.lcov(symbols, lines.ok_or("lcov needs debug info")?),
+            Format::Text => self.text(symbols),
+        };
+        out.write_all(&text)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+
+    /// drcov version 2, as read by Lighthouse and other DynamoRIO tools.
+    /// The module table only has the traced binary: traces don't record
+    /// which libraries were loaded, so blocks outside it are left out.
+    fn drcov(&self, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+        let (base, end) = symbols
+            .image_range()
+            .ok_or("drcov needs the traced binary (pass --binary)")?;
+        let mut out = format!(
+            "DRCOV VERSION: 2\n\
+             DRCOV FLAVOR: tdb\n\
+             Module Table: version 2, count 1\n\
+             Columns: id, base, end, entry, checksum, timestamp, path\n \
+             0, 0x{:016x}, 0x{:016x}, 0x0000000000000000, 0x00000000, 0x00000000, {}\n\
+             BB Table: {} bbs\n",
+            base,
+            end,
+            symbols.path(),
+            self.blocks.len()
+        )
+        .into_bytes();
+        for (&start, block) in &self.blocks {
+            out.extend_from_slice(&(start as u32).to_le_bytes());
+            out.extend_from_slice(&(block.size.min(u16::MAX as u64) as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+        }
+        Ok(out)
+    }
+
+    /// lcov tracefile with a hit count for every line in the line table, and
+    /// function records for symbols that have line info.
+    fn lcov(&self, symbols: &SymbolTable, lines: &LineTable) -> Vec<u8> {
+        #[derive(Default)]
+        struct FileCov {
+            lines: BTreeMap<u32, u64>,
+            functions: Vec<(u32, String, u64)>,
+        }
+
+        // Line rows use file addresses; instruction offsets are from the image start.
+        let (base, _) = symbols.image_range().unwrap_or((0, 0));
+        let file_base = base.wrapping_sub(symbols.slide());
+        let hits = |file_addr: u64| self.insns.get(&file_addr.wrapping_sub(file_base));
+
+        let mut files: BTreeMap<&str, FileCov> = BTreeMap::new();
+        for row in lines.rows() {
+            let cov = files.entry(&row.file).or_default();
+            // A line's count is that of its most executed instruction.
+            let count = self
+                .insns
+                .range(row.addr.wrapping_sub(file_base)..)
+                .take_while(|(&off, _)| off < row.addr.wrapping_sub(file_base) + row.len)
+                .map(|(_, &n)| n)
+                .max()
+                .unwrap_or(0);
+            let line = cov.lines.entry(row.line).or_default();
+            *line = (*line).max(count);
+        }
+        for sym in symbols.symbols() {
+            let file_addr = sym.addr.wrapping_sub(symbols.slide());
+            if let Some(row) = lines.lookup(file_addr) {
+                let count = hits(file_addr).copied().unwrap_or(0);
+                let cov = files.entry(&row.file).or_default();
+                cov.functions.push((row.line, sym.name, count));
+            }
+        }
+
+        let mut out = String::from("TN:\n");
+        for (file, cov) in files {
+            out += &format!("SF:{}\n", file);
+            for (line, name, _) in &cov.functions {
+                out += &format!("FN:{},{}\n", line, name);
+            }
+            for (_, name, count) in &cov.functions {
+                out += &format!("FNDA:{},{}\n", count, name);
+            }
+            let fn_hit = cov.functions.iter().filter(|f| f.2 > 0).count();
+            out += &format!("FNF:{}\nFNH:{}\n", cov.functions.len(), fn_hit);
+            for (line, count) in &cov.lines {
+                out += &format!("DA:{},{}\n", line, count);
+            }
+            let hit = cov.lines.values().filter(|&&n| n > 0).count();
+            out += &format!("LF:{}\nLH:{}\nend_of_record\n", cov.lines.len(), hit);
+        }
+        out.into_bytes()
+    }
+
+    /// Human-readable summary with per-function instruction coverage.
+    fn text(&self, symbols: &SymbolTable) -> Vec<u8> {
+        let (base, _) = symbols.image_range().unwrap_or((0, 0));
+        // (unique instructions hit, total executions) per function.
+        let mut functions: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+        for (&offset, &n) in &self.insns {
+            let name = symbols
+                .lookup(base + offset)
+                .map(|(name, _)| name.to_string())
+                .unwrap_or_else(|| "(unknown)".to_string());
+            let f = functions.entry(name).or_default();
+            f.0 += 1;
+            f.1 += n;
+        }
+        let total_functions = symbols.symbols().count();
+
+        let mut out = String::new();
+        out += "  Coverage\n";
+        out += "  --------\n";
+        out += &format!("  Traces:            {}\n", self.traces);
+        if let Some(path) = self.module.as_deref().filter(|p| !p.is_empty()) {
+            out += &format!("  Binary:            {}\n", path);
+        }
+        out += &format!("  Instructions hit:  {}\n", self.insns.len());
+        out += &format!("  Basic blocks hit:  {}\n", self.blocks.len());
+        if total_functions > 0 {
+            let hit = functions.keys().filter(|n| *n != "(unknown)").count();
+            out += &format!("  Functions hit:     {} / {}\n", hit, total_functions);
+        }
+        out += &format!("  Steps outside binary: {}\n", self.outside);
+
+        let mut by_insns: Vec<_> = functions.into_iter().collect();
+        by_insns.sort_by_key(|(_, (insns, _))| std::cmp::Reverse(*insns));
+        out += "\n  Function                          Insns hit  Executions\n";
+        for (name, (insns, execs)) in by_insns {
+            out += &format!("  {:<32} {:>10}  {:>10}\n", name, insns, execs);
+        }
+        out.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::entry;
+    use crate::symbols::{LineRow, Symbol};
+
+    /// A loop body at 0x1000..0x100c run twice, then a fall-through.
+    fn looping() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        let pcs = [
+            (0x1000, "mov x0, #1"),
+            (0x1004, "add x0, x0, #1"),
+            (0x1008, "b.ne #0x1000"),
+            (0x1000, "mov x0, #1"),
+            (0x1004, "add x0, x0, #1"),
+            (0x1008, "b.ne #0x1000"),
+            (0x100c, "ret ; RETURN [depth:0]"),
+        ];
+        for (step, (pc, insn)) in pcs.iter().enumerate() {
+            db.insert(entry(step as u64, *pc, insn)).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![Symbol {
+            addr: 0x1000,
+            size: 0x10,
+            name: "loop".to_string(),
+        }])
+    }
+
+    fn coverage_of(dbs: &[TraceDb]) -> Coverage {
+        let mut cov = Coverage::default();
+        for db in dbs {
+            cov.add_trace(db, &symbols()).unwrap();
+        }
+        cov
+    }
+
+    fn render(cov: &Coverage, format: Format, lines: Option<&LineTable>) -> Vec<u8> {
+        let mut out = Vec::new();
+        cov.write(format, &symbols(), lines, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn blocks_split_at_branches() {
+        let cov = coverage_of(&[looping()]);
+        assert_eq!(cov.blocks.get(&0x1000), Some(&Block { size: 12, hits: 2 }));
+        assert_eq!(cov.blocks.get(&0x100c), Some(&Block { size: 4, hits: 1 }));
+        assert_eq!(cov.blocks.len(), 2);
+        assert_eq!(cov.insns.get(&0x1004), Some(&2));
+    }
+
+    #[test]
+    fn gap_in_steps_starts_new_block() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(entry(0, 0x1000, "nop")).unwrap();
+        db.insert(entry(5, 0x1004, "nop")).unwrap();
+        let cov = coverage_of(&[db]);
+        assert_eq!(cov.blocks.len(), 2);
+    }
+
+    #[test]
+    fn merging_traces_adds_hits() {
+        let cov = coverage_of(&[looping(), looping()]);
+        assert_eq!(cov.traces, 2);
+        assert_eq!(cov.insns.get(&0x1000), Some(&4));
+        assert_eq!(cov.blocks[&0x1000].hits, 4);
+    }
+
+    #[test]
+    fn merging_different_binaries_fails() {
+        let mut cov = Coverage::default();
+        cov.add_trace(&looping(), &symbols()).unwrap();
+        let other = SymbolTable::load(std::env::current_exe().unwrap().to_str().unwrap()).unwrap();
+        assert!(cov.add_trace(&looping(), &other).is_err());
+    }
+
+    #[test]
+    fn drcov_without_binary_fails() {
+        let cov = coverage_of(&[looping()]);
+        let mut out = Vec::new();
+        let err = cov
+            .write(Format::Drcov, &symbols(), None, &mut out)
+            .unwrap_err();
+        assert!(err.contains("--binary"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn drcov_header_and_block_table() {
+        let exe = std::env::current_exe().unwrap();
+        let table = SymbolTable::load(exe.to_str().unwrap()).unwrap();
+        let (base, _) = table.image_range().unwrap();
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(entry(0, base + 0x40, "nop")).unwrap();
+        db.insert(entry(1, base + 0x44, "ret ; RETURN [depth:0]"))
+            .unwrap();
+        db.insert(entry(2, 0xdead_0000, "nop")).unwrap();
+        let mut cov = Coverage::default();
+        cov.add_trace(&db, &table).unwrap();
+        assert_eq!(cov.outside, 1);
+
+        let mut out = Vec::new();
+        cov.write(Format::Drcov, &table, None, &mut out).unwrap();
+        let header_end = out.windows(4).position(|w| w == b"bbs\n").unwrap() + 4;
+        let header = String::from_utf8_lossy(&out[..header_end]);
+        assert!(header.starts_with("DRCOV VERSION: 2\n"));
+        assert!(header.contains(&format!("0x{:016x}", base)));
+        assert!(header.contains("BB Table: 1 bbs"));
+        // start offset 0x40, size 8, module 0
+        assert_eq!(&out[header_end..], &[0x40, 0, 0, 0, 8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn lcov_counts_lines_and_functions() {
+        let cov = coverage_of(&[looping()]);
+        let row = |addr, len, line| LineRow {
+            addr,
+            len,
+            file: "loop.c".to_string(),
+            line,
+        };
+        let lines = LineTable::from_rows(vec![
+            row(0x1000, 8, 3),
+            row(0x1008, 4, 4),
+            row(0x100c, 4, 5),
+            row(0x1010, 4, 9),
+        ]);
+        let out = String::from_utf8(render(&cov, Format::Lcov, Some(&lines))).unwrap();
+        let expected = "TN:\nSF:loop.c\nFN:3,loop\nFNDA:2,loop\nFNF:1\nFNH:1\n\
+                        DA:3,2\nDA:4,2\nDA:5,1\nDA:9,0\nLF:4\nLH:3\nend_of_record\n";
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn text_lists_functions() {
+        let cov = coverage_of(&[looping()]);
+        let out = String::from_utf8(render(&cov, Format::Text, None)).unwrap();
+        assert!(out.contains("Instructions hit:  4"));
+        assert!(out.contains("Functions hit:     1 / 1"));
+        assert!(out.contains("loop"));
+    }
+
+    #[test]
+    fn format_parses() {
+        assert_eq!("lcov".parse::<Format>(), Ok(Format::Lcov));
+        assert!("gcov".parse::<Format>().is_err());
+    }
+}
@@ -0,0 +1,584 @@
+//! Registers and memory an instruction reads and writes, decoded again from
+//! its recorded bytes.
+//!
+//! x86_64 operands carry their access type in Capstone's detail; arm64 ones
+//! don't, so there the usual operand order is assumed: the first register
+//! is the destination and the rest are sources, except for stores,
+//! compares and branches, which only read. Memory addresses are computed
+//! from the register snapshot taken before the step ran.
+
+use crate::storage::TraceEntry;
+use capstone::arch::arm64::{Arm64CC, Arm64OperandType, Arm64Shift};
+use capstone::arch::x86::X86OperandType;
+use capstone::arch::ArchOperand;
+use capstone::prelude::*;
+use capstone::RegId;
+
+/// What one instruction reads and writes. Register names match the keys of
+/// the trace's register snapshots ("x0", "fp", "rax", "rflags", ...).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Access {
+    /// Registers whose values flow into the result.
+    pub reads: Vec<String>,
+    /// Registers only used to form a memory address.
+    pub addr_reads: Vec<String>,
+    pub writes: Vec<String>,
+    /// (address, length) ranges loaded and stored.
+    pub loads: Vec<(u64, u64)>,
+    pub stores: Vec<(u64, u64)>,
+}
+
+impl Access {
+    fn read(&mut self, reg: Option<String>) {
+        if let Some(reg) = reg {
+            if !self.reads.contains(&reg) {
+                self.reads.push(reg);
+            }
+        }
+    }
+
+    fn write(&mut self, reg: Option<String>) {
+        if let Some(reg) = reg {
+            if !self.writes.contains(&reg) {
+                self.writes.push(reg);
+            }
+        }
+    }
+
+    fn addr_read(&mut self, reg: Option<String>) {
+        if let Some(reg) = reg {
+            if !self.addr_reads.contains(&reg) {
+                self.addr_reads.push(reg);
+            }
+        }
+    }
+}
+
+pub struct Decoder {
+    cs: Capstone,
+    x86: bool,
+}
+
+impl Decoder {
+    /// Decoder for a trace recorded on `arch`; anything but "x86_64" is
+    /// taken to be arm64.
+    pub fn new(arch: &str) -> Result<Self, String> {
+        let x86 = arch == "x86_64";
+        let cs = if x86 {
+            Capstone::new()
+                .x86()
+                .mode(arch::x86::ArchMode::Mode64)
+                .detail(true)
+                .build()
+        } else {
+            Capstone::new()
+                .arm64()
+                .mode(arch::arm64::ArchMode::Arm)
+                .detail(true)
+                .build()
+        }
+        .map_err(|e| format!("Capstone init failed: {}", e))?;
+        Ok(Self { cs, x86 })
+    }
+
+    /// Decode the instruction of `entry`. Unknown instructions access
+    /// nothing.
+    pub fn decode(&self, entry: &TraceEntry) -> Access {
+        let Ok(insns) = self.cs.disasm_count(&entry.insn_bytes, entry.pc, 1) else {
+            return Access::default();
+        };
+        let Some(insn) = insns.iter().next() else {
+            return Access::default();
+        };
+        let Ok(detail) = self.cs.insn_detail(insn) else {
+            return Access::default();
+        };
+        let regs: serde_json::Value = serde_json::from_str(&entry.regs).unwrap_or_default();
+        let reg_value = |name: &str| regs[name].as_u64().unwrap_or(0);
+        let mnemonic = insn.mnemonic().unwrap_or("");
+        let name = |id: RegId| reg_name(&self.cs.reg_name(id)?, self.x86);
+
+        let mut access = Access::default();
+        for &id in detail.regs_read() {
+            access.read(name(id));
+        }
+        for &id in detail.regs_write() {
+            access.write(name(id));
+        }
+        let arch_detail = detail.arch_detail();
+        let operands = arch_detail.operands();
+        if self.x86 {
+            self.x86_operands(
+                &mut access,
+                mnemonic,
+                &operands,
+                insn.len() as u64,
+                entry,
+                &reg_value,
+            );
+        } else {
+            let arm = arch_detail.arm64().expect("arm64 detail");
+            let cc = arm.cc();
+            if arm.update_flags() {
+                access.write(Some("cpsr".to_string()));
+            }
+            if !matches!(
+                cc,
+                Arm64CC::ARM64_CC_INVALID | Arm64CC::ARM64_CC_AL | Arm64CC::ARM64_CC_NV
+            ) {
+                access.read(Some("cpsr".to_string()));
+            }
+            self.arm64_operands(
+                &mut access,
+                mnemonic,
+                &operands,
+                arm.writeback(),
+                &reg_value,
+            );
+        }
+        access
+    }
+
+    fn arm64_operands(
+        &self,
+        access: &mut Access,
+        mnemonic: &str,
+        operands: &[ArchOperand],
+        writeback: bool,
+        reg_value: &dyn Fn(&str) -> u64,
+    ) {
+        let name = |id: RegId| self.cs.reg_name(id).and_then(|n| arm64_reg(&n));
+        let mut data: Vec<(String, u64)> = Vec::new();
+        let mut mem = None;
+        for op in operands {
+            let ArchOperand::Arm64Operand(op) = op else {
+                continue;
+            };
+            match &op.op_type {
+                Arm64OperandType::Reg(id) => {
+                    let raw = self.cs.reg_name(*id).unwrap_or_default();
+                    data.push((name(*id).unwrap_or_default(), arm64_width(&raw)));
+                }
+                Arm64OperandType::Mem(m) => {
+                    let shift = match op.shift {
+                        Arm64Shift::Lsl(n) => n,
+                        _ => 0,
+                    };
+                    mem = Some((*m, shift));
+                }
+                _ => {}
+            }
+        }
+
+        let load = mnemonic.starts_with("ld");
+        let store = mnemonic.starts_with("st");
+        // stxr w1, x0, [x2]: the first register receives the status.
+        let status = store && (mnemonic.starts_with("stx") || mnemonic.starts_with("stlx"));
+        let read_only = store
+            || matches!(
+                mnemonic,
+                "cmp" | "cmn" | "tst" | "ccmp" | "ccmn" | "fcmp" | "fcmpe" | "fccmp"
+            )
+            || is_arm64_branch(mnemonic);
+        // Instructions that update only part of their destination.
+        let read_modify_write = matches!(mnemonic, "movk" | "bfi" | "bfxil" | "bfm" | "ins");
+
+        let first = 1.min(data.len());
+        let (dests, sources) = if load {
+            (&data[..], &data[..0])
+        } else if read_only && !status {
+            (&data[..0], &data[..])
+        } else {
+            data.split_at(first)
+        };
+        for (reg, _) in sources {
+            access.read(Some(reg.clone()).filter(|r| !r.is_empty()));
+        }
+        for (reg, _) in dests {
+            access.write(Some(reg.clone()).filter(|r| !r.is_empty()));
+            if read_modify_write {
+                access.read(Some(reg.clone()).filter(|r| !r.is_empty()));
+            }
+        }
+        if matches!(mnemonic, "bl" | "blr" | "blraa" | "blrab") {
+            access.write(Some("lr".to_string()));
+        }
+        if matches!(mnemonic, "svc") {
+            // Darwin returns syscall results in x0 and x1.
+            access.write(Some("x0".to_string()));
+            access.write(Some("x1".to_string()));
+        }
+
+        let Some((m, shift)) = mem else {
+            return;
+        };
+        let base = name(m.base());
+        let index = name(m.index());
+        let addr = base
+            .as_deref()
+            .map_or(0, reg_value)
+            .wrapping_add(index.as_deref().map_or(0, reg_value) << shift)
+            .wrapping_add(m.disp() as i64 as u64);
+        access.addr_read(base.clone());
+        access.addr_read(index);
+        if writeback {
+            access.write(base.clone());
+            access.read(base);
+        }
+        // The registers moved to or from memory, minus a store's status.
+        let moved = if status { &data[first..] } else { &data[..] };
+        let len: u64 = moved
+            .iter()
+            .map(|(_, width)| arm64_access_size(mnemonic).unwrap_or(*width))
+            .sum();
+        if len == 0 {
+            return;
+        }
+        if load {
+            access.loads.push((addr, len));
+        } else if store {
+            access.stores.push((addr, len));
+        }
+    }
+
+    fn x86_operands(
+        &self,
+        access: &mut Access,
+        mnemonic: &str,
+        operands: &[ArchOperand],
+        len: u64,
+        entry: &TraceEntry,
+        reg_value: &dyn Fn(&str) -> u64,
+    ) {
+        let name = |id: RegId| self.cs.reg_name(id).map(|n| x86_reg(&n));
+        let mut regs: Vec<(String, bool)> = Vec::new();
+        for op in operands {
+            let ArchOperand::X86Operand(op) = op else {
+                continue;
+            };
+            let (read, write) = match op.access {
+                Some(a) => (a.is_readable(), a.is_writable()),
+                None => (true, false),
+            };
+            match &op.op_type {
+                X86OperandType::Reg(id) => {
+                    let Some((reg, partial)) = name(*id) else {
+                        continue;
+                    };
+                    regs.push((reg.clone(), read));
+                    if write {
+                        access.write(Some(reg.clone()));
+                        // Writing al or ax keeps the rest of rax.
+                        if partial {
+                            access.read(Some(reg.clone()));
+                        }
+                    }
+                    if read {
+                        access.read(Some(reg));
+                    }
+                }
+                X86OperandType::Mem(m) => {
+                    if m.segment().0 != 0 {
+                        // fs/gs-relative: the segment base isn't recorded.
+                        continue;
+                    }
+                    let value = |id: RegId| match name(id) {
+                        Some((r, _)) if r == "rip" => entry.pc + len,
+                        Some((r, _)) => reg_value(&r),
+                        None => 0,
+                    };
+                    let addr = value(m.base())
+                        .wrapping_add(value(m.index()).wrapping_mul(m.scale() as u64))
+                        .wrapping_add(m.disp() as u64);
+                    let addr_regs = [name(m.base()), name(m.index())];
+                    if mnemonic == "lea" {
+                        // The address itself is the result.
+                        for (reg, _) in addr_regs.into_iter().flatten() {
+                            if reg != "rip" {
+                                access.read(Some(reg));
+                            }
+                        }
+                        continue;
+                    }
+                    for (reg, _) in addr_regs.into_iter().flatten() {
+                        if reg != "rip" {
+                            access.addr_read(Some(reg));
+                        }
+                    }
+                    if read {
+                        access.loads.push((addr, op.size as u64));
+                    }
+                    if write {
+                        access.stores.push((addr, op.size as u64));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // xor eax, eax and friends don't depend on the old value.
+        if matches!(mnemonic, "xor" | "sub" | "pxor" | "xorps" | "xorpd")
+            && regs.len() == 2
+            && regs[0].0 == regs[1].0
+        {
+            let zeroed = regs[0].0.clone();
+            access.reads.retain(|r| *r != zeroed);
+        }
+
+        // Implicit stack accesses.
+        let sp = reg_value("rsp");
+        match mnemonic {
+            "push" | "pushq" => {
+                let size = operands
+                    .first()
+                    .and_then(|op| match op {
+                        ArchOperand::X86Operand(op) => Some(op.size as u64),
+                        _ => None,
+                    })
+                    .unwrap_or(8);
+                access.stores.push((sp.wrapping_sub(size), size));
+            }
+            "call" | "callq" => access.stores.push((sp.wrapping_sub(8), 8)),
+            "pop" | "popq" => access.loads.push((sp, 8)),
+            "ret" | "retq" => access.loads.push((sp, 8)),
+            "syscall" => {
+                // Darwin returns syscall results in rax and rdx.
+                access.write(Some("rax".to_string()));
+                access.write(Some("rdx".to_string()));
+            }
+            _ => {}
+        }
+        // The stack pointer is bookkeeping for the above, not data.
+        if matches!(
+            mnemonic,
+            "push" | "pushq" | "pop" | "popq" | "call" | "callq" | "ret" | "retq"
+        ) {
+            access.reads.retain(|r| r != "rsp");
+            access.addr_read(Some("rsp".to_string()));
+        }
+    }
+}
+
+/// Trace name of a register typed by the user, None for zero registers.
+pub(super) fn reg_name(name: &str, x86: bool) -> Option<String> {
+    if x86 {
+        Some(x86_reg(name).0)
+    } else {
+        arm64_reg(name)
+    }
+}
+
+fn is_arm64_branch(m: &str) -> bool {
+    crate::stats::is_branch_mnemonic(m)
+        || matches!(
+            m,
+            "bl" | "blr" | "blraa" | "blrab" | "ret" | "retaa" | "retab"
+        )
+}
+
+/// Trace name of an arm64 register: w and x views share one name, SIMD
+/// views share "vN", and the zero registers are dropped.
+fn arm64_reg(name: &str) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    match name.as_str() {
+        "xzr" | "wzr" => return None,
+        "x29" | "w29" | "fp" => return Some("fp".to_string()),
+        "x30" | "w30" | "lr" => return Some("lr".to_string()),
+        "sp" | "wsp" => return Some("sp".to_string()),
+        "nzcv" => return Some("cpsr".to_string()),
+        _ => {}
+    }
+    let (prefix, num) = name.split_at(1);
+    if num.parse::<u32>().is_ok() {
+        return Some(match prefix {
+            "x" | "w" => format!("x{}", num),
+            "v" | "q" | "d" | "s" | "h" | "b" => format!("v{}", num),
+            _ => name.clone(),
+        });
+    }
+    Some(name)
+}
+
+/// Bytes held by an arm64 register view.
+fn arm64_width(name: &str) -> u64 {
+    match name.chars().next() {
+        Some('w') | Some('s') => 4,
+        Some('q') | Some('v') => 16,
+        Some('h') => 2,
+        Some('b') => 1,
+        _ => 8,
+    }
+}
+
+/// Bytes per register of loads and stores whose size isn't the register's.
+fn arm64_access_size(mnemonic: &str) -> Option<u64> {
+    if matches!(mnemonic, "ldraa" | "ldrab") {
+        // Pointer-authenticated loads, not byte loads.
+        None
+    } else if mnemonic.ends_with("sw") {
+        Some(4)
+    } else if mnemonic.ends_with('b') || mnemonic.ends_with("sb") {
+        Some(1)
+    } else if mnemonic.ends_with('h') || mnemonic.ends_with("sh") {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Trace name of an x86_64 register, and whether the name is an 8 or 16-bit
+/// view (whose writes keep the rest of the register).
+fn x86_reg(name: &str) -> (String, bool) {
+    const GPRS: [(&str, &str, &str, &str, &str); 8] = [
+        ("rax", "eax", "ax", "al", "ah"),
+        ("rbx", "ebx", "bx", "bl", "bh"),
+        ("rcx", "ecx", "cx", "cl", "ch"),
+        ("rdx", "edx", "dx", "dl", "dh"),
+        ("rsi", "esi", "si", "sil", ""),
+        ("rdi", "edi", "di", "dil", ""),
+        ("rbp", "ebp", "bp", "bpl", ""),
+        ("rsp", "esp", "sp", "spl", ""),
+    ];
+    let name = name.to_ascii_lowercase();
+    for (full, r32, r16, r8, r8h) in GPRS {
+        if name == full || name == r32 {
+            return (full.to_string(), false);
+        }
+        if name == r16 || name == r8 || (!r8h.is_empty() && name == r8h) {
+            return (full.to_string(), true);
+        }
+    }
+    if let Some(rest) = name.strip_prefix('r') {
+        let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+        if !digits.is_empty() {
+            let full = format!("r{}", digits);
+            return match &rest[digits.len()..] {
+                "" | "d" => (full, false),
+                _ => (full, true),
+            };
+        }
+    }
+    match name.as_str() {
+        "eflags" | "rflags" => ("rflags".to_string(), false),
+        "rip" | "eip" => ("rip".to_string(), false),
+        _ => match name.strip_prefix("ymm").or(name.strip_prefix("zmm")) {
+            Some(n) => (format!("xmm{}", n), false),
+            None => (name, false),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::InsnKind;
+
+    fn decode(arch: &str, bytes: &[u8], regs: serde_json::Value) -> Access {
+        let entry = TraceEntry {
+            step: 0,
+            pc: 0x1000,
+            insn_bytes: bytes.to_vec(),
+            insn_text: String::new(),
+            regs: regs.to_string(),
+            mem_changes: vec![],
+            kind: InsnKind::Other,
+            depth: 0,
+        };
+        Decoder::new(arch).unwrap().decode(&entry)
+    }
+
+    fn names(regs: &[&str]) -> Vec<String> {
+        regs.iter().map(|r| r.to_string()).collect()
+    }
+
+    #[test]
+    fn arm64_store_pair_with_writeback() {
+        // stp x29, x30, [sp, #-16]!
+        let a = decode(
+            "arm64",
+            &[0xfd, 0x7b, 0xbf, 0xa9],
+            serde_json::json!({"sp": 0x7010}),
+        );
+        assert_eq!(a.reads, names(&["fp", "lr", "sp"]));
+        assert_eq!(a.writes, names(&["sp"]));
+        assert_eq!(a.addr_reads, names(&["sp"]));
+        assert_eq!(a.stores, vec![(0x7000, 16)]);
+    }
+
+    #[test]
+    fn arm64_byte_load_and_flags() {
+        // ldrb w4, [x0]
+        let a = decode(
+            "arm64",
+            &[0x04, 0x00, 0x40, 0x39],
+            serde_json::json!({"x0": 0x5000}),
+        );
+        assert_eq!(a.writes, names(&["x4"]));
+        assert_eq!(a.loads, vec![(0x5000, 1)]);
+        assert!(a.reads.is_empty());
+
+        // cmp x0, #1
+        let a = decode("arm64", &[0x1f, 0x04, 0x00, 0xf1], serde_json::json!({}));
+        assert_eq!(a.reads, names(&["x0"]));
+        assert_eq!(a.writes, names(&["cpsr"]));
+
+        // csel x3, x1, x2, eq
+        let a = decode("arm64", &[0x23, 0x00, 0x82, 0x9a], serde_json::json!({}));
+        assert_eq!(a.writes, names(&["x3"]));
+        for r in ["x1", "x2", "cpsr"] {
+            assert!(a.reads.contains(&r.to_string()), "{:?}", a.reads);
+        }
+    }
+
+    #[test]
+    fn x86_memory_operands() {
+        // mov qword ptr [rsp + 8], rdi
+        let a = decode(
+            "x86_64",
+            &[0x48, 0x89, 0x7c, 0x24, 0x08],
+            serde_json::json!({"rsp": 0x7000}),
+        );
+        assert_eq!(a.reads, names(&["rdi"]));
+        assert_eq!(a.addr_reads, names(&["rsp"]));
+        assert_eq!(a.stores, vec![(0x7008, 8)]);
+
+        // lea rdi, [rip + 0x10]: the address is the value, nothing is loaded.
+        let a = decode(
+            "x86_64",
+            &[0x48, 0x8d, 0x3d, 0x10, 0x00, 0x00, 0x00],
+            serde_json::json!({}),
+        );
+        assert_eq!(a.writes, names(&["rdi"]));
+        assert!(a.loads.is_empty() && a.reads.is_empty());
+    }
+
+    #[test]
+    fn x86_implicit_and_partial_accesses() {
+        // push rbp
+        let a = decode("x86_64", &[0x55], serde_json::json!({"rsp": 0x7000}));
+        assert_eq!(a.reads, names(&["rbp"]));
+        assert_eq!(a.stores, vec![(0x6ff8, 8)]);
+        assert!(a.writes.contains(&"rsp".to_string()));
+
+        // xor eax, eax doesn't depend on rax.
+        let a = decode("x86_64", &[0x31, 0xc0], serde_json::json!({}));
+        assert_eq!(a.writes, names(&["rflags", "rax"]));
+        assert!(!a.reads.contains(&"rax".to_string()));
+
+        // mov al, 1 keeps the upper bytes of rax.
+        let a = decode("x86_64", &[0xb0, 0x01], serde_json::json!({}));
+        assert_eq!(a.writes, names(&["rax"]));
+        assert_eq!(a.reads, names(&["rax"]));
+    }
+
+    #[test]
+    fn register_names() {
+        assert_eq!(reg_name("w3", false).as_deref(), Some("x3"));
+        assert_eq!(reg_name("x30", false).as_deref(), Some("lr"));
+        assert_eq!(reg_name("q2", false).as_deref(), Some("v2"));
+        assert_eq!(reg_name("xzr", false), None);
+        assert_eq!(reg_name("r10d", true).as_deref(), Some("r10"));
+        assert_eq!(reg_name("sil", true).as_deref(), Some("rsi"));
+        assert_eq!(reg_name("eflags", true).as_deref(), Some("rflags"));
+    }
+}
@@ -0,0 +1,439 @@
+//! Backward data-flow slicing: which earlier steps produced a value.
+//!
+//! Starting from a register or memory range as it was before some step,
+//! the trace is walked backwards. The last step to write any needed
+//! location joins the slice, and what that step read becomes needed in
+//! turn, until every value is traced to a constant or to the state the
+//! trace started in.
+
+mod access;
+
+pub use access::{Access, Decoder};
+
+use crate::storage::TraceEntry;
+use std::collections::HashMap;
+
+/// Longest memory range a slice can start from. Each byte is tracked on
+/// its own.
+const MAX_MEM_LEN: u64 = 0x1000;
+
+/// The value whose origin is wanted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Reg(String),
+    Mem { addr: u64, len: u64 },
+}
+
+impl Target {
+    /// Parse "x0" / "eax" or "0x7000[:len]" (8 bytes by default). Register
+    /// views are named as in the trace: "w0" becomes "x0", "eax" "rax".
+    pub fn parse(s: &str, arch: &str) -> Result<Self, String> {
+        if let Some(hex) = s.strip_prefix("0x") {
+            let (addr, len) = match hex.split_once(':') {
+                Some((addr, len)) => (
+                    addr,
+                    len.parse()
+                        .map_err(|_| format!("Invalid length in '{}'", s))?,
+                ),
+                None => (hex, 8),
+            };
+            let addr =
+                u64::from_str_radix(addr, 16).map_err(|_| format!("Invalid address '{}'", s))?;
+            if len == 0 {
+                return Err(format!("Empty memory range '{}'", s));
+            }
+            if len > MAX_MEM_LEN || addr.checked_add(len).is_none() {
+                return Err(format!(
+                    "Memory range '{}' is longer than {:#x} bytes or past the end of memory",
+                    s, MAX_MEM_LEN
+                ));
+            }
+            return Ok(Target::Mem { addr, len });
+        }
+        access::reg_name(s, arch == "x86_64")
+            .map(Target::Reg)
+            .ok_or_else(|| format!("Invalid register '{}'", s))
+    }
+
+    fn locations(&self) -> Vec<Location> {
+        match self {
+            Target::Reg(r) => vec![Location::Reg(r.clone())],
+            Target::Mem { addr, len } => (0..*len)
+                .map(|i| Location::Byte(addr.saturating_add(i)))
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Reg(r) => write!(f, "{}", r),
+            Target::Mem { addr, len } => {
+                write!(f, "{}", mem_range(*addr, addr.saturating_add(*len)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DepOptions {
+    /// Also follow the registers used to form load and store addresses.
+    pub addresses: bool,
+}
+
+/// A value a step (or the target) depends on, and the step that produced
+/// it; None when it was already there when the trace started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    /// "x8" or "mem 0x7000..0x7008".
+    pub location: String,
+    pub from: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepNode {
+    pub step: u64,
+    pub pc: u64,
+    pub insn: String,
+    /// What this step wrote that later steps in the slice needed.
+    pub defines: Vec<String>,
+    /// Empty for constants.
+    pub inputs: Vec<Input>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepSlice {
+    pub target: Target,
+    pub step: u64,
+    /// Where the target's value came from.
+    pub sources: Vec<Input>,
+    /// Contributing steps, latest first.
+    pub nodes: Vec<DepNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Location {
+    Reg(String),
+    Byte(u64),
+}
+
+/// The backward slice of `target` as it was before `step` ran. `entries`
+/// must be in step order; `arch` is the trace's architecture.
+pub fn backward_slice(
+    entries: &[TraceEntry],
+    arch: &str,
+    step: u64,
+    target: &Target,
+    opts: &DepOptions,
+) -> Result<DepSlice, String> {
+    let end = entries
+        .binary_search_by_key(&step, |e| e.step)
+        .map_err(|_| format!("Step {} is not in the trace", step))?;
+    if let Target::Reg(r) = target {
+        let regs: serde_json::Value = serde_json::from_str(&entries[end].regs).unwrap_or_default();
+        if regs.get(r).is_none() {
+            return Err(format!("Register {} is not recorded in the trace", r));
+        }
+    }
+    let decoder = Decoder::new(arch)?;
+
+    // Needed location -> who needs it: 0 for the target, node index + 1
+    // for the nodes.
+    let mut live: HashMap<Location, Vec<usize>> = HashMap::new();
+    for loc in target.locations() {
+        live.insert(loc, vec![0]);
+    }
+    let mut found: Vec<Vec<(Location, Option<u64>)>> = vec![Vec::new()];
+    let mut nodes: Vec<(&TraceEntry, Vec<Location>)> = Vec::new();
+
+    for e in entries[..end].iter().rev() {
+        if live.is_empty() {
+            break;
+        }
+        let access = decoder.decode(e);
+        let defs = access
+            .writes
+            .iter()
+            .map(|r| Location::Reg(r.clone()))
+            .chain(bytes(&access.stores));
+        let mut defined = Vec::new();
+        for loc in defs {
+            if let Some(consumers) = live.remove(&loc) {
+                for c in consumers {
+                    found[c].push((loc.clone(), Some(e.step)));
+                }
+                defined.push(loc);
+            }
+        }
+        if defined.is_empty() {
+            continue;
+        }
+
+        let id = nodes.len() + 1;
+        nodes.push((e, defined));
+        found.push(Vec::new());
+        let addr_reads = if opts.addresses {
+            &access.addr_reads[..]
+        } else {
+            &[]
+        };
+        let inputs = access
+            .reads
+            .iter()
+            .chain(addr_reads)
+            .map(|r| Location::Reg(r.clone()))
+            .chain(bytes(&access.loads));
+        for loc in inputs {
+            let consumers = live.entry(loc).or_default();
+            if !consumers.contains(&id) {
+                consumers.push(id);
+            }
+        }
+    }
+    for (loc, consumers) in live {
+        for c in consumers {
+            found[c].push((loc.clone(), None));
+        }
+    }
+
+    let mut found = found.into_iter().map(coalesce);
+    let sources = found.next().unwrap_or_default();
+    let nodes = nodes
+        .into_iter()
+        .zip(found)
+        .map(|((e, defined), inputs)| DepNode {
+            step: e.step,
+            pc: e.pc,
+            insn: e.insn_text.clone(),
+            defines: coalesce(defined.into_iter().map(|l| (l, None)).collect())
+                .into_iter()
+                .map(|i| i.location)
+                .collect(),
+            inputs,
+        })
+        .collect();
+    Ok(DepSlice {
+        target: target.clone(),
+        step,
+        sources,
+        nodes,
+    })
+}
+
+impl DepSlice {
+    /// The latest step that wrote (part of) the target.
+    pub fn origin(&self) -> Option<u64> {
+        self.sources.iter().filter_map(|i| i.from).max()
+    }
+
+    pub fn print(&self) {
+        println!("Origin of {} before step {}:", self.target, self.step);
+        print_inputs(&self.sources);
+        for node in &self.nodes {
+            println!();
+            println!(
+                "  step {:<8} 0x{:<14x} {:<32} -> {}",
+                node.step,
+                node.pc,
+                node.insn,
+                node.defines.join(", ")
+            );
+            if node.inputs.is_empty() {
+                println!("      (constant)");
+            }
+            print_inputs(&node.inputs);
+        }
+
+        let constants = self.nodes.iter().filter(|n| n.inputs.is_empty()).count();
+        let initial = self
+            .nodes
+            .iter()
+            .flat_map(|n| &n.inputs)
+            .chain(&self.sources)
+            .filter(|i| i.from.is_none())
+            .count();
+        println!(
+            "\n{} steps in the slice, {} constant origins, {} values from before the trace",
+            self.nodes.len(),
+            constants,
+            initial
+        );
+    }
+}
+
+fn print_inputs(inputs: &[Input]) {
+    for input in inputs {
+        match input.from {
+            Some(step) => println!("      {} <- step {}", input.location, step),
+            None => println!("      {} <- before the trace", input.location),
+        }
+    }
+}
+
+fn bytes(ranges: &[(u64, u64)]) -> impl Iterator<Item = Location> + '_ {
+    ranges
+        .iter()
+        .flat_map(|&(addr, len)| (0..len).map(move |i| Location::Byte(addr.wrapping_add(i))))
+}
+
+/// Registers by name, then memory with adjacent bytes from the same step
+/// merged into ranges.
+fn coalesce(mut locs: Vec<(Location, Option<u64>)>) -> Vec<Input> {
+    locs.sort_by(|a, b| match (&a.0, &b.0) {
+        (Location::Byte(x), Location::Byte(y)) => (a.1, x).cmp(&(b.1, y)),
+        _ => a.0.cmp(&b.0),
+    });
+    let mut inputs: Vec<Input> = Vec::new();
+    let mut run: Option<(u64, u64, Option<u64>)> = None;
+    let flush = |run: &mut Option<(u64, u64, Option<u64>)>, inputs: &mut Vec<Input>| {
+        if let Some((start, end, from)) = run.take() {
+            inputs.push(Input {
+                location: mem_range(start, end),
+                from,
+            });
+        }
+    };
+    for (loc, from) in locs {
+        match loc {
+            Location::Reg(r) => inputs.push(Input { location: r, from }),
+            Location::Byte(addr) => match &mut run {
+                Some((_, end, f)) if *end == addr && *f == from => *end += 1,
+                _ => {
+                    flush(&mut run, &mut inputs);
+                    run = Some((addr, addr + 1, from));
+                }
+            },
+        }
+    }
+    flush(&mut run, &mut inputs);
+    inputs
+}
+
+fn mem_range(start: u64, end: u64) -> String {
+    if end - start == 1 {
+        format!("mem 0x{:x}", start)
+    } else {
+        format!("mem 0x{:x}..0x{:x}", start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        step: u64,
+        pc: u64,
+        bytes: [u8; 4],
+        insn: &str,
+        regs: serde_json::Value,
+    ) -> TraceEntry {
+        TraceEntry {
+            insn_bytes: bytes.to_vec(),
+            regs: regs.to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    /// x1 = 5; spill it to [sp + 8]; x2 = 7 (unrelated); reload into x8;
+    /// x0 = x8 + x9, where x9 comes from before the trace.
+    fn trace() -> Vec<TraceEntry> {
+        let regs = serde_json::json!({"x0": 0, "x1": 0, "x2": 0, "x8": 0, "x9": 3, "sp": 0x7000});
+        [
+            ([0xa1, 0x00, 0x80, 0xd2], "mov x1, #5"),
+            ([0xe1, 0x07, 0x00, 0xf9], "str x1, [sp, #8]"),
+            ([0xe2, 0x00, 0x80, 0xd2], "mov x2, #7"),
+            ([0xe8, 0x07, 0x40, 0xf9], "ldr x8, [sp, #8]"),
+            ([0x00, 0x01, 0x09, 0x8b], "add x0, x8, x9"),
+            ([0x1f, 0x20, 0x03, 0xd5], "nop"),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, (bytes, insn))| entry(i as u64, 0x1000 + 4 * i as u64, bytes, insn, regs.clone()))
+        .collect()
+    }
+
+    fn steps(slice: &DepSlice) -> Vec<u64> {
+        slice.nodes.iter().map(|n| n.step).collect()
+    }
+
+    #[test]
+    fn register_traced_through_memory() {
+        let target = Target::parse("w0", "arm64").unwrap();
+        let slice = backward_slice(&trace(), "arm64", 5, &target, &DepOptions::default()).unwrap();
+        assert_eq!(slice.origin(), Some(4));
+        assert_eq!(steps(&slice), vec![4, 3, 1, 0]);
+
+        let add = &slice.nodes[0];
+        assert_eq!(add.defines, vec!["x0"]);
+        assert_eq!(
+            add.inputs,
+            vec![
+                Input {
+                    location: "x8".into(),
+                    from: Some(3)
+                },
+                Input {
+                    location: "x9".into(),
+                    from: None
+                },
+            ]
+        );
+        let load = &slice.nodes[1];
+        assert_eq!(load.inputs[0].location, "mem 0x7008..0x7010");
+        assert_eq!(load.inputs[0].from, Some(1));
+        // mov x1, #5 has no inputs.
+        assert!(slice.nodes[3].inputs.is_empty());
+    }
+
+    #[test]
+    fn memory_target() {
+        let target = Target::parse("0x7008:4", "arm64").unwrap();
+        let slice = backward_slice(&trace(), "arm64", 3, &target, &DepOptions::default()).unwrap();
+        assert_eq!(slice.sources[0].location, "mem 0x7008..0x700c");
+        assert_eq!(steps(&slice), vec![1, 0]);
+    }
+
+    #[test]
+    fn address_registers_are_optional() {
+        let target = Target::Reg("x8".into());
+        let opts = DepOptions { addresses: true };
+        let slice = backward_slice(&trace(), "arm64", 4, &target, &opts).unwrap();
+        let load = &slice.nodes[0];
+        assert!(load
+            .inputs
+            .iter()
+            .any(|i| i.location == "sp" && i.from.is_none()));
+    }
+
+    #[test]
+    fn unknown_step_or_register_is_an_error() {
+        let target = Target::Reg("x0".into());
+        let opts = DepOptions::default();
+        assert!(backward_slice(&trace(), "arm64", 99, &target, &opts).is_err());
+        let bogus = Target::Reg("x99".into());
+        assert!(backward_slice(&trace(), "arm64", 2, &bogus, &opts).is_err());
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(
+            Target::parse("eax", "x86_64").unwrap(),
+            Target::Reg("rax".into())
+        );
+        assert_eq!(
+            Target::parse("x29", "arm64").unwrap(),
+            Target::Reg("fp".into())
+        );
+        assert_eq!(
+            Target::parse("0x10", "arm64").unwrap(),
+            Target::Mem { addr: 0x10, len: 8 }
+        );
+        assert!(Target::parse("0xzz", "arm64").is_err());
+        assert!(Target::parse("0x10:0", "arm64").is_err());
+        assert!(Target::parse("0x10:4097", "arm64").is_err());
+        assert!(Target::parse("0xfffffffffffffffc:8", "arm64").is_err());
+        assert!(Target::parse("0x10:4096", "arm64").is_ok());
+    }
+}
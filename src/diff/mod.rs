@@ -0,0 +1,691 @@
+//! Compare two recordings and find where they stop doing the same thing.
+//!
+//! The traces are walked step for step. While both execute the same
+//! instruction, registers and memory writes are compared; when the PCs
+//! differ, both sides skip ahead to the return from the function the
+//! divergence happened in and carry on from there if they land on the same
+//! instruction (otherwise the next frame out is tried). A divergence in the
+//! outermost function has no return to wait for, so the rest of both runs
+//! counts as executed by one side only.
+//!
+//! Only stack memory is recorded, so writes are compared by their offset
+//! from each run's initial stack pointer: stack ASLR moves both stacks as a
+//! whole.
+
+use crate::storage::{InsnKind, TraceDb, TraceEntry};
+use crate::symbols::SymbolTable;
+use std::collections::{HashMap, HashSet};
+
+/// Name used for code outside every known function.
+const UNKNOWN: &str = "(unknown)";
+
+/// One side of a comparison: a trace and the symbols of its program.
+#[derive(Clone, Copy)]
+pub struct Side<'a> {
+    pub db: &'a TraceDb,
+    pub symbols: &'a SymbolTable,
+}
+
+impl<'a> Side<'a> {
+    /// PC with the ASLR slide removed, so runs loaded at different
+    /// addresses still compare equal. Addresses outside the binary are
+    /// left alone.
+    fn pc(&self, pc: u64) -> u64 {
+        match self.symbols.image_range() {
+            Some((start, end)) if (start..end).contains(&pc) => {
+                pc.wrapping_sub(self.symbols.slide())
+            }
+            _ => pc,
+        }
+    }
+
+    fn function(&self, pc: u64) -> &'a str {
+        self.symbols.lookup(pc).map_or(UNKNOWN, |(name, _)| name)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Registers left out of the data comparison (e.g. `sp,fp` when the
+    /// stacks are at different addresses); "mem" leaves out memory writes.
+    pub ignore: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegDiff {
+    pub name: String,
+    pub a: u64,
+    pub b: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DivergenceKind {
+    /// The two runs went on to different instructions.
+    Control,
+    /// Same instruction, but registers that were equal now differ, or the
+    /// previous instruction wrote a different value to a stack byte that
+    /// matched until then.
+    Data { regs: Vec<RegDiff>, mem: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// First steps at which the difference is visible.
+    pub a_step: u64,
+    pub b_step: u64,
+    /// Function of the instruction that caused it.
+    pub function: String,
+    pub kind: DivergenceKind,
+}
+
+/// Where the comparison picked up again after a control divergence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resync {
+    pub a_step: u64,
+    pub b_step: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionDiff {
+    pub name: String,
+    /// Steps executed identically by both runs.
+    pub matched: u64,
+    pub data: u64,
+    pub control: u64,
+    /// Steps skipped while resynchronizing, or after the other trace ended.
+    pub only_a: u64,
+    pub only_b: u64,
+}
+
+impl FunctionDiff {
+    fn differs(&self) -> bool {
+        self.data + self.control + self.only_a + self.only_b > 0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceDiff {
+    pub steps_a: u64,
+    pub steps_b: u64,
+    pub matched: u64,
+    pub first_control: Option<Divergence>,
+    pub first_data: Option<Divergence>,
+    pub control_divergences: usize,
+    pub data_divergences: usize,
+    pub resyncs: Vec<Resync>,
+    /// Functions with at least one difference, most different first.
+    pub functions: Vec<FunctionDiff>,
+}
+
+impl TraceDiff {
+    pub fn is_identical(&self) -> bool {
+        self.control_divergences == 0
+            && self.data_divergences == 0
+            && self.matched == self.steps_a
+            && self.matched == self.steps_b
+    }
+
+    pub fn print(&self, a: Side, b: Side, context: u64) {
+        println!("  Trace Diff");
+        println!("  ----------");
+        println!(
+            "  Steps:               a {}, b {}",
+            self.steps_a, self.steps_b
+        );
+        println!("  Matched steps:       {}", self.matched);
+        println!(
+            "  Control divergences: {} ({} resynchronized)",
+            self.control_divergences,
+            self.resyncs.len()
+        );
+        println!("  Data divergences:    {}", self.data_divergences);
+        if self.is_identical() {
+            println!("\n  Traces are identical");
+            return;
+        }
+
+        if let Some(d) = &self.first_control {
+            println!(
+                "\n  First control divergence: a step {}, b step {}, in {}",
+                d.a_step, d.b_step, d.function
+            );
+            print_context(a, b, d, context);
+        }
+        if let Some(d) = &self.first_data {
+            println!(
+                "\n  First data divergence: a step {}, b step {}, in {}",
+                d.a_step, d.b_step, d.function
+            );
+            if let DivergenceKind::Data { regs, mem } = &d.kind {
+                for r in regs {
+                    println!("    {:<6} a 0x{:x}  b 0x{:x}", r.name, r.a, r.b);
+                }
+                if *mem {
+                    println!("    memory writes differ");
+                }
+            }
+            print_context(a, b, d, context);
+        }
+
+        if !self.functions.is_empty() {
+            println!("\n  Functions with differences:");
+            println!(
+                "    {:<32} {:>8} {:>6} {:>8} {:>9} {:>9}",
+                "Function", "Matched", "Data", "Control", "Only in a", "Only in b"
+            );
+            for f in &self.functions {
+                println!(
+                    "    {:<32} {:>8} {:>6} {:>8} {:>9} {:>9}",
+                    f.name, f.matched, f.data, f.control, f.only_a, f.only_b
+                );
+            }
+        }
+    }
+}
+
+/// The shared steps leading up to a divergence, then the next steps of each
+/// run.
+fn print_context(a: Side, b: Side, d: &Divergence, context: u64) {
+    if context == 0 {
+        return;
+    }
+    let line = |marker: &str, side: Side, e: &TraceEntry| {
+        let symbol = side.symbols.describe(e.pc).unwrap_or_default();
+        println!(
+            "    {:<2}{:>8}  0x{:012x}  {:<24} {}",
+            marker, e.step, e.pc, symbol, e.insn_text
+        );
+    };
+    for e in
+        a.db.get_range(d.a_step.saturating_sub(context), d.a_step.saturating_sub(1))
+    {
+        if e.step < d.a_step {
+            line("", a, &e);
+        }
+    }
+    for e in a.db.get_range(d.a_step, d.a_step + context - 1) {
+        line("a", a, &e);
+    }
+    for e in b.db.get_range(d.b_step, d.b_step + context - 1) {
+        line("b", b, &e);
+    }
+}
+
+/// Align `a` and `b` and collect every divergence between them.
+pub fn diff<'a>(a: Side<'a>, b: Side<'a>, opts: &DiffOptions) -> TraceDiff {
+    let ea = a.db.get_all();
+    let eb = b.db.get_all();
+    let ignore: HashSet<&str> = opts
+        .ignore
+        .iter()
+        .map(String::as_str)
+        .chain(["pc", "rip"])
+        .collect();
+    let compare_mem = !ignore.contains("mem");
+    let (base_a, base_b) = (base_sp(&ea), base_sp(&eb));
+
+    let mut result = TraceDiff {
+        steps_a: ea.len() as u64,
+        steps_b: eb.len() as u64,
+        ..Default::default()
+    };
+    // Keyed by names borrowed from the symbol tables, so the per-step
+    // bookkeeping does not allocate.
+    let mut functions: HashMap<&'a str, FunctionDiff> = HashMap::new();
+    // Registers currently known to differ, so a value that stays different
+    // is reported once rather than on every following step.
+    let mut differing: HashSet<String> = HashSet::new();
+    // Likewise for stack bytes, by offset from the initial SP.
+    let mut differing_mem: HashSet<u64> = HashSet::new();
+
+    let (mut i, mut j) = (0, 0);
+    while i < ea.len() && j < eb.len() {
+        // Divergences are blamed on the instruction before the one where
+        // they show up.
+        let cause = a.function(ea[i.saturating_sub(1)].pc);
+        if a.pc(ea[i].pc) == b.pc(eb[j].pc) {
+            stats(&mut functions, a.function(ea[i].pc)).matched += 1;
+            result.matched += 1;
+            let regs = new_reg_diffs(&ea[i], &eb[j], &ignore, &mut differing);
+            let mem =
+                compare_mem && new_mem_diffs(&ea[i], base_a, &eb[j], base_b, &mut differing_mem);
+            if !regs.is_empty() || mem {
+                stats(&mut functions, cause).data += 1;
+                result.data_divergences += 1;
+                result.first_data.get_or_insert_with(|| Divergence {
+                    a_step: ea[i].step,
+                    b_step: eb[j].step,
+                    function: cause.to_string(),
+                    kind: DivergenceKind::Data { regs, mem },
+                });
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+
+        stats(&mut functions, cause).control += 1;
+        result.control_divergences += 1;
+        result.first_control.get_or_insert_with(|| Divergence {
+            a_step: ea[i].step,
+            b_step: eb[j].step,
+            function: cause.to_string(),
+            kind: DivergenceKind::Control,
+        });
+
+        let (next_i, next_j) = resync(a, &ea, i, b, &eb, j).unwrap_or((ea.len(), eb.len()));
+        for e in &ea[i..next_i] {
+            stats(&mut functions, a.function(e.pc)).only_a += 1;
+        }
+        for e in &eb[j..next_j] {
+            stats(&mut functions, b.function(e.pc)).only_b += 1;
+        }
+        if next_i < ea.len() {
+            result.resyncs.push(Resync {
+                a_step: ea[next_i].step,
+                b_step: eb[next_j].step,
+            });
+        }
+        (i, j) = (next_i, next_j);
+    }
+    // Whatever is left ran in one trace only.
+    for e in &ea[i..] {
+        stats(&mut functions, a.function(e.pc)).only_a += 1;
+    }
+    for e in &eb[j..] {
+        stats(&mut functions, b.function(e.pc)).only_b += 1;
+    }
+
+    let mut functions: Vec<FunctionDiff> = functions
+        .into_values()
+        .filter(FunctionDiff::differs)
+        .collect();
+    functions.sort_by(|x, y| {
+        let weight = |f: &FunctionDiff| f.control + f.data + f.only_a + f.only_b;
+        weight(y).cmp(&weight(x)).then_with(|| x.name.cmp(&y.name))
+    });
+    result.functions = functions;
+    result
+}
+
+fn stats<'m, 'a>(
+    functions: &'m mut HashMap<&'a str, FunctionDiff>,
+    name: &'a str,
+) -> &'m mut FunctionDiff {
+    functions.entry(name).or_insert_with(|| FunctionDiff {
+        name: name.to_string(),
+        ..Default::default()
+    })
+}
+
+/// Call depth the instruction at `k` runs at.
+fn depth_at(entries: &[TraceEntry], k: usize) -> u64 {
+    match k.checked_sub(1) {
+        Some(prev) => entries[prev].depth,
+        None => {
+            let e = &entries[k];
+            match e.kind {
+                InsnKind::Call => e.depth.saturating_sub(1),
+                InsnKind::Return => e.depth + 1,
+                _ => e.depth,
+            }
+        }
+    }
+}
+
+/// After diverging at `i` / `j`, find the first pair of positions where both
+/// runs are back on the same instruction: just after returning from the
+/// diverging function, or from one of its callers. None at depth 0, where
+/// there is no caller to return to.
+fn resync(
+    a: Side,
+    ea: &[TraceEntry],
+    i: usize,
+    b: Side,
+    eb: &[TraceEntry],
+    j: usize,
+) -> Option<(usize, usize)> {
+    let mut level = depth_at(ea, i).min(depth_at(eb, j));
+    while level > 0 {
+        let ka = i + ea[i..].iter().position(|e| e.depth < level)? + 1;
+        let kb = j + eb[j..].iter().position(|e| e.depth < level)? + 1;
+        if ka < ea.len() && kb < eb.len() && a.pc(ea[ka].pc) == b.pc(eb[kb].pc) {
+            return Some((ka, kb));
+        }
+        level -= 1;
+    }
+    None
+}
+
+/// Registers that differ at this step but did not at the previous one.
+fn new_reg_diffs(
+    a: &TraceEntry,
+    b: &TraceEntry,
+    ignore: &HashSet<&str>,
+    differing: &mut HashSet<String>,
+) -> Vec<RegDiff> {
+    if a.regs == b.regs {
+        differing.clear();
+        return Vec::new();
+    }
+    let ra: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&a.regs).unwrap_or_default();
+    let rb: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&b.regs).unwrap_or_default();
+    let mut diffs = Vec::new();
+    for (name, va) in &ra {
+        if ignore.contains(name.as_str()) {
+            continue;
+        }
+        let (Some(va), Some(vb)) = (va.as_u64(), rb.get(name).and_then(|v| v.as_u64())) else {
+            continue;
+        };
+        if va == vb {
+            differing.remove(name);
+        } else if differing.insert(name.clone()) {
+            diffs.push(RegDiff {
+                name: name.clone(),
+                a: va,
+                b: vb,
+            });
+        }
+    }
+    diffs
+}
+
+/// Stack pointer before the first step, or 0 if the trace has none.
+fn base_sp(entries: &[TraceEntry]) -> u64 {
+    let Some(first) = entries.first() else {
+        return 0;
+    };
+    let regs: serde_json::Value = serde_json::from_str(&first.regs).unwrap_or_default();
+    regs.get("sp")
+        .or(regs.get("rsp"))
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0)
+}
+
+/// Whether a stack byte written at this step, compared by its offset from
+/// the base SP, now holds different values in the two runs when it did
+/// not before.
+fn new_mem_diffs(
+    a: &TraceEntry,
+    base_a: u64,
+    b: &TraceEntry,
+    base_b: u64,
+    differing: &mut HashSet<u64>,
+) -> bool {
+    let writes = |e: &TraceEntry, base: u64| -> HashMap<u64, u8> {
+        e.mem_changes
+            .iter()
+            .map(|m| (m.addr.wrapping_sub(base), m.new_val))
+            .collect()
+    };
+    let (wa, wb) = (writes(a, base_a), writes(b, base_b));
+    let mut new = false;
+    for offset in wa.keys().chain(wb.keys()) {
+        if wa.get(offset) == wb.get(offset) {
+            differing.remove(offset);
+        } else if differing.insert(*offset) {
+            new = true;
+        }
+    }
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemChange;
+    use crate::symbols::Symbol;
+
+    /// Build a trace from (pc, insn, x0) triples; depth follows the CALL /
+    /// RETURN annotations.
+    fn trace(steps: &[(u64, &str, u64)]) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        let mut depth = 0;
+        for (step, &(pc, insn, x0)) in steps.iter().enumerate() {
+            let kind = InsnKind::classify(insn);
+            match kind {
+                InsnKind::Call => depth += 1,
+                InsnKind::Return => depth -= 1,
+                _ => {}
+            }
+            db.insert(TraceEntry {
+                step: step as u64,
+                pc,
+                insn_bytes: vec![0x00],
+                insn_text: insn.to_string(),
+                regs: serde_json::json!({"x0": x0, "sp": 0x7000, "pc": pc}).to_string(),
+                mem_changes: vec![],
+                kind,
+                depth,
+            })
+            .unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, size, name: &str| Symbol {
+            addr,
+            size,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![
+            sym(0x1000, 0x100, "main"),
+            sym(0x2000, 0x100, "check"),
+        ])
+    }
+
+    fn run(a: &TraceDb, b: &TraceDb) -> TraceDiff {
+        let symbols = symbols();
+        diff(
+            Side {
+                db: a,
+                symbols: &symbols,
+            },
+            Side {
+                db: b,
+                symbols: &symbols,
+            },
+            &DiffOptions::default(),
+        )
+    }
+
+    const CALL: &str = "bl #0x2000 ; CALL";
+    const RET: &str = "ret ; RETURN";
+
+    #[test]
+    fn identical_traces() {
+        let steps = [(0x1000, "mov x0, #1", 1), (0x1004, "nop", 1)];
+        let d = run(&trace(&steps), &trace(&steps));
+        assert!(d.is_identical());
+        assert_eq!(d.matched, 2);
+        assert!(d.functions.is_empty());
+    }
+
+    #[test]
+    fn first_data_divergence_reports_new_register_once() {
+        let a = trace(&[
+            (0x1000, "ldr x0, [x1]", 0),
+            (0x1004, "nop", 5),
+            (0x1008, "nop", 5),
+        ]);
+        let b = trace(&[
+            (0x1000, "ldr x0, [x1]", 0),
+            (0x1004, "nop", 6),
+            (0x1008, "nop", 6),
+        ]);
+        let d = run(&a, &b);
+        assert_eq!(d.control_divergences, 0);
+        assert_eq!(d.data_divergences, 1);
+        let first = d.first_data.unwrap();
+        assert_eq!((first.a_step, first.b_step), (1, 1));
+        assert_eq!(first.function, "main");
+        assert_eq!(
+            first.kind,
+            DivergenceKind::Data {
+                regs: vec![RegDiff {
+                    name: "x0".to_string(),
+                    a: 5,
+                    b: 6
+                }],
+                mem: false
+            }
+        );
+    }
+
+    #[test]
+    fn ignored_registers_and_memory_writes() {
+        let a = trace(&[(0x1000, "nop", 1)]);
+        let b = trace(&[(0x1000, "nop", 2)]);
+        let symbols = symbols();
+        let side = |db| Side {
+            db,
+            symbols: &symbols,
+        };
+        let opts = DiffOptions {
+            ignore: vec!["x0".to_string()],
+        };
+        assert!(diff(side(&a), side(&b), &opts).is_identical());
+
+        b.insert(TraceEntry {
+            mem_changes: vec![MemChange {
+                addr: 0x7000,
+                old_val: 0,
+                new_val: 1,
+            }],
+            ..b.get(0).unwrap()
+        })
+        .unwrap();
+        let d = diff(side(&a), side(&b), &opts);
+        assert_eq!(
+            d.first_data.unwrap().kind,
+            DivergenceKind::Data {
+                regs: vec![],
+                mem: true
+            }
+        );
+    }
+
+    /// Record a write of `val` to `sp + offset` at every step, with the
+    /// stack at `sp`.
+    fn with_stack(db: &TraceDb, sp: u64, offset: i64, val: u8) {
+        for mut e in db.get_all() {
+            let mut regs: serde_json::Value = serde_json::from_str(&e.regs).unwrap();
+            regs["sp"] = sp.into();
+            e.regs = regs.to_string();
+            e.mem_changes = vec![MemChange {
+                addr: sp.wrapping_add_signed(offset),
+                old_val: 0,
+                new_val: val,
+            }];
+            db.insert(e).unwrap();
+        }
+    }
+
+    #[test]
+    fn memory_compared_relative_to_stack() {
+        let steps = [(0x1000, "str x0, [sp, #-8]", 1), (0x1004, "nop", 1)];
+        let (a, b) = (trace(&steps), trace(&steps));
+        with_stack(&a, 0x7000, -8, 1);
+        with_stack(&b, 0x9000, -8, 1);
+        let symbols = symbols();
+        let side = |db| Side {
+            db,
+            symbols: &symbols,
+        };
+        let opts = DiffOptions {
+            ignore: vec!["sp".to_string()],
+        };
+        assert!(diff(side(&a), side(&b), &opts).is_identical());
+
+        // The same byte differing on every step is one divergence.
+        with_stack(&b, 0x9000, -8, 2);
+        assert_eq!(diff(side(&a), side(&b), &opts).data_divergences, 1);
+
+        let opts = DiffOptions {
+            ignore: vec!["sp".to_string(), "mem".to_string()],
+        };
+        assert!(diff(side(&a), side(&b), &opts).is_identical());
+    }
+
+    #[test]
+    fn divergence_in_outermost_function_does_not_resync() {
+        let a = trace(&[
+            (0x1000, "b.eq #0x1010", 0),
+            (0x1004, "nop", 0),
+            (0x1020, "nop", 0),
+        ]);
+        let b = trace(&[
+            (0x1000, "b.eq #0x1010", 0),
+            (0x1010, "nop", 0),
+            (0x1020, "nop", 0),
+        ]);
+        let d = run(&a, &b);
+        assert!(d.resyncs.is_empty());
+        assert_eq!(d.matched, 1);
+    }
+
+    #[test]
+    fn control_divergence_resyncs_after_return() {
+        // Both call check, take different branches inside it, then return to
+        // the same place in main.
+        let a = trace(&[
+            (0x1000, CALL, 0),
+            (0x2000, "b.eq #0x2010", 0),
+            (0x2004, "nop", 0),
+            (0x2008, RET, 0),
+            (0x1004, "nop", 0),
+        ]);
+        let b = trace(&[
+            (0x1000, CALL, 0),
+            (0x2000, "b.eq #0x2010", 0),
+            (0x2010, "nop", 0),
+            (0x2014, "nop", 0),
+            (0x2018, RET, 0),
+            (0x1004, "nop", 0),
+        ]);
+        let d = run(&a, &b);
+        assert_eq!(d.control_divergences, 1);
+        let first = d.first_control.unwrap();
+        assert_eq!((first.a_step, first.b_step), (2, 2));
+        assert_eq!(first.function, "check");
+        assert_eq!(
+            d.resyncs,
+            vec![Resync {
+                a_step: 4,
+                b_step: 5
+            }]
+        );
+        assert_eq!(d.matched, 3);
+        assert_eq!(d.functions.len(), 1);
+        let check = &d.functions[0];
+        assert_eq!(check.name, "check");
+        assert_eq!((check.control, check.only_a, check.only_b), (1, 2, 3));
+    }
+
+    #[test]
+    fn unsynchronized_tail_counts_as_only_in_one_trace() {
+        let a = trace(&[
+            (0x1000, "b.eq #0x1010", 0),
+            (0x1004, "nop", 0),
+            (0x1008, "nop", 0),
+        ]);
+        let b = trace(&[(0x1000, "b.eq #0x1010", 0), (0x1010, "nop", 0)]);
+        let d = run(&a, &b);
+        assert!(d.resyncs.is_empty());
+        assert_eq!(d.functions[0].only_a, 2);
+        assert_eq!(d.functions[0].only_b, 1);
+    }
+
+    #[test]
+    fn longer_trace_is_not_identical() {
+        let a = trace(&[(0x1000, "nop", 0)]);
+        let b = trace(&[(0x1000, "nop", 0), (0x1004, "nop", 0)]);
+        let d = run(&a, &b);
+        assert!(!d.is_identical());
+        assert_eq!(d.functions[0].only_b, 1);
+    }
+}
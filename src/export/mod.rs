@@ -0,0 +1,468 @@
+mod sqlite;
+mod tenet;
+mod timeline;
+
+use crate::storage::{TraceDb, TraceEntry, TraceStream};
+use crate::symbols::SymbolTable;
+use std::io::Write;
+
+pub const DEFAULT_FIELDS: &str = "step,pc,symbol,insn,regs,mem";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Jsonl,
+    Csv,
+    /// Chrome Trace Event JSON of function calls.
+    Chrome,
+    /// Perfetto protobuf of function calls.
+    Perfetto,
+    /// Tenet register/memory delta trace.
+    Tenet,
+    /// SQLite database of steps, registers, writes, calls and symbols.
+    Sqlite,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "jsonl" => Ok(Format::Jsonl),
+            "csv" => Ok(Format::Csv),
+            "chrome" => Ok(Format::Chrome),
+            "perfetto" => Ok(Format::Perfetto),
+            "tenet" => Ok(Format::Tenet),
+            "sqlite" => Ok(Format::Sqlite),
+            _ => Err(format!(
+                "Unknown export format '{}' (expected jsonl, csv, chrome, perfetto, tenet or sqlite)",
+                s
+            )),
+        }
+    }
+}
+
+/// One column of an export.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Step,
+    Pc,
+    Symbol,
+    Insn,
+    Bytes,
+    Kind,
+    Depth,
+    /// Every register, as a JSON object.
+    Regs,
+    /// A single named register.
+    Reg(String),
+    Mem,
+}
+
+impl Field {
+    fn parse(name: &str) -> Result<Self, String> {
+        Ok(match name {
+            "step" => Field::Step,
+            "pc" => Field::Pc,
+            "symbol" => Field::Symbol,
+            "insn" => Field::Insn,
+            "bytes" => Field::Bytes,
+            "kind" => Field::Kind,
+            "depth" => Field::Depth,
+            "regs" => Field::Regs,
+            "mem" => Field::Mem,
+            reg if !reg.is_empty() && reg.chars().all(|c| c.is_ascii_alphanumeric()) => {
+                Field::Reg(reg.to_string())
+            }
+            _ => return Err(format!("Invalid field name '{}'", name)),
+        })
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Field::Step => "step",
+            Field::Pc => "pc",
+            Field::Symbol => "symbol",
+            Field::Insn => "insn",
+            Field::Bytes => "bytes",
+            Field::Kind => "kind",
+            Field::Depth => "depth",
+            Field::Regs => "regs",
+            Field::Reg(name) => name,
+            Field::Mem => "mem",
+        }
+    }
+}
+
+/// Parse a comma-separated field list. Names other than the built-in
+/// fields are taken as register names.
+pub fn parse_fields(spec: &str) -> Result<Vec<Field>, String> {
+    spec.split(',').map(|f| Field::parse(f.trim())).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    pub format: Format,
+    pub fields: Vec<Field>,
+    /// Inclusive step range; None exports everything.
+    pub steps: Option<(u64, u64)>,
+}
+
+/// Write the trace to `out` in the requested format: one record per step
+/// for JSON Lines, CSV and Tenet, one slice per call for the timeline formats.
+/// Returns the number of records.
+pub fn export(
+    db: &TraceDb,
+    symbols: &SymbolTable,
+    opts: &ExportOptions,
+    out: &mut impl Write,
+) -> Result<u64, String> {
+    let Some((start, end)) = step_range(db, opts.steps) else {
+        return Ok(0);
+    };
+
+    match opts.format {
+        Format::Chrome | Format::Perfetto => {
+            return timeline::export(db, symbols, opts.format, (start, end), out)
+        }
+        Format::Tenet => return tenet::export(db, (start, end), out),
+        Format::Sqlite => return Err("SQLite export needs an output file (-o)".to_string()),
+        Format::Jsonl | Format::Csv => {}
+    }
+    write_records(symbols, opts, out, |f| db.try_for_each_in(start, end, f))
+}
+
+/// [`export`] for JSON Lines and CSV, reading the entries from the trace
+/// file one block at a time instead of from a loaded database, so memory use
+/// stays flat however large the trace is.
+pub fn export_stream(
+    stream: TraceStream,
+    symbols: &SymbolTable,
+    opts: &ExportOptions,
+    out: &mut impl Write,
+) -> Result<u64, String> {
+    if !matches!(opts.format, Format::Jsonl | Format::Csv) {
+        return Err("Only JSON Lines and CSV exports can be streamed".to_string());
+    }
+    let (start, end) = opts.steps.unwrap_or((0, u64::MAX));
+    write_records(symbols, opts, out, |f| {
+        stream.try_for_each_in(start, end, f)
+    })
+}
+
+/// Write one JSON Lines or CSV record for every entry `for_each` visits.
+fn write_records(
+    symbols: &SymbolTable,
+    opts: &ExportOptions,
+    out: &mut impl Write,
+    for_each: impl FnOnce(&mut dyn FnMut(&TraceEntry) -> Result<(), String>) -> Result<(), String>,
+) -> Result<u64, String> {
+    if opts.format == Format::Csv {
+        let header: Vec<&str> = opts.fields.iter().map(Field::name).collect();
+        writeln!(out, "{}", header.join(",")).map_err(write_err)?;
+    }
+    let mut written = 0;
+    for_each(&mut |entry| {
+        let line = match opts.format {
+            Format::Csv => csv_record(entry, symbols, &opts.fields),
+            _ => jsonl_record(entry, symbols, &opts.fields),
+        };
+        written += 1;
+        writeln!(out, "{}", line).map_err(write_err)
+    })?;
+    out.flush().map_err(write_err)?;
+    Ok(written)
+}
+
+/// Write the trace to a new SQLite database at `path`. Unlike the other
+/// formats this needs a real file rather than a stream. Returns the number
+/// of steps.
+pub fn export_sqlite(
+    db: &TraceDb,
+    symbols: &SymbolTable,
+    steps: Option<(u64, u64)>,
+    path: &str,
+) -> Result<u64, String> {
+    let range = step_range(db, steps).unwrap_or((0, 0));
+    sqlite::export(db, symbols, range, path)
+}
+
+/// The requested inclusive range, clamped to the recorded steps. None for
+/// an empty trace.
+fn step_range(db: &TraceDb, steps: Option<(u64, u64)>) -> Option<(u64, u64)> {
+    let (first, last) = db.step_bounds()?;
+    let (start, end) = steps.unwrap_or((first, last));
+    Some((start, end.min(last)))
+}
+
+fn write_err(e: std::io::Error) -> String {
+    format!("Write failed: {}", e)
+}
+
+fn parsed_regs(entry: &TraceEntry, fields: &[Field]) -> serde_json::Value {
+    let needed = fields
+        .iter()
+        .any(|f| matches!(f, Field::Regs | Field::Reg(_)));
+    if needed {
+        serde_json::from_str(&entry.regs).unwrap_or_default()
+    } else {
+        serde_json::Value::Null
+    }
+}
+
+fn jsonl_record(entry: &TraceEntry, symbols: &SymbolTable, fields: &[Field]) -> String {
+    use serde_json::{json, Value};
+
+    let regs = parsed_regs(entry, fields);
+    // Built by hand rather than through a serde_json::Map so the keys keep
+    // the requested order.
+    let members: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            let value = match f {
+                Field::Step => json!(entry.step),
+                Field::Pc => json!(entry.pc),
+                Field::Symbol => json!(symbols.describe(entry.pc)),
+                Field::Insn => json!(entry.insn_text),
+                Field::Bytes => json!(hex_bytes(&entry.insn_bytes)),
+                Field::Kind => json!(entry.kind.name()),
+                Field::Depth => json!(entry.depth),
+                Field::Regs => regs.clone(),
+                Field::Reg(name) => regs.get(name).cloned().unwrap_or(Value::Null),
+                Field::Mem => json!(entry.mem_changes),
+            };
+            format!("{}:{}", json!(f.name()), value)
+        })
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+fn csv_record(entry: &TraceEntry, symbols: &SymbolTable, fields: &[Field]) -> String {
+    let regs = parsed_regs(entry, fields);
+    let cells: Vec<String> = fields
+        .iter()
+        .map(|f| match f {
+            Field::Step => entry.step.to_string(),
+            Field::Pc => format!("0x{:x}", entry.pc),
+            Field::Symbol => csv_escape(&symbols.describe(entry.pc).unwrap_or_default()),
+            Field::Insn => csv_escape(&entry.insn_text),
+            Field::Bytes => hex_bytes(&entry.insn_bytes),
+            Field::Kind => entry.kind.name().to_string(),
+            Field::Depth => entry.depth.to_string(),
+            Field::Regs => csv_escape(&entry.regs),
+            Field::Reg(name) => regs.get(name).map(|v| v.to_string()).unwrap_or_default(),
+            Field::Mem => entry
+                .mem_changes
+                .iter()
+                .map(|c| format!("0x{:x}:{:02x}>{:02x}", c.addr, c.old_val, c.new_val))
+                .collect::<Vec<_>>()
+                .join(" "),
+        })
+        .collect();
+    cells.join(",")
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Quote a CSV cell if it contains a separator, quote or line break.
+pub(crate) fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemChange;
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, insn: &str) -> TraceEntry {
+        TraceEntry {
+            regs: format!(r#"{{"x0":{},"sp":4096}}"#, step),
+            ..crate::storage::entry(step, 0x1000 + step * 4, insn)
+        }
+    }
+
+    fn db() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(entry(0, "mov x0, #0")).unwrap();
+        db.insert(TraceEntry {
+            mem_changes: vec![MemChange {
+                addr: 0x7000,
+                old_val: 0,
+                new_val: 0xab,
+            }],
+            ..entry(1, "str x0, [sp]")
+        })
+        .unwrap();
+        db.insert(entry(2, "bl #0x2000 ; CALL [depth:1]")).unwrap();
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![Symbol {
+            addr: 0x1000,
+            size: 0x100,
+            name: "main".to_string(),
+        }])
+    }
+
+    fn run(format: Format, fields: &str, steps: Option<(u64, u64)>) -> String {
+        let opts = ExportOptions {
+            format,
+            fields: parse_fields(fields).unwrap(),
+            steps,
+        };
+        let mut out = Vec::new();
+        export(&db(), &symbols(), &opts, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // ── fields ──
+
+    #[test]
+    fn parse_fields_builtin_and_registers() {
+        assert_eq!(
+            parse_fields("step, pc,x0,rax").unwrap(),
+            vec![
+                Field::Step,
+                Field::Pc,
+                Field::Reg("x0".into()),
+                Field::Reg("rax".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_fields_rejects_garbage() {
+        assert!(parse_fields("step,,pc").is_err());
+        assert!(parse_fields("x0;x1").is_err());
+    }
+
+    #[test]
+    fn format_parses() {
+        assert_eq!("jsonl".parse::<Format>(), Ok(Format::Jsonl));
+        assert_eq!("csv".parse::<Format>(), Ok(Format::Csv));
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    // ── jsonl ──
+
+    #[test]
+    fn jsonl_one_object_per_step_in_field_order() {
+        let out = run(Format::Jsonl, "step,symbol,x0,kind", None);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[1],
+            r#"{"step":1,"symbol":"main+0x4","x0":1,"kind":"store"}"#
+        );
+    }
+
+    #[test]
+    fn jsonl_mem_and_regs_are_structured() {
+        let out = run(Format::Jsonl, "regs,mem", Some((1, 1)));
+        let v: serde_json::Value = serde_json::from_str(out.trim()).unwrap();
+        assert_eq!(v["regs"]["sp"], 4096);
+        assert_eq!(v["mem"][0]["addr"], 0x7000);
+        assert_eq!(v["mem"][0]["new_val"], 0xab);
+    }
+
+    #[test]
+    fn jsonl_missing_register_is_null() {
+        let out = run(Format::Jsonl, "rax", Some((0, 0)));
+        assert_eq!(out.trim(), r#"{"rax":null}"#);
+    }
+
+    // ── csv ──
+
+    #[test]
+    fn csv_has_header_and_quotes_commas() {
+        let out = run(Format::Csv, "step,pc,insn,mem", None);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "step,pc,insn,mem");
+        assert_eq!(lines[1], r#"0,0x1000,"mov x0, #0","#);
+        assert_eq!(lines[2], r#"1,0x1004,"str x0, [sp]",0x7000:00>ab"#);
+    }
+
+    #[test]
+    fn csv_escapes_quotes() {
+        assert_eq!(csv_escape(r#"a"b"#), r#""a""b""#);
+        assert_eq!(csv_escape("plain"), "plain");
+    }
+
+    // ── ranges ──
+
+    #[test]
+    fn step_range_limits_records() {
+        let out = run(Format::Jsonl, "step", Some((1, 100)));
+        assert_eq!(out, "{\"step\":1}\n{\"step\":2}\n");
+    }
+
+    #[test]
+    fn empty_trace_exports_nothing() {
+        let opts = ExportOptions {
+            format: Format::Jsonl,
+            fields: vec![Field::Step],
+            steps: None,
+        };
+        let mut out = Vec::new();
+        let db = TraceDb::new(":memory:").unwrap();
+        assert_eq!(
+            export(&db, &SymbolTable::default(), &opts, &mut out).unwrap(),
+            0
+        );
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn export_writes_every_step() {
+        let db = TraceDb::new(":memory:").unwrap();
+        let n = 10_000;
+        for step in 0..n {
+            db.insert(entry(step, "nop")).unwrap();
+        }
+        let opts = ExportOptions {
+            format: Format::Csv,
+            fields: vec![Field::Step],
+            steps: None,
+        };
+        let mut out = Vec::new();
+        assert_eq!(
+            export(&db, &SymbolTable::default(), &opts, &mut out).unwrap(),
+            n
+        );
+        assert_eq!(out.iter().filter(|&&b| b == b'\n').count() as u64, n + 1);
+    }
+
+    #[test]
+    fn streamed_export_matches_loaded_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.tdb");
+        let path = path.to_str().unwrap();
+        let db = db();
+        let saved = TraceDb::new(path).unwrap();
+        for e in db.get_all() {
+            saved.insert(e).unwrap();
+        }
+        saved.save().unwrap();
+
+        for format in [Format::Jsonl, Format::Csv] {
+            let opts = ExportOptions {
+                format,
+                fields: parse_fields(DEFAULT_FIELDS).unwrap(),
+                steps: Some((1, 100)),
+            };
+            let (mut loaded, mut streamed) = (Vec::new(), Vec::new());
+            export(&db, &symbols(), &opts, &mut loaded).unwrap();
+            let stream = TraceStream::open(path).unwrap().unwrap();
+            let n = export_stream(stream, &symbols(), &opts, &mut streamed).unwrap();
+            assert_eq!(n, 2);
+            assert_eq!(String::from_utf8(streamed), String::from_utf8(loaded));
+        }
+    }
+}
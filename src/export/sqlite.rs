@@ -0,0 +1,196 @@
+//! SQLite export: the trace tables written by `storage`, plus the traced
+//! program's functions and the calls made between them.
+
+use crate::calltree::CallTree;
+use crate::storage::{sql_err, TraceDb};
+use crate::symbols::SymbolTable;
+use rusqlite::{params, Connection};
+
+const SCHEMA: &str = "
+CREATE TABLE symbols (addr INTEGER PRIMARY KEY, size INTEGER NOT NULL, name TEXT NOT NULL);
+CREATE TABLE calls (
+    call_step INTEGER PRIMARY KEY REFERENCES steps(step),
+    return_step INTEGER REFERENCES steps(step),
+    target INTEGER NOT NULL,
+    name TEXT NOT NULL
+);
+";
+
+const INDEXES: &str = "
+CREATE INDEX symbols_name ON symbols(name);
+CREATE INDEX calls_name ON calls(name);
+CREATE INDEX calls_target ON calls(target);
+";
+
+/// Write `start..=end` to a new SQLite database at `path`, replacing any
+/// file already there. Returns the number of steps.
+pub fn export(
+    db: &TraceDb,
+    symbols: &SymbolTable,
+    (start, end): (u64, u64),
+    path: &str,
+) -> Result<u64, String> {
+    // Opening an existing database would add to it rather than replace it.
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("Remove {} failed: {}", path, e))
+        }
+        _ => {}
+    }
+    let mut conn = Connection::open(path).map_err(sql_err)?;
+    let steps = db.save_sqlite(&mut conn, (start, end))?;
+
+    let tx = conn.transaction().map_err(sql_err)?;
+    tx.execute_batch(SCHEMA).map_err(sql_err)?;
+    {
+        let mut put_symbol = tx
+            .prepare("INSERT INTO symbols (addr, size, name) VALUES (?1, ?2, ?3)")
+            .map_err(sql_err)?;
+        for s in symbols.symbols() {
+            put_symbol
+                .execute(params![s.addr as i64, s.size as i64, s.name])
+                .map_err(sql_err)?;
+        }
+
+        let mut put_call = tx
+            .prepare(
+                "INSERT INTO calls (call_step, return_step, target, name)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )
+            .map_err(sql_err)?;
+        for node in CallTree::build_range(db, symbols, (start, end)).nodes {
+            put_call
+                .execute(params![
+                    node.call_step as i64,
+                    node.return_step.map(|s| s as i64),
+                    node.target.unwrap_or(0) as i64,
+                    node.name()
+                ])
+                .map_err(sql_err)?;
+        }
+    }
+    tx.execute_batch(INDEXES).map_err(sql_err)?;
+    tx.commit().map_err(sql_err)?;
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InsnKind, MemChange, TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, insn: &str) -> TraceEntry {
+        TraceEntry {
+            regs: serde_json::json!({"x0": step, "sp": 0x7000, "x1": u64::MAX}).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    fn db() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            command: vec!["./demo".into()],
+            arch: "arm64".into(),
+            ..Default::default()
+        });
+        db.insert(entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"))
+            .unwrap();
+        db.insert(TraceEntry {
+            mem_changes: vec![MemChange {
+                addr: 0x7000,
+                old_val: 0,
+                new_val: 0xab,
+            }],
+            ..entry(1, 0x2000, "str x0, [sp]")
+        })
+        .unwrap();
+        db.insert(entry(2, 0x2004, "ret ; RETURN [depth:0]"))
+            .unwrap();
+        db.insert(entry(3, 0x1004, "bl #0x2000 ; CALL [depth:1]"))
+            .unwrap();
+        db.insert(entry(4, 0x2000, "nop")).unwrap();
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![
+            Symbol {
+                addr: 0x1000,
+                size: 0x100,
+                name: "main".to_string(),
+            },
+            Symbol {
+                addr: 0x2000,
+                size: 0x10,
+                name: "helper".to_string(),
+            },
+        ])
+    }
+
+    #[test]
+    fn tables_answer_queries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.sqlite");
+        let path = path.to_str().unwrap();
+        assert_eq!(export(&db(), &symbols(), (0, 4), path).unwrap(), 5);
+
+        let conn = Connection::open(path).unwrap();
+        let query = |sql: &str| -> i64 { conn.query_row(sql, [], |r| r.get(0)).unwrap() };
+        assert_eq!(query("SELECT COUNT(*) FROM steps"), 5);
+        assert_eq!(
+            query("SELECT step FROM memory_writes WHERE addr = 0x7000"),
+            1
+        );
+        assert_eq!(
+            query("SELECT value FROM registers WHERE step = 3 AND name = 'x0'"),
+            3
+        );
+        assert_eq!(query("SELECT COUNT(*) FROM calls WHERE name = 'helper'"), 2);
+        assert_eq!(
+            query("SELECT return_step FROM calls WHERE call_step = 0"),
+            2
+        );
+        assert_eq!(
+            query("SELECT COUNT(*) FROM calls WHERE return_step IS NULL"),
+            1
+        );
+        let name: String = conn
+            .query_row(
+                "SELECT name FROM symbols WHERE addr <= 0x2004 ORDER BY addr DESC LIMIT 1",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(name, "helper");
+    }
+
+    #[test]
+    fn trace_db_opens_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.sqlite");
+        let path = path.to_str().unwrap();
+        let original = db();
+        export(&original, &symbols(), (0, 4), path).unwrap();
+
+        let loaded = TraceDb::load(path).unwrap();
+        assert_eq!(loaded.get_all(), original.get_all());
+        assert_eq!(loaded.meta(), original.meta());
+        assert_eq!(
+            loaded
+                .index()
+                .steps(crate::storage::IndexKey::Kind(InsnKind::Call)),
+            &[0, 3]
+        );
+    }
+
+    #[test]
+    fn export_replaces_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.sqlite");
+        let path = path.to_str().unwrap();
+        export(&db(), &symbols(), (0, 4), path).unwrap();
+        assert_eq!(export(&db(), &symbols(), (1, 2), path).unwrap(), 2);
+        assert_eq!(TraceDb::load(path).unwrap().count(), 2);
+    }
+}
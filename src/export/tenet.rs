@@ -0,0 +1,240 @@
+//! Tenet execution traces, for the IDA / Binary Ninja plugin.
+//!
+//! One line per step: `reg=0xvalue` for every register that changed since the
+//! previous step (all of them on the first line), the program counter on
+//! every line, and `mw=addr:hexbytes` for each run of bytes the step wrote.
+//! tdb only records writes, so there are no `mr=` entries.
+
+use super::write_err;
+use crate::storage::{MemChange, TraceDb};
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Registers Tenet knows, as (tdb name, Tenet name). The program counter
+/// comes last.
+const AMD64: &[(&str, &str)] = &[
+    ("rax", "rax"),
+    ("rbx", "rbx"),
+    ("rcx", "rcx"),
+    ("rdx", "rdx"),
+    ("rbp", "rbp"),
+    ("rsp", "rsp"),
+    ("rsi", "rsi"),
+    ("rdi", "rdi"),
+    ("r8", "r8"),
+    ("r9", "r9"),
+    ("r10", "r10"),
+    ("r11", "r11"),
+    ("r12", "r12"),
+    ("r13", "r13"),
+    ("r14", "r14"),
+    ("r15", "r15"),
+    ("rip", "rip"),
+];
+
+const ARM64: &[(&str, &str)] = &[
+    ("x0", "x0"),
+    ("x1", "x1"),
+    ("x2", "x2"),
+    ("x3", "x3"),
+    ("x4", "x4"),
+    ("x5", "x5"),
+    ("x6", "x6"),
+    ("x7", "x7"),
+    ("x8", "x8"),
+    ("x9", "x9"),
+    ("x10", "x10"),
+    ("x11", "x11"),
+    ("x12", "x12"),
+    ("x13", "x13"),
+    ("x14", "x14"),
+    ("x15", "x15"),
+    ("x16", "x16"),
+    ("x17", "x17"),
+    ("x18", "x18"),
+    ("x19", "x19"),
+    ("x20", "x20"),
+    ("x21", "x21"),
+    ("x22", "x22"),
+    ("x23", "x23"),
+    ("x24", "x24"),
+    ("x25", "x25"),
+    ("x26", "x26"),
+    ("x27", "x27"),
+    ("x28", "x28"),
+    ("fp", "x29"),
+    ("lr", "x30"),
+    ("sp", "sp"),
+    ("pc", "pc"),
+];
+
+/// Write `start..=end` as a Tenet trace. Returns the number of lines.
+pub fn export(db: &TraceDb, (start, end): (u64, u64), out: &mut impl Write) -> Result<u64, String> {
+    let arch = db.meta().arch;
+    let registers = if arch == "x86_64" { AMD64 } else { ARM64 };
+    let (pc_name, _) = registers[registers.len() - 1];
+
+    let mut prev: HashMap<&str, u64> = HashMap::new();
+    let mut written = 0;
+    db.try_for_each_in(start, end, |entry| {
+        let regs: serde_json::Value = serde_json::from_str(&entry.regs).unwrap_or_default();
+        let mut fields = Vec::new();
+        for &(name, tenet) in registers {
+            let value = match regs.get(name).and_then(|v| v.as_u64()) {
+                Some(v) => v,
+                None if name == pc_name => entry.pc,
+                None => continue,
+            };
+            if name == pc_name || prev.insert(name, value) != Some(value) {
+                fields.push(format!("{}=0x{:x}", tenet, value));
+            }
+        }
+        fields.extend(
+            write_runs(&entry.mem_changes)
+                .into_iter()
+                .map(|(addr, bytes)| format!("mw=0x{:x}:{}", addr, bytes)),
+        );
+        written += 1;
+        writeln!(out, "{}", fields.join(",")).map_err(write_err)
+    })?;
+    out.flush().map_err(write_err)?;
+    Ok(written)
+}
+
+/// Group written bytes into contiguous runs of (start address, hex bytes).
+fn write_runs(changes: &[MemChange]) -> Vec<(u64, String)> {
+    let mut sorted: Vec<&MemChange> = changes.iter().collect();
+    sorted.sort_by_key(|c| c.addr);
+    let mut runs: Vec<(u64, u64, String)> = Vec::new();
+    for c in sorted {
+        match runs.last_mut() {
+            Some((_, next, bytes)) if *next == c.addr => {
+                bytes.push_str(&format!("{:02x}", c.new_val));
+                *next += 1;
+            }
+            _ => runs.push((c.addr, c.addr + 1, format!("{:02x}", c.new_val))),
+        }
+    }
+    runs.into_iter()
+        .map(|(addr, _, bytes)| (addr, bytes))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+
+    fn entry(step: u64, regs: serde_json::Value, writes: &[(u64, u8)]) -> TraceEntry {
+        let pc = regs["pc"].as_u64().or(regs["rip"].as_u64()).unwrap();
+        TraceEntry {
+            regs: regs.to_string(),
+            mem_changes: writes
+                .iter()
+                .map(|&(addr, new_val)| MemChange {
+                    addr,
+                    old_val: 0,
+                    new_val,
+                })
+                .collect(),
+            ..crate::storage::entry(step, pc, "nop")
+        }
+    }
+
+    fn render(db: &TraceDb) -> Vec<String> {
+        let mut out = Vec::new();
+        let (first, last) = db.step_bounds().unwrap();
+        export(db, (first, last), &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn arm64_lines_carry_only_changed_registers() {
+        use serde_json::json;
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".into(),
+            ..Default::default()
+        });
+        db.insert(entry(
+            0,
+            json!({"x0": 1, "fp": 2, "lr": 3, "sp": 4096, "pc": 0x1000}),
+            &[],
+        ))
+        .unwrap();
+        db.insert(entry(
+            1,
+            json!({"x0": 1, "fp": 2, "lr": 3, "sp": 4080, "pc": 0x1004}),
+            &[],
+        ))
+        .unwrap();
+        db.insert(entry(
+            2,
+            json!({"x0": 7, "fp": 2, "lr": 3, "sp": 4080, "pc": 0x1008}),
+            &[],
+        ))
+        .unwrap();
+
+        let lines = render(&db);
+        assert_eq!(lines[0], "x0=0x1,x29=0x2,x30=0x3,sp=0x1000,pc=0x1000");
+        assert_eq!(lines[1], "sp=0xff0,pc=0x1004");
+        assert_eq!(lines[2], "x0=0x7,pc=0x1008");
+    }
+
+    #[test]
+    fn amd64_uses_rip_and_drops_unknown_registers() {
+        use serde_json::json;
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "x86_64".into(),
+            ..Default::default()
+        });
+        db.insert(entry(
+            0,
+            json!({"rax": 0, "rflags": 0x202, "rip": 0x401000}),
+            &[],
+        ))
+        .unwrap();
+        assert_eq!(render(&db), vec!["rax=0x0,rip=0x401000"]);
+    }
+
+    #[test]
+    fn writes_grouped_into_runs() {
+        let changes = |v: &[(u64, u8)]| -> Vec<MemChange> {
+            v.iter()
+                .map(|&(addr, new_val)| MemChange {
+                    addr,
+                    old_val: 0,
+                    new_val,
+                })
+                .collect()
+        };
+        let runs = write_runs(&changes(&[
+            (0x7001, 0xbb),
+            (0x7000, 0xaa),
+            (0x7002, 0xcc),
+            (0x8000, 0x01),
+        ]));
+        assert_eq!(
+            runs,
+            vec![(0x7000, "aabbcc".to_string()), (0x8000, "01".to_string())]
+        );
+    }
+
+    #[test]
+    fn memory_writes_follow_registers() {
+        use serde_json::json;
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(entry(
+            0,
+            json!({"pc": 0x1000}),
+            &[(0x7000, 0x41), (0x7001, 0x42)],
+        ))
+        .unwrap();
+        assert_eq!(render(&db), vec!["pc=0x1000,mw=0x7000:4142"]);
+    }
+}
@@ -0,0 +1,340 @@
+//! Function calls as timeline slices, for chrome://tracing and ui.perfetto.dev.
+//!
+//! Each CALL opens a slice named after the function it enters and the
+//! matching RETURN closes it. Step numbers are used as timestamps.
+
+use super::{write_err, Format};
+use crate::calltree::CallTree;
+use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
+use std::io::Write;
+
+/// Track that all call slices are placed on in Perfetto output.
+const TRACK_UUID: u64 = 1;
+/// Perfetto requires a non-zero writer sequence id.
+const SEQUENCE_ID: u64 = 1;
+
+/// One function invocation seen in the trace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallSpan {
+    pub name: String,
+    /// Step of the CALL instruction.
+    pub begin: u64,
+    /// Step of the matching RETURN, or the last step if it never returned.
+    pub end: u64,
+}
+
+/// The calls made inside `start..=end`, in call order. Returns without a
+/// call in range (functions entered before `start`) are ignored.
+pub fn call_spans(db: &TraceDb, symbols: &SymbolTable, (start, end): (u64, u64)) -> Vec<CallSpan> {
+    CallTree::build_range(db, symbols, (start, end))
+        .nodes
+        .into_iter()
+        .map(|node| CallSpan {
+            name: node.name(),
+            begin: node.call_step,
+            end: node.return_step.unwrap_or(end),
+        })
+        .collect()
+}
+
+/// Write the call slices of `start..=end` as Chrome Trace Event JSON or a
+/// Perfetto protobuf trace. Returns the number of slices.
+pub fn export(
+    db: &TraceDb,
+    symbols: &SymbolTable,
+    format: Format,
+    range: (u64, u64),
+    out: &mut impl Write,
+) -> Result<u64, String> {
+    let spans = call_spans(db, symbols, range);
+    let meta = db.meta();
+    let process = meta
+        .command
+        .first()
+        .cloned()
+        .unwrap_or_else(|| format!("pid {}", meta.pid));
+    let data = match format {
+        Format::Perfetto => perfetto(&spans, &process),
+        _ => chrome(&spans, &process, meta.pid).into_bytes(),
+    };
+    out.write_all(&data).map_err(write_err)?;
+    out.flush().map_err(write_err)?;
+    Ok(spans.len() as u64)
+}
+
+/// Begin/end events in timestamp order. Spans come from `call_spans` in
+/// call order, already nested, so a stack keeps ends balanced even when
+/// neighbouring events share a step.
+fn events(spans: &[CallSpan]) -> Vec<(u64, bool, &CallSpan)> {
+    let mut events = Vec::with_capacity(spans.len() * 2);
+    let mut open: Vec<&CallSpan> = Vec::new();
+    for span in spans {
+        while let Some(top) = open.pop_if(|top| top.end < span.begin) {
+            events.push((top.end, false, top));
+        }
+        events.push((span.begin, true, span));
+        open.push(span);
+    }
+    while let Some(top) = open.pop() {
+        events.push((top.end, false, top));
+    }
+    events
+}
+
+fn chrome(spans: &[CallSpan], process: &str, pid: i32) -> String {
+    use serde_json::json;
+
+    let mut trace_events = vec![json!({
+        "name": "process_name",
+        "ph": "M",
+        "pid": pid,
+        "tid": pid,
+        "args": { "name": process },
+    })];
+    for (ts, begin, span) in events(spans) {
+        let mut event = json!({
+            "name": span.name,
+            "cat": "call",
+            "ph": if begin { "B" } else { "E" },
+            "ts": ts,
+            "pid": pid,
+            "tid": pid,
+        });
+        if begin {
+            event["args"] = json!({ "call_step": span.begin, "return_step": span.end });
+        }
+        trace_events.push(event);
+    }
+    json!({ "traceEvents": trace_events }).to_string()
+}
+
+/// Encode a Perfetto `Trace` message: one track descriptor followed by a
+/// SLICE_BEGIN / SLICE_END track event per call boundary.
+fn perfetto(spans: &[CallSpan], process: &str) -> Vec<u8> {
+    // Field numbers from perfetto/protos/perfetto/trace/.
+    const TRACE_PACKET: u64 = 1;
+    const PACKET_TIMESTAMP: u64 = 8;
+    const PACKET_SEQUENCE_ID: u64 = 10;
+    const PACKET_TRACK_EVENT: u64 = 11;
+    const PACKET_TRACK_DESCRIPTOR: u64 = 60;
+    const DESCRIPTOR_UUID: u64 = 1;
+    const DESCRIPTOR_NAME: u64 = 2;
+    const EVENT_TYPE: u64 = 9;
+    const EVENT_TRACK_UUID: u64 = 11;
+    const EVENT_NAME: u64 = 23;
+    const SLICE_BEGIN: u64 = 1;
+    const SLICE_END: u64 = 2;
+
+    let mut trace = Vec::new();
+
+    let mut descriptor = Vec::new();
+    proto_varint_field(&mut descriptor, DESCRIPTOR_UUID, TRACK_UUID);
+    proto_bytes_field(&mut descriptor, DESCRIPTOR_NAME, process.as_bytes());
+    let mut packet = Vec::new();
+    proto_varint_field(&mut packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+    proto_bytes_field(&mut packet, PACKET_TRACK_DESCRIPTOR, &descriptor);
+    proto_bytes_field(&mut trace, TRACE_PACKET, &packet);
+
+    for (ts, begin, span) in events(spans) {
+        let mut event = Vec::new();
+        if begin {
+            proto_varint_field(&mut event, EVENT_TYPE, SLICE_BEGIN);
+            proto_varint_field(&mut event, EVENT_TRACK_UUID, TRACK_UUID);
+            proto_bytes_field(&mut event, EVENT_NAME, span.name.as_bytes());
+        } else {
+            proto_varint_field(&mut event, EVENT_TYPE, SLICE_END);
+            proto_varint_field(&mut event, EVENT_TRACK_UUID, TRACK_UUID);
+        }
+        let mut packet = Vec::new();
+        proto_varint_field(&mut packet, PACKET_TIMESTAMP, ts);
+        proto_varint_field(&mut packet, PACKET_SEQUENCE_ID, SEQUENCE_ID);
+        proto_bytes_field(&mut packet, PACKET_TRACK_EVENT, &event);
+        proto_bytes_field(&mut trace, TRACE_PACKET, &packet);
+    }
+    trace
+}
+
+fn proto_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn proto_varint_field(out: &mut Vec<u8>, field: u64, v: u64) {
+    proto_varint(out, field << 3);
+    proto_varint(out, v);
+}
+
+fn proto_bytes_field(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    proto_varint(out, (field << 3) | 2);
+    proto_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::entry;
+    use crate::symbols::Symbol;
+
+    /// main (0x1000) calls f (0x2000) at step 1; f calls g (0x3000) at step 3.
+    fn nested() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        for e in [
+            entry(0, 0x1000, "mov x0, #1"),
+            entry(1, 0x1004, "bl #0x2000 ; CALL [depth:1]"),
+            entry(2, 0x2000, "mov x0, #2"),
+            entry(3, 0x2004, "bl #0x3000 ; CALL [depth:2]"),
+            entry(4, 0x3000, "ret ; RETURN [depth:1]"),
+            entry(5, 0x2008, "ret ; RETURN [depth:0]"),
+            entry(6, 0x1008, "mov x0, #0"),
+        ] {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![sym(0x1000, "main"), sym(0x2000, "f")])
+    }
+
+    #[test]
+    fn calls_pair_with_returns() {
+        let spans = call_spans(&nested(), &symbols(), (0, 6));
+        assert_eq!(
+            spans,
+            vec![
+                CallSpan {
+                    name: "f".into(),
+                    begin: 1,
+                    end: 5
+                },
+                CallSpan {
+                    name: "0x3000".into(),
+                    begin: 3,
+                    end: 4
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unreturned_call_ends_at_range_end() {
+        let spans = call_spans(&nested(), &symbols(), (0, 3));
+        assert_eq!(spans.len(), 2);
+        assert!(spans.iter().all(|s| s.end == 3));
+    }
+
+    #[test]
+    fn return_from_before_range_is_ignored() {
+        let spans = call_spans(&nested(), &symbols(), (2, 6));
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].begin, spans[0].end), (3, 4));
+    }
+
+    #[test]
+    fn chrome_events_are_balanced_and_nested() {
+        let mut out = Vec::new();
+        let n = export(&nested(), &symbols(), Format::Chrome, (0, 6), &mut out).unwrap();
+        assert_eq!(n, 2);
+
+        let v: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        let phases: Vec<(String, u64)> = v["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] != "M")
+            .map(|e| {
+                (
+                    e["ph"].as_str().unwrap().to_string(),
+                    e["ts"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        let expected = [("B", 1), ("B", 3), ("E", 4), ("E", 5)];
+        assert_eq!(phases, expected.map(|(p, ts)| (p.to_string(), ts)).to_vec());
+        assert_eq!(v["traceEvents"][1]["name"], "f");
+    }
+
+    #[test]
+    fn calls_at_range_end_stay_nested() {
+        let spans = call_spans(&nested(), &symbols(), (0, 3));
+        let order: Vec<(u64, bool)> = events(&spans)
+            .iter()
+            .map(|&(ts, begin, _)| (ts, begin))
+            .collect();
+        assert_eq!(order, vec![(1, true), (3, true), (3, false), (3, false)]);
+        assert_eq!(events(&spans)[2].2.begin, 3, "inner call closes first");
+    }
+
+    /// Split a protobuf message into (field, wire type, payload) triples.
+    fn decode(mut data: &[u8]) -> Vec<(u64, u8, Vec<u8>)> {
+        fn varint(data: &mut &[u8]) -> u64 {
+            let mut v = 0;
+            for shift in (0..64).step_by(7) {
+                let b = data[0];
+                *data = &data[1..];
+                v |= ((b & 0x7F) as u64) << shift;
+                if b & 0x80 == 0 {
+                    break;
+                }
+            }
+            v
+        }
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let tag = varint(&mut data);
+            let wire = (tag & 7) as u8;
+            let payload = if wire == 2 {
+                let len = varint(&mut data) as usize;
+                let (p, rest) = data.split_at(len);
+                data = rest;
+                p.to_vec()
+            } else {
+                let mut v = Vec::new();
+                proto_varint(&mut v, varint(&mut data));
+                v
+            };
+            fields.push((tag >> 3, wire, payload));
+        }
+        fields
+    }
+
+    #[test]
+    fn perfetto_packets_encode_track_and_slices() {
+        let mut out = Vec::new();
+        export(&nested(), &symbols(), Format::Perfetto, (0, 6), &mut out).unwrap();
+
+        let packets = decode(&out);
+        // Descriptor + two begins + two ends.
+        assert_eq!(packets.len(), 5);
+        assert!(packets
+            .iter()
+            .all(|(field, wire, _)| *field == 1 && *wire == 2));
+
+        let descriptor = decode(&packets[0].2);
+        assert!(descriptor.iter().any(|(f, _, _)| *f == 60));
+
+        let first = decode(&packets[1].2);
+        let ts = first.iter().find(|(f, _, _)| *f == 8).unwrap();
+        assert_eq!(ts.2, vec![1]);
+        let event = decode(&first.iter().find(|(f, _, _)| *f == 11).unwrap().2);
+        let name = event.iter().find(|(f, _, _)| *f == 23).unwrap();
+        assert_eq!(name.2, b"f");
+    }
+
+    #[test]
+    fn varint_encoding() {
+        let mut v = Vec::new();
+        proto_varint(&mut v, 300);
+        assert_eq!(v, vec![0xAC, 0x02]);
+    }
+}
@@ -0,0 +1,471 @@
+//! Loops found from the back-edges the trace took, and a timeline with
+//! every loop run folded into one row.
+//!
+//! A taken jump to an address at or below the jumping instruction, within
+//! one function activation, is a back-edge to a loop head. A run of a loop
+//! starts at the first execution of its head and ends when execution in
+//! that activation leaves `head..=latch` (or the function returns); each
+//! execution of the head in between is one iteration. Loops whose code is
+//! not laid out between head and latch end a run early and start a new
+//! one on the next back-edge.
+
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How many trip counts `print` lists per loop.
+const TOP_TRIPS: usize = 4;
+
+/// One execution of a loop, from entering it to leaving it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LoopRun {
+    pub head: u64,
+    /// Highest address that branched back to `head`.
+    pub latch: u64,
+    /// Symbol of `head`, "name+0x40".
+    pub function: Option<String>,
+    /// First and last step of the run.
+    pub start: u64,
+    pub end: u64,
+    pub iterations: u64,
+}
+
+impl LoopRun {
+    /// "loop at main+0x40, 10,000 iterations"
+    pub fn label(&self) -> String {
+        let at = self
+            .function
+            .clone()
+            .unwrap_or_else(|| format!("0x{:x}", self.head));
+        format!("loop at {}, {} iterations", at, thousands(self.iterations))
+    }
+}
+
+/// Every run of one loop head.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Loop {
+    pub head: u64,
+    pub latch: u64,
+    pub function: Option<String>,
+    pub runs: u64,
+    pub iterations: u64,
+    /// Steps spent in the loop, callees included.
+    pub steps: u64,
+    /// Iterations per run -> how many runs had that many.
+    pub trips: BTreeMap<u64, u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Loops {
+    /// Most steps first.
+    pub loops: Vec<Loop>,
+    /// Sorted by start step, enclosing runs before the runs nested in them.
+    pub runs: Vec<LoopRun>,
+}
+
+/// One line of the summarized timeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Row {
+    /// Position of the row's (first) step in the list it was built from.
+    pub index: usize,
+    /// The collapsed run the row stands for.
+    pub run: Option<usize>,
+}
+
+/// A run that has not left its loop yet.
+struct Active {
+    head: u64,
+    latch: u64,
+    /// Address past the latch instruction.
+    latch_end: u64,
+    /// Call depth of the activation the loop runs in.
+    frame: usize,
+    start: u64,
+    iterations: u64,
+}
+
+impl Loops {
+    pub fn detect(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        let mut runs = Vec::new();
+        // Last step each PC ran at, per open activation.
+        let mut frames: Vec<HashMap<u64, u64>> = vec![HashMap::new()];
+        let mut active: Vec<Active> = Vec::new();
+        // (step, pc, length, kind) of the previous instruction.
+        let mut prev: Option<(u64, u64, u64, InsnKind)> = None;
+        let mut finish = |a: Active, end: u64, frames: &mut Vec<HashMap<u64, u64>>| {
+            if let Some(seen) = frames.get_mut(a.frame - 1) {
+                // A later run must not start from this one's head.
+                seen.remove(&a.head);
+            }
+            if a.iterations >= 2 {
+                runs.push(LoopRun {
+                    head: a.head,
+                    latch: a.latch,
+                    function: symbols.describe(a.head),
+                    start: a.start,
+                    end,
+                    iterations: a.iterations,
+                });
+            }
+        };
+
+        db.for_each_in(0, u64::MAX, |e| {
+            let len = e.insn_bytes.len().max(1) as u64;
+            let edge = match prev {
+                Some((step, pc, len, kind)) if step + 1 == e.step => {
+                    match kind {
+                        InsnKind::Call => frames.push(HashMap::new()),
+                        InsnKind::Return if frames.len() > 1 => {
+                            frames.pop();
+                        }
+                        _ => {}
+                    }
+                    let back = e.pc <= pc && !matches!(kind, InsnKind::Call | InsnKind::Return);
+                    back.then_some((pc, len))
+                }
+                // A gap in a sliced trace: nothing carries over.
+                Some((step, ..)) => {
+                    while let Some(a) = active.pop() {
+                        finish(a, step, &mut frames);
+                    }
+                    frames = vec![HashMap::new()];
+                    None
+                }
+                None => None,
+            };
+            let frame = frames.len();
+
+            // Runs of activations that returned, and of this one when
+            // execution left the loop body.
+            while let Some(a) = active.last() {
+                let left =
+                    a.frame > frame || (a.frame == frame && !(a.head..a.latch_end).contains(&e.pc));
+                if !left {
+                    break;
+                }
+                let a = active.pop().unwrap();
+                finish(a, prev.map_or(e.step, |p| p.0), &mut frames);
+            }
+
+            if let Some((latch, latch_len)) = edge {
+                let same = active
+                    .iter()
+                    .rposition(|a| a.frame == frame && a.head == e.pc);
+                match same {
+                    Some(k) => {
+                        // Inner loops jumped over by the back-edge are done.
+                        while active.len() > k + 1 {
+                            let a = active.pop().unwrap();
+                            finish(a, prev.map_or(e.step, |p| p.0), &mut frames);
+                        }
+                        let a = &mut active[k];
+                        a.iterations += 1;
+                        if latch >= a.latch {
+                            a.latch = latch;
+                            a.latch_end = latch + latch_len;
+                        }
+                    }
+                    None => {
+                        // Loops entered at the head already ran one iteration;
+                        // rotated ones (entered at the condition) start here.
+                        let (start, iterations) =
+                            match frames.last().and_then(|seen| seen.get(&e.pc)) {
+                                Some(&first) => (first, 2),
+                                None => (e.step, 1),
+                            };
+                        active.push(Active {
+                            head: e.pc,
+                            latch,
+                            latch_end: latch + latch_len,
+                            frame,
+                            start,
+                            iterations,
+                        });
+                    }
+                }
+            }
+
+            if let Some(seen) = frames.last_mut() {
+                seen.insert(e.pc, e.step);
+            }
+            prev = Some((e.step, e.pc, len, e.kind));
+        });
+        if let Some((step, ..)) = prev {
+            while let Some(a) = active.pop() {
+                finish(a, step, &mut frames);
+            }
+        }
+
+        runs.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+        let mut loops: BTreeMap<u64, Loop> = BTreeMap::new();
+        for r in &runs {
+            let l = loops.entry(r.head).or_insert_with(|| Loop {
+                head: r.head,
+                latch: r.latch,
+                function: r.function.clone(),
+                runs: 0,
+                iterations: 0,
+                steps: 0,
+                trips: BTreeMap::new(),
+            });
+            l.latch = l.latch.max(r.latch);
+            l.runs += 1;
+            l.iterations += r.iterations;
+            l.steps += r.end - r.start + 1;
+            *l.trips.entry(r.iterations).or_default() += 1;
+        }
+        let mut loops: Vec<Loop> = loops.into_values().collect();
+        loops.sort_by(|a, b| b.steps.cmp(&a.steps).then(a.head.cmp(&b.head)));
+        Self { loops, runs }
+    }
+
+    /// Timeline rows for the entries recorded at `steps` (ascending), with
+    /// every run not in `expanded` folded into the row of its first step.
+    /// Runs nested in an expanded run are folded on their own.
+    pub fn timeline(&self, steps: &[u64], expanded: &HashSet<usize>) -> Vec<Row> {
+        let mut rows = Vec::new();
+        let mut next = 0;
+        let mut i = 0;
+        while i < steps.len() {
+            let step = steps[i];
+            while next < self.runs.len() && self.runs[next].start < step {
+                next += 1;
+            }
+            let mut folded = None;
+            while next < self.runs.len() && self.runs[next].start == step {
+                if !expanded.contains(&next) {
+                    folded = Some(next);
+                    break;
+                }
+                next += 1;
+            }
+            rows.push(Row {
+                index: i,
+                run: folded,
+            });
+            i += match folded {
+                Some(r) => steps[i..].partition_point(|&s| s <= self.runs[r].end),
+                None => 1,
+            };
+        }
+        rows
+    }
+
+    /// Print the `limit` loops that took the most steps.
+    pub fn print(&self, limit: usize) {
+        println!("  Loops");
+        println!("  -----");
+        if self.loops.is_empty() {
+            println!("    No loops found");
+            return;
+        }
+        println!(
+            "    {:<32} {:>6} {:>10} {:>10}  Trip counts (runs)",
+            "Loop", "Runs", "Iterations", "Steps"
+        );
+        for l in self.loops.iter().take(limit) {
+            let mut trips: Vec<(u64, u64)> = l.trips.iter().map(|(&t, &n)| (t, n)).collect();
+            trips.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let mut common: Vec<String> = trips
+                .iter()
+                .take(TOP_TRIPS)
+                .map(|(t, n)| format!("{} ({})", t, n))
+                .collect();
+            if trips.len() > TOP_TRIPS {
+                common.push(format!("... {} more", trips.len() - TOP_TRIPS));
+            }
+            println!(
+                "    {:<32} {:>6} {:>10} {:>10}  {}",
+                l.function
+                    .clone()
+                    .unwrap_or_else(|| format!("0x{:x}", l.head)),
+                l.runs,
+                l.iterations,
+                l.steps,
+                common.join(", ")
+            );
+        }
+        if self.loops.len() > limit {
+            println!("    ... and {} more", self.loops.len() - limit);
+        }
+    }
+}
+
+/// 10000 -> "10,000"
+pub fn thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::entry;
+    use crate::symbols::Symbol;
+
+    fn db(pcs: &[(u64, &str)]) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        for (step, &(pc, insn)) in pcs.iter().enumerate() {
+            db.insert(entry(step as u64, pc, insn)).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        SymbolTable::from_symbols(vec![
+            Symbol {
+                addr: 0x1000,
+                size: 0x100,
+                name: "main".to_string(),
+            },
+            Symbol {
+                addr: 0x2000,
+                size: 0x100,
+                name: "helper".to_string(),
+            },
+        ])
+    }
+
+    const SUB: (u64, &str) = (0x1004, "sub x0, x0, #1");
+    const CBNZ: (u64, &str) = (0x1008, "cbnz x0, #0x1004");
+
+    /// Outer loop at 0x1004..0x1014 (twice) around an inner loop at
+    /// 0x1008..0x100c that runs three times, then two times.
+    fn nested() -> Vec<(u64, &'static str)> {
+        let inner = |n: usize| {
+            (0..n).flat_map(|_| [(0x1008, "sub x1, x1, #1"), (0x100c, "cbnz x1, #0x1008")])
+        };
+        let mut pcs = vec![(0x1000, "mov x0, #2")];
+        for n in [3, 2] {
+            pcs.push((0x1004, "mov x1, #3"));
+            pcs.extend(inner(n));
+            pcs.push((0x1010, "sub x0, x0, #1"));
+            pcs.push((0x1014, "cbnz x0, #0x1004"));
+        }
+        pcs.push((0x1018, "ret ; RETURN"));
+        pcs
+    }
+
+    #[test]
+    fn counts_iterations_of_a_simple_loop() {
+        let loops = Loops::detect(
+            &db(&[
+                (0x1000, "mov x0, #3"),
+                SUB,
+                CBNZ,
+                SUB,
+                CBNZ,
+                SUB,
+                CBNZ,
+                (0x100c, "ret"),
+            ]),
+            &symbols(),
+        );
+        assert_eq!(
+            loops.runs,
+            vec![LoopRun {
+                head: 0x1004,
+                latch: 0x1008,
+                function: Some("main+0x4".to_string()),
+                start: 1,
+                end: 6,
+                iterations: 3,
+            }]
+        );
+        assert_eq!(loops.loops[0].trips, BTreeMap::from([(3, 1)]));
+        assert_eq!(loops.runs[0].label(), "loop at main+0x4, 3 iterations");
+    }
+
+    #[test]
+    fn nested_loops_and_trip_counts() {
+        let loops = Loops::detect(&db(&nested()), &symbols());
+        let runs: Vec<(u64, u64, u64, u64)> = loops
+            .runs
+            .iter()
+            .map(|r| (r.head, r.start, r.end, r.iterations))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![(0x1004, 1, 16, 2), (0x1008, 2, 7, 3), (0x1008, 11, 14, 2),]
+        );
+        let inner = loops.loops.iter().find(|l| l.head == 0x1008).unwrap();
+        assert_eq!((inner.runs, inner.iterations), (2, 5));
+        assert_eq!(inner.trips, BTreeMap::from([(2, 1), (3, 1)]));
+        assert_eq!(loops.loops[0].head, 0x1004);
+    }
+
+    #[test]
+    fn calls_inside_a_loop_belong_to_it() {
+        let mut pcs = vec![(0x1000, "mov x0, #2")];
+        for _ in 0..2 {
+            pcs.push((0x1004, "bl #0x2000 ; CALL"));
+            pcs.push((0x2000, "nop"));
+            // Returning jumps backwards, but is no back-edge.
+            pcs.push((0x2004, "ret ; RETURN"));
+            pcs.push((0x1008, "cbnz x0, #0x1004"));
+        }
+        pcs.push((0x100c, "ret"));
+        let loops = Loops::detect(&db(&pcs), &symbols());
+        assert_eq!(loops.runs.len(), 1);
+        assert_eq!(
+            (
+                loops.runs[0].start,
+                loops.runs[0].end,
+                loops.runs[0].iterations
+            ),
+            (1, 8, 2)
+        );
+    }
+
+    #[test]
+    fn timeline_folds_runs_unless_expanded() {
+        let pcs = nested();
+        let loops = Loops::detect(&db(&pcs), &symbols());
+        let steps: Vec<u64> = (0..pcs.len() as u64).collect();
+
+        let rows = loops.timeline(&steps, &HashSet::new());
+        assert_eq!(
+            rows,
+            vec![
+                Row {
+                    index: 0,
+                    run: None
+                },
+                Row {
+                    index: 1,
+                    run: Some(0)
+                },
+                Row {
+                    index: 17,
+                    run: None
+                },
+            ]
+        );
+
+        // The outer loop open: its steps, with both inner runs folded.
+        let rows = loops.timeline(&steps, &HashSet::from([0]));
+        let folded: Vec<(usize, Option<usize>)> = rows.iter().map(|r| (r.index, r.run)).collect();
+        assert_eq!(
+            &folded[..4],
+            &[(0, None), (1, None), (2, Some(1)), (8, None)]
+        );
+        assert!(folded.contains(&(11, Some(2))));
+        assert_eq!(rows.len(), 10);
+    }
+
+    #[test]
+    fn thousands_separators() {
+        assert_eq!(thousands(7), "7");
+        assert_eq!(thousands(1000), "1,000");
+        assert_eq!(thousands(10_000), "10,000");
+        assert_eq!(thousands(1_234_567), "1,234,567");
+    }
+}
@@ -3,11 +3,25 @@ mod storage;
 mod server;
 mod stats;
 mod launcher;
+mod backtrace;
+mod calltree;
+mod cfg;
+mod check;
+mod coverage;
+mod deps;
+mod diff;
+mod export;
+mod loops;
+mod query;
+mod slice;
+mod symbols;
 mod tui;
+mod verify;
 
 use std::env;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 #[tokio::main]
 async fn main() {
@@ -61,11 +75,138 @@ async fn main() {
         }
         "stats" => {
             if args.len() < 3 {
-                eprintln!("Usage: {} stats <trace.tdb>", args[0]);
+                eprintln!(
+                    "Usage: {} stats <trace.tdb> [--profile] [--loops] [--branches] [--branches-json out.json] [--indirect] [--indirect-json out.json] [--memory] [--memory-json out.json] [--folded out.folded] [--flamegraph out.svg] [--format text|json|csv] [--binary path]",
+                    args[0]
+                );
                 std::process::exit(1);
             }
-            let trace_file = &args[2];
-            show_stats(trace_file);
+            show_stats(&args[2], &args[3..]);
+        }
+        "slice" => {
+            if args.len() < 5 {
+                eprintln!(
+                    "Usage: {} slice <in.tdb> <out.tdb> (--steps A..B | --call STEP | --before-signal N) [--renumber]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            slice_trace(&args[2], &args[3], &args[4..]);
+        }
+        "slice-deps" => {
+            if args.len() < 5 {
+                eprintln!(
+                    "Usage: {} slice-deps <trace.tdb> <step> <reg|0xaddr[:len]> [--addresses]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            slice_deps(&args[2], &args[3], &args[4], &args[5..]);
+        }
+        "watch" => {
+            if args.len() < 4 {
+                eprintln!(
+                    "Usage: {} watch <trace.tdb> <addr> [len] [--before N] [--after N] [--value COND] [--steps A..B]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            watch_memory(&args[2], &args[3], &args[4..]);
+        }
+        "query" => {
+            if args.len() < 4 {
+                eprintln!(
+                    "Usage: {} query <trace.tdb> <expression> [--limit N] [--count] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            query_trace(&args[2], &args[3], &args[4..]);
+        }
+        "check" => {
+            if args.len() < 3 {
+                eprintln!(
+                    "Usage: {} check <trace.tdb> [--only stack,uninit,abi,indirect] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            check_trace(&args[2], &args[3..]);
+        }
+        "verify" => {
+            if args.len() < 3 {
+                eprintln!("Usage: {} verify <trace.tdb>", args[0]);
+                std::process::exit(1);
+            }
+            verify_trace(&args[2]);
+        }
+        "export" => {
+            if args.len() < 3 {
+                eprintln!(
+                    "Usage: {} export --format jsonl|csv|chrome|perfetto|tenet|sqlite <trace.tdb> [-o out] [--fields f1,f2,...] [--steps A..B] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            export_trace(&args[2..]);
+        }
+        "coverage" => {
+            if args.len() < 3 {
+                eprintln!(
+                    "Usage: {} coverage <trace.tdb>... [--format drcov|lcov|text] [-o out] [--binary path]",
+                    args[0]
+                );
+                eprintln!("Covers the traced binary only: traces don't record which libraries were loaded.");
+                std::process::exit(1);
+            }
+            coverage_report(&args[2..]);
+        }
+        "calls" => {
+            if args.len() < 3 {
+                eprintln!(
+                    "Usage: {} calls <trace.tdb> [--depth N] [--args N] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            show_calls(&args[2], &args[3..]);
+        }
+        "backtrace" => {
+            if args.len() < 4 {
+                eprintln!(
+                    "Usage: {} backtrace <trace.tdb> <step> [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            show_backtrace(&args[2], &args[3], &args[4..]);
+        }
+        "diff" => {
+            if args.len() < 4 {
+                eprintln!(
+                    "Usage: {} diff <a.tdb> <b.tdb> [--context N] [--ignore reg1,reg2,mem] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            diff_traces(&args[2], &args[3], &args[4..]);
+        }
+        "cfg" => {
+            if args.len() < 3 {
+                eprintln!(
+                    "Usage: {} cfg <trace.tdb> [--function name] [--format dot|json] [-o out] [--binary path]",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+            show_cfg(&args[2], &args[3..]);
+        }
+        "migrate" => {
+            if args.len() < 4 {
+                eprintln!("Usage: {} migrate <old.tdb> <new.tdb>", args[0]);
+                std::process::exit(1);
+            }
+            migrate_trace(&args[2], &args[3]);
         }
         _ => {
             eprintln!("Unknown command: {}", args[1]);
@@ -83,7 +224,20 @@ fn print_usage(prog: &str) {
     eprintln!("  trace <pid> <output.tdb>              Attach to running process");
     eprintln!("  view <trace.tdb> [port]               View trace in browser");
     eprintln!("  tui <trace.tdb>                       View trace in terminal");
-    eprintln!("  stats <trace.tdb>                     Show trace statistics");
+    eprintln!("  stats <trace.tdb>                     Show trace statistics and function profile");
+    eprintln!("  slice <in.tdb> <out.tdb> <selection>  Extract part of a trace");
+    eprintln!("  slice-deps <trace.tdb> <step> <loc>   Where a register or memory value came from");
+    eprintln!("  watch <trace.tdb> <addr> [len]        Find the steps that wrote memory");
+    eprintln!("  query <trace.tdb> <expression>        Find the steps matching an expression");
+    eprintln!("  check <trace.tdb>                     Find stack smashing and other bugs");
+    eprintln!("  verify <trace.tdb>                    Check trace integrity");
+    eprintln!("  migrate <old.tdb> <new.tdb>           Convert a trace to the current format");
+    eprintln!("  export --format <fmt> <trace.tdb>     Write steps or calls to other tools");
+    eprintln!("  coverage <trace.tdb>...               Code coverage of the binary (drcov, lcov, text)");
+    eprintln!("  diff <a.tdb> <b.tdb>                  Find where two runs diverge");
+    eprintln!("  calls <trace.tdb>                     Show the call tree");
+    eprintln!("  backtrace <trace.tdb> <step>          Show the call stack at a step");
+    eprintln!("  cfg <trace.tdb>                       Control-flow graph (DOT, JSON)");
 }
 
 fn run_and_trace(program: &str, args: &[String], output: &str) {
@@ -91,7 +245,9 @@ fn run_and_trace(program: &str, args: &[String], output: &str) {
     let launcher = launcher::ProcessLauncher::launch(program, args)
         .expect("Failed to launch program");
     println!("Process started with PID: {} (stopped at entry)", launcher.pid);
-    trace_loop(launcher.pid, output);
+    let mut command = vec![program.to_string()];
+    command.extend_from_slice(args);
+    trace_loop(launcher.pid, output, command);
 }
 
 fn attach_and_trace(pid: i32, output: &str) {
@@ -99,14 +255,19 @@ fn attach_and_trace(pid: i32, output: &str) {
     let _launcher = launcher::ProcessLauncher::attach(pid)
         .expect("Failed to attach (need sudo?)");
     println!("Attached and stopped.");
-    trace_loop(pid, output);
+    trace_loop(pid, output, Vec::new());
 }
 
-fn trace_loop(pid: i32, output: &str) {
+fn trace_loop(pid: i32, output: &str, command: Vec<String>) {
     println!("\n  TDB - Timeless Debugger\n");
 
     let mut tracer = tracer::Tracer::new(pid, output)
         .expect("Failed to create tracer");
+    let started = Instant::now();
+    let recorded_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
 
     // Ctrl+C sets the flag so we can save before exiting
     let running = Arc::new(AtomicBool::new(true));
@@ -189,7 +350,16 @@ fn trace_loop(pid: i32, output: &str) {
 
     // Always save, even after Ctrl+C
     println!("\n  Saving trace to {}...", output);
-    tracer.db().save().expect("Failed to save trace");
+    let db = tracer.db();
+    db.set_meta(storage::TraceMeta {
+        command,
+        pid,
+        arch: tracer::ARCH.to_string(),
+        recorded_at,
+        duration_ms: started.elapsed().as_millis() as u64,
+        ..db.meta()
+    });
+    db.save().expect("Failed to save trace");
     tracer.detach();
 
     let total = tracer.step_count();
@@ -215,18 +385,933 @@ async fn view_trace(trace_file: &str, port: u16) {
     );
     println!("Loaded {} steps", db.count());
 
-    let stats = stats::TraceStats::analyze(&db);
+    let symbols = Arc::new(load_symbols(&db, None));
+    let stats = stats::TraceStats::analyze(&db, &symbols);
     println!(
         "  {} unique addrs, {} calls, {} returns, {} mem changes",
         stats.unique_addresses, stats.call_count, stats.ret_count, stats.mem_change_count
     );
 
-    server::serve(db, port).await;
+    server::serve(db, symbols, port).await;
 }
 
-fn show_stats(trace_file: &str) {
+fn show_stats(trace_file: &str, opts: &[String]) {
+    let mut profile = false;
+    let mut show_loops = false;
+    let mut branches = false;
+    let mut branches_json = None;
+    let mut indirect = false;
+    let mut indirect_json = None;
+    let mut memory = false;
+    let mut memory_json = None;
+    let mut folded = None;
+    let mut flamegraph = None;
+    let mut binary = None;
+    let mut format = stats::Format::Text;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--format", Some(v)) => {
+                format = v.parse::<stats::Format>().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+                i += 2;
+            }
+            ("--profile", _) => {
+                profile = true;
+                i += 1;
+            }
+            ("--loops", _) => {
+                show_loops = true;
+                i += 1;
+            }
+            ("--branches", _) => {
+                branches = true;
+                i += 1;
+            }
+            ("--branches-json", Some(v)) => {
+                branches_json = Some(v.clone());
+                i += 2;
+            }
+            ("--indirect", _) => {
+                indirect = true;
+                i += 1;
+            }
+            ("--indirect-json", Some(v)) => {
+                indirect_json = Some(v.clone());
+                i += 2;
+            }
+            ("--memory", _) => {
+                memory = true;
+                i += 1;
+            }
+            ("--memory-json", Some(v)) => {
+                memory_json = Some(v.clone());
+                i += 2;
+            }
+            ("--folded", Some(v)) => {
+                folded = Some(v.clone());
+                i += 2;
+            }
+            ("--flamegraph", Some(v)) => {
+                flamegraph = Some(v.clone());
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete stats option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let only_summary = !profile
+        && !show_loops
+        && !branches
+        && branches_json.is_none()
+        && !indirect
+        && indirect_json.is_none()
+        && !memory
+        && memory_json.is_none()
+        && folded.is_none()
+        && flamegraph.is_none();
+    if format != stats::Format::Text {
+        if !only_summary {
+            eprintln!("--format json|csv prints only the summary statistics; drop the other stats options");
+            std::process::exit(1);
+        }
+        let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+        let symbols = load_symbols(&db, binary.as_deref());
+        let stats = full_stats(&db, &symbols);
+        let mut out = std::io::stdout().lock();
+        let result = match format {
+            stats::Format::Csv => stats.write_csv(&mut out),
+            _ => stats.write_json(&mut out),
+        };
+        if let Err(e) = result {
+            eprintln!("Stats failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Loading trace from {}...\n", trace_file);
     let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
-    let stats = stats::TraceStats::analyze(&db);
+    let symbols = load_symbols(&db, binary.as_deref());
+    let stats = full_stats(&db, &symbols);
     stats.print();
+    if only_summary {
+        return;
+    }
+
+    if show_loops {
+        println!();
+        loops::Loops::detect(&db, &symbols).print(30);
+    }
+    if branches || branches_json.is_some() {
+        let branch_stats = stats::BranchStats::build(&db, &symbols);
+        if branches {
+            println!();
+            branch_stats.print(30);
+        }
+        if let Some(path) = branches_json {
+            let result = open_output(Some(&path))
+                .and_then(|mut out| branch_stats.write_json(&mut out));
+            if let Err(e) = result {
+                eprintln!("Branch stats failed: {}", e);
+                std::process::exit(1);
+            }
+            println!("\nWrote branch sites to {}", path);
+        }
+    }
+    if indirect || indirect_json.is_some() {
+        let targets = stats::IndirectTargets::build(&db, &symbols);
+        if indirect {
+            println!();
+            targets.print(30);
+        }
+        if let Some(path) = indirect_json {
+            let result = open_output(Some(&path))
+                .and_then(|mut out| targets.write_json(&mut out));
+            if let Err(e) = result {
+                eprintln!("Indirect target stats failed: {}", e);
+                std::process::exit(1);
+            }
+            println!("\nWrote indirect call and jump targets to {}", path);
+        }
+    }
+    if memory || memory_json.is_some() {
+        let buckets = stats::MemoryHeatmap::DEFAULT_BUCKETS;
+        let heatmap = match stats::MemoryHeatmap::build(&db, &symbols, buckets) {
+            Ok(heatmap) => heatmap,
+            Err(e) => {
+                eprintln!("Memory stats failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if memory {
+            println!();
+            heatmap.print(20);
+        }
+        if let Some(path) = memory_json {
+            let result = open_output(Some(&path))
+                .and_then(|mut out| heatmap.write_json(&mut out));
+            if let Err(e) = result {
+                eprintln!("Memory stats failed: {}", e);
+                std::process::exit(1);
+            }
+            println!("\nWrote memory heatmap to {}", path);
+        }
+    }
+    if !profile && folded.is_none() && flamegraph.is_none() {
+        return;
+    }
+    let profile_data = stats::Profile::build(&db, &symbols);
+    if profile {
+        println!();
+        profile_data.print(30);
+    }
+    if let Some(path) = folded {
+        let result = open_output(Some(&path))
+            .and_then(|mut out| profile_data.write_folded(&mut out));
+        if let Err(e) = result {
+            eprintln!("Profile failed: {}", e);
+            std::process::exit(1);
+        }
+        println!("\nWrote folded stacks to {}", path);
+    }
+    if let Some(path) = flamegraph {
+        if let Err(e) = std::fs::write(&path, profile_data.flamegraph()) {
+            eprintln!("Profile failed: write {}: {}", path, e);
+            std::process::exit(1);
+        }
+        println!("\nWrote flame graph to {}", path);
+    }
+}
+
+fn slice_trace(input: &str, output: &str, opts: &[String]) {
+    let mut spec = None;
+    let mut renumber = false;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--steps", Some(v)) => {
+                let (start, end) = parse_step_range(v);
+                spec = Some(slice::SliceSpec::Steps { start, end });
+                i += 2;
+            }
+            ("--call", Some(v)) => {
+                spec = Some(slice::SliceSpec::Call(parse_arg(v, "step")));
+                i += 2;
+            }
+            ("--before-signal", Some(v)) => {
+                spec = Some(slice::SliceSpec::BeforeSignal(parse_arg(v, "step count")));
+                i += 2;
+            }
+            ("--renumber", _) => {
+                renumber = true;
+                i += 1;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete slice option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+    let spec = spec.unwrap_or_else(|| {
+        eprintln!("Missing selection: --steps A..B, --call STEP or --before-signal N");
+        std::process::exit(1);
+    });
+
+    let db = storage::TraceDb::load(input).expect("Failed to load trace");
+    match slice::slice(&db, &spec, renumber, output) {
+        Ok(out) => {
+            let info = out.meta().slice.expect("slice info is always set");
+            println!(
+                "Wrote {} steps ({}..{} of {}) to {}",
+                out.count(),
+                info.start,
+                info.end,
+                input,
+                output
+            );
+        }
+        Err(e) => {
+            eprintln!("Slice failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_step_range(v: &str) -> (u64, u64) {
+    let range = v
+        .split_once("..")
+        .and_then(|(a, b)| Some((a.parse().ok()?, b.parse().ok()?)))
+        .filter(|(a, b)| a <= b);
+    range.unwrap_or_else(|| {
+        eprintln!("Invalid step range '{}', expected A..B with A <= B", v);
+        std::process::exit(1);
+    })
+}
+
+/// Summary statistics including the decoded memory accesses, which only
+/// `tdb stats` pays for.
+fn full_stats(db: &storage::TraceDb, symbols: &symbols::SymbolTable) -> stats::TraceStats {
+    let mut stats = stats::TraceStats::analyze(db, symbols);
+    if let Err(e) = stats.count_memory_accesses(db) {
+        eprintln!("Warning: memory accesses not counted ({})", e);
+    }
+    stats
+}
+
+/// A numeric option value, or exit with a message naming `what`.
+fn parse_arg<T: std::str::FromStr>(v: &str, what: &str) -> T {
+    v.parse().unwrap_or_else(|_| {
+        eprintln!("Invalid {} '{}'", what, v);
+        std::process::exit(1);
+    })
+}
+
+/// A buffered writer for `path`, or stdout when no path is given.
+fn open_output(path: Option<&str>) -> Result<Box<dyn std::io::Write>, String> {
+    let out: Box<dyn std::io::Write> = match path {
+        Some(path) => Box::new(
+            std::fs::File::create(path).map_err(|e| format!("Create {} failed: {}", path, e))?,
+        ),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    Ok(Box::new(std::io::BufWriter::new(out)))
+}
+
+/// Symbols for a trace, or an empty table (with a warning) if the binary
+/// cannot be read.
+fn load_symbols(db: &storage::TraceDb, binary: Option<&str>) -> symbols::SymbolTable {
+    symbols_or_empty(symbols::SymbolTable::for_trace(db, binary))
+}
+
+fn symbols_or_empty(table: Result<symbols::SymbolTable, String>) -> symbols::SymbolTable {
+    match table {
+        Ok(table) if table.is_empty() => {
+            eprintln!("Warning: binary has no function symbols");
+            table
+        }
+        Ok(table) => table,
+        Err(e) => {
+            eprintln!("Warning: no symbols ({})", e);
+            symbols::SymbolTable::default()
+        }
+    }
+}
+
+fn export_trace(opts: &[String]) {
+    let mut format = None;
+    let mut trace_file = None;
+    let mut output = None;
+    let mut fields = export::DEFAULT_FIELDS.to_string();
+    let mut steps = None;
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--format", Some(v)) => {
+                format = Some(v.parse::<export::Format>().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }));
+                i += 2;
+            }
+            ("-o" | "--output", Some(v)) => {
+                output = Some(v.clone());
+                i += 2;
+            }
+            ("--fields", Some(v)) => {
+                fields = v.clone();
+                i += 2;
+            }
+            ("--steps", Some(v)) => {
+                steps = Some(parse_step_range(v));
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) if opt.starts_with('-') => {
+                eprintln!("Unknown or incomplete export option: {}", opt);
+                std::process::exit(1);
+            }
+            (file, _) => {
+                trace_file = Some(file.to_string());
+                i += 1;
+            }
+        }
+    }
+    let (Some(format), Some(trace_file)) = (format, trace_file) else {
+        eprintln!("Missing --format jsonl|csv|chrome|perfetto|tenet|sqlite or trace file");
+        std::process::exit(1);
+    };
+    if format == export::Format::Sqlite && output.is_none() {
+        eprintln!("SQLite export needs an output file (-o)");
+        std::process::exit(1);
+    }
+    let fields = export::parse_fields(&fields).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    // JSON Lines and CSV are read from the file a block at a time, so a
+    // large trace never has to fit in memory. Header-less and SQLite traces
+    // can't be streamed and are loaded whole, as for the other formats.
+    let stream = match format {
+        export::Format::Jsonl | export::Format::Csv => {
+            storage::TraceStream::open(&trace_file).unwrap_or_else(|e| {
+                eprintln!("Export failed: {}", e);
+                std::process::exit(1);
+            })
+        }
+        _ => None,
+    };
+    let result = match stream {
+        Some(stream) => {
+            // Finding the binary's slide takes one more pass over the file.
+            let symbols = if fields.contains(&export::Field::Symbol) {
+                symbols_or_empty(symbols::SymbolTable::for_trace_file(
+                    &trace_file,
+                    stream.meta(),
+                    binary.as_deref(),
+                ))
+            } else {
+                symbols::SymbolTable::default()
+            };
+            let opts = export::ExportOptions { format, fields, steps };
+            open_output(output.as_deref())
+                .and_then(|mut out| export::export_stream(stream, &symbols, &opts, &mut out))
+        }
+        None => {
+            let db = storage::TraceDb::load(&trace_file).expect("Failed to load trace");
+            let symbols = load_symbols(&db, binary.as_deref());
+            match (format, &output) {
+                (export::Format::Sqlite, Some(path)) => {
+                    export::export_sqlite(&db, &symbols, steps, path)
+                }
+                _ => {
+                    let opts = export::ExportOptions { format, fields, steps };
+                    open_output(output.as_deref())
+                        .and_then(|mut out| export::export(&db, &symbols, &opts, &mut out))
+                }
+            }
+        }
+    };
+    match result {
+        Ok(n) => {
+            if let Some(path) = output {
+                eprintln!("Wrote {} records to {}", n, path);
+            }
+        }
+        Err(e) => {
+            eprintln!("Export failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn coverage_report(opts: &[String]) {
+    let mut format = coverage::Format::Text;
+    let mut traces = Vec::new();
+    let mut output = None;
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--format", Some(v)) => {
+                format = v.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+                i += 2;
+            }
+            ("-o" | "--output", Some(v)) => {
+                output = Some(v.clone());
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) if opt.starts_with('-') => {
+                eprintln!("Unknown or incomplete coverage option: {}", opt);
+                std::process::exit(1);
+            }
+            (file, _) => {
+                traces.push(file.to_string());
+                i += 1;
+            }
+        }
+    }
+    if traces.is_empty() {
+        eprintln!("Missing trace file");
+        std::process::exit(1);
+    }
+
+    let mut cov = coverage::Coverage::default();
+    let mut symbols = symbols::SymbolTable::default();
+    for trace_file in &traces {
+        let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+        symbols = load_symbols(&db, binary.as_deref());
+        if let Err(e) = cov.add_trace(&db, &symbols) {
+            eprintln!("Coverage failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let lines = match format {
+        coverage::Format::Lcov => match symbols::LineTable::load(symbols.path()) {
+            Ok(lines) => Some(lines),
+            Err(e) => {
+                eprintln!("Coverage failed: lcov needs debug info ({})", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let result = open_output(output.as_deref())
+        .and_then(|mut out| cov.write(format, &symbols, lines.as_ref(), &mut out));
+    if let Err(e) = result {
+        eprintln!("Coverage failed: {}", e);
+        std::process::exit(1);
+    }
+    // The text summary already counts these steps.
+    if cov.outside > 0 && format != coverage::Format::Text {
+        eprintln!(
+            "Note: {} steps ran outside the binary (dyld, system libraries) and are left out; \
+             traces don't record which libraries were loaded",
+            cov.outside
+        );
+    }
+}
+
+fn show_calls(trace_file: &str, opts: &[String]) {
+    let mut max_depth = None;
+    let mut args = 4;
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--depth", Some(v)) => {
+                max_depth = Some(parse_arg(v, "depth"));
+                i += 2;
+            }
+            ("--args", Some(v)) => {
+                args = parse_arg(v, "argument count");
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete calls option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let symbols = load_symbols(&db, binary.as_deref());
+    calltree::CallTree::build(&db, &symbols).print(max_depth, args);
+}
+
+fn show_backtrace(trace_file: &str, step: &str, opts: &[String]) {
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete backtrace option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+    let step: u64 = parse_arg(step, "step number");
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let symbols = load_symbols(&db, binary.as_deref());
+    match backtrace::Backtrace::at(&db, &symbols, step) {
+        Some(bt) => bt.print(),
+        None => {
+            eprintln!("Step {} is not in the trace", step);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn slice_deps(trace_file: &str, step: &str, target: &str, opts: &[String]) {
+    let mut options = deps::DepOptions::default();
+    for opt in opts {
+        match opt.as_str() {
+            "--addresses" => options.addresses = true,
+            _ => {
+                eprintln!("Unknown slice-deps option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+    let step: u64 = parse_arg(step, "step number");
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let arch = db.meta().arch;
+    let result = deps::Target::parse(target, &arch).and_then(|target| {
+        deps::backward_slice(&db.get_range(0, step), &arch, step, &target, &options)
+    });
+    match result {
+        Ok(slice) => slice.print(),
+        Err(e) => {
+            eprintln!("Slice failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn watch_memory(trace_file: &str, addr: &str, opts: &[String]) {
+    // An optional length comes straight after the address.
+    let (len, opts) = match opts.first() {
+        Some(len) if !len.starts_with("--") => (Some(len.as_str()), &opts[1..]),
+        _ => (None, opts),
+    };
+    let mut watch = storage::Watch::parse(addr, len).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut before = None;
+    let mut after = None;
+    let mut steps = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--before", Some(v)) => {
+                before = Some(parse_arg(v, "step number"));
+                i += 2;
+            }
+            ("--after", Some(v)) => {
+                after = Some(parse_arg(v, "step number"));
+                i += 2;
+            }
+            ("--value", Some(v)) => {
+                watch.condition = Some(v.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }));
+                i += 2;
+            }
+            ("--steps", Some(v)) => {
+                steps = Some(parse_step_range(v));
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete watch option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let hits: Vec<storage::WatchHit> = match (before, after) {
+        (Some(step), _) => db.last_write_before(&watch, step).into_iter().collect(),
+        (None, Some(step)) => db.next_write_after(&watch, step).into_iter().collect(),
+        (None, None) => db.watch(&watch, steps.unwrap_or((0, u64::MAX))),
+    };
+    if hits.is_empty() {
+        match before {
+            Some(step) => println!("No write to {} before step {}", watch, step),
+            None => println!("No writes to {}", watch),
+        }
+        return;
+    }
+    println!("Writes to {}:", watch);
+    for hit in &hits {
+        let value = match (hit.old_value, hit.new_value) {
+            (Some(old), Some(new)) => format!("{:#x} -> {:#x}", old, new),
+            _ => hit
+                .changes
+                .iter()
+                .map(|c| format!("[{:#x}] {:#04x} -> {:#04x}", c.addr, c.old_val, c.new_val))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        println!(
+            "  step {:<8} 0x{:<14x} {:<32} {}",
+            hit.step, hit.pc, hit.insn, value
+        );
+    }
+    println!(
+        "\n{} write{}",
+        hits.len(),
+        if hits.len() == 1 { "" } else { "s" }
+    );
+}
+
+fn show_cfg(trace_file: &str, opts: &[String]) {
+    let mut function = None;
+    let mut format = cfg::Format::Dot;
+    let mut output = None;
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--function", Some(v)) => {
+                function = Some(v.clone());
+                i += 2;
+            }
+            ("--format", Some(v)) => {
+                format = v.parse().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                });
+                i += 2;
+            }
+            ("-o" | "--output", Some(v)) => {
+                output = Some(v.clone());
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete cfg option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let symbols = load_symbols(&db, binary.as_deref());
+    let mut graph = cfg::Cfg::build(&db, &symbols);
+    if let Some(name) = &function {
+        let functions = graph.functions();
+        graph = graph.function(name);
+        if graph.blocks.is_empty() {
+            eprintln!("No executed blocks in function {}", name);
+            if !functions.is_empty() {
+                let hottest: Vec<&str> = functions.iter().take(10).map(String::as_str).collect();
+                eprintln!("Hottest functions: {}", hottest.join(", "));
+            }
+            std::process::exit(1);
+        }
+    }
+    let result = open_output(output.as_deref()).and_then(|mut out| graph.write(format, &mut out));
+    match result {
+        Ok(()) => {
+            if let Some(path) = output {
+                eprintln!(
+                    "Wrote {} blocks and {} edges to {}",
+                    graph.blocks.len(),
+                    graph.edges.len(),
+                    path
+                );
+            }
+        }
+        Err(e) => {
+            eprintln!("CFG failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn diff_traces(a_file: &str, b_file: &str, opts: &[String]) {
+    let mut context = 5;
+    let mut options = diff::DiffOptions::default();
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--context", Some(v)) => {
+                context = parse_arg(v, "context line count");
+                i += 2;
+            }
+            ("--ignore", Some(v)) => {
+                options.ignore = v.split(',').map(|r| r.trim().to_string()).collect();
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete diff option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let a = storage::TraceDb::load(a_file).expect("Failed to load trace");
+    let b = storage::TraceDb::load(b_file).expect("Failed to load trace");
+    let a_symbols = load_symbols(&a, binary.as_deref());
+    let b_symbols = load_symbols(&b, binary.as_deref());
+    let a = diff::Side { db: &a, symbols: &a_symbols };
+    let b = diff::Side { db: &b, symbols: &b_symbols };
+    println!("Comparing {} (a) with {} (b)...\n", a_file, b_file);
+    let result = diff::diff(a, b, &options);
+    result.print(a, b, context);
+    if !result.is_identical() {
+        std::process::exit(1);
+    }
+}
+
+fn query_trace(trace_file: &str, expression: &str, opts: &[String]) {
+    let query: query::Query = expression.parse().unwrap_or_else(|e| {
+        eprintln!("Bad query: {}", e);
+        std::process::exit(1);
+    });
+    let mut limit = None;
+    let mut count_only = false;
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--limit", Some(v)) => {
+                limit = Some(parse_arg(v, "limit"));
+                i += 2;
+            }
+            ("--count", _) => {
+                count_only = true;
+                i += 1;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete query option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let symbols = load_symbols(&db, binary.as_deref());
+    let steps = query.run(&db, &symbols).unwrap_or_else(|e| {
+        eprintln!("Query failed: {}", e);
+        std::process::exit(1);
+    });
+    if !count_only {
+        for &step in steps.iter().take(limit.unwrap_or(usize::MAX)) {
+            let Some(e) = db.get(step) else {
+                continue;
+            };
+            println!(
+                "  step {:<8} 0x{:<14x} {:<28} {}",
+                e.step,
+                e.pc,
+                symbols.describe(e.pc).unwrap_or_else(|| "??".to_string()),
+                e.insn_text
+            );
+        }
+        if limit.is_some_and(|n| steps.len() > n) {
+            println!("  ... and {} more", steps.len() - limit.unwrap_or(0));
+        }
+        println!();
+    }
+    println!(
+        "{} matching step{}",
+        steps.len(),
+        if steps.len() == 1 { "" } else { "s" }
+    );
+}
+
+fn check_trace(trace_file: &str, opts: &[String]) {
+    let mut checks = check::Check::ALL.to_vec();
+    let mut binary = None;
+    let mut i = 0;
+    while i < opts.len() {
+        let value = opts.get(i + 1);
+        match (opts[i].as_str(), value) {
+            ("--only", Some(v)) => {
+                checks = v
+                    .split(',')
+                    .map(|c| c.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    });
+                i += 2;
+            }
+            ("--binary", Some(v)) => {
+                binary = Some(v.clone());
+                i += 2;
+            }
+            (opt, _) => {
+                eprintln!("Unknown or incomplete check option: {}", opt);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let db = storage::TraceDb::load(trace_file).expect("Failed to load trace");
+    let symbols = load_symbols(&db, binary.as_deref());
+    match check::run(&db, &symbols, &checks) {
+        Ok(findings) => {
+            check::print(&findings);
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("Check failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn verify_trace(trace_file: &str) {
+    println!("Verifying {}...\n", trace_file);
+    let report = verify::verify_file(trace_file).unwrap_or_else(|e| {
+        eprintln!("Verify failed: {}", e);
+        std::process::exit(1);
+    });
+    report.print();
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+}
+
+fn migrate_trace(input: &str, output: &str) {
+    match storage::migrate(input, output) {
+        Ok(m) => {
+            println!(
+                "Migrated {} steps from format v{} to v{}",
+                m.steps,
+                m.from_version,
+                storage::FORMAT_VERSION
+            );
+            println!("  Calls/returns: {}/{}", m.calls, m.returns);
+            println!(
+                "  Architecture:  {}",
+                if m.arch.is_empty() { "unknown" } else { &m.arch }
+            );
+            println!("Wrote {}", output);
+        }
+        Err(e) => {
+            eprintln!("Migrate failed: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
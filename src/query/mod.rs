@@ -0,0 +1,416 @@
+//! Trace queries: boolean expressions over the fields of a step, shared by
+//! `tdb query`, the TUI search prompt and `/api/query`.
+//!
+//! ```text
+//! pc in main && x0 == 0 && mem.write(0x1000..0x1100)
+//! mnemonic =~ "^st" || depth > 3
+//! reg.changed(rax) && !(function == "memcpy")
+//! ```
+//!
+//! Fields are `step`, `pc`, `depth`, `insn`, `mnemonic`, `operands`,
+//! `function` and `kind` (call, return, branch, load, store, other); any
+//! other name is a register, read from the snapshot taken before the step
+//! ran. `x in a..b` is a half-open range and `x in name` means inside that
+//! function. `mem.read(..)` / `mem.write(..)` test the memory the
+//! instruction accessed, decoded from it like `tdb check` does, and
+//! `reg.changed(r)` whether the step left `r` with a new value.
+
+mod parse;
+
+use crate::deps::{Access, Decoder};
+use crate::storage::{InsnKind, TraceDb, TraceEntry};
+use crate::symbols::SymbolTable;
+use regex::Regex;
+use std::cell::OnceCell;
+
+/// A parsed query.
+#[derive(Debug, Clone)]
+pub struct Query {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Test(Test),
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    /// Numbers with any of == != < <= > >=; text with == and !=.
+    Compare(Operand, &'static str, Operand),
+    /// `=~`, or `!~` when the flag is set.
+    Match(Operand, Regex, bool),
+    In(Operand, Span),
+    MemRead(Option<(u64, u64)>),
+    MemWrite(Option<(u64, u64)>),
+    RegChanged(String),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Field(Field),
+    Reg(String),
+    Num(u64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Field {
+    Step,
+    Pc,
+    Depth,
+    Insn,
+    Mnemonic,
+    Operands,
+    Function,
+    Kind,
+}
+
+#[derive(Debug, Clone)]
+enum Span {
+    Range(u64, u64),
+    Function(String),
+}
+
+impl Operand {
+    fn is_number(&self) -> bool {
+        match self {
+            Operand::Field(f) => matches!(f, Field::Step | Field::Pc | Field::Depth),
+            Operand::Reg(_) | Operand::Num(_) => true,
+            Operand::Str(_) => false,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Operand::Field(f) => format!("{:?}", f).to_lowercase(),
+            Operand::Reg(r) => format!("register {}", r),
+            Operand::Num(_) => "a number".to_string(),
+            Operand::Str(_) => "a string".to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for Query {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Ok(Query {
+            expr: parse::parse(s)?,
+        })
+    }
+}
+
+impl Query {
+    /// A matcher for steps of a trace recorded on `arch`.
+    pub fn matcher<'a>(
+        &'a self,
+        symbols: &'a SymbolTable,
+        arch: &str,
+    ) -> Result<Matcher<'a>, String> {
+        let decoder = if self.expr.uses_memory() {
+            Some(Decoder::new(arch)?)
+        } else {
+            None
+        };
+        Ok(Matcher {
+            query: self,
+            symbols,
+            decoder,
+        })
+    }
+
+    /// Steps of the whole trace that match, in order.
+    pub fn run(&self, db: &TraceDb, symbols: &SymbolTable) -> Result<Vec<u64>, String> {
+        let matcher = self.matcher(symbols, &db.meta().arch)?;
+        let mut steps = Vec::new();
+        // Each step is tested once the next one shows what it changed.
+        let mut prev: Option<TraceEntry> = None;
+        db.try_for_each_in(0, u64::MAX, |e| -> Result<(), String> {
+            if let Some(p) = prev.replace(e.clone()) {
+                let next = (p.step + 1 == e.step).then_some(e);
+                if matcher.matches(&p, next) {
+                    steps.push(p.step);
+                }
+            }
+            Ok(())
+        })?;
+        if let Some(p) = prev {
+            if matcher.matches(&p, None) {
+                steps.push(p.step);
+            }
+        }
+        Ok(steps)
+    }
+}
+
+impl Expr {
+    fn uses_memory(&self) -> bool {
+        match self {
+            Expr::Or(a, b) | Expr::And(a, b) => a.uses_memory() || b.uses_memory(),
+            Expr::Not(e) => e.uses_memory(),
+            Expr::Test(t) => matches!(t, Test::MemRead(_) | Test::MemWrite(_)),
+        }
+    }
+}
+
+/// Tests steps against a query.
+pub struct Matcher<'a> {
+    query: &'a Query,
+    symbols: &'a SymbolTable,
+    decoder: Option<Decoder>,
+}
+
+/// One step being tested; registers and memory accesses are worked out
+/// only if the query asks for them.
+struct Step<'a> {
+    entry: &'a TraceEntry,
+    next: Option<&'a TraceEntry>,
+    regs: OnceCell<serde_json::Value>,
+    next_regs: OnceCell<serde_json::Value>,
+    access: OnceCell<Access>,
+}
+
+enum Value<'a> {
+    Num(u64),
+    Str(std::borrow::Cow<'a, str>),
+}
+
+impl Matcher<'_> {
+    /// Whether the step at `entry` matches. `next` is the step after it, if
+    /// recorded; without it `reg.changed` never matches.
+    pub fn matches(&self, entry: &TraceEntry, next: Option<&TraceEntry>) -> bool {
+        let step = Step {
+            entry,
+            next,
+            regs: OnceCell::new(),
+            next_regs: OnceCell::new(),
+            access: OnceCell::new(),
+        };
+        self.eval(&self.query.expr, &step)
+    }
+
+    fn eval(&self, expr: &Expr, step: &Step) -> bool {
+        match expr {
+            Expr::Or(a, b) => self.eval(a, step) || self.eval(b, step),
+            Expr::And(a, b) => self.eval(a, step) && self.eval(b, step),
+            Expr::Not(e) => !self.eval(e, step),
+            Expr::Test(t) => self.test(t, step),
+        }
+    }
+
+    fn test(&self, test: &Test, step: &Step) -> bool {
+        let overlaps = |ranges: &[(u64, u64)], want: Option<(u64, u64)>| match want {
+            None => !ranges.is_empty(),
+            Some((lo, hi)) => ranges
+                .iter()
+                .any(|&(addr, len)| addr < hi && lo < addr.saturating_add(len)),
+        };
+        match test {
+            Test::Compare(left, op, right) => {
+                match (self.value(left, step), self.value(right, step)) {
+                    (Some(Value::Num(a)), Some(Value::Num(b))) => match *op {
+                        "==" => a == b,
+                        "!=" => a != b,
+                        "<" => a < b,
+                        "<=" => a <= b,
+                        ">" => a > b,
+                        _ => a >= b,
+                    },
+                    (Some(Value::Str(a)), Some(Value::Str(b))) => (a == b) == (*op == "=="),
+                    _ => false,
+                }
+            }
+            Test::Match(operand, regex, negate) => match self.value(operand, step) {
+                Some(Value::Str(s)) => regex.is_match(&s) != *negate,
+                _ => false,
+            },
+            Test::In(operand, span) => {
+                let Some(Value::Num(n)) = self.value(operand, step) else {
+                    return false;
+                };
+                match span {
+                    Span::Range(lo, hi) => (*lo..*hi).contains(&n),
+                    Span::Function(name) => self.symbols.lookup(n).is_some_and(|(f, _)| f == name),
+                }
+            }
+            Test::MemRead(range) => overlaps(&self.access(step).loads, *range),
+            // Recorded memory changes were made by an earlier store, so only
+            // the decoded stores of this instruction count.
+            Test::MemWrite(range) => overlaps(&self.access(step).stores, *range),
+            Test::RegChanged(reg) => {
+                let Some(next) = step.next else {
+                    return false;
+                };
+                let before = step.regs.get_or_init(|| parse_regs(&step.entry.regs));
+                let after = step.next_regs.get_or_init(|| parse_regs(&next.regs));
+                before[reg.as_str()].as_u64().is_some()
+                    && before[reg.as_str()] != after[reg.as_str()]
+            }
+        }
+    }
+
+    fn access<'s>(&self, step: &'s Step) -> &'s Access {
+        step.access.get_or_init(|| {
+            self.decoder
+                .as_ref()
+                .map(|d| d.decode(step.entry))
+                .unwrap_or_default()
+        })
+    }
+
+    fn value<'s>(&self, operand: &'s Operand, step: &'s Step) -> Option<Value<'s>> {
+        let e = step.entry;
+        let text = e.insn_text.split(" ; ").next().unwrap_or("").trim();
+        let mut parts = text.splitn(2, char::is_whitespace);
+        Some(match operand {
+            Operand::Num(n) => Value::Num(*n),
+            Operand::Str(s) => Value::Str(s.into()),
+            Operand::Reg(r) => {
+                let regs = step.regs.get_or_init(|| parse_regs(&e.regs));
+                Value::Num(regs[r.as_str()].as_u64()?)
+            }
+            Operand::Field(f) => match f {
+                Field::Step => Value::Num(e.step),
+                Field::Pc => Value::Num(e.pc),
+                Field::Depth => Value::Num(e.depth),
+                Field::Insn => Value::Str(e.insn_text.as_str().into()),
+                Field::Mnemonic => Value::Str(parts.next().unwrap_or("").into()),
+                Field::Operands => Value::Str(parts.nth(1).unwrap_or("").trim().into()),
+                Field::Function => Value::Str(self.symbols.lookup(e.pc)?.0.to_string().into()),
+                Field::Kind => Value::Str(
+                    match e.kind {
+                        InsnKind::Call => "call",
+                        InsnKind::Return => "return",
+                        InsnKind::Branch => "branch",
+                        InsnKind::Load => "load",
+                        InsnKind::Store => "store",
+                        InsnKind::Other => "other",
+                    }
+                    .into(),
+                ),
+            },
+        })
+    }
+}
+
+fn parse_regs(regs: &str) -> serde_json::Value {
+    serde_json::from_str(regs).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemChange, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, bytes: &[u8], insn: &str, regs: serde_json::Value) -> TraceEntry {
+        TraceEntry {
+            insn_bytes: bytes.to_vec(),
+            regs: regs.to_string(),
+            depth: step / 2,
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    const STR_X1_X0: &[u8] = &[0x01, 0x00, 0x00, 0xf9]; // str x1, [x0]
+    const LDR_X1_X0: &[u8] = &[0x01, 0x00, 0x40, 0xf9]; // ldr x1, [x0]
+
+    fn db() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".to_string(),
+            ..Default::default()
+        });
+        let regs = |x0: u64, x1: u64| serde_json::json!({ "x0": x0, "x1": x1, "sp": 0x8000 });
+        let entries = vec![
+            entry(0, 0x1000, &[0; 4], "mov x0, #0x1000", regs(0, 7)),
+            entry(1, 0x1004, STR_X1_X0, "str x1, [x0]", regs(0x1000, 7)),
+            entry(2, 0x1008, LDR_X1_X0, "ldr x1, [x0]", regs(0x1000, 7)),
+            entry(3, 0x100c, &[0; 4], "bl #0x2000 ; CALL", regs(0x1000, 7)),
+            entry(4, 0x2000, &[0; 4], "mov x0, #0", regs(0x1000, 7)),
+            entry(5, 0x2004, &[0; 4], "ret ; RETURN", regs(0, 7)),
+        ];
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![sym(0x1000, "main"), sym(0x2000, "helper")])
+    }
+
+    fn run(query: &str) -> Vec<u64> {
+        let q: Query = query.parse().unwrap();
+        q.run(&db(), &symbols()).unwrap()
+    }
+
+    #[test]
+    fn fields_and_registers() {
+        assert_eq!(run("pc in main && x0 == 0x1000"), vec![1, 2, 3]);
+        assert_eq!(run("function == \"helper\""), vec![4, 5]);
+        assert_eq!(run("depth > 1"), vec![4, 5]);
+        assert_eq!(run("step in 1..3"), vec![1, 2]);
+        assert_eq!(run("kind == \"call\" || kind == \"return\""), vec![3, 5]);
+        assert_eq!(run("x0 in helper"), Vec::<u64>::new());
+        // A register the step doesn't have never matches.
+        assert_eq!(run("rax == 0"), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn text_and_regex() {
+        assert_eq!(run("mnemonic =~ \"^(st|ld)r\""), vec![1, 2]);
+        assert_eq!(run("mnemonic == \"mov\" && operands =~ \"#0$\""), vec![4]);
+        assert_eq!(run("insn =~ \"CALL\""), vec![3]);
+        assert_eq!(run("!(insn !~ \"CALL\")"), vec![3]);
+    }
+
+    #[test]
+    fn memory_accesses() {
+        assert_eq!(run("mem.write(0x1000..0x1100)"), vec![1]);
+        assert_eq!(run("mem.write(0x1004)"), vec![1]);
+        assert_eq!(run("mem.write(0x1008)"), Vec::<u64>::new());
+        assert_eq!(run("mem.read()"), vec![2]);
+        assert_eq!(run("mem.read() || mem.write()"), vec![1, 2]);
+    }
+
+    #[test]
+    fn recorded_changes_are_not_this_steps_writes() {
+        let q: Query = "mem.write(0x7ff0)".parse().unwrap();
+        let symbols = symbols();
+        let m = q.matcher(&symbols, "arm64").unwrap();
+        let mut e = entry(
+            0,
+            0x1000,
+            &[0; 4],
+            "stp x29, x30, [sp, #-16]!",
+            serde_json::json!({}),
+        );
+        // The sample before this step shows an earlier instruction's store.
+        e.mem_changes.push(MemChange {
+            addr: 0x7ff0,
+            old_val: 0,
+            new_val: 1,
+        });
+        assert!(!m.matches(&e, None));
+    }
+
+    #[test]
+    fn changed_registers() {
+        assert_eq!(run("reg.changed(x0)"), vec![0, 4]);
+        // The last step has nothing after it to compare with.
+        assert_eq!(
+            run("reg.changed(x0) || step == 5 && reg.changed(sp)"),
+            vec![0, 4]
+        );
+    }
+}
@@ -0,0 +1,481 @@
+//! Tokenizer and recursive-descent parser for query expressions.
+
+use super::{Expr, Field, Operand, Span, Test};
+use regex::Regex;
+
+/// Deepest nesting of parentheses and `!`. Parsing and matching recurse
+/// once per level, and queries come from the web server too.
+const MAX_NESTING: usize = 64;
+/// Most tests in one query, which bounds how deep `&&` / `||` chains go.
+const MAX_TESTS: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Num(u64),
+    Str(String),
+    /// ==, !=, <, <=, >, >=, =~, !~
+    Op(&'static str),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+    DotDot,
+    In,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Ident(s) => format!("'{}'", s),
+            Token::Num(n) => format!("'{}'", n),
+            Token::Str(s) => format!("\"{}\"", s),
+            Token::Op(op) => format!("'{}'", op),
+            Token::And => "'&&'".to_string(),
+            Token::Or => "'||'".to_string(),
+            Token::Not => "'!'".to_string(),
+            Token::Open => "'('".to_string(),
+            Token::Close => "')'".to_string(),
+            Token::Comma => "','".to_string(),
+            Token::DotDot => "'..'".to_string(),
+            Token::In => "'in'".to_string(),
+        }
+    }
+}
+
+/// Split `input` into tokens, each with its byte offset.
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, String> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        let start = i;
+        let two = input.get(i..i + 2).unwrap_or("");
+        let token = match c {
+            _ if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            '"' => {
+                let mut s = String::new();
+                let mut chars = input[i + 1..].char_indices();
+                loop {
+                    match chars.next() {
+                        Some((j, '"')) => {
+                            i += j + 2;
+                            break;
+                        }
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => {
+                                if c != '"' && c != '\\' {
+                                    s.push('\\');
+                                }
+                                s.push(c);
+                            }
+                            None => return Err(error("Unterminated string", start)),
+                        },
+                        Some((_, c)) => s.push(c),
+                        None => return Err(error("Unterminated string", start)),
+                    }
+                }
+                Token::Str(s)
+            }
+            '0'..='9' => {
+                let (digits, radix) = match two {
+                    "0x" | "0X" => (i + 2, 16),
+                    _ => (i, 10),
+                };
+                let end = input[digits..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .map_or(input.len(), |n| digits + n);
+                let text = input[digits..end].replace('_', "");
+                let n = u64::from_str_radix(&text, radix)
+                    .map_err(|_| error(&format!("Bad number '{}'", &input[i..end]), start))?;
+                i = end;
+                Token::Num(n)
+            }
+            _ if c.is_ascii_alphabetic() || c == '_' => {
+                let end = input[i..]
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '$')
+                    .map_or(input.len(), |n| i + n);
+                // `a..b` never starts with a name, but `reg.changed` is one.
+                let end = input[i..end].find("..").map_or(end, |n| i + n);
+                let word = &input[i..end];
+                i = end;
+                if word == "in" {
+                    Token::In
+                } else {
+                    Token::Ident(word.to_string())
+                }
+            }
+            _ => {
+                let (token, len) = match two {
+                    "==" => (Token::Op("=="), 2),
+                    "!=" => (Token::Op("!="), 2),
+                    "<=" => (Token::Op("<="), 2),
+                    ">=" => (Token::Op(">="), 2),
+                    "=~" => (Token::Op("=~"), 2),
+                    "!~" => (Token::Op("!~"), 2),
+                    "&&" => (Token::And, 2),
+                    "||" => (Token::Or, 2),
+                    ".." => (Token::DotDot, 2),
+                    _ => match c {
+                        '<' => (Token::Op("<"), 1),
+                        '>' => (Token::Op(">"), 1),
+                        '!' => (Token::Not, 1),
+                        '(' => (Token::Open, 1),
+                        ')' => (Token::Close, 1),
+                        ',' => (Token::Comma, 1),
+                        _ => return Err(error(&format!("Unexpected '{}'", c), start)),
+                    },
+                };
+                i += len;
+                token
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+fn error(msg: &str, offset: usize) -> String {
+    format!("{} at column {}", msg, offset + 1)
+}
+
+pub(super) fn parse(input: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        pos: 0,
+        len: input.len(),
+        nesting: 0,
+        tests: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Err("Empty query".to_string());
+    }
+    let expr = parser.or()?;
+    match parser.peek() {
+        None => Ok(expr),
+        Some(t) => Err(parser.unexpected(&t.describe())),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    /// Input length, for errors at the end.
+    len: usize,
+    nesting: usize,
+    tests: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, t)| t)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).map(|(_, t)| t.clone());
+        self.pos += 1;
+        t
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.len, |(o, _)| *o)
+    }
+
+    fn unexpected(&self, what: &str) -> String {
+        error(&format!("Unexpected {}", what), self.offset())
+    }
+
+    /// Consume `want`, or fail saying what was expected.
+    fn expect(&mut self, want: Token) -> Result<(), String> {
+        match self.peek() {
+            Some(t) if *t == want => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(t) => Err(error(
+                &format!("Expected {}, found {}", want.describe(), t.describe()),
+                self.offset(),
+            )),
+            None => Err(error(
+                &format!("Expected {} at the end", want.describe()),
+                self.offset(),
+            )),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut left = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut left = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, String> {
+        let nested = matches!(self.peek(), Some(Token::Not | Token::Open));
+        if nested {
+            if self.nesting == MAX_NESTING {
+                return Err(error("Query nested too deeply", self.offset()));
+            }
+            self.nesting += 1;
+        }
+        let expr = match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Expr::Not(Box::new(self.not()?))
+            }
+            Some(Token::Open) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                self.expect(Token::Close)?;
+                expr
+            }
+            _ => {
+                if self.tests == MAX_TESTS {
+                    return Err(error("Query has too many tests", self.offset()));
+                }
+                self.tests += 1;
+                Expr::Test(self.test()?)
+            }
+        };
+        if nested {
+            self.nesting -= 1;
+        }
+        Ok(expr)
+    }
+
+    fn test(&mut self) -> Result<Test, String> {
+        let start = self.offset();
+        if let (Some(Token::Ident(name)), Some((_, Token::Open))) =
+            (self.peek().cloned(), self.tokens.get(self.pos + 1))
+        {
+            self.pos += 2;
+            let test = match name.as_str() {
+                "mem.read" => Test::MemRead(self.optional_range()?),
+                "mem.write" => Test::MemWrite(self.optional_range()?),
+                "reg.changed" => match self.next() {
+                    Some(Token::Ident(reg)) => Test::RegChanged(reg.to_lowercase()),
+                    _ => return Err(error("reg.changed() takes a register name", start)),
+                },
+                _ => {
+                    return Err(error(
+                        &format!(
+                            "Unknown function '{}' (expected mem.read, mem.write, reg.changed)",
+                            name
+                        ),
+                        start,
+                    ))
+                }
+            };
+            self.expect(Token::Close)?;
+            return Ok(test);
+        }
+
+        let left = self.operand()?;
+        let op_offset = self.offset();
+        match self.next() {
+            Some(Token::In) => {
+                if !left.is_number() {
+                    return Err(error(
+                        &format!("'in' needs a number on the left, not {}", left.describe()),
+                        start,
+                    ));
+                }
+                let span = match self.next() {
+                    Some(Token::Ident(name)) | Some(Token::Str(name)) => Span::Function(name),
+                    Some(Token::Num(lo)) => {
+                        self.expect(Token::DotDot)?;
+                        match self.next() {
+                            Some(Token::Num(hi)) => Span::Range(lo, hi),
+                            _ => return Err(error("Expected the end of the range", op_offset)),
+                        }
+                    }
+                    _ => {
+                        return Err(error(
+                            "Expected a range (0x1000..0x2000) or function name after 'in'",
+                            op_offset,
+                        ))
+                    }
+                };
+                Ok(Test::In(left, span))
+            }
+            Some(Token::Op(op @ ("=~" | "!~"))) => {
+                if left.is_number() {
+                    return Err(error(
+                        &format!("'{}' needs text on the left, not {}", op, left.describe()),
+                        start,
+                    ));
+                }
+                let pattern = match self.next() {
+                    Some(Token::Str(s)) => s,
+                    _ => return Err(error(&format!("'{}' takes a \"regex\"", op), op_offset)),
+                };
+                let regex = Regex::new(&pattern)
+                    .map_err(|e| error(&format!("Bad regex: {}", e), op_offset))?;
+                Ok(Test::Match(left, regex, op == "!~"))
+            }
+            Some(Token::Op(op)) => {
+                let right = self.operand()?;
+                if left.is_number() != right.is_number() {
+                    return Err(error(
+                        &format!(
+                            "Can't compare {} with {}",
+                            left.describe(),
+                            right.describe()
+                        ),
+                        start,
+                    ));
+                }
+                if !left.is_number() && !matches!(op, "==" | "!=") {
+                    return Err(error(
+                        &format!("'{}' compares numbers; use == or =~ for text", op),
+                        op_offset,
+                    ));
+                }
+                Ok(Test::Compare(left, op, right))
+            }
+            Some(t) => Err(error(
+                &format!("Expected a comparison, found {}", t.describe()),
+                op_offset,
+            )),
+            None => Err(error(
+                &format!("Expected a comparison after {}", left.describe()),
+                op_offset,
+            )),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Num(n)) => Ok(Operand::Num(n)),
+            Some(Token::Str(s)) => Ok(Operand::Str(s)),
+            Some(Token::Ident(name)) => Ok(match name.as_str() {
+                "step" => Operand::Field(Field::Step),
+                "pc" => Operand::Field(Field::Pc),
+                "depth" => Operand::Field(Field::Depth),
+                "insn" => Operand::Field(Field::Insn),
+                "mnemonic" => Operand::Field(Field::Mnemonic),
+                "operands" => Operand::Field(Field::Operands),
+                "function" => Operand::Field(Field::Function),
+                "kind" => Operand::Field(Field::Kind),
+                _ => Operand::Reg(name.to_lowercase()),
+            }),
+            Some(t) => {
+                self.pos -= 1;
+                Err(self.unexpected(&t.describe()))
+            }
+            None => Err(error("Unexpected end of query", self.len)),
+        }
+    }
+
+    /// `()`, `(0x1000)` for one byte, or `(0x1000..0x1100)`.
+    fn optional_range(&mut self) -> Result<Option<(u64, u64)>, String> {
+        let Some(Token::Num(lo)) = self.peek().cloned() else {
+            return Ok(None);
+        };
+        self.pos += 1;
+        if self.peek() != Some(&Token::DotDot) {
+            return Ok(Some((lo, lo.saturating_add(1))));
+        }
+        self.pos += 1;
+        match self.next() {
+            Some(Token::Num(hi)) => Ok(Some((lo, hi))),
+            _ => Err(error("Expected the end of the range", self.offset())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let tokens: Vec<Token> = tokenize(r#"pc in 0x10..0x2_0 && mnemonic =~ "^st\"x""#)
+            .unwrap()
+            .into_iter()
+            .map(|(_, t)| t)
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("pc".into()),
+                Token::In,
+                Token::Num(0x10),
+                Token::DotDot,
+                Token::Num(0x20),
+                Token::And,
+                Token::Ident("mnemonic".into()),
+                Token::Op("=~"),
+                Token::Str("^st\"x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn precedence() {
+        // && binds tighter than ||, ! tighter than &&.
+        let expr = parse("depth > 1 || !x0 == 0 && x1 == 2").unwrap();
+        let Expr::Or(_, right) = expr else {
+            panic!("expected ||: {:?}", expr);
+        };
+        let Expr::And(left, _) = *right else {
+            panic!("expected &&");
+        };
+        assert!(matches!(*left, Expr::Not(_)));
+    }
+
+    #[test]
+    fn errors_say_where() {
+        assert_eq!(
+            parse("x0 == ").unwrap_err(),
+            "Unexpected end of query at column 7"
+        );
+        assert_eq!(
+            parse("mnemonic > 3").unwrap_err(),
+            "Can't compare mnemonic with a number at column 1"
+        );
+        assert_eq!(
+            parse("(pc == 1").unwrap_err(),
+            "Expected ')' at the end at column 9"
+        );
+        assert!(parse("insn =~ \"(\"").unwrap_err().starts_with("Bad regex"));
+        assert!(parse("ldr").is_err());
+        assert!(parse("0x1000").is_err());
+        assert!(parse("mem.frob()")
+            .unwrap_err()
+            .starts_with("Unknown function 'mem.frob'"));
+    }
+
+    #[test]
+    fn nesting_and_size_are_bounded() {
+        let deep = format!("{}x0 == 0{}", "(".repeat(100_000), ")".repeat(100_000));
+        assert_eq!(
+            parse(&deep).unwrap_err(),
+            "Query nested too deeply at column 65"
+        );
+        assert!(parse(&"!".repeat(100_000)).is_err());
+        let nested = format!("{}x0 == 0{}", "(".repeat(64), ")".repeat(64));
+        assert!(parse(&nested).is_ok());
+        let long = vec!["x0 == 0"; 2000].join(" || ");
+        assert!(parse(&long)
+            .unwrap_err()
+            .starts_with("Query has too many tests"));
+    }
+}
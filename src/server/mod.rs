@@ -1,15 +1,20 @@
 use axum::{
     extract::{Path, Query, State},
     http::StatusCode,
-    response::{Html, Json},
+    response::{Html, IntoResponse, Json, Response},
     routing::get,
     Router,
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use tower_http::cors::CorsLayer;
-use crate::storage::TraceDb;
-use crate::stats::TraceStats;
+use crate::backtrace::Backtrace;
+use crate::calltree::CallTree;
+use crate::cfg::Cfg;
+use crate::loops::Loops;
+use crate::storage::{TraceDb, Watch, WatchHit};
+use crate::stats::{MemoryHeatmap, TraceStats};
+use crate::symbols::SymbolTable;
 
 // Embed the web viewer directly in the binary so it works from any directory.
 const INDEX_HTML: &str = include_str!("../../web/index.html");
@@ -17,6 +22,47 @@ const INDEX_HTML: &str = include_str!("../../web/index.html");
 #[derive(Clone)]
 pub struct AppState {
     pub db: Arc<TraceDb>,
+    pub symbols: Arc<SymbolTable>,
+    cache: Arc<Analyses>,
+}
+
+/// Whole-trace analyses, built on the first request that needs them and
+/// kept: the served trace doesn't change.
+#[derive(Default)]
+struct Analyses {
+    calltree: OnceLock<Arc<CallTree>>,
+    cfg: OnceLock<Arc<Cfg>>,
+    loops: OnceLock<Arc<Loops>>,
+    /// With the default number of buckets, which the viewer asks for.
+    heatmap: OnceLock<Arc<Result<MemoryHeatmap, String>>>,
+}
+
+/// Run `f` on the blocking thread pool, so a whole-trace analysis doesn't
+/// stall the async workers.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Result<T, StatusCode> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// The analysis kept in `cell`, built with `build` the first time.
+async fn cached<T: Send + Sync + 'static>(
+    state: &AppState,
+    cell: fn(&Analyses) -> &OnceLock<Arc<T>>,
+    build: fn(&TraceDb, &SymbolTable) -> T,
+) -> Result<Arc<T>, StatusCode> {
+    if let Some(done) = cell(&state.cache).get() {
+        return Ok(done.clone());
+    }
+    let state = state.clone();
+    blocking(move || {
+        cell(&state.cache)
+            .get_or_init(|| Arc::new(build(&state.db, &state.symbols)))
+            .clone()
+    })
+    .await
 }
 
 #[derive(Deserialize)]
@@ -61,6 +107,16 @@ pub async fn get_step(
         .ok_or(StatusCode::NOT_FOUND)
 }
 
+/// Call stack when `step` was about to execute.
+pub async fn get_backtrace(
+    State(state): State<AppState>,
+    Path(step): Path<u64>,
+) -> Result<Json<Backtrace>, StatusCode> {
+    Backtrace::at(&state.db, &state.symbols, step)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
 pub async fn get_count(
     State(state): State<AppState>,
 ) -> Json<CountResponse> {
@@ -70,23 +126,166 @@ pub async fn get_count(
 pub async fn get_stats(
     State(state): State<AppState>,
 ) -> Json<TraceStats> {
-    Json(TraceStats::analyze(&state.db))
+    Json(TraceStats::analyze(&state.db, &state.symbols))
+}
+
+/// Call tree of the whole trace, or of the calls made in `start..=end`.
+pub async fn get_calltree(
+    State(state): State<AppState>,
+    Query(range): Query<RangeQuery>,
+) -> Result<Response, StatusCode> {
+    match (range.start, range.end) {
+        (Some(start), Some(end)) if start > end => Err(StatusCode::BAD_REQUEST),
+        (Some(start), Some(end)) => {
+            let tree = blocking(move || {
+                CallTree::build_range(&state.db, &state.symbols, (start, end))
+            })
+            .await?;
+            Ok(Json(tree).into_response())
+        }
+        _ => {
+            let tree = cached(&state, |c| &c.calltree, CallTree::build).await?;
+            Ok(Json(&*tree).into_response())
+        }
+    }
 }
 
-pub fn create_router(db: Arc<TraceDb>) -> Router {
-    let state = AppState { db };
+#[derive(Deserialize)]
+pub struct CfgQuery {
+    function: Option<String>,
+}
+
+/// Control-flow graph of the whole trace, or of one function.
+pub async fn get_cfg(
+    State(state): State<AppState>,
+    Query(query): Query<CfgQuery>,
+) -> Result<Response, StatusCode> {
+    let cfg = cached(&state, |c| &c.cfg, Cfg::build).await?;
+    Ok(match query.function {
+        Some(name) => Json(cfg.function(&name)).into_response(),
+        None => Json(&*cfg).into_response(),
+    })
+}
+
+/// Loops with their iteration counts, and every run of them in step order
+/// for the viewer to fold.
+pub async fn get_loops(
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let loops = cached(&state, |c| &c.loops, Loops::detect).await?;
+    Ok(Json(&*loops).into_response())
+}
+
+#[derive(Deserialize)]
+pub struct QueryParams {
+    q: String,
+}
+
+/// Steps matching a query expression, e.g. `?q=pc in main && x0 == 0`.
+/// A query that doesn't parse is a 400 with the error as the body.
+pub async fn get_query(
+    State(state): State<AppState>,
+    Query(params): Query<QueryParams>,
+) -> Result<Json<Vec<u64>>, (StatusCode, String)> {
+    let query: crate::query::Query = params
+        .q
+        .parse()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    // Matching may decode every step, so the query runs on the blocking pool.
+    blocking(move || query.run(&state.db, &state.symbols))
+        .await
+        .map_err(|status| (status, String::new()))?
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+#[derive(Deserialize)]
+pub struct MemoryQuery {
+    buckets: Option<usize>,
+}
+
+/// Loads and stores per page and region, split into `buckets` time slices
+/// (32 by default) for the heatmap.
+pub async fn get_memory(
+    State(state): State<AppState>,
+    Query(query): Query<MemoryQuery>,
+) -> Result<Response, StatusCode> {
+    let buckets = match query.buckets {
+        Some(n @ 1..=1024) => n,
+        Some(_) => return Err(StatusCode::BAD_REQUEST),
+        None => MemoryHeatmap::DEFAULT_BUCKETS,
+    };
+    if buckets != MemoryHeatmap::DEFAULT_BUCKETS {
+        let heatmap = blocking(move || MemoryHeatmap::build(&state.db, &state.symbols, buckets))
+            .await?;
+        return heatmap
+            .map(|h| Json(h).into_response())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let heatmap = cached(&state, |c| &c.heatmap, |db, symbols| {
+        MemoryHeatmap::build(db, symbols, MemoryHeatmap::DEFAULT_BUCKETS)
+    })
+    .await?;
+    match &*heatmap {
+        Ok(h) => Ok(Json(h).into_response()),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    addr: String,
+    len: Option<String>,
+    value: Option<String>,
+    before: Option<u64>,
+    after: Option<u64>,
+}
+
+/// Writes to `addr..addr+len`: all of them, or only the last one before /
+/// first one after a step. `value` keeps writes whose result matches, e.g.
+/// "0" or "!=0".
+pub async fn get_watch(
+    State(state): State<AppState>,
+    Query(query): Query<WatchQuery>,
+) -> Result<Json<Vec<WatchHit>>, StatusCode> {
+    let mut watch = Watch::parse(&query.addr, query.len.as_deref())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    if let Some(value) = &query.value {
+        watch.condition = Some(value.parse().map_err(|_| StatusCode::BAD_REQUEST)?);
+    }
+    let hits = match (query.before, query.after) {
+        (Some(step), _) => state.db.last_write_before(&watch, step).into_iter().collect(),
+        (None, Some(step)) => state.db.next_write_after(&watch, step).into_iter().collect(),
+        (None, None) => state.db.watch(&watch, (0, u64::MAX)),
+    };
+    Ok(Json(hits))
+}
+
+pub fn create_router(db: Arc<TraceDb>, symbols: Arc<SymbolTable>) -> Router {
+    let state = AppState {
+        db,
+        symbols,
+        cache: Arc::default(),
+    };
     Router::new()
         .route("/", get(index_handler))
         .route("/api/trace", get(get_trace))
         .route("/api/trace/count", get(get_count))
         .route("/api/trace/:step", get(get_step))
+        .route("/api/trace/:step/backtrace", get(get_backtrace))
         .route("/api/stats", get(get_stats))
+        .route("/api/calltree", get(get_calltree))
+        .route("/api/cfg", get(get_cfg))
+        .route("/api/loops", get(get_loops))
+        .route("/api/memory", get(get_memory))
+        .route("/api/query", get(get_query))
+        .route("/api/watch", get(get_watch))
         .layer(CorsLayer::permissive())
         .with_state(state)
 }
 
-pub async fn serve(db: Arc<TraceDb>, port: u16) {
-    let app = create_router(db);
+pub async fn serve(db: Arc<TraceDb>, symbols: Arc<SymbolTable>, port: u16) {
+    let app = create_router(db, symbols);
     let addr = format!("127.0.0.1:{}", port);
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     println!("\n  Server running at http://{}", addr);
@@ -97,7 +296,7 @@ pub async fn serve(db: Arc<TraceDb>, port: u16) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::{MemChange, TraceEntry};
+    use crate::storage::{InsnKind, MemChange, TraceEntry};
     use axum::body::Body;
     use http_body_util::BodyExt;
     use tower::ServiceExt;
@@ -110,6 +309,8 @@ mod tests {
             insn_text: insn.to_string(),
             regs: serde_json::json!({"x0": step, "sp": 0x7000}).to_string(),
             mem_changes: vec![],
+            kind: InsnKind::classify(insn),
+            depth: 0,
         }
     }
 
@@ -128,6 +329,8 @@ mod tests {
             insn_text: insn.to_string(),
             regs: serde_json::json!({"x0": 42}).to_string(),
             mem_changes: changes,
+            kind: InsnKind::classify(insn),
+            depth: 0,
         }
     }
 
@@ -151,7 +354,7 @@ mod tests {
 
     #[tokio::test]
     async fn index_returns_html() {
-        let app = create_router(test_db(vec![]));
+        let app = create_router(test_db(vec![]), Arc::default());
         let resp = app.oneshot(request("GET", "/")).await.unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
 
@@ -184,7 +387,7 @@ mod tests {
             make_entry(1, 0x1004, "mov x0, #1"),
             make_entry(2, 0x1008, "ret"),
         ];
-        let app = create_router(test_db(entries));
+        let app = create_router(test_db(entries), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace"))
             .await
@@ -199,7 +402,7 @@ mod tests {
 
     #[tokio::test]
     async fn trace_empty_db() {
-        let app = create_router(test_db(vec![]));
+        let app = create_router(test_db(vec![]), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace"))
             .await
@@ -217,7 +420,7 @@ mod tests {
         let entries: Vec<TraceEntry> = (0..10)
             .map(|i| make_entry(i, 0x1000 + i * 4, "nop"))
             .collect();
-        let app = create_router(test_db(entries));
+        let app = create_router(test_db(entries), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace?start=3&end=7"))
             .await
@@ -237,7 +440,7 @@ mod tests {
     #[tokio::test]
     async fn get_step_existing() {
         let entries = vec![make_entry(42, 0xCAFE, "add x0, x1, x2")];
-        let app = create_router(test_db(entries));
+        let app = create_router(test_db(entries), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace/42"))
             .await
@@ -253,7 +456,7 @@ mod tests {
 
     #[tokio::test]
     async fn get_step_not_found() {
-        let app = create_router(test_db(vec![make_entry(0, 0x1000, "nop")]));
+        let app = create_router(test_db(vec![make_entry(0, 0x1000, "nop")]), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace/999"))
             .await
@@ -268,7 +471,7 @@ mod tests {
         let entries: Vec<TraceEntry> = (0..5)
             .map(|i| make_entry(i, 0x1000 + i * 4, "nop"))
             .collect();
-        let app = create_router(test_db(entries));
+        let app = create_router(test_db(entries), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace/count"))
             .await
@@ -282,7 +485,7 @@ mod tests {
 
     #[tokio::test]
     async fn count_empty_db() {
-        let app = create_router(test_db(vec![]));
+        let app = create_router(test_db(vec![]), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/trace/count"))
             .await
@@ -304,7 +507,7 @@ mod tests {
             make_entry_with_mem(2, "str x0, [sp]", 3),
             make_entry(3, 0x2008, "ret ; RETURN [depth:0]"),
         ];
-        let app = create_router(test_db(entries));
+        let app = create_router(test_db(entries), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/stats"))
             .await
@@ -319,11 +522,256 @@ mod tests {
         assert_eq!(stats.mem_change_count, 3);
     }
 
+    // ── GET /api/calltree ──
+
+    #[tokio::test]
+    async fn calltree_endpoint() {
+        let entries = vec![
+            make_entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"),
+            make_entry(1, 0x2000, "bl #0x3000 ; CALL [depth:2]"),
+            make_entry(2, 0x3000, "ret ; RETURN [depth:1]"),
+            make_entry(3, 0x2004, "ret ; RETURN [depth:0]"),
+            make_entry(4, 0x1004, "nop"),
+        ];
+        let db = test_db(entries);
+        let app = create_router(db.clone(), Arc::default());
+        let resp = app
+            .clone()
+            .oneshot(request("GET", "/api/calltree"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let tree: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tree["roots"], serde_json::json!([0]));
+        assert_eq!(tree["nodes"][0]["children"], serde_json::json!([1]));
+        assert_eq!(tree["nodes"][0]["target"], 0x2000);
+        assert_eq!(tree["nodes"][1]["return_step"], 2);
+        // x0 of make_entry is the step number.
+        assert_eq!(tree["nodes"][1]["ret"], 3);
+
+        // Built once and kept for later requests.
+        db.insert(make_entry(5, 0x1008, "bl #0x2000 ; CALL [depth:1]"))
+            .unwrap();
+        let resp = app
+            .oneshot(request("GET", "/api/calltree"))
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let tree: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tree["roots"], serde_json::json!([0]));
+    }
+
+    #[tokio::test]
+    async fn calltree_range_query() {
+        let entries = vec![
+            make_entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"),
+            make_entry(1, 0x2000, "bl #0x3000 ; CALL [depth:2]"),
+            make_entry(2, 0x3000, "ret ; RETURN [depth:1]"),
+        ];
+        let app = create_router(test_db(entries), Arc::default());
+        let resp = app
+            .oneshot(request("GET", "/api/calltree?start=1&end=2"))
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let tree: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(tree["nodes"].as_array().unwrap().len(), 1);
+        assert_eq!(tree["nodes"][0]["call_step"], 1);
+
+        let resp = create_router(test_db(vec![]), Arc::default())
+            .oneshot(request("GET", "/api/calltree?start=10&end=5"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // ── GET /api/cfg ──
+
+    #[tokio::test]
+    async fn cfg_endpoint() {
+        let entries = vec![
+            // One-byte instructions: 0x1002 follows the branch.
+            make_entry(0, 0x1000, "mov x0, #1"),
+            make_entry(1, 0x1001, "b.ne #0x1000"),
+            make_entry(2, 0x1000, "mov x0, #1"),
+            make_entry(3, 0x1001, "b.ne #0x1000"),
+            make_entry(4, 0x1002, "nop"),
+        ];
+        let app = create_router(test_db(entries), Arc::default());
+        let resp = app
+            .oneshot(request("GET", "/api/cfg"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let cfg: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(cfg["blocks"].as_array().unwrap().len(), 2);
+        assert_eq!(cfg["blocks"][0]["count"], 2);
+        assert_eq!(cfg["blocks"][0]["taken"], 1);
+        assert_eq!(cfg["blocks"][0]["not_taken"], 1);
+        assert_eq!(cfg["edges"][0]["kind"], "taken");
+    }
+
+    #[tokio::test]
+    async fn backtrace_endpoint() {
+        let entries = vec![
+            TraceEntry {
+                depth: 1,
+                ..make_entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]")
+            },
+            TraceEntry {
+                depth: 1,
+                ..make_entry(1, 0x2000, "nop")
+            },
+        ];
+        let db = test_db(entries);
+        let resp = create_router(db.clone(), Arc::default())
+            .oneshot(request("GET", "/api/trace/1/backtrace"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let bt: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let frames = bt["frames"].as_array().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["pc"], 0x2000);
+        assert_eq!(frames[0]["call_step"], 0);
+        assert_eq!(frames[1]["pc"], 0x1000);
+        assert_eq!(frames[1]["source"], "call");
+
+        let resp = create_router(db, Arc::default())
+            .oneshot(request("GET", "/api/trace/9/backtrace"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn watch_endpoint() {
+        // Both steps write 0x1000; only the second also writes 0x1001.
+        let entries = vec![
+            make_entry(0, 0x1000, "nop"),
+            make_entry_with_mem(1, "str x0, [sp]", 1),
+            make_entry_with_mem(2, "str x0, [sp]", 2),
+        ];
+        let db = test_db(entries);
+
+        let resp = create_router(db.clone(), Arc::default())
+            .oneshot(request("GET", "/api/watch?addr=0x1001"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let hits: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(hits.as_array().unwrap().len(), 1);
+        assert_eq!(hits[0]["step"], 2);
+        assert_eq!(hits[0]["new_value"], 1);
+
+        let resp = create_router(db.clone(), Arc::default())
+            .oneshot(request("GET", "/api/watch?addr=0x1000&len=2&before=2"))
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let hits: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // No store decodes from these bytes, so each write stays at the step
+        // whose sample shows it, and step 2 already sees its own.
+        assert_eq!(hits.as_array().unwrap().len(), 1);
+        assert_eq!(hits[0]["step"], 2);
+        assert_eq!(hits[0]["seen"], 2);
+
+        let resp = create_router(db.clone(), Arc::default())
+            .oneshot(request("GET", "/api/watch?addr=0x0&len=0xffffffffffffffff"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = create_router(db, Arc::default())
+            .oneshot(request("GET", "/api/watch?addr=sp"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn loops_endpoint() {
+        let mut entries = vec![make_entry(0, 0x1000, "mov x0, #2")];
+        for step in [1, 3] {
+            entries.push(make_entry(step, 0x1004, "sub x0, x0, #1"));
+            entries.push(make_entry(step + 1, 0x1005, "cbnz x0, #0x1004"));
+        }
+        entries.push(make_entry(5, 0x1006, "ret ; RETURN"));
+        let resp = create_router(test_db(entries), Arc::default())
+            .oneshot(request("GET", "/api/loops"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let loops: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(loops["loops"][0]["head"], 0x1004);
+        assert_eq!(loops["loops"][0]["trips"]["2"], 1);
+        let run = &loops["runs"][0];
+        assert_eq!((run["start"].as_u64(), run["end"].as_u64()), (Some(1), Some(4)));
+        assert_eq!(run["iterations"], 2);
+    }
+
+    #[tokio::test]
+    async fn query_endpoint() {
+        let entries = (0..4).map(|s| make_entry(s, 0x1000 + s * 4, "nop")).collect();
+        // x0 >= 2 && pc != 0x100c
+        let resp = create_router(test_db(entries), Arc::default())
+            .oneshot(request("GET", "/api/query?q=x0%20%3E%3D%202%20%26%26%20pc%20!%3D%200x100c"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let steps: Vec<u64> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(steps, vec![2]);
+
+        let resp = create_router(test_db(vec![]), Arc::default())
+            .oneshot(request("GET", "/api/query?q=x0%20%3D%3D"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Unexpected end of query at column 6");
+    }
+
+    #[tokio::test]
+    async fn memory_endpoint() {
+        // ldr x1, [x0] reading a heap page, twice.
+        let entries: Vec<TraceEntry> = (0..2)
+            .map(|step| TraceEntry {
+                insn_bytes: vec![0x01, 0x00, 0x40, 0xf9],
+                regs: serde_json::json!({"x0": 0x6000_0000_0010u64, "sp": 0x7000}).to_string(),
+                ..make_entry(step, 0x1000 + step * 4, "ldr x1, [x0]")
+            })
+            .collect();
+        let resp = create_router(test_db(entries), Arc::default())
+            .oneshot(request("GET", "/api/memory?buckets=2"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let map: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(map["pages"][0]["addr"], 0x6000_0000_0000u64);
+        assert_eq!(map["pages"][0]["region"], "heap");
+        assert_eq!(map["pages"][0]["read_buckets"], serde_json::json!([1, 1]));
+
+        let resp = create_router(test_db(vec![]), Arc::default())
+            .oneshot(request("GET", "/api/memory?buckets=0"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
     // ── 404 for unknown routes ──
 
     #[tokio::test]
     async fn unknown_route_returns_404() {
-        let app = create_router(test_db(vec![]));
+        let app = create_router(test_db(vec![]), Arc::default());
         let resp = app
             .oneshot(request("GET", "/api/nonexistent"))
             .await
@@ -335,7 +783,7 @@ mod tests {
 
     #[tokio::test]
     async fn cors_headers_present() {
-        let app = create_router(test_db(vec![]));
+        let app = create_router(test_db(vec![]), Arc::default());
         let req = axum::http::Request::builder()
             .method("GET")
             .uri("/api/trace/count")
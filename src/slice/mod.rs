@@ -0,0 +1,333 @@
+use crate::storage::{IndexKey, InsnKind, SliceInfo, TraceDb, TraceMeta};
+
+/// Which part of a trace to extract.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SliceSpec {
+    /// Inclusive step range.
+    Steps { start: u64, end: u64 },
+    /// One function invocation: the CALL at this step through its matching RETURN.
+    Call(u64),
+    /// The last N steps up to and including the last recorded signal.
+    BeforeSignal(u64),
+}
+
+impl SliceSpec {
+    /// Resolve the spec to an inclusive step range of `db`.
+    pub fn resolve(&self, db: &TraceDb) -> Result<(u64, u64), String> {
+        let (first, last) = db.step_bounds().ok_or("Trace is empty")?;
+        match *self {
+            SliceSpec::Steps { start, end } => {
+                if start > end {
+                    return Err(format!("Empty step range {}..{}", start, end));
+                }
+                if end < first || start > last {
+                    return Err(format!(
+                        "Step range {}..{} is outside the trace ({}..{})",
+                        start, end, first, last
+                    ));
+                }
+                Ok((start.max(first), end.min(last)))
+            }
+            SliceSpec::Call(call_step) => {
+                let entry = db
+                    .get(call_step)
+                    .ok_or_else(|| format!("No step {} in trace", call_step))?;
+                if entry.kind != InsnKind::Call {
+                    return Err(format!(
+                        "Step {} is not a call: {}",
+                        call_step, entry.insn_text
+                    ));
+                }
+                // A call that never returns (exit, crash, Ctrl+C) runs to the end.
+                Ok((call_step, matching_return(db, call_step).unwrap_or(last)))
+            }
+            SliceSpec::BeforeSignal(count) => {
+                if count == 0 {
+                    return Err("Step count must be at least 1".to_string());
+                }
+                let signal = db
+                    .meta()
+                    .signals
+                    .last()
+                    .cloned()
+                    .ok_or("Trace recorded no signals")?;
+                let start = (signal.step + 1).saturating_sub(count).max(first);
+                Ok((start, signal.step))
+            }
+        }
+    }
+}
+
+/// Find the RETURN that balances the CALL at `call_step`, skipping nested
+/// call/return pairs.
+fn matching_return(db: &TraceDb, call_step: u64) -> Option<u64> {
+    let index = db.index();
+    let calls = index.steps(IndexKey::Kind(InsnKind::Call));
+    let rets = index.steps(IndexKey::Kind(InsnKind::Return));
+    let mut ci = calls.partition_point(|&s| s <= call_step);
+    let mut ri = rets.partition_point(|&s| s <= call_step);
+    let mut nested = 0u64;
+    loop {
+        let ret = *rets.get(ri)?;
+        match calls.get(ci) {
+            Some(&call) if call < ret => {
+                nested += 1;
+                ci += 1;
+            }
+            _ => {
+                if nested == 0 {
+                    return Some(ret);
+                }
+                nested -= 1;
+                ri += 1;
+            }
+        }
+    }
+}
+
+/// Copy the steps selected by `spec` into a new trace at `out_path`.
+///
+/// The original metadata is kept, signals outside the slice are dropped, and
+/// the last known value of every byte written before the slice is stored in
+/// `initial_memory` so the memory state at the first step can be rebuilt.
+/// With `renumber`, steps are shifted to start at 0.
+pub fn slice(
+    db: &TraceDb,
+    spec: &SliceSpec,
+    renumber: bool,
+    out_path: &str,
+) -> Result<TraceDb, String> {
+    let (start, end) = spec.resolve(db)?;
+    let offset = if renumber { start } else { 0 };
+    let src_meta = db.meta();
+
+    let mut initial_memory = src_meta.initial_memory.clone();
+    {
+        let index = db.index();
+        for addr in index.written_addrs() {
+            let Some(step) = index.prev_before(IndexKey::MemWrite(addr), start) else {
+                continue;
+            };
+            let last_write = db
+                .get(step)
+                .and_then(|e| e.mem_changes.iter().rev().find(|c| c.addr == addr).cloned());
+            if let Some(c) = last_write {
+                initial_memory.insert(addr, c.new_val);
+            }
+        }
+    }
+
+    let signals = src_meta
+        .signals
+        .iter()
+        .filter(|s| (start..=end).contains(&s.step))
+        .map(|s| {
+            let mut s = s.clone();
+            s.step -= offset;
+            s
+        })
+        .collect();
+
+    let out = TraceDb::new(out_path)?;
+    for mut entry in db.get_range(start, end) {
+        entry.step -= offset;
+        out.insert(entry)?;
+    }
+    out.set_meta(TraceMeta {
+        signals,
+        initial_memory,
+        slice: Some(SliceInfo {
+            source: db.path().to_string(),
+            start,
+            end,
+        }),
+        ..src_meta
+    });
+    out.save()?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemChange, SignalEvent, TraceEntry};
+
+    fn entry(step: u64, insn: &str) -> TraceEntry {
+        TraceEntry {
+            regs: r#"{"x0":0,"sp":4096}"#.to_string(),
+            ..crate::storage::entry(step, 0x1000 + step * 4, insn)
+        }
+    }
+
+    fn write(step: u64, addr: u64, old_val: u8, new_val: u8) -> TraceEntry {
+        TraceEntry {
+            mem_changes: vec![MemChange {
+                addr,
+                old_val,
+                new_val,
+            }],
+            ..entry(step, "str x0, [sp]")
+        }
+    }
+
+    fn db_with(entries: Vec<TraceEntry>) -> TraceDb {
+        let db = TraceDb::new("source.tdb").unwrap();
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    /// main calls f (step 1), f calls g (step 3), g returns (5), f returns (7).
+    fn nested_calls() -> TraceDb {
+        db_with(vec![
+            entry(0, "mov x0, #1"),
+            entry(1, "bl #0x2000 ; CALL [depth:1]"),
+            entry(2, "mov x0, #2"),
+            entry(3, "bl #0x3000 ; CALL [depth:2]"),
+            entry(4, "mov x0, #3"),
+            entry(5, "ret ; RETURN [depth:1]"),
+            entry(6, "mov x0, #4"),
+            entry(7, "ret ; RETURN [depth:0]"),
+            entry(8, "mov x0, #5"),
+        ])
+    }
+
+    fn out_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("out.tdb").to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn steps_range_is_clamped_to_trace() {
+        let db = nested_calls();
+        let spec = SliceSpec::Steps { start: 6, end: 100 };
+        assert_eq!(spec.resolve(&db).unwrap(), (6, 8));
+    }
+
+    #[test]
+    fn steps_range_outside_trace_fails() {
+        let db = nested_calls();
+        let spec = SliceSpec::Steps {
+            start: 50,
+            end: 100,
+        };
+        assert!(spec.resolve(&db).is_err());
+    }
+
+    #[test]
+    fn call_skips_nested_pairs() {
+        let db = nested_calls();
+        assert_eq!(SliceSpec::Call(1).resolve(&db).unwrap(), (1, 7));
+        assert_eq!(SliceSpec::Call(3).resolve(&db).unwrap(), (3, 5));
+    }
+
+    #[test]
+    fn call_without_return_runs_to_end() {
+        let db = db_with(vec![
+            entry(0, "bl #0x2000 ; CALL [depth:1]"),
+            entry(1, "mov x0, #0"),
+            entry(2, "svc #0x80"),
+        ]);
+        assert_eq!(SliceSpec::Call(0).resolve(&db).unwrap(), (0, 2));
+    }
+
+    #[test]
+    fn call_on_non_call_step_fails() {
+        let db = nested_calls();
+        let err = SliceSpec::Call(2).resolve(&db).unwrap_err();
+        assert!(err.contains("not a call"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn before_signal_uses_last_signal() {
+        let db = nested_calls();
+        db.record_signal(SignalEvent {
+            step: 2,
+            signal: "SIGALRM".into(),
+        });
+        db.record_signal(SignalEvent {
+            step: 6,
+            signal: "SIGSEGV".into(),
+        });
+        assert_eq!(SliceSpec::BeforeSignal(3).resolve(&db).unwrap(), (4, 6));
+        assert_eq!(SliceSpec::BeforeSignal(100).resolve(&db).unwrap(), (0, 6));
+    }
+
+    #[test]
+    fn before_signal_without_signals_fails() {
+        let db = nested_calls();
+        assert!(SliceSpec::BeforeSignal(3).resolve(&db).is_err());
+    }
+
+    #[test]
+    fn slice_keeps_steps_and_meta() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = nested_calls();
+        db.set_meta(TraceMeta {
+            command: vec!["./complex".into()],
+            arch: "arm64".into(),
+            ..Default::default()
+        });
+
+        let out = slice(&db, &SliceSpec::Call(3), false, &out_path(&dir)).unwrap();
+        let loaded = TraceDb::load(&out_path(&dir)).unwrap();
+        assert_eq!(loaded.count(), 3);
+        assert_eq!(loaded.step_bounds(), Some((3, 5)));
+
+        let meta = loaded.meta();
+        assert_eq!(meta, out.meta());
+        assert_eq!(meta.command, vec!["./complex".to_string()]);
+        assert_eq!(meta.arch, "arm64");
+        let info = meta.slice.unwrap();
+        assert_eq!(
+            (info.source.as_str(), info.start, info.end),
+            ("source.tdb", 3, 5)
+        );
+    }
+
+    #[test]
+    fn slice_renumbers_steps_and_signals() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = nested_calls();
+        db.record_signal(SignalEvent {
+            step: 1,
+            signal: "SIGALRM".into(),
+        });
+        db.record_signal(SignalEvent {
+            step: 6,
+            signal: "SIGSEGV".into(),
+        });
+
+        let out = slice(&db, &SliceSpec::BeforeSignal(2), true, &out_path(&dir)).unwrap();
+        assert_eq!(out.step_bounds(), Some((0, 1)));
+        assert_eq!(out.get(0).unwrap().pc, 0x1000 + 5 * 4);
+        let signals = out.meta().signals;
+        assert_eq!(signals.len(), 1);
+        assert_eq!(signals[0].step, 1);
+        assert_eq!(signals[0].signal, "SIGSEGV");
+    }
+
+    #[test]
+    fn slice_carries_memory_written_before_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = db_with(vec![
+            write(0, 0x7000, 0x00, 0x11),
+            write(1, 0x7000, 0x11, 0x22),
+            write(2, 0x7001, 0x00, 0x33),
+            entry(3, "nop"),
+            write(4, 0x7000, 0x22, 0x44),
+        ]);
+
+        let out = slice(
+            &db,
+            &SliceSpec::Steps { start: 2, end: 4 },
+            false,
+            &out_path(&dir),
+        )
+        .unwrap();
+        let mem = out.meta().initial_memory;
+        assert_eq!(mem.get(&0x7000), Some(&0x22));
+        // Written inside the slice only: its old_val already covers it.
+        assert_eq!(mem.get(&0x7001), None);
+    }
+}
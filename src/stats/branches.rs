@@ -0,0 +1,357 @@
+//! Per-site branch behavior: how often each conditional branch went each
+//! way, and where each indirect branch went.
+
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// How many targets `print` lists per indirect branch.
+const TOP_TARGETS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchKind {
+    /// b.cond, cbz, cbnz, tbz, tbnz; x86 jcc.
+    Conditional,
+    /// br; x86 jmp through a register or memory.
+    Indirect,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BranchTarget {
+    pub addr: u64,
+    pub function: Option<String>,
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BranchSite {
+    pub addr: u64,
+    pub function: Option<String>,
+    /// Disassembly, without the tracer's annotations.
+    pub insn: String,
+    pub kind: BranchKind,
+    pub count: u64,
+    /// Executions whose next step is recorded, split by direction. The last
+    /// step of a trace (or of a sliced range) has neither.
+    pub taken: u64,
+    pub not_taken: u64,
+    /// Executions that went the other way from the one before at this site,
+    /// a rough stand-in for mispredictions.
+    pub flips: u64,
+    /// Where an indirect branch went, most frequent first.
+    pub targets: Vec<BranchTarget>,
+}
+
+impl BranchSite {
+    /// Share of known outcomes that jumped, 0.0..=1.0.
+    pub fn taken_ratio(&self) -> f64 {
+        let known = self.taken + self.not_taken;
+        if known == 0 {
+            0.0
+        } else {
+            self.taken as f64 / known as f64
+        }
+    }
+
+    /// Share of executions after the first that changed direction.
+    pub fn flip_ratio(&self) -> f64 {
+        let known = self.taken + self.not_taken;
+        if known < 2 {
+            0.0
+        } else {
+            self.flips as f64 / (known - 1) as f64
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BranchStats {
+    /// Conditional and indirect branches executed.
+    pub executed: u64,
+    /// Most executed first.
+    pub sites: Vec<BranchSite>,
+}
+
+/// A site while the trace is read.
+struct Site {
+    insn: String,
+    kind: BranchKind,
+    count: u64,
+    taken: u64,
+    not_taken: u64,
+    flips: u64,
+    last: Option<bool>,
+    targets: HashMap<u64, u64>,
+}
+
+impl BranchStats {
+    pub fn build(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        let mut sites: HashMap<u64, Site> = HashMap::new();
+        // (step, pc, length) of a branch whose outcome the next step shows.
+        let mut pending: Option<(u64, u64, u64)> = None;
+
+        db.for_each_in(0, u64::MAX, |e| {
+            if let Some((step, pc, len)) = pending.take() {
+                if step + 1 == e.step {
+                    let site = sites.get_mut(&pc).expect("pending site");
+                    let taken = e.pc != pc + len;
+                    if taken {
+                        site.taken += 1;
+                    } else {
+                        site.not_taken += 1;
+                    }
+                    if site.last.is_some_and(|last| last != taken) {
+                        site.flips += 1;
+                    }
+                    site.last = Some(taken);
+                    if site.kind == BranchKind::Indirect {
+                        *site.targets.entry(e.pc).or_default() += 1;
+                    }
+                }
+            }
+            if e.kind != InsnKind::Branch {
+                return;
+            }
+            let text = e.insn_text.split(" ; ").next().unwrap_or("").trim();
+            let Some(kind) = branch_kind(text) else {
+                return;
+            };
+            sites
+                .entry(e.pc)
+                .or_insert_with(|| Site {
+                    insn: text.to_string(),
+                    kind,
+                    count: 0,
+                    taken: 0,
+                    not_taken: 0,
+                    flips: 0,
+                    last: None,
+                    targets: HashMap::new(),
+                })
+                .count += 1;
+            pending = Some((e.step, e.pc, e.insn_bytes.len().max(1) as u64));
+        });
+
+        let mut sites: Vec<BranchSite> = sites
+            .into_iter()
+            .map(|(addr, s)| {
+                let mut targets: Vec<BranchTarget> = s
+                    .targets
+                    .into_iter()
+                    .map(|(addr, count)| BranchTarget {
+                        addr,
+                        function: symbols.describe(addr),
+                        count,
+                    })
+                    .collect();
+                targets.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+                BranchSite {
+                    addr,
+                    function: symbols.describe(addr),
+                    insn: s.insn,
+                    kind: s.kind,
+                    count: s.count,
+                    taken: s.taken,
+                    not_taken: s.not_taken,
+                    flips: s.flips,
+                    targets,
+                }
+            })
+            .collect();
+        sites.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+        Self {
+            executed: sites.iter().map(|s| s.count).sum(),
+            sites,
+        }
+    }
+
+    /// Print the `limit` most executed sites.
+    pub fn print(&self, limit: usize) {
+        println!("  Branch Sites");
+        println!("  ------------");
+        if self.sites.is_empty() {
+            println!("    No conditional or indirect branches executed");
+            return;
+        }
+        println!(
+            "    {:<14} {:<28} {:>8} {:>7} {:>7}  Instruction",
+            "Address", "Function", "Count", "Taken", "Flips"
+        );
+        for s in self.sites.iter().take(limit) {
+            let taken = match s.kind {
+                BranchKind::Conditional => format!("{:.1}%", 100.0 * s.taken_ratio()),
+                BranchKind::Indirect => "-".to_string(),
+            };
+            println!(
+                "    0x{:<12x} {:<28} {:>8} {:>7} {:>6.1}%  {}",
+                s.addr,
+                s.function.as_deref().unwrap_or("??"),
+                s.count,
+                taken,
+                100.0 * s.flip_ratio(),
+                s.insn
+            );
+            if s.kind == BranchKind::Indirect {
+                let mut targets: Vec<String> = s
+                    .targets
+                    .iter()
+                    .take(TOP_TARGETS)
+                    .map(|t| match &t.function {
+                        Some(f) => format!("{} ({})", f, t.count),
+                        None => format!("0x{:x} ({})", t.addr, t.count),
+                    })
+                    .collect();
+                if s.targets.len() > TOP_TARGETS {
+                    targets.push(format!("... {} more", s.targets.len() - TOP_TARGETS));
+                }
+                println!("{:>20}-> {}", "", targets.join(", "));
+            }
+        }
+        if self.sites.len() > limit {
+            println!("    ... and {} more", self.sites.len() - limit);
+        }
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        writeln!(out, "{}", json)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+}
+
+/// Conditional or indirect, from the disassembly; None for direct jumps,
+/// which always go the same way.
+fn branch_kind(text: &str) -> Option<BranchKind> {
+    let mut parts = text.splitn(2, char::is_whitespace);
+    let m = parts.next().unwrap_or("");
+    let operand = parts.next().unwrap_or("").trim();
+    match m {
+        "b" => None,
+        "br" => Some(BranchKind::Indirect),
+        // A direct jmp's operand is its target address.
+        "jmp" if operand.starts_with("0x") => None,
+        "jmp" => Some(BranchKind::Indirect),
+        _ => Some(BranchKind::Conditional),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::entry;
+    use crate::symbols::Symbol;
+
+    fn db(pcs: &[(u64, &str)]) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        for (step, &(pc, insn)) in pcs.iter().enumerate() {
+            db.insert(entry(step as u64, pc, insn)).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![
+            sym(0x1000, "main"),
+            sym(0x2000, "on_add"),
+            sym(0x3000, "on_sub"),
+        ])
+    }
+
+    #[test]
+    fn conditional_directions_and_flips() {
+        // b.ne at 0x1004: taken, taken, not taken, taken.
+        let stats = BranchStats::build(
+            &db(&[
+                (0x1000, "subs x0, x0, #1"),
+                (0x1004, "b.ne #0x1000"),
+                (0x1000, "subs x0, x0, #1"),
+                (0x1004, "b.ne #0x1000"),
+                (0x1000, "subs x0, x0, #1"),
+                (0x1004, "b.ne #0x1000"),
+                (0x1008, "b #0x1000"),
+                (0x1000, "subs x0, x0, #1"),
+                (0x1004, "b.ne #0x1000 ; [depth:0]"),
+                (0x1000, "nop"),
+            ]),
+            &symbols(),
+        );
+        // The unconditional b is not a site.
+        assert_eq!(stats.sites.len(), 1);
+        assert_eq!(stats.executed, 4);
+        let s = &stats.sites[0];
+        assert_eq!(s.insn, "b.ne #0x1000");
+        assert_eq!(s.function.as_deref(), Some("main+0x4"));
+        assert_eq!((s.count, s.taken, s.not_taken, s.flips), (4, 3, 1, 2));
+        assert_eq!(s.taken_ratio(), 0.75);
+        assert!((s.flip_ratio() - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn indirect_target_distribution() {
+        let stats = BranchStats::build(
+            &db(&[
+                (0x1000, "br x8"),
+                (0x2000, "b #0x1000"),
+                (0x1000, "br x8"),
+                (0x3000, "b #0x1000"),
+                (0x1000, "br x8"),
+                (0x2000, "nop"),
+                (0x1010, "jmp 0x1000"),
+            ]),
+            &symbols(),
+        );
+        assert_eq!(stats.sites.len(), 1);
+        let s = &stats.sites[0];
+        assert_eq!(s.kind, BranchKind::Indirect);
+        let targets: Vec<(Option<&str>, u64)> = s
+            .targets
+            .iter()
+            .map(|t| (t.function.as_deref(), t.count))
+            .collect();
+        assert_eq!(targets, vec![(Some("on_add"), 2), (Some("on_sub"), 1)]);
+    }
+
+    #[test]
+    fn last_step_outcome_is_unknown() {
+        let stats = BranchStats::build(&db(&[(0x1000, "cbz x0, #0x1000")]), &symbols());
+        let s = &stats.sites[0];
+        assert_eq!((s.count, s.taken, s.not_taken), (1, 0, 0));
+        assert_eq!(s.taken_ratio(), 0.0);
+    }
+
+    #[test]
+    fn branch_kinds_from_disassembly() {
+        assert_eq!(branch_kind("jne 0x1000"), Some(BranchKind::Conditional));
+        assert_eq!(
+            branch_kind("tbz w0, #3, #0x10"),
+            Some(BranchKind::Conditional)
+        );
+        assert_eq!(
+            branch_kind("jmp qword ptr [rax*8 + 0x1000]"),
+            Some(BranchKind::Indirect)
+        );
+        assert_eq!(branch_kind("jmp rax"), Some(BranchKind::Indirect));
+        assert_eq!(branch_kind("jmp 0x1000"), None);
+        assert_eq!(branch_kind("b #0x1000"), None);
+    }
+
+    #[test]
+    fn json_lists_sites() {
+        let stats = BranchStats::build(&db(&[(0x1000, "br x8"), (0x2000, "nop")]), &symbols());
+        let mut out = Vec::new();
+        stats.write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["sites"][0]["kind"], "indirect");
+        assert_eq!(json["sites"][0]["targets"][0]["function"], "on_add");
+        assert_eq!(json["executed"], 1);
+    }
+}
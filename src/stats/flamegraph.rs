@@ -0,0 +1,189 @@
+//! SVG flame graphs from folded stacks.
+//!
+//! Frames are stacked bottom-up, each as wide as the steps spent in it and
+//! its callees, with siblings in alphabetical order as flamegraph.pl does.
+//! Hovering a frame shows its full name and step count.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const WIDTH: f64 = 1200.0;
+const FRAME_HEIGHT: f64 = 16.0;
+/// Space for the title above the frames.
+const TOP: f64 = 36.0;
+const PAD: f64 = 10.0;
+/// Frames narrower than this are left out.
+const MIN_WIDTH: f64 = 0.1;
+/// Approximate width of one character at the label font size.
+const CHAR_WIDTH: f64 = 7.0;
+
+#[derive(Default)]
+struct Frame {
+    value: u64,
+    children: BTreeMap<String, Frame>,
+}
+
+impl Frame {
+    fn depth(&self) -> usize {
+        self.children
+            .values()
+            .map(|c| c.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Render `folded` ("a;b;c" -> count) as a standalone SVG document.
+pub fn render(folded: &BTreeMap<String, u64>, title: &str) -> String {
+    let mut root = Frame::default();
+    for (stack, &count) in folded {
+        root.value += count;
+        let mut frame = &mut root;
+        for name in stack.split(';') {
+            frame = frame.children.entry(name.to_string()).or_default();
+            frame.value += count;
+        }
+    }
+
+    let height = TOP + (root.depth() + 1) as f64 * FRAME_HEIGHT + PAD;
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<?xml version="1.0" standalone="no"?>"#);
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}" font-family="Verdana, sans-serif" font-size="12">"#,
+        WIDTH, height, WIDTH, height
+    );
+    let _ = writeln!(
+        svg,
+        r##"<rect width="100%" height="100%" fill="#f8f8f8"/>"##
+    );
+    let _ = writeln!(
+        svg,
+        r#"<text x="{}" y="24" text-anchor="middle" font-size="17">{}</text>"#,
+        WIDTH / 2.0,
+        escape(title)
+    );
+    if root.value > 0 {
+        let scale = (WIDTH - 2.0 * PAD) / root.value as f64;
+        let y = height - PAD - FRAME_HEIGHT;
+        frame(&mut svg, "all", &root, root.value, PAD, y, scale);
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn frame(svg: &mut String, name: &str, f: &Frame, total: u64, x: f64, y: f64, scale: f64) {
+    let width = f.value as f64 * scale;
+    if width < MIN_WIDTH {
+        return;
+    }
+    let percent = f.value as f64 * 100.0 / total as f64;
+    let chars = ((width - 6.0) / CHAR_WIDTH) as usize;
+    let label = match name.chars().count() {
+        n if n <= chars => name.to_string(),
+        _ if chars >= 3 => format!("{}..", name.chars().take(chars - 2).collect::<String>()),
+        _ => String::new(),
+    };
+    let _ = writeln!(
+        svg,
+        r#"<g><title>{} ({} steps, {:.2}%)</title><rect x="{:.1}" y="{:.1}" width="{:.1}" height="{}" rx="2" fill="{}"/><text x="{:.1}" y="{:.1}">{}</text></g>"#,
+        escape(name),
+        f.value,
+        percent,
+        x,
+        y,
+        width,
+        FRAME_HEIGHT - 1.0,
+        color(name),
+        x + 3.0,
+        y + 11.5,
+        escape(&label)
+    );
+    let mut child_x = x;
+    for (child_name, child) in &f.children {
+        frame(
+            svg,
+            child_name,
+            child,
+            total,
+            child_x,
+            y - FRAME_HEIGHT,
+            scale,
+        );
+        child_x += child.value as f64 * scale;
+    }
+}
+
+/// A warm colour derived from the name, so a function keeps its colour
+/// across graphs.
+fn color(name: &str) -> String {
+    // FNV-1a
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    let r = 205 + (hash % 50);
+    let g = (hash >> 8) % 230;
+    let b = (hash >> 16) % 55;
+    format!("rgb({},{},{})", r, g, b)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folded(stacks: &[(&str, u64)]) -> BTreeMap<String, u64> {
+        stacks.iter().map(|&(s, n)| (s.to_string(), n)).collect()
+    }
+
+    #[test]
+    fn frames_are_sized_by_inclusive_count() {
+        let svg = render(&folded(&[("prog;main", 1), ("prog;main;work", 3)]), "demo");
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("<title>all (4 steps, 100.00%)</title>"));
+        assert!(svg.contains("<title>main (4 steps, 100.00%)</title>"));
+        assert!(svg.contains("<title>work (3 steps, 75.00%)</title>"));
+        // 1180px of usable width, three quarters of it for work.
+        assert!(svg.contains(r#"width="885.0""#));
+        // all, prog, main and work are stacked on four rows.
+        assert!(svg.contains(r#"height="110""#));
+    }
+
+    #[test]
+    fn names_are_escaped_and_labels_truncated() {
+        let long = "std::vector<int>::push_back".repeat(4);
+        let svg = render(
+            &folded(&[
+                ("p;tiny", 1),
+                ("p;wide", 80_000),
+                (&format!("p;{}", long), 20_000),
+            ]),
+            "a & b",
+        );
+        assert!(svg.contains("a &amp; b"));
+        assert!(svg.contains("std::vector&lt;int&gt;"));
+        assert!(!svg.contains("<int>"));
+        // Too narrow to be drawn at all.
+        assert!(!svg.contains("tiny"));
+        assert!(svg.contains(".."));
+    }
+
+    #[test]
+    fn empty_profile_has_no_frames() {
+        let svg = render(&BTreeMap::new(), "empty");
+        assert!(!svg.contains("<g>"));
+    }
+
+    #[test]
+    fn colors_are_stable() {
+        assert_eq!(color("main"), color("main"));
+        assert_ne!(color("main"), color("work"));
+    }
+}
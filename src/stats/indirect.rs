@@ -0,0 +1,458 @@
+//! Where indirect calls and jumps went: every `blr` / `br` / `call rax` /
+//! `jmp [mem]` site with the targets it resolved to.
+//!
+//! No memory map is recorded, so "loaded code" is worked out from the
+//! trace: the traced binary's image, the shared cache, and any page that
+//! execution reached by falling through or by a direct branch or call from
+//! loaded code (which covers dyld and the code a trace started in). An
+//! indirect target on the stack, or on a page only ever entered indirectly,
+//! is suspicious: that is what a hijacked function pointer or injected code
+//! looks like. A library loaded with `dlopen` is only entered indirectly
+//! too, so its targets are flagged as well.
+
+use crate::storage::{InsnKind, TraceDb};
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+/// Granularity of the loaded-code map.
+const PAGE: u64 = 0x1000;
+
+/// Bytes below the lowest SP seen that still count as stack.
+const STACK_BELOW: u64 = 0x1000;
+/// Bytes above the highest SP seen that still count as stack.
+const STACK_ABOVE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferKind {
+    /// blr, blraa, ...; x86 call through a register or memory.
+    Call,
+    /// br, braa, ...; x86 jmp through a register or memory.
+    Jump,
+}
+
+/// Why a target looks wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Suspicion {
+    /// Inside the range SP moved through.
+    Stack,
+    /// Not in the binary or the shared cache, and never reached by direct
+    /// control flow.
+    Unmapped,
+}
+
+impl Suspicion {
+    pub fn label(self) -> &'static str {
+        match self {
+            Suspicion::Stack => "on the stack",
+            Suspicion::Unmapped => "outside any loaded code",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndirectTarget {
+    pub addr: u64,
+    pub function: Option<String>,
+    pub count: u64,
+    /// Step of the first transfer here, at the site.
+    pub first_step: u64,
+    pub suspicious: Option<Suspicion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndirectSite {
+    pub addr: u64,
+    pub function: Option<String>,
+    /// Disassembly, without the tracer's annotations.
+    pub insn: String,
+    pub kind: TransferKind,
+    pub count: u64,
+    /// Most frequent first.
+    pub targets: Vec<IndirectTarget>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct IndirectTargets {
+    /// Indirect calls and jumps executed.
+    pub executed: u64,
+    /// Most executed first.
+    pub sites: Vec<IndirectSite>,
+}
+
+/// A site while the trace is read.
+struct Site {
+    insn: String,
+    kind: TransferKind,
+    count: u64,
+    /// Target -> (count, first step).
+    targets: HashMap<u64, (u64, u64)>,
+}
+
+impl IndirectTargets {
+    pub fn build(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        let x86 = db.meta().arch == "x86_64";
+        let sp_reg = if x86 { "rsp" } else { "sp" };
+        let shared = super::shared_region(&db.meta().arch);
+        // A table built without the file has no image range; its symbols'
+        // span stands in for it.
+        let image = symbols.image_range().or_else(|| {
+            let first = symbols.symbols().next()?;
+            let last = symbols.symbols().last()?;
+            Some((first.addr, last.addr + last.size))
+        });
+
+        let mut sites: HashMap<u64, Site> = HashMap::new();
+        // Pages entered by direct control flow from loaded code.
+        let mut loaded: HashSet<u64> = HashSet::new();
+        let mut sp_range: Option<(u64, u64)> = None;
+        // Step, pc and how the previous instruction left.
+        let mut prev: Option<(u64, u64, Leave)> = None;
+
+        db.for_each_in(0, u64::MAX, |e| {
+            let regs: serde_json::Value = serde_json::from_str(&e.regs).unwrap_or_default();
+            if let Some(sp) = regs[sp_reg].as_u64() {
+                sp_range = Some(sp_range.map_or((sp, sp), |(lo, hi)| (lo.min(sp), hi.max(sp))));
+            }
+            match prev {
+                // The first step, and the step after a gap in a sliced
+                // trace, are taken as loaded code.
+                None => {
+                    loaded.insert(e.pc / PAGE);
+                }
+                Some((step, _, _)) if step + 1 != e.step => {
+                    loaded.insert(e.pc / PAGE);
+                }
+                Some((_, pc, Leave::Direct)) => {
+                    if loaded.contains(&(pc / PAGE)) {
+                        loaded.insert(e.pc / PAGE);
+                    }
+                }
+                Some((_, pc, Leave::Indirect)) => {
+                    let target = sites
+                        .get_mut(&pc)
+                        .expect("pending site")
+                        .targets
+                        .entry(e.pc)
+                        .or_insert((0, e.step - 1));
+                    target.0 += 1;
+                }
+                Some((_, _, Leave::Return)) => {}
+            }
+
+            let text = e.insn_text.split(" ; ").next().unwrap_or("").trim();
+            let leave = match transfer_kind(text, e.kind) {
+                Some(kind) => {
+                    sites
+                        .entry(e.pc)
+                        .or_insert_with(|| Site {
+                            insn: text.to_string(),
+                            kind,
+                            count: 0,
+                            targets: HashMap::new(),
+                        })
+                        .count += 1;
+                    Leave::Indirect
+                }
+                None if e.kind == InsnKind::Return => Leave::Return,
+                None => Leave::Direct,
+            };
+            prev = Some((e.step, e.pc, leave));
+        });
+
+        let suspicion = |addr: u64| {
+            let in_range = |(start, end): (u64, u64)| start <= addr && addr < end;
+            if sp_range.is_some_and(|(lo, hi)| {
+                in_range((
+                    lo.saturating_sub(STACK_BELOW),
+                    hi.saturating_add(STACK_ABOVE),
+                ))
+            }) {
+                Some(Suspicion::Stack)
+            } else if image.is_none()
+                || image.is_some_and(in_range)
+                || in_range(shared)
+                || loaded.contains(&(addr / PAGE))
+            {
+                // Without the binary's symbols its image is unknown, so only
+                // stack targets are flagged.
+                None
+            } else {
+                Some(Suspicion::Unmapped)
+            }
+        };
+
+        let mut sites: Vec<IndirectSite> = sites
+            .into_iter()
+            .map(|(addr, s)| {
+                let mut targets: Vec<IndirectTarget> = s
+                    .targets
+                    .into_iter()
+                    .map(|(addr, (count, first_step))| IndirectTarget {
+                        addr,
+                        function: symbols.describe(addr),
+                        count,
+                        first_step,
+                        suspicious: suspicion(addr),
+                    })
+                    .collect();
+                targets.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+                IndirectSite {
+                    addr,
+                    function: symbols.describe(addr),
+                    insn: s.insn,
+                    kind: s.kind,
+                    count: s.count,
+                    targets,
+                }
+            })
+            .collect();
+        sites.sort_by(|a, b| b.count.cmp(&a.count).then(a.addr.cmp(&b.addr)));
+        Self {
+            executed: sites.iter().map(|s| s.count).sum(),
+            sites,
+        }
+    }
+
+    /// Suspicious targets with their sites, in site order.
+    pub fn suspicious(&self) -> impl Iterator<Item = (&IndirectSite, &IndirectTarget)> {
+        self.sites.iter().flat_map(|s| {
+            s.targets
+                .iter()
+                .filter(|t| t.suspicious.is_some())
+                .map(move |t| (s, t))
+        })
+    }
+
+    /// Print the `limit` most executed sites with all their targets.
+    pub fn print(&self, limit: usize) {
+        println!("  Indirect Calls and Jumps");
+        println!("  ------------------------");
+        if self.sites.is_empty() {
+            println!("    No indirect calls or jumps executed");
+            return;
+        }
+        println!(
+            "    {:<14} {:<28} {:<5} {:>8} {:>8}  Instruction",
+            "Address", "Function", "Kind", "Count", "Targets"
+        );
+        for s in self.sites.iter().take(limit) {
+            let kind = match s.kind {
+                TransferKind::Call => "call",
+                TransferKind::Jump => "jump",
+            };
+            println!(
+                "    0x{:<12x} {:<28} {:<5} {:>8} {:>8}  {}",
+                s.addr,
+                s.function.as_deref().unwrap_or("??"),
+                kind,
+                s.count,
+                s.targets.len(),
+                s.insn
+            );
+            for t in &s.targets {
+                let name = match &t.function {
+                    Some(f) => format!("{} (0x{:x})", f, t.addr),
+                    None => format!("0x{:x}", t.addr),
+                };
+                let flag = match t.suspicious {
+                    Some(s) => format!("  ! {}", s.label()),
+                    None => String::new(),
+                };
+                println!("{:>20}-> {:<40} {:>8}{}", "", name, t.count, flag);
+            }
+        }
+        if self.sites.len() > limit {
+            println!("    ... and {} more", self.sites.len() - limit);
+        }
+        let suspicious = self.suspicious().count();
+        if suspicious > 0 {
+            println!(
+                "\n    {} suspicious target{} (see tdb check --only indirect)",
+                suspicious,
+                if suspicious == 1 { "" } else { "s" }
+            );
+        }
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        writeln!(out, "{}", json)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+}
+
+/// How an instruction hands over to the next step.
+#[derive(Debug, Clone, Copy)]
+enum Leave {
+    /// Falls through, or branches or calls to a fixed address.
+    Direct,
+    Indirect,
+    Return,
+}
+
+/// Indirect call or jump, from the disassembly; None for everything else,
+/// including returns.
+fn transfer_kind(text: &str, kind: InsnKind) -> Option<TransferKind> {
+    let mut parts = text.splitn(2, char::is_whitespace);
+    let m = parts.next().unwrap_or("");
+    let operand = parts.next().unwrap_or("").trim();
+    match m {
+        _ if kind == InsnKind::Return => None,
+        "blr" | "blraa" | "blraaz" | "blrab" | "blrabz" => Some(TransferKind::Call),
+        "br" | "braa" | "braaz" | "brab" | "brabz" => Some(TransferKind::Jump),
+        // A direct call or jmp's operand is its target address.
+        "call" | "callq" if !operand.starts_with("0x") => Some(TransferKind::Call),
+        "jmp" | "jmpq" if !operand.starts_with("0x") => Some(TransferKind::Jump),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    fn entry(step: u64, pc: u64, insn: &str) -> TraceEntry {
+        TraceEntry {
+            regs: serde_json::json!({ "sp": 0x1_6fdf_f000u64 }).to_string(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    fn db(pcs: &[(u64, &str)]) -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".to_string(),
+            ..Default::default()
+        });
+        for (step, &(pc, insn)) in pcs.iter().enumerate() {
+            db.insert(entry(step as u64, pc, insn)).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![
+            sym(0x1_0000_1000, "main"),
+            sym(0x1_0000_2000, "on_add"),
+            sym(0x1_0000_3000, "on_sub"),
+        ])
+    }
+
+    #[test]
+    fn targets_per_site() {
+        let t = IndirectTargets::build(
+            &db(&[
+                (0x1_0000_1000, "blr x8 ; CALL"),
+                (0x1_0000_2000, "ret ; RETURN"),
+                (0x1_0000_1004, "b #0x1000"),
+                (0x1_0000_1000, "blr x8 ; CALL"),
+                (0x1_0000_3000, "ret ; RETURN"),
+                (0x1_0000_1004, "b #0x1000"),
+                (0x1_0000_1000, "blr x8 ; CALL"),
+                (0x1_0000_2000, "br x16"),
+                (0x1_9000_0000, "ret ; RETURN"),
+            ]),
+            &symbols(),
+        );
+        assert_eq!(t.executed, 4);
+        let s = &t.sites[0];
+        assert_eq!(
+            (s.kind, s.count, s.insn.as_str()),
+            (TransferKind::Call, 3, "blr x8")
+        );
+        let targets: Vec<(Option<&str>, u64, u64)> = s
+            .targets
+            .iter()
+            .map(|t| (t.function.as_deref(), t.count, t.first_step))
+            .collect();
+        assert_eq!(
+            targets,
+            vec![(Some("on_add"), 2, 0), (Some("on_sub"), 1, 3)]
+        );
+        // A stub into the shared cache is fine.
+        assert_eq!(t.sites[1].kind, TransferKind::Jump);
+        assert_eq!(t.sites[1].targets[0].suspicious, None);
+        assert_eq!(t.suspicious().count(), 0);
+    }
+
+    #[test]
+    fn stack_and_unmapped_targets_are_suspicious() {
+        let t = IndirectTargets::build(
+            &db(&[
+                (0x1_0000_1000, "blr x8 ; CALL"),
+                // Shellcode on the heap; its next page is reached by falling
+                // through, which doesn't make it loaded code.
+                (0x6000_0000_0ffc, "nop"),
+                (0x6000_0000_1000, "br x0"),
+                (0x1_6fdf_e000, "nop"),
+            ]),
+            &symbols(),
+        );
+        let got: Vec<(u64, u64, Option<Suspicion>)> = t
+            .suspicious()
+            .map(|(s, t)| (s.addr, t.addr, t.suspicious))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (0x1_0000_1000, 0x6000_0000_0ffc, Some(Suspicion::Unmapped)),
+                (0x6000_0000_1000, 0x1_6fdf_e000, Some(Suspicion::Stack)),
+            ]
+        );
+    }
+
+    #[test]
+    fn directly_reached_code_is_loaded() {
+        // The trace starts in dyld, which calls into itself directly and
+        // then through a register.
+        let t = IndirectTargets::build(
+            &db(&[
+                (0x1_0400_0000, "bl #0x1_0401_0000 ; CALL"),
+                (0x1_0401_0000, "blr x8 ; CALL"),
+                (0x1_0400_0004, "nop"),
+            ]),
+            &symbols(),
+        );
+        assert_eq!(t.sites[0].targets[0].suspicious, None);
+    }
+
+    #[test]
+    fn transfer_kinds_from_disassembly() {
+        let k = |text| transfer_kind(text, InsnKind::classify(text));
+        assert_eq!(k("blraaz x8"), Some(TransferKind::Call));
+        assert_eq!(k("braa x16, x17"), Some(TransferKind::Jump));
+        assert_eq!(k("call qword ptr [rax + 0x10]"), Some(TransferKind::Call));
+        assert_eq!(k("jmp rax"), Some(TransferKind::Jump));
+        assert_eq!(k("call 0x100003f00"), None);
+        assert_eq!(k("bl #0x1000"), None);
+        assert_eq!(k("b.ne #0x1000"), None);
+        assert_eq!(k("brk #0x1"), None);
+        assert_eq!(k("ret ; RETURN"), None);
+    }
+
+    #[test]
+    fn json_lists_targets() {
+        let t = IndirectTargets::build(
+            &db(&[(0x1_0000_1000, "blr x8 ; CALL"), (0x1_6fdf_f000, "nop")]),
+            &symbols(),
+        );
+        let mut out = Vec::new();
+        t.write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["sites"][0]["kind"], "call");
+        assert_eq!(json["sites"][0]["targets"][0]["suspicious"], "stack");
+        assert_eq!(json["executed"], 1);
+    }
+}
@@ -0,0 +1,397 @@
+//! Memory access heatmap: loads and stores per page and per region, over
+//! time.
+//!
+//! Accesses are decoded from each instruction and the registers recorded
+//! before it ran, so heap and global accesses count too, not only the
+//! stack bytes the tracer samples. Regions come from what the trace knows:
+//! the stack is the range SP moved through, the image is the traced
+//! binary's mapping, and everything else (malloc'd memory, other modules'
+//! data) is counted as heap.
+
+use crate::deps::Decoder;
+use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+/// Bytes below the lowest SP seen that still count as stack (red zone,
+/// probes).
+const STACK_BELOW: u64 = 0x1000;
+/// Bytes above the highest SP seen that still count as stack: frames of
+/// callers entered before the trace started, arguments, environment.
+const STACK_ABOVE: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionKind {
+    Stack,
+    Image,
+    Heap,
+}
+
+/// Access counts for a page or a region.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Activity {
+    pub reads: u64,
+    pub writes: u64,
+    pub first_step: u64,
+    pub last_step: u64,
+    /// Reads and writes per time bucket.
+    pub read_buckets: Vec<u64>,
+    pub write_buckets: Vec<u64>,
+}
+
+impl Activity {
+    fn new(buckets: usize, step: u64) -> Self {
+        Self {
+            first_step: step,
+            last_step: step,
+            read_buckets: vec![0; buckets],
+            write_buckets: vec![0; buckets],
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, other: &Activity) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.first_step = self.first_step.min(other.first_step);
+        self.last_step = self.last_step.max(other.last_step);
+        for (a, b) in self.read_buckets.iter_mut().zip(&other.read_buckets) {
+            *a += b;
+        }
+        for (a, b) in self.write_buckets.iter_mut().zip(&other.write_buckets) {
+            *a += b;
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.reads + self.writes
+    }
+
+    /// One block character per bucket, scaled to the busiest one.
+    pub fn sparkline(&self) -> String {
+        const BARS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let totals: Vec<u64> = self
+            .read_buckets
+            .iter()
+            .zip(&self.write_buckets)
+            .map(|(r, w)| r + w)
+            .collect();
+        let max = totals.iter().copied().max().unwrap_or(0).max(1);
+        totals
+            .iter()
+            .map(|&n| match n {
+                0 => BARS[0],
+                n => BARS[(1 + (n * 7) / max) as usize],
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PageStats {
+    pub addr: u64,
+    pub region: RegionKind,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RegionStats {
+    pub kind: RegionKind,
+    /// "stack", "heap", or the binary's file name.
+    pub name: String,
+    /// Pages touched.
+    pub pages: u64,
+    #[serde(flatten)]
+    pub activity: Activity,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct MemoryHeatmap {
+    pub page_size: u64,
+    /// Steps covered by each time bucket; the last one may be shorter.
+    pub bucket_steps: u64,
+    pub first_step: u64,
+    pub last_step: u64,
+    pub regions: Vec<RegionStats>,
+    /// Every page accessed, by address.
+    pub pages: Vec<PageStats>,
+}
+
+impl MemoryHeatmap {
+    /// Time buckets when the caller doesn't ask for a number.
+    pub const DEFAULT_BUCKETS: usize = 32;
+
+    pub fn build(db: &TraceDb, symbols: &SymbolTable, buckets: usize) -> Result<Self, String> {
+        let Some((first, last)) = db.step_bounds() else {
+            return Ok(Self::default());
+        };
+        let arch = db.meta().arch;
+        let x86 = arch == "x86_64";
+        // macOS pages are 16 KiB on Apple silicon.
+        let page_size: u64 = if x86 { 0x1000 } else { 0x4000 };
+        let sp_reg = if x86 { "rsp" } else { "sp" };
+        let buckets = buckets.max(1);
+        let bucket_steps = (last - first) / buckets as u64 + 1;
+        let decoder = Decoder::new(&arch)?;
+
+        let mut pages: HashMap<u64, Activity> = HashMap::new();
+        let mut sp_range: Option<(u64, u64)> = None;
+        db.try_for_each_in(first, last, |e| -> Result<(), String> {
+            let regs: serde_json::Value = serde_json::from_str(&e.regs).unwrap_or_default();
+            if let Some(sp) = regs[sp_reg].as_u64() {
+                sp_range = Some(sp_range.map_or((sp, sp), |(lo, hi)| (lo.min(sp), hi.max(sp))));
+            }
+            let access = decoder.decode(e);
+            let bucket = ((e.step - first) / bucket_steps) as usize;
+            let mut count = |addr: u64, len: u64, write: bool| {
+                let end = addr.saturating_add(len.max(1) - 1);
+                let mut page = addr & !(page_size - 1);
+                while page <= end {
+                    let a = pages
+                        .entry(page)
+                        .or_insert_with(|| Activity::new(buckets, e.step));
+                    a.last_step = e.step;
+                    if write {
+                        a.writes += 1;
+                        a.write_buckets[bucket] += 1;
+                    } else {
+                        a.reads += 1;
+                        a.read_buckets[bucket] += 1;
+                    }
+                    page = match page.checked_add(page_size) {
+                        Some(p) => p,
+                        None => break,
+                    };
+                }
+            };
+            for &(addr, len) in &access.loads {
+                count(addr, len, false);
+            }
+            for &(addr, len) in &access.stores {
+                count(addr, len, true);
+            }
+            Ok(())
+        })?;
+
+        let image = symbols.image_range();
+        let image_name = super::image_name(symbols);
+        let region_of = |page: u64| {
+            let end = page + page_size;
+            if sp_range.is_some_and(|(lo, hi)| {
+                end > lo.saturating_sub(STACK_BELOW) && page <= hi.saturating_add(STACK_ABOVE)
+            }) {
+                RegionKind::Stack
+            } else if image.is_some_and(|(start, stop)| end > start && page < stop) {
+                RegionKind::Image
+            } else {
+                RegionKind::Heap
+            }
+        };
+
+        let mut pages: Vec<PageStats> = pages
+            .into_iter()
+            .map(|(addr, activity)| PageStats {
+                addr,
+                region: region_of(addr),
+                activity,
+            })
+            .collect();
+        pages.sort_by_key(|p| p.addr);
+
+        let mut regions: BTreeMap<RegionKind, RegionStats> = BTreeMap::new();
+        for p in &pages {
+            let r = regions.entry(p.region).or_insert_with(|| RegionStats {
+                kind: p.region,
+                name: match p.region {
+                    RegionKind::Stack => "stack".to_string(),
+                    RegionKind::Image => image_name.clone(),
+                    RegionKind::Heap => "heap".to_string(),
+                },
+                pages: 0,
+                activity: Activity::new(buckets, p.activity.first_step),
+            });
+            r.pages += 1;
+            r.activity.add(&p.activity);
+        }
+
+        Ok(Self {
+            page_size,
+            bucket_steps,
+            first_step: first,
+            last_step: last,
+            regions: regions.into_values().collect(),
+            pages,
+        })
+    }
+
+    /// Print the regions and the `limit` busiest pages.
+    pub fn print(&self, limit: usize) {
+        println!("  Memory Access");
+        println!("  -------------");
+        if self.pages.is_empty() {
+            println!("    No memory accesses decoded");
+            return;
+        }
+        println!(
+            "    {} KiB pages, {} steps per column",
+            self.page_size / 1024,
+            self.bucket_steps
+        );
+        println!();
+        println!(
+            "    {:<18} {:>6} {:>10} {:>10}  Activity",
+            "Region", "Pages", "Reads", "Writes"
+        );
+        for r in &self.regions {
+            println!(
+                "    {:<18} {:>6} {:>10} {:>10}  {}",
+                r.name,
+                r.pages,
+                r.activity.reads,
+                r.activity.writes,
+                r.activity.sparkline()
+            );
+        }
+
+        let mut busiest: Vec<&PageStats> = self.pages.iter().collect();
+        busiest.sort_by(|a, b| {
+            b.activity
+                .total()
+                .cmp(&a.activity.total())
+                .then(a.addr.cmp(&b.addr))
+        });
+        println!();
+        println!(
+            "    {:<18} {:<6} {:>10} {:>10}  Activity",
+            "Page", "Region", "Reads", "Writes"
+        );
+        for p in busiest.iter().take(limit) {
+            let region = match p.region {
+                RegionKind::Stack => "stack",
+                RegionKind::Image => "image",
+                RegionKind::Heap => "heap",
+            };
+            println!(
+                "    0x{:<16x} {:<6} {:>10} {:>10}  {}",
+                p.addr,
+                region,
+                p.activity.reads,
+                p.activity.writes,
+                p.activity.sparkline()
+            );
+        }
+        if self.pages.len() > limit {
+            println!("    ... and {} more", self.pages.len() - limit);
+        }
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        writeln!(out, "{}", json)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{TraceEntry, TraceMeta};
+
+    fn entry(step: u64, bytes: &[u8], insn: &str, regs: serde_json::Value) -> TraceEntry {
+        TraceEntry {
+            insn_bytes: bytes.to_vec(),
+            regs: regs.to_string(),
+            ..crate::storage::entry(step, 0x1000 + step * 4, insn)
+        }
+    }
+
+    const STR_X1_SP: &[u8] = &[0xe1, 0x07, 0x00, 0xf9]; // str x1, [sp, #8]
+    const LDR_X1_X0: &[u8] = &[0x01, 0x00, 0x40, 0xf9]; // ldr x1, [x0]
+
+    /// Two stack stores, then four heap loads from 0x6000_0000_0000.
+    fn db() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".to_string(),
+            ..Default::default()
+        });
+        let regs =
+            serde_json::json!({ "sp": 0x1_6fdf_f000u64, "x0": 0x6000_0000_0010u64, "x1": 1 });
+        for step in 0..2 {
+            db.insert(entry(step, STR_X1_SP, "str x1, [sp, #8]", regs.clone()))
+                .unwrap();
+        }
+        for step in 2..6 {
+            db.insert(entry(step, LDR_X1_X0, "ldr x1, [x0]", regs.clone()))
+                .unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn counts_per_page_and_region() {
+        let map = MemoryHeatmap::build(&db(), &SymbolTable::default(), 3).unwrap();
+        assert_eq!(map.page_size, 0x4000);
+        assert_eq!(map.bucket_steps, 2);
+        let pages: Vec<(u64, RegionKind, u64, u64)> = map
+            .pages
+            .iter()
+            .map(|p| (p.addr, p.region, p.activity.reads, p.activity.writes))
+            .collect();
+        assert_eq!(
+            pages,
+            vec![
+                (0x1_6fdf_c000, RegionKind::Stack, 0, 2),
+                (0x6000_0000_0000, RegionKind::Heap, 4, 0),
+            ]
+        );
+        let heap = &map.pages[1].activity;
+        assert_eq!(heap.read_buckets, vec![0, 2, 2]);
+        assert_eq!((heap.first_step, heap.last_step), (2, 5));
+        assert_eq!(
+            map.regions
+                .iter()
+                .map(|r| r.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["stack", "heap"]
+        );
+    }
+
+    #[test]
+    fn accesses_spanning_pages_count_on_both() {
+        let db = TraceDb::new(":memory:").unwrap();
+        let regs = serde_json::json!({ "sp": 0x8000, "x0": 0x3ffc, "x1": 0 });
+        db.insert(entry(0, LDR_X1_X0, "ldr x1, [x0]", regs))
+            .unwrap();
+        let map = MemoryHeatmap::build(&db, &SymbolTable::default(), 1).unwrap();
+        let addrs: Vec<u64> = map.pages.iter().map(|p| p.addr).collect();
+        assert_eq!(addrs, vec![0x0, 0x4000]);
+    }
+
+    #[test]
+    fn sparkline_scales_to_busiest_bucket() {
+        let a = Activity {
+            read_buckets: vec![0, 1, 7, 14],
+            write_buckets: vec![0, 0, 0, 0],
+            ..Default::default()
+        };
+        assert_eq!(a.sparkline(), " ▁▄█");
+    }
+
+    #[test]
+    fn json_has_pages_and_buckets() {
+        let map = MemoryHeatmap::build(&db(), &SymbolTable::default(), 2).unwrap();
+        let mut out = Vec::new();
+        map.write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["pages"][1]["region"], "heap");
+        assert_eq!(json["pages"][1]["reads"], 4);
+        assert_eq!(json["pages"][1]["read_buckets"], serde_json::json!([1, 3]));
+        assert_eq!(json["regions"][0]["name"], "stack");
+    }
+}
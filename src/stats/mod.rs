@@ -1,24 +1,107 @@
+mod branches;
+mod flamegraph;
+mod indirect;
+mod memory;
+mod profile;
+
+pub use branches::BranchStats;
+pub use indirect::IndirectTargets;
+pub use memory::MemoryHeatmap;
+pub use profile::Profile;
+
+use crate::deps::Decoder;
 use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::io::Write;
+
+/// How many mnemonics `print` lists.
+const TOP_INSTRUCTIONS: usize = 20;
+
+/// The dyld shared cache, where system libraries live (SHARED_REGION_BASE
+/// and SIZE in <mach/shared_region.h>).
+const ARM64_SHARED_REGION: (u64, u64) = (0x1_8000_0000, 0x3_0000_0000);
+const X86_SHARED_REGION: (u64, u64) = (0x7ff8_0000_0000, 0x8000_0000_0000);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TraceStats {
     pub total_steps: u64,
     pub unique_addresses: usize,
+    /// Every mnemonic executed, most frequent first.
     pub instruction_counts: Vec<(String, usize)>,
     pub most_executed_address: Option<(u64, usize)>,
     pub call_count: usize,
     pub ret_count: usize,
     pub jump_count: usize,
     pub mem_change_count: usize,
+    /// Steps per module, most first: the traced binary, the shared cache
+    /// (system libraries), and everything else (dyld, `dlopen`ed code).
+    pub module_counts: Vec<(String, u64)>,
+    /// Filled in by [`TraceStats::count_memory_accesses`], which decodes
+    /// every step and is left out when a trace is just being opened.
+    pub memory: Option<MemoryAccesses>,
+    pub max_depth: u64,
+    /// Wall-clock recording time; 0 for traces that didn't store it.
+    pub duration_ms: u64,
+}
+
+/// Loads and stores decoded from the instructions, and their bytes.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MemoryAccesses {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// Output formats of `tdb stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!(
+                "Unknown stats format '{}' (expected text, json or csv)",
+                s
+            )),
+        }
+    }
+}
+
+/// Address range of the shared cache for `arch`.
+pub(crate) fn shared_region(arch: &str) -> (u64, u64) {
+    if arch == "x86_64" {
+        X86_SHARED_REGION
+    } else {
+        ARM64_SHARED_REGION
+    }
+}
+
+/// File name of the binary the symbols came from, or "image".
+pub(crate) fn image_name(symbols: &SymbolTable) -> String {
+    std::path::Path::new(symbols.path())
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "image".to_string())
 }
 
 /// Returns true if the mnemonic is a branch/jump (not a call).
 ///
 /// ARM64 branches: b, b.cond, br, cbz, cbnz, tbz, tbnz
 /// x86_64 branches: j* (jmp, je, jne, jz, jnz, jg, jge, jl, jle, ja, jb, etc.)
-fn is_branch_mnemonic(m: &str) -> bool {
+pub(crate) fn is_branch_mnemonic(m: &str) -> bool {
     // x86_64: all jumps start with 'j'
     if m.starts_with('j') {
         return true;
@@ -32,20 +115,19 @@ fn is_branch_mnemonic(m: &str) -> bool {
 }
 
 impl TraceStats {
-    pub fn analyze(db: &TraceDb) -> Self {
+    pub fn analyze(db: &TraceDb, symbols: &SymbolTable) -> Self {
         let entries = db.get_all();
         let total_steps = entries.len() as u64;
+        let meta = db.meta();
 
-        let mut addr_counts: HashMap<u64, usize> = HashMap::new();
         let mut insn_counts: HashMap<String, usize> = HashMap::new();
         let mut call_count = 0;
         let mut ret_count = 0;
         let mut jump_count = 0;
         let mut mem_change_count = 0;
+        let mut max_depth = 0;
 
         for entry in &entries {
-            *addr_counts.entry(entry.pc).or_insert(0) += 1;
-
             let mnemonic = entry.insn_text.split_whitespace().next().unwrap_or("");
             *insn_counts.entry(mnemonic.to_string()).or_insert(0) += 1;
 
@@ -59,18 +141,35 @@ impl TraceStats {
                 jump_count += 1;
             }
             mem_change_count += entry.mem_changes.len();
+            max_depth = max_depth.max(entry.depth);
         }
 
-        let unique_addresses = addr_counts.len();
-
-        let most_executed_address = addr_counts
-            .iter()
-            .max_by_key(|(_, &count)| count)
-            .map(|(&addr, &count)| (addr, count));
+        let index = db.index();
+        let unique_addresses = index.unique_pcs();
+        let most_executed_address = index.pc_counts().max_by_key(|&(_, count)| count);
+
+        let image = symbols.image_range();
+        let image_name = image_name(symbols);
+        let (shared_start, shared_end) = shared_region(&meta.arch);
+        let mut module_counts: HashMap<&str, u64> = HashMap::new();
+        for (pc, count) in index.pc_counts() {
+            let module = if image.is_some_and(|(start, end)| (start..end).contains(&pc)) {
+                image_name.as_str()
+            } else if (shared_start..shared_end).contains(&pc) {
+                "shared cache"
+            } else {
+                "other"
+            };
+            *module_counts.entry(module).or_default() += count as u64;
+        }
+        let mut module_counts: Vec<(String, u64)> = module_counts
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect();
+        module_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
         let mut instruction_counts: Vec<_> = insn_counts.into_iter().collect();
-        instruction_counts.sort_by(|a, b| b.1.cmp(&a.1));
-        instruction_counts.truncate(20);
+        instruction_counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
 
         Self {
             total_steps,
@@ -81,9 +180,28 @@ impl TraceStats {
             ret_count,
             jump_count,
             mem_change_count,
+            module_counts,
+            memory: None,
+            max_depth,
+            duration_ms: meta.duration_ms,
         }
     }
 
+    /// Decode every step for the loads and stores it made.
+    pub fn count_memory_accesses(&mut self, db: &TraceDb) -> Result<(), String> {
+        let decoder = Decoder::new(&db.meta().arch)?;
+        let mut memory = MemoryAccesses::default();
+        db.for_each_in(0, u64::MAX, |entry| {
+            let access = decoder.decode(entry);
+            memory.reads += access.loads.len() as u64;
+            memory.writes += access.stores.len() as u64;
+            memory.bytes_read += access.loads.iter().map(|&(_, len)| len).sum::<u64>();
+            memory.bytes_written += access.stores.iter().map(|&(_, len)| len).sum::<u64>();
+        });
+        self.memory = Some(memory);
+        Ok(())
+    }
+
     pub fn print(&self) {
         println!("  Trace Statistics");
         println!("  ----------------");
@@ -93,22 +211,107 @@ impl TraceStats {
         println!("  Returns:          {}", self.ret_count);
         println!("  Jumps/branches:   {}", self.jump_count);
         println!("  Memory changes:   {}", self.mem_change_count);
+        if let Some(m) = &self.memory {
+            println!("  Memory reads:     {} ({} bytes)", m.reads, m.bytes_read);
+            println!(
+                "  Memory writes:    {} ({} bytes)",
+                m.writes, m.bytes_written
+            );
+        }
+        println!("  Max call depth:   {}", self.max_depth);
+        if self.duration_ms > 0 {
+            println!(
+                "  Recorded in:      {:.1}s",
+                self.duration_ms as f64 / 1000.0
+            );
+        }
 
         if let Some((addr, count)) = self.most_executed_address {
             println!("  Most executed:    0x{:x} ({} times)", addr, count);
         }
 
+        if !self.module_counts.is_empty() {
+            println!("\n  Modules:");
+            for (module, count) in &self.module_counts {
+                println!("    {:20} {:>8}", module, count);
+            }
+        }
+
         println!("\n  Top instructions:");
-        for (insn, count) in &self.instruction_counts {
+        for (insn, count) in self.instruction_counts.iter().take(TOP_INSTRUCTIONS) {
             println!("    {:12} {:>8}", insn, count);
         }
+        if self.instruction_counts.len() > TOP_INSTRUCTIONS {
+            println!(
+                "    ... and {} more",
+                self.instruction_counts.len() - TOP_INSTRUCTIONS
+            );
+        }
+    }
+
+    pub fn write_json(&self, out: &mut impl Write) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        writeln!(out, "{}", json)
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
+    }
+
+    /// One `section,name,value` row per number: the summary counts, then
+    /// every module and mnemonic.
+    pub fn write_csv(&self, out: &mut impl Write) -> Result<(), String> {
+        let summary = [
+            ("total_steps", self.total_steps),
+            ("unique_addresses", self.unique_addresses as u64),
+            ("call_count", self.call_count as u64),
+            ("ret_count", self.ret_count as u64),
+            ("jump_count", self.jump_count as u64),
+            ("mem_change_count", self.mem_change_count as u64),
+            ("max_depth", self.max_depth),
+            ("duration_ms", self.duration_ms),
+        ];
+        let mut rows: Vec<(&str, String, String)> = summary
+            .iter()
+            .map(|&(name, value)| ("summary", name.to_string(), value.to_string()))
+            .collect();
+        if let Some(m) = &self.memory {
+            for (name, value) in [
+                ("reads", m.reads),
+                ("writes", m.writes),
+                ("bytes_read", m.bytes_read),
+                ("bytes_written", m.bytes_written),
+            ] {
+                rows.push(("memory", name.to_string(), value.to_string()));
+            }
+        }
+        if let Some((addr, count)) = self.most_executed_address {
+            rows.push(("most_executed", format!("0x{:x}", addr), count.to_string()));
+        }
+        for (module, count) in &self.module_counts {
+            rows.push(("module", module.clone(), count.to_string()));
+        }
+        for (mnemonic, count) in &self.instruction_counts {
+            rows.push(("mnemonic", mnemonic.clone(), count.to_string()));
+        }
+
+        let mut text = String::from("section,name,value\n");
+        for (section, name, value) in rows {
+            text.push_str(&format!(
+                "{},{},{}\n",
+                section,
+                crate::export::csv_escape(&name),
+                value
+            ));
+        }
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.flush())
+            .map_err(|e| format!("Write failed: {}", e))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::storage::{MemChange, TraceEntry};
+    use crate::storage::{InsnKind, MemChange, TraceEntry};
 
     fn entry(step: u64, pc: u64, insn: &str) -> TraceEntry {
         TraceEntry {
@@ -118,6 +321,8 @@ mod tests {
             insn_text: insn.to_string(),
             regs: r#"{"x0":0,"sp":4096}"#.to_string(),
             mem_changes: vec![],
+            kind: InsnKind::classify(insn),
+            depth: 0,
         }
     }
 
@@ -136,6 +341,8 @@ mod tests {
             insn_text: insn.to_string(),
             regs: r#"{"x0":0,"sp":4096}"#.to_string(),
             mem_changes: changes,
+            kind: InsnKind::classify(insn),
+            depth: 0,
         }
     }
 
@@ -150,7 +357,7 @@ mod tests {
     #[test]
     fn empty_trace_gives_all_zeros() {
         let db = db_with(vec![]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.total_steps, 0);
         assert_eq!(s.unique_addresses, 0);
         assert_eq!(s.call_count, 0);
@@ -164,7 +371,7 @@ mod tests {
     #[test]
     fn single_step_counted() {
         let db = db_with(vec![entry(0, 0x1000, "mov x0, #1")]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.total_steps, 1);
         assert_eq!(s.unique_addresses, 1);
     }
@@ -176,7 +383,7 @@ mod tests {
             entry(1, 0x2000, "mov x0, #0"),
             entry(2, 0x2004, "bl #0x3000 ; CALL [depth:2]"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.call_count, 2);
     }
 
@@ -187,7 +394,7 @@ mod tests {
             entry(1, 0x2000, "mov x0, #0"),
             entry(2, 0x2004, "ret ; RETURN [depth:0]"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.ret_count, 2);
     }
 
@@ -197,7 +404,7 @@ mod tests {
             entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"),
             entry(1, 0x2000, "ret ; RETURN [depth:0]"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.call_count, 1);
         assert_eq!(s.ret_count, 1);
     }
@@ -210,7 +417,7 @@ mod tests {
             entry(2, 0x3000, "jne 0x4000"),
             entry(3, 0x4000, "mov eax, 1"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.jump_count, 3);
     }
 
@@ -225,7 +432,7 @@ mod tests {
             entry(5, 0x6000, "tbz x2, #3, #0x7000"),
             entry(6, 0x7000, "tbnz x3, #5, #0x8000"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.jump_count, 7);
     }
 
@@ -236,7 +443,7 @@ mod tests {
             entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"),
             entry(1, 0x2000, "blr x8 ; CALL [depth:2]"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.jump_count, 0, "bl/blr should not be counted as jumps");
     }
 
@@ -249,7 +456,7 @@ mod tests {
             entry(2, 0x1008, "bfi x3, x4, #0, #8"),
             entry(3, 0x100c, "bfxil x5, x6, #0, #16"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(
             s.jump_count, 0,
             "bic/bfm/bfi/bfxil should not be counted as jumps"
@@ -263,7 +470,7 @@ mod tests {
             entry(1, 0x1004, "mov x1, #0"),
             entry_with_mem(2, 0x1008, "stp x0, x1, [sp, #-16]!", 5),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.mem_change_count, 8);
     }
 
@@ -276,7 +483,7 @@ mod tests {
             entry(3, 0x1008, "mov x2, #3"),
             entry(4, 0x1000, "mov x0, #1"), // same PC again
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         assert_eq!(s.unique_addresses, 3);
     }
 
@@ -289,7 +496,7 @@ mod tests {
             entry(3, 0xA, "nop"),
             entry(4, 0xB, "nop"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         let (addr, count) = s.most_executed_address.unwrap();
         assert_eq!(addr, 0xA);
         assert_eq!(count, 3);
@@ -304,7 +511,7 @@ mod tests {
             entry(3, 0x100c, "add x0, x0, #2"),
             entry(4, 0x1010, "add x0, x0, #3"),
         ]);
-        let s = TraceStats::analyze(&db);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
         // "add" appears 3 times, "mov" 2 times
         assert_eq!(s.instruction_counts[0].0, "add");
         assert_eq!(s.instruction_counts[0].1, 3);
@@ -313,7 +520,7 @@ mod tests {
     }
 
     #[test]
-    fn instruction_counts_truncated_to_20() {
+    fn instruction_counts_keep_every_mnemonic() {
         let entries: Vec<TraceEntry> = (0..25)
             .map(|i| {
                 let mnemonic = format!("insn{} x0, #1", i);
@@ -321,14 +528,73 @@ mod tests {
             })
             .collect();
         let db = db_with(entries);
-        let s = TraceStats::analyze(&db);
-        assert!(
-            s.instruction_counts.len() <= 20,
-            "instruction_counts should be truncated to 20, got {}",
-            s.instruction_counts.len()
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
+        assert_eq!(s.instruction_counts.len(), 25);
+    }
+
+    #[test]
+    fn depth_memory_and_modules() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(crate::storage::TraceMeta {
+            arch: "arm64".to_string(),
+            duration_ms: 1500,
+            ..Default::default()
+        });
+        let mut store = entry(0, 0x1000, "stp x29, x30, [sp, #-16]!");
+        store.insn_bytes = vec![0xfd, 0x7b, 0xbf, 0xa9];
+        let mut call = entry(1, 0x1004, "bl #0x1_8000_1000 ; CALL");
+        call.depth = 1;
+        let mut load = entry(2, 0x1_8000_1000, "ldr x1, [sp]");
+        load.insn_bytes = vec![0xe1, 0x03, 0x40, 0xf9];
+        load.depth = 1;
+        for e in [store, call, load] {
+            db.insert(e).unwrap();
+        }
+        let mut s = TraceStats::analyze(&db, &SymbolTable::default());
+        assert_eq!(s.memory, None);
+        s.count_memory_accesses(&db).unwrap();
+        let m = s.memory.as_ref().unwrap();
+        assert_eq!((m.reads, m.bytes_read), (1, 8));
+        assert_eq!((m.writes, m.bytes_written), (1, 16));
+        assert_eq!(s.max_depth, 1);
+        assert_eq!(s.duration_ms, 1500);
+        assert_eq!(
+            s.module_counts,
+            vec![("other".to_string(), 2), ("shared cache".to_string(), 1)]
         );
     }
 
+    #[test]
+    fn csv_rows_per_number() {
+        let db = db_with(vec![
+            entry(0, 0x1000, "add x0, x0, #1"),
+            entry(1, 0x1000, "add x0, x0, #1"),
+            entry(2, 0x1004, "mov x1, x0"),
+        ]);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
+        let mut out = Vec::new();
+        s.write_csv(&mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "section,name,value");
+        assert_eq!(lines[1], "summary,total_steps,3");
+        assert!(lines.contains(&"mnemonic,add,2"), "{}", csv);
+        assert!(lines.contains(&"module,other,3"), "{}", csv);
+        assert!(lines.contains(&"most_executed,0x1000,2"), "{}", csv);
+    }
+
+    #[test]
+    fn json_has_every_field() {
+        let db = db_with(vec![entry(0, 0x1000, "nop")]);
+        let s = TraceStats::analyze(&db, &SymbolTable::default());
+        let mut out = Vec::new();
+        s.write_json(&mut out).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(json["total_steps"], 1);
+        assert_eq!(json["instruction_counts"][0], serde_json::json!(["nop", 1]));
+        assert_eq!(json["max_depth"], 0);
+    }
+
     // ── is_branch_mnemonic unit tests ──
 
     #[test]
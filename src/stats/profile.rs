@@ -0,0 +1,261 @@
+//! Per-function instruction profile and folded call stacks.
+
+use super::flamegraph;
+use crate::calltree::{CallNode, CallTree};
+use crate::storage::TraceDb;
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+
+/// How many callers `print` lists per function.
+const TOP_CALLERS: usize = 3;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    /// Steps inside the function or its callees. Recursive activations are
+    /// only counted once, at the outermost call.
+    pub inclusive: u64,
+    /// Steps inside the function itself.
+    pub exclusive: u64,
+    /// Calling functions and how often each called this one, most frequent
+    /// first.
+    pub callers: Vec<(String, u64)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// Bottom frame of every stack: the traced program.
+    pub root: String,
+    pub total_steps: u64,
+    /// Hottest functions (by exclusive steps) first.
+    pub functions: Vec<FunctionProfile>,
+    /// "root;main;compute" -> steps spent with exactly that stack.
+    pub folded: BTreeMap<String, u64>,
+}
+
+impl Profile {
+    pub fn build(db: &TraceDb, symbols: &SymbolTable) -> Self {
+        let tree = CallTree::build(db, symbols);
+        let meta = db.meta();
+        let root = meta
+            .command
+            .first()
+            .map(|c| c.rsplit('/').next().unwrap_or(c).to_string())
+            .unwrap_or_else(|| format!("pid {}", meta.pid));
+        let total_steps = db.count();
+
+        let names: Vec<String> = tree
+            .nodes
+            .iter()
+            .map(|n| function_name(n, symbols))
+            .collect();
+        // Folded stack of every node; parents come before their children.
+        let mut stacks: Vec<String> = Vec::with_capacity(tree.nodes.len());
+        let mut folded: BTreeMap<String, u64> = BTreeMap::new();
+        let mut functions: HashMap<&str, FunctionProfile> = HashMap::new();
+        // Nodes come in call order, so the current call stack can be kept
+        // alongside, with how many frames of each function it holds.
+        let mut path: Vec<usize> = Vec::new();
+        let mut active: HashMap<&str, usize> = HashMap::new();
+        for node in &tree.nodes {
+            let name = names[node.id].as_str();
+            while path.last().is_some() && path.last().copied() != node.parent {
+                let done = path.pop().unwrap();
+                *active.get_mut(names[done].as_str()).unwrap() -= 1;
+            }
+            let recursive = active.get(name).is_some_and(|&n| n > 0);
+            path.push(node.id);
+            *active.entry(name).or_default() += 1;
+
+            let caller = node.parent.map_or(root.as_str(), |p| names[p].as_str());
+            let stack = format!(
+                "{};{}",
+                node.parent.map_or(root.as_str(), |p| stacks[p].as_str()),
+                name
+            );
+            *folded.entry(stack.clone()).or_default() += node.exclusive;
+            stacks.push(stack);
+
+            let f = functions.entry(name).or_insert_with(|| FunctionProfile {
+                name: name.to_string(),
+                ..Default::default()
+            });
+            f.calls += 1;
+            f.exclusive += node.exclusive;
+            if !recursive {
+                f.inclusive += node.inclusive;
+            }
+            match f.callers.iter_mut().find(|(c, _)| c == caller) {
+                Some((_, n)) => *n += 1,
+                None => f.callers.push((caller.to_string(), 1)),
+            }
+        }
+        let in_calls: u64 = tree.roots.iter().map(|&r| tree.nodes[r].inclusive).sum();
+        let top_level = total_steps.saturating_sub(in_calls);
+        if top_level > 0 {
+            *folded.entry(root.clone()).or_default() += top_level;
+        }
+
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        for f in &mut functions {
+            f.callers
+                .sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        }
+        functions.sort_by(|a, b| {
+            b.exclusive
+                .cmp(&a.exclusive)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Self {
+            root,
+            total_steps,
+            functions,
+            folded,
+        }
+    }
+
+    /// Print the `limit` hottest functions.
+    pub fn print(&self, limit: usize) {
+        println!("  Function Profile");
+        println!("  ----------------");
+        println!(
+            "    {:<32} {:>7} {:>10} {:>10}  Top callers",
+            "Function", "Calls", "Inclusive", "Exclusive"
+        );
+        for f in self.functions.iter().take(limit) {
+            let callers: Vec<String> = f
+                .callers
+                .iter()
+                .take(TOP_CALLERS)
+                .map(|(name, n)| format!("{} ({})", name, n))
+                .collect();
+            println!(
+                "    {:<32} {:>7} {:>10} {:>10}  {}",
+                f.name,
+                f.calls,
+                f.inclusive,
+                f.exclusive,
+                callers.join(", ")
+            );
+        }
+        if self.functions.len() > limit {
+            println!("    ... and {} more", self.functions.len() - limit);
+        }
+    }
+
+    /// Write the stacks in folded format ("a;b;c count" per line), as read
+    /// by flamegraph.pl, inferno and speedscope.
+    pub fn write_folded(&self, out: &mut impl Write) -> Result<(), String> {
+        let err = |e: std::io::Error| format!("Write failed: {}", e);
+        for (stack, count) in &self.folded {
+            writeln!(out, "{} {}", stack, count).map_err(err)?;
+        }
+        out.flush().map_err(err)
+    }
+
+    /// Render the folded stacks as an SVG flame graph.
+    pub fn flamegraph(&self) -> String {
+        flamegraph::render(
+            &self.folded,
+            &format!("{} ({} steps)", self.root, self.total_steps),
+        )
+    }
+}
+
+/// Function a call entered, without an offset, so calls into the middle of a
+/// function are grouped with the rest of it.
+fn function_name(node: &CallNode, symbols: &SymbolTable) -> String {
+    node.target
+        .and_then(|t| symbols.lookup(t))
+        .map(|(name, _)| name.to_string())
+        .unwrap_or_else(|| node.name())
+        // ';' separates frames in the folded format.
+        .replace(';', ":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{entry, TraceMeta};
+    use crate::symbols::Symbol;
+
+    const CALL: &str = "bl ; CALL";
+    const RET: &str = "ret ; RETURN";
+
+    /// main calls fib, which calls itself once; main then calls fib again.
+    fn trace() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            command: vec!["/tmp/demo".into()],
+            ..Default::default()
+        });
+        for e in [
+            entry(0, 0x1000, "nop"),
+            entry(1, 0x1004, CALL),
+            entry(2, 0x2000, CALL),
+            entry(3, 0x2000, "nop"),
+            entry(4, 0x2004, RET),
+            entry(5, 0x2008, RET),
+            entry(6, 0x1008, CALL),
+            entry(7, 0x2000, RET),
+            entry(8, 0x100c, "nop"),
+        ] {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn symbols() -> SymbolTable {
+        let sym = |addr, name: &str| Symbol {
+            addr,
+            size: 0x100,
+            name: name.to_string(),
+        };
+        SymbolTable::from_symbols(vec![sym(0x1000, "main"), sym(0x2000, "fib")])
+    }
+
+    #[test]
+    fn recursion_counted_once_in_inclusive() {
+        let p = Profile::build(&trace(), &symbols());
+        assert_eq!(p.root, "demo");
+        assert_eq!(p.functions.len(), 1);
+        let fib = &p.functions[0];
+        assert_eq!(fib.calls, 3);
+        // Outer fib 1..5 (5 steps) and the second call 6..7 (2 steps); the
+        // recursive call 2..4 is inside the first.
+        assert_eq!(fib.inclusive, 7);
+        assert_eq!(fib.exclusive, 7);
+        assert_eq!(
+            fib.callers,
+            vec![("demo".to_string(), 2), ("fib".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn folded_stacks_sum_to_total_steps() {
+        let p = Profile::build(&trace(), &symbols());
+        let expected: BTreeMap<String, u64> = [
+            ("demo".to_string(), 2),
+            ("demo;fib".to_string(), 4),
+            ("demo;fib;fib".to_string(), 3),
+        ]
+        .into();
+        assert_eq!(p.folded, expected);
+        assert_eq!(p.folded.values().sum::<u64>(), p.total_steps);
+
+        let mut out = Vec::new();
+        p.write_folded(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "demo 2\ndemo;fib 4\ndemo;fib;fib 3\n"
+        );
+    }
+
+    #[test]
+    fn unknown_targets_use_addresses() {
+        let p = Profile::build(&trace(), &SymbolTable::default());
+        assert_eq!(p.functions[0].name, "0x2000");
+    }
+}
@@ -0,0 +1,680 @@
+//! On-disk trace format.
+//!
+//! Current layout (version 2):
+//!
+//! ```text
+//! MAGIC (8 bytes) | version (u32 LE)
+//! frame*          | one Meta, any number of Entries blocks, one Index, End
+//!
+//! frame  = kind (u8) | first_step (u64) | last_step (u64) | len (u64)
+//!          | payload_crc (u32) | header_crc (u32) | payload (len bytes)
+//! ```
+//!
+//! Every frame carries a CRC32 of its payload and of its own header, so a
+//! reader can tell exactly which step ranges are damaged and still skip
+//! over a corrupt payload as long as its header is intact.
+//!
+//! Version 1 is the original header-less bare-bincode
+//! `BTreeMap<u64, TraceEntry>`, with no metadata, index or checksums. It is
+//! still read and upgraded on the fly: entries get their `kind` and `depth`
+//! recovered from the tracer's `CALL [depth:N]` / `RETURN [depth:N]`
+//! annotations.
+
+use super::{InsnKind, MemChange, TraceEntry, TraceIndex, TraceMeta};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+pub(super) const MAGIC: &[u8; 8] = b"TDBTRACE";
+pub const FORMAT_VERSION: u32 = 2;
+/// Version reported for header-less files.
+pub const LEGACY_VERSION: u32 = 1;
+/// Entries per checksummed block.
+const BLOCK_SIZE: usize = 4096;
+const HEADER_LEN: usize = 1 + 8 + 8 + 8 + 4 + 4;
+
+const FRAME_META: u8 = 1;
+const FRAME_ENTRIES: u8 = 2;
+const FRAME_INDEX: u8 = 3;
+const FRAME_END: u8 = 0xFF;
+
+/// Entry layout of version 1, before `kind` and `depth` existed.
+#[derive(Serialize, Deserialize)]
+struct LegacyEntry {
+    step: u64,
+    pc: u64,
+    insn_bytes: Vec<u8>,
+    insn_text: String,
+    regs: String,
+    mem_changes: Vec<MemChange>,
+}
+
+/// A region of a trace file that failed its integrity checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptRange {
+    /// Byte offset of the damaged frame.
+    pub offset: u64,
+    /// Steps the damaged block claims to hold, when its header is readable.
+    pub steps: Option<(u64, u64)>,
+    pub reason: String,
+}
+
+/// Everything that could be read from a trace file, good and bad.
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub version: u32,
+    /// Entries from intact blocks, in file order (not deduplicated).
+    pub entries: Vec<TraceEntry>,
+    pub meta: Option<TraceMeta>,
+    pub index: Option<TraceIndex>,
+    pub corrupt: Vec<CorruptRange>,
+}
+
+impl Scan {
+    pub fn has_checksums(&self) -> bool {
+        self.version == FORMAT_VERSION
+    }
+}
+
+pub(super) fn encode(
+    meta: &TraceMeta,
+    entries: &BTreeMap<u64, TraceEntry>,
+    index: &TraceIndex,
+) -> Result<Vec<u8>, String> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    write_frame(&mut out, FRAME_META, (0, 0), &serialize(meta)?);
+    let values: Vec<&TraceEntry> = entries.values().collect();
+    for block in values.chunks(BLOCK_SIZE) {
+        let steps = (block[0].step, block[block.len() - 1].step);
+        write_frame(&mut out, FRAME_ENTRIES, steps, &serialize(&block)?);
+    }
+    write_frame(&mut out, FRAME_INDEX, (0, 0), &serialize(index)?);
+    write_frame(&mut out, FRAME_END, (0, 0), &[]);
+    Ok(out)
+}
+
+fn serialize<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, String> {
+    bincode::serialize(value).map_err(|e| format!("Serialize failed: {}", e))
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, steps: (u64, u64), payload: &[u8]) {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.push(kind);
+    header.extend_from_slice(&steps.0.to_le_bytes());
+    header.extend_from_slice(&steps.1.to_le_bytes());
+    header.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    header.extend_from_slice(&crc32(payload).to_le_bytes());
+    let header_crc = crc32(&header);
+    header.extend_from_slice(&header_crc.to_le_bytes());
+    out.extend_from_slice(&header);
+    out.extend_from_slice(payload);
+}
+
+/// Read a trace file, recovering whatever is intact.
+///
+/// Only fails outright when the file cannot be recognised at all. Damage
+/// inside a checksummed file is reported in [`Scan::corrupt`]; older formats
+/// have no checksums, so any damage there fails the whole file.
+pub fn scan(data: &[u8]) -> Result<Scan, String> {
+    let Some(rest) = data.strip_prefix(MAGIC) else {
+        // Legacy file: no header and no persisted indexes.
+        let entries: BTreeMap<u64, LegacyEntry> = deserialize(data)?;
+        return Ok(Scan {
+            version: LEGACY_VERSION,
+            entries: upgrade(entries.into_values().collect()),
+            ..Default::default()
+        });
+    };
+    if rest.len() < 4 {
+        return Err("Deserialize failed: truncated header".to_string());
+    }
+    let version = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+    let payload = &rest[4..];
+    let base = MAGIC.len() + 4;
+    match version {
+        FORMAT_VERSION => Ok(scan_frames(payload, base, version)),
+        _ => Err(format!("Unsupported trace format version {}", version)),
+    }
+}
+
+/// Convert version 1 entries, recovering `kind` and `depth` from the
+/// annotated instruction text. Non-call/return steps inherit the depth of
+/// the step before them.
+fn upgrade(mut entries: Vec<LegacyEntry>) -> Vec<TraceEntry> {
+    entries.sort_by_key(|e| e.step);
+    let mut depth = 0;
+    entries
+        .into_iter()
+        .map(|e| {
+            depth = annotated_depth(&e.insn_text).unwrap_or(depth);
+            TraceEntry {
+                kind: InsnKind::classify(&e.insn_text),
+                depth,
+                step: e.step,
+                pc: e.pc,
+                insn_bytes: e.insn_bytes,
+                insn_text: e.insn_text,
+                regs: e.regs,
+                mem_changes: e.mem_changes,
+            }
+        })
+        .collect()
+}
+
+/// Serialize entries in the original header-less layout, for tests of the
+/// upgrade path.
+#[cfg(test)]
+pub(crate) fn encode_legacy(entries: &[TraceEntry]) -> Vec<u8> {
+    let map: BTreeMap<u64, LegacyEntry> = entries
+        .iter()
+        .map(|e| {
+            let old = LegacyEntry {
+                step: e.step,
+                pc: e.pc,
+                insn_bytes: e.insn_bytes.clone(),
+                insn_text: e.insn_text.clone(),
+                regs: e.regs.clone(),
+                mem_changes: e.mem_changes.clone(),
+            };
+            (e.step, old)
+        })
+        .collect();
+    bincode::serialize(&map).unwrap()
+}
+
+/// Parse N out of a "... ; CALL [depth:N]" or "... ; RETURN [depth:N]" suffix.
+fn annotated_depth(insn_text: &str) -> Option<u64> {
+    let (_, rest) = insn_text.split_once("[depth:")?;
+    let (n, _) = rest.split_once(']')?;
+    n.parse().ok()
+}
+
+fn deserialize<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    bincode::deserialize(payload).map_err(|e| format!("Deserialize failed: {}", e))
+}
+
+/// Walk the frames of a current-format file.
+fn scan_frames(data: &[u8], base: usize, version: u32) -> Scan {
+    let mut scan = Scan {
+        version,
+        ..Default::default()
+    };
+    let mut pos = 0;
+    let mut ended = false;
+    while pos < data.len() && !ended {
+        let offset = (base + pos) as u64;
+        let Some(header) = data.get(pos..pos + HEADER_LEN) else {
+            scan.corrupt.push(CorruptRange {
+                offset,
+                steps: None,
+                reason: "truncated frame header".to_string(),
+            });
+            return scan;
+        };
+        let field = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let word = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        if crc32(&header[..HEADER_LEN - 4]) != word(HEADER_LEN - 4) {
+            // Without a trustworthy length there is no way to find the next frame.
+            scan.corrupt.push(CorruptRange {
+                offset,
+                steps: None,
+                reason: "frame header checksum mismatch; rest of file unreadable".to_string(),
+            });
+            return scan;
+        }
+        let kind = header[0];
+        let steps = (field(1), field(9));
+        let len = field(17) as usize;
+        let payload_start = pos + HEADER_LEN;
+        let Some(payload) = payload_start
+            .checked_add(len)
+            .and_then(|end| data.get(payload_start..end))
+        else {
+            scan.corrupt.push(CorruptRange {
+                offset,
+                steps: (kind == FRAME_ENTRIES).then_some(steps),
+                reason: "truncated frame payload".to_string(),
+            });
+            return scan;
+        };
+        pos = payload_start + len;
+
+        let decoded = if crc32(payload) != word(25) {
+            Err("payload checksum mismatch".to_string())
+        } else {
+            match kind {
+                FRAME_META => deserialize(payload).map(|meta| scan.meta = Some(meta)),
+                FRAME_ENTRIES => {
+                    deserialize::<Vec<TraceEntry>>(payload).map(|block| scan.entries.extend(block))
+                }
+                FRAME_INDEX => deserialize(payload).map(|index| scan.index = Some(index)),
+                FRAME_END => {
+                    ended = true;
+                    Ok(())
+                }
+                other => Err(format!("unknown frame kind {}", other)),
+            }
+        };
+        if let Err(reason) = decoded {
+            scan.corrupt.push(CorruptRange {
+                offset,
+                steps: (kind == FRAME_ENTRIES).then_some(steps),
+                reason,
+            });
+        }
+    }
+    if !ended {
+        scan.corrupt.push(CorruptRange {
+            offset: (base + pos) as u64,
+            steps: None,
+            reason: "missing end marker (file truncated?)".to_string(),
+        });
+    }
+    scan
+}
+
+/// A current-format trace file read front to back one block of entries at a
+/// time, so memory use stays at one block however long the trace is.
+pub struct TraceStream<R = BufReader<File>> {
+    reader: R,
+    offset: u64,
+    meta: TraceMeta,
+}
+
+struct FrameHeader {
+    offset: u64,
+    kind: u8,
+    steps: (u64, u64),
+    len: u64,
+    payload_crc: u32,
+}
+
+impl TraceStream {
+    /// Open `path` and read its metadata. None for header-less and SQLite
+    /// files, which have to be loaded whole.
+    pub fn open(path: &str) -> Result<Option<Self>, String> {
+        let file = File::open(path).map_err(|e| format!("Read failed: {}", e))?;
+        Self::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> TraceStream<R> {
+    pub fn new(mut reader: R) -> Result<Option<Self>, String> {
+        let mut head = [0u8; 12];
+        match reader.read_exact(&mut head) {
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result.map_err(|e| format!("Read failed: {}", e))?,
+        }
+        if head[..8] != MAGIC[..] {
+            return Ok(None);
+        }
+        let version = u32::from_le_bytes(head[8..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(format!("Unsupported trace format version {}", version));
+        }
+        let mut stream = Self {
+            reader,
+            offset: head.len() as u64,
+            meta: TraceMeta::default(),
+        };
+        let frame = stream.header()?;
+        if frame.kind != FRAME_META {
+            return Err(corrupt(&frame, "expected the metadata frame first"));
+        }
+        stream.meta = deserialize(&stream.payload(&frame)?)?;
+        Ok(Some(stream))
+    }
+
+    pub fn meta(&self) -> &TraceMeta {
+        &self.meta
+    }
+
+    /// Visit the entries of `start..=end` in step order, stopping at the
+    /// first error. Blocks outside the range are skipped without being
+    /// decoded. Consumes the stream; open the file again for another pass.
+    pub fn try_for_each_in(
+        mut self,
+        start: u64,
+        end: u64,
+        mut f: impl FnMut(&TraceEntry) -> Result<(), String>,
+    ) -> Result<(), String> {
+        loop {
+            let frame = self.header()?;
+            match frame.kind {
+                // Blocks are written in step order.
+                FRAME_ENTRIES if frame.steps.0 > end => return Ok(()),
+                FRAME_ENTRIES if frame.steps.1 < start => self.skip(&frame)?,
+                FRAME_ENTRIES => {
+                    let block: Vec<TraceEntry> =
+                        deserialize(&self.payload(&frame)?).map_err(|e| corrupt(&frame, &e))?;
+                    block
+                        .iter()
+                        .filter(|e| (start..=end).contains(&e.step))
+                        .try_for_each(&mut f)?;
+                }
+                // The index and end marker follow the last block.
+                FRAME_INDEX | FRAME_END => return Ok(()),
+                other => return Err(corrupt(&frame, &format!("unknown frame kind {}", other))),
+            }
+        }
+    }
+
+    fn header(&mut self) -> Result<FrameHeader, String> {
+        let mut header = [0u8; HEADER_LEN];
+        let offset = self.offset;
+        self.reader.read_exact(&mut header).map_err(|_| {
+            format!(
+                "Corrupt trace at offset {}: truncated frame header (run `tdb verify` for details)",
+                offset
+            )
+        })?;
+        self.offset += HEADER_LEN as u64;
+        let field = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+        let word = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        if crc32(&header[..HEADER_LEN - 4]) != word(HEADER_LEN - 4) {
+            return Err(format!(
+                "Corrupt trace at offset {}: frame header checksum mismatch (run `tdb verify` for details)",
+                offset
+            ));
+        }
+        Ok(FrameHeader {
+            offset,
+            kind: header[0],
+            steps: (field(1), field(9)),
+            len: field(17),
+            payload_crc: word(25),
+        })
+    }
+
+    fn payload(&mut self, frame: &FrameHeader) -> Result<Vec<u8>, String> {
+        let mut payload = Vec::new();
+        (&mut self.reader)
+            .take(frame.len)
+            .read_to_end(&mut payload)
+            .map_err(|e| format!("Read failed: {}", e))?;
+        self.offset += payload.len() as u64;
+        if payload.len() as u64 != frame.len {
+            return Err(corrupt(frame, "truncated frame payload"));
+        }
+        if crc32(&payload) != frame.payload_crc {
+            return Err(corrupt(frame, "payload checksum mismatch"));
+        }
+        Ok(payload)
+    }
+
+    fn skip(&mut self, frame: &FrameHeader) -> Result<(), String> {
+        let len = i64::try_from(frame.len).map_err(|_| corrupt(frame, "frame too long"))?;
+        self.reader
+            .seek(SeekFrom::Current(len))
+            .map_err(|e| format!("Read failed: {}", e))?;
+        self.offset += frame.len;
+        Ok(())
+    }
+}
+
+/// The error for a damaged frame, worded like [`super::TraceDb::load`]'s.
+fn corrupt(frame: &FrameHeader, reason: &str) -> String {
+    let steps = match frame.kind {
+        FRAME_ENTRIES => format!(" in steps {}..{}", frame.steps.0, frame.steps.1),
+        _ => String::new(),
+    };
+    format!(
+        "Corrupt trace{} at offset {}: {} (run `tdb verify` for details)",
+        steps, frame.offset, reason
+    )
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib and PNG.
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xEDB8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    let mut crc = !0u32;
+    for &b in data {
+        crc = TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(n: u64) -> BTreeMap<u64, TraceEntry> {
+        (0..n)
+            .map(|step| {
+                let e = TraceEntry {
+                    step,
+                    pc: 0x1000 + step * 4,
+                    insn_bytes: vec![0x1F, 0x20, 0x03, 0xD5],
+                    insn_text: "nop".to_string(),
+                    regs: r#"{"x0":0}"#.to_string(),
+                    mem_changes: vec![],
+                    kind: InsnKind::Other,
+                    depth: 0,
+                };
+                (step, e)
+            })
+            .collect()
+    }
+
+    /// Old-layout entries: main calls f at step 1, f returns at step 3.
+    fn old_entries() -> Vec<LegacyEntry> {
+        [
+            "mov x0, #1",
+            "bl #0x2000 ; CALL [depth:1]",
+            "str x0, [sp]",
+            "ret ; RETURN [depth:0]",
+            "mov x0, #2",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, text)| LegacyEntry {
+            step: i as u64,
+            pc: 0x1000 + i as u64 * 4,
+            insn_bytes: vec![0x00],
+            insn_text: text.to_string(),
+            regs: "{}".to_string(),
+            mem_changes: vec![],
+        })
+        .collect()
+    }
+
+    fn assert_upgraded(entries: &[TraceEntry]) {
+        let kinds: Vec<InsnKind> = entries.iter().map(|e| e.kind).collect();
+        let depths: Vec<u64> = entries.iter().map(|e| e.depth).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                InsnKind::Other,
+                InsnKind::Call,
+                InsnKind::Store,
+                InsnKind::Return,
+                InsnKind::Other
+            ]
+        );
+        assert_eq!(depths, vec![0, 1, 1, 0, 0]);
+    }
+
+    fn encoded(n: u64) -> Vec<u8> {
+        let entries = entries(n);
+        let index = TraceIndex::build(entries.values());
+        encode(&TraceMeta::default(), &entries, &index).unwrap()
+    }
+
+    /// Byte offset of the first payload byte of the `n`th frame.
+    fn payload_offset(data: &[u8], n: usize) -> usize {
+        let mut pos = MAGIC.len() + 4;
+        for _ in 0..n {
+            let len = u64::from_le_bytes(data[pos + 17..pos + 25].try_into().unwrap());
+            pos += HEADER_LEN + len as usize;
+        }
+        pos + HEADER_LEN
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn clean_file_scans_without_damage() {
+        let scan = scan(&encoded(10)).unwrap();
+        assert_eq!(scan.version, FORMAT_VERSION);
+        assert!(scan.has_checksums());
+        assert!(scan.corrupt.is_empty());
+        assert_eq!(scan.entries.len(), 10);
+        assert!(scan.meta.is_some());
+        assert!(scan.index.is_some());
+    }
+
+    #[test]
+    fn entries_split_into_blocks() {
+        let n = BLOCK_SIZE as u64 * 2 + 5;
+        let scan = scan(&encoded(n)).unwrap();
+        assert_eq!(scan.entries.len() as u64, n);
+    }
+
+    #[test]
+    fn corrupt_payload_reports_block_steps_and_keeps_others() {
+        let n = BLOCK_SIZE as u64 * 3;
+        let mut data = encoded(n);
+        // Frame 0 is meta; frame 2 is the second entries block.
+        let at = payload_offset(&data, 2) + 10;
+        data[at] ^= 0xFF;
+
+        let scan = scan(&data).unwrap();
+        assert_eq!(scan.corrupt.len(), 1);
+        let bad = &scan.corrupt[0];
+        assert_eq!(
+            bad.steps,
+            Some((BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64 - 1))
+        );
+        assert!(bad.reason.contains("checksum"));
+        assert_eq!(scan.entries.len(), 2 * BLOCK_SIZE);
+        assert!(
+            scan.index.is_some(),
+            "frames after the damage are still read"
+        );
+    }
+
+    #[test]
+    fn corrupt_header_stops_scan() {
+        let mut data = encoded(10);
+        let at = payload_offset(&data, 1) - HEADER_LEN + 3;
+        data[at] ^= 0xFF;
+
+        let scan = scan(&data).unwrap();
+        assert_eq!(scan.corrupt.len(), 1);
+        assert!(scan.corrupt[0].reason.contains("header"));
+        assert!(scan.entries.is_empty());
+    }
+
+    #[test]
+    fn truncated_file_is_reported() {
+        let data = encoded(10);
+        let scan = scan(&data[..data.len() - HEADER_LEN]).unwrap();
+        assert_eq!(scan.entries.len(), 10);
+        assert!(scan.corrupt[0].reason.contains("end marker"));
+    }
+
+    fn stream(data: Vec<u8>) -> TraceStream<std::io::Cursor<Vec<u8>>> {
+        TraceStream::new(std::io::Cursor::new(data))
+            .unwrap()
+            .unwrap()
+    }
+
+    fn streamed_steps(data: Vec<u8>, start: u64, end: u64) -> Result<Vec<u64>, String> {
+        let mut steps = Vec::new();
+        stream(data).try_for_each_in(start, end, |e| {
+            steps.push(e.step);
+            Ok(())
+        })?;
+        Ok(steps)
+    }
+
+    #[test]
+    fn stream_visits_range_across_blocks() {
+        let n = BLOCK_SIZE as u64 * 3;
+        assert_eq!(stream(encoded(n)).meta(), &TraceMeta::default());
+        let (start, end) = (BLOCK_SIZE as u64 - 2, BLOCK_SIZE as u64 + 1);
+        assert_eq!(
+            streamed_steps(encoded(n), start, end).unwrap(),
+            (start..=end).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            streamed_steps(encoded(n), 0, u64::MAX).unwrap().len() as u64,
+            n
+        );
+    }
+
+    #[test]
+    fn stream_skips_damaged_blocks_outside_the_range() {
+        let n = BLOCK_SIZE as u64 * 3;
+        let mut data = encoded(n);
+        // Frame 0 is meta; frame 1 is the first entries block.
+        let at = payload_offset(&data, 1) + 10;
+        data[at] ^= 0xFF;
+
+        let last = n - 1;
+        assert_eq!(
+            streamed_steps(data.clone(), last, last).unwrap(),
+            vec![last]
+        );
+        let err = streamed_steps(data, 0, last).unwrap_err();
+        assert!(err.contains("in steps 0.."), "unexpected error: {}", err);
+        assert!(err.contains("checksum"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn stream_reports_truncation_and_other_formats() {
+        let data = encoded(10);
+        let cut = payload_offset(&data, 1) + 4;
+        let err = streamed_steps(data[..cut].to_vec(), 0, 9).unwrap_err();
+        assert!(err.contains("truncated"), "unexpected error: {}", err);
+
+        let legacy = encode_legacy(&entries(2).into_values().collect::<Vec<_>>());
+        let cursor = std::io::Cursor::new(legacy);
+        assert!(TraceStream::new(cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_file_scans_as_version_1() {
+        let map: BTreeMap<u64, LegacyEntry> =
+            old_entries().into_iter().map(|e| (e.step, e)).collect();
+        let data = bincode::serialize(&map).unwrap();
+        let scan = scan(&data).unwrap();
+        assert_eq!(scan.version, LEGACY_VERSION);
+        assert!(!scan.has_checksums());
+        assert!(scan.index.is_none());
+        assert_upgraded(&scan.entries);
+    }
+
+    #[test]
+    fn annotated_depth_parses_suffix() {
+        assert_eq!(annotated_depth("bl #0x2000 ; CALL [depth:12]"), Some(12));
+        assert_eq!(annotated_depth("ret ; RETURN [depth:0]"), Some(0));
+        assert_eq!(annotated_depth("mov x0, #1"), None);
+    }
+}
@@ -0,0 +1,337 @@
+use super::TraceEntry;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Coarse classification of an instruction, derived from its text.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum InsnKind {
+    Call,
+    Return,
+    Branch,
+    Load,
+    Store,
+    Other,
+}
+
+impl InsnKind {
+    /// Classify an instruction from its recorded text.
+    ///
+    /// Calls and returns are taken from the tracer's `CALL` / `RETURN`
+    /// annotations; everything else is decided by the mnemonic.
+    pub fn classify(insn_text: &str) -> Self {
+        if insn_text.contains("CALL") {
+            return InsnKind::Call;
+        }
+        if insn_text.contains("RETURN") {
+            return InsnKind::Return;
+        }
+        let m = insn_text.split_whitespace().next().unwrap_or("");
+        if crate::stats::is_branch_mnemonic(m) {
+            InsnKind::Branch
+        } else if crate::tracer::is_store_mnemonic(m) {
+            InsnKind::Store
+        } else if is_load_mnemonic(m) {
+            InsnKind::Load
+        } else {
+            InsnKind::Other
+        }
+    }
+
+    /// Lowercase name used in exports.
+    pub fn name(self) -> &'static str {
+        match self {
+            InsnKind::Call => "call",
+            InsnKind::Return => "return",
+            InsnKind::Branch => "branch",
+            InsnKind::Load => "load",
+            InsnKind::Store => "store",
+            InsnKind::Other => "other",
+        }
+    }
+
+    /// Inverse of `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "call" => InsnKind::Call,
+            "return" => InsnKind::Return,
+            "branch" => InsnKind::Branch,
+            "load" => InsnKind::Load,
+            "store" => InsnKind::Store,
+            "other" => InsnKind::Other,
+            _ => return None,
+        })
+    }
+}
+
+/// ARM64 loads (ldr, ldp, ldur, ldar, ldxr, ...) and x86_64 pops / string loads.
+fn is_load_mnemonic(m: &str) -> bool {
+    m.starts_with("ld") || m.starts_with("lods") || matches!(m, "pop" | "popq")
+}
+
+/// What to look up in a [`TraceIndex`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexKey {
+    /// Every execution of the instruction at this address.
+    Pc(u64),
+    /// Every step that wrote the byte at this address.
+    MemWrite(u64),
+    /// Every step whose instruction has this kind.
+    Kind(InsnKind),
+    /// Every step that changed memory at all.
+    AnyMemWrite,
+}
+
+/// Secondary indexes mapping PCs, written addresses and instruction kinds
+/// to the (sorted) steps where they occur.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TraceIndex {
+    by_pc: BTreeMap<u64, Vec<u64>>,
+    by_mem_write: BTreeMap<u64, Vec<u64>>,
+    by_kind: BTreeMap<InsnKind, Vec<u64>>,
+    mem_steps: Vec<u64>,
+}
+
+impl TraceIndex {
+    pub fn build<'a>(entries: impl IntoIterator<Item = &'a TraceEntry>) -> Self {
+        let mut index = Self::default();
+        for entry in entries {
+            index.add(entry);
+        }
+        index
+    }
+
+    pub fn add(&mut self, entry: &TraceEntry) {
+        let step = entry.step;
+        insert_sorted(self.by_pc.entry(entry.pc).or_default(), step);
+        insert_sorted(self.by_kind.entry(entry.kind).or_default(), step);
+        for c in &entry.mem_changes {
+            insert_sorted(self.by_mem_write.entry(c.addr).or_default(), step);
+        }
+        if !entry.mem_changes.is_empty() {
+            insert_sorted(&mut self.mem_steps, step);
+        }
+    }
+
+    pub fn remove(&mut self, entry: &TraceEntry) {
+        let step = entry.step;
+        remove_keyed(&mut self.by_pc, entry.pc, step);
+        remove_keyed(&mut self.by_kind, entry.kind, step);
+        for c in &entry.mem_changes {
+            remove_keyed(&mut self.by_mem_write, c.addr, step);
+        }
+        remove_sorted(&mut self.mem_steps, step);
+    }
+
+    /// All steps matching `key`, in ascending order.
+    pub fn steps(&self, key: IndexKey) -> &[u64] {
+        let steps = match key {
+            IndexKey::Pc(pc) => self.by_pc.get(&pc),
+            IndexKey::MemWrite(addr) => self.by_mem_write.get(&addr),
+            IndexKey::Kind(kind) => self.by_kind.get(&kind),
+            IndexKey::AnyMemWrite => Some(&self.mem_steps),
+        };
+        steps.map(|v| v.as_slice()).unwrap_or(&[])
+    }
+
+    /// First step matching `key` strictly after `step`.
+    pub fn next_after(&self, key: IndexKey, step: u64) -> Option<u64> {
+        let steps = self.steps(key);
+        let pos = steps.partition_point(|&s| s <= step);
+        steps.get(pos).copied()
+    }
+
+    /// Last step matching `key` strictly before `step`.
+    pub fn prev_before(&self, key: IndexKey, step: u64) -> Option<u64> {
+        let steps = self.steps(key);
+        let pos = steps.partition_point(|&s| s < step);
+        pos.checked_sub(1).map(|p| steps[p])
+    }
+
+    /// Every step that wrote any byte in `addrs`, in ascending order.
+    pub fn mem_write_steps(&self, addrs: std::ops::Range<u64>) -> Vec<u64> {
+        let mut steps: Vec<u64> = self
+            .by_mem_write
+            .range(addrs)
+            .flat_map(|(_, s)| s.iter().copied())
+            .collect();
+        steps.sort_unstable();
+        steps.dedup();
+        steps
+    }
+
+    /// Last step strictly before `step` that wrote any byte in `addrs`.
+    pub fn mem_write_before(&self, addrs: std::ops::Range<u64>, step: u64) -> Option<u64> {
+        self.by_mem_write
+            .range(addrs)
+            .filter_map(|(_, steps)| {
+                let pos = steps.partition_point(|&s| s < step);
+                pos.checked_sub(1).map(|p| steps[p])
+            })
+            .max()
+    }
+
+    /// First step strictly after `step` that wrote any byte in `addrs`.
+    pub fn mem_write_after(&self, addrs: std::ops::Range<u64>, step: u64) -> Option<u64> {
+        self.by_mem_write
+            .range(addrs)
+            .filter_map(|(_, steps)| {
+                let pos = steps.partition_point(|&s| s <= step);
+                steps.get(pos).copied()
+            })
+            .min()
+    }
+
+    /// Number of distinct PCs executed.
+    pub fn unique_pcs(&self) -> usize {
+        self.by_pc.len()
+    }
+
+    /// Every address written at least once, in ascending order.
+    pub fn written_addrs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.by_mem_write.keys().copied()
+    }
+
+    /// Execution count per PC, in address order.
+    pub fn pc_counts(&self) -> impl Iterator<Item = (u64, usize)> + '_ {
+        self.by_pc.iter().map(|(&pc, steps)| (pc, steps.len()))
+    }
+}
+
+fn insert_sorted(v: &mut Vec<u64>, step: u64) {
+    // Steps are almost always recorded in order, so appending is the fast path.
+    if v.last().is_none_or(|&last| last < step) {
+        v.push(step);
+    } else if let Err(pos) = v.binary_search(&step) {
+        v.insert(pos, step);
+    }
+}
+
+fn remove_sorted(v: &mut Vec<u64>, step: u64) {
+    if let Ok(pos) = v.binary_search(&step) {
+        v.remove(pos);
+    }
+}
+
+fn remove_keyed<K: Ord>(map: &mut BTreeMap<K, Vec<u64>>, key: K, step: u64) {
+    if let Some(v) = map.get_mut(&key) {
+        remove_sorted(v, step);
+        if v.is_empty() {
+            map.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemChange;
+
+    fn entry(step: u64, pc: u64, insn: &str, writes: &[u64]) -> TraceEntry {
+        TraceEntry {
+            mem_changes: writes
+                .iter()
+                .map(|&addr| MemChange {
+                    addr,
+                    old_val: 0,
+                    new_val: 1,
+                })
+                .collect(),
+            ..crate::storage::entry(step, pc, insn)
+        }
+    }
+
+    #[test]
+    fn classify_annotations() {
+        assert_eq!(
+            InsnKind::classify("bl #0x2000 ; CALL [depth:1]"),
+            InsnKind::Call
+        );
+        assert_eq!(
+            InsnKind::classify("ret ; RETURN [depth:0]"),
+            InsnKind::Return
+        );
+    }
+
+    #[test]
+    fn classify_mnemonics() {
+        assert_eq!(InsnKind::classify("b.eq #0x3000"), InsnKind::Branch);
+        assert_eq!(InsnKind::classify("jne 0x4000"), InsnKind::Branch);
+        assert_eq!(InsnKind::classify("str x0, [sp]"), InsnKind::Store);
+        assert_eq!(InsnKind::classify("push rbp"), InsnKind::Store);
+        assert_eq!(InsnKind::classify("ldp x29, x30, [sp]"), InsnKind::Load);
+        assert_eq!(InsnKind::classify("pop rbp"), InsnKind::Load);
+        assert_eq!(InsnKind::classify("mov x0, #1"), InsnKind::Other);
+        assert_eq!(InsnKind::classify(""), InsnKind::Other);
+    }
+
+    #[test]
+    fn pc_lookup_returns_sorted_steps() {
+        let entries = vec![
+            entry(0, 0xA, "nop", &[]),
+            entry(1, 0xB, "nop", &[]),
+            entry(2, 0xA, "nop", &[]),
+            entry(3, 0xA, "nop", &[]),
+        ];
+        let idx = TraceIndex::build(&entries);
+        assert_eq!(idx.steps(IndexKey::Pc(0xA)), &[0, 2, 3]);
+        assert_eq!(idx.steps(IndexKey::Pc(0xB)), &[1]);
+        assert!(idx.steps(IndexKey::Pc(0xC)).is_empty());
+        assert_eq!(idx.unique_pcs(), 2);
+    }
+
+    #[test]
+    fn mem_write_lookup() {
+        let entries = vec![
+            entry(0, 0x1000, "str x0, [sp]", &[0x7000, 0x7001]),
+            entry(1, 0x1004, "mov x0, #1", &[]),
+            entry(2, 0x1008, "str x0, [sp]", &[0x7000]),
+        ];
+        let idx = TraceIndex::build(&entries);
+        assert_eq!(idx.steps(IndexKey::MemWrite(0x7000)), &[0, 2]);
+        assert_eq!(idx.steps(IndexKey::MemWrite(0x7001)), &[0]);
+        assert_eq!(idx.steps(IndexKey::AnyMemWrite), &[0, 2]);
+        assert_eq!(idx.mem_write_steps(0x7000..0x7002), vec![0, 2]);
+        assert_eq!(idx.mem_write_steps(0x7001..0x7008), vec![0]);
+        assert!(idx.mem_write_steps(0x7002..0x7008).is_empty());
+        assert_eq!(idx.mem_write_before(0x7000..0x7002, 2), Some(0));
+        assert_eq!(idx.mem_write_before(0x7001..0x7002, 0), None);
+        assert_eq!(idx.mem_write_after(0x7000..0x7002, 0), Some(2));
+        assert_eq!(idx.mem_write_after(0x7001..0x7008, 0), None);
+    }
+
+    #[test]
+    fn kind_lookup_and_navigation() {
+        let entries = vec![
+            entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]", &[]),
+            entry(1, 0x2000, "mov x0, #0", &[]),
+            entry(2, 0x2004, "ret ; RETURN [depth:0]", &[]),
+            entry(3, 0x1004, "bl #0x2000 ; CALL [depth:1]", &[]),
+        ];
+        let idx = TraceIndex::build(&entries);
+        let call = IndexKey::Kind(InsnKind::Call);
+        assert_eq!(idx.steps(call), &[0, 3]);
+        assert_eq!(idx.next_after(call, 0), Some(3));
+        assert_eq!(idx.next_after(call, 3), None);
+        assert_eq!(idx.prev_before(call, 3), Some(0));
+        assert_eq!(idx.prev_before(call, 0), None);
+    }
+
+    #[test]
+    fn out_of_order_adds_stay_sorted() {
+        let entries = vec![
+            entry(5, 0xA, "nop", &[]),
+            entry(1, 0xA, "nop", &[]),
+            entry(3, 0xA, "nop", &[]),
+        ];
+        let idx = TraceIndex::build(&entries);
+        assert_eq!(idx.steps(IndexKey::Pc(0xA)), &[1, 3, 5]);
+    }
+
+    #[test]
+    fn remove_drops_all_keys() {
+        let e = entry(0, 0xA, "str x0, [sp]", &[0x7000]);
+        let mut idx = TraceIndex::build([&e]);
+        idx.remove(&e);
+        assert_eq!(idx, TraceIndex::default());
+    }
+}
@@ -1,4 +1,16 @@
-use parking_lot::RwLock;
+mod format;
+mod index;
+mod sqlite;
+mod watch;
+
+#[cfg(test)]
+pub(crate) use format::encode_legacy;
+pub use format::{scan, CorruptRange, TraceStream, FORMAT_VERSION};
+pub use index::*;
+pub use sqlite::sql_err;
+pub use watch::{Watch, WatchHit};
+
+use parking_lot::{RwLock, RwLockReadGuard};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::sync::Arc;
@@ -15,13 +27,55 @@ pub struct TraceEntry {
     pub step: u64,
     pub pc: u64,
     pub insn_bytes: Vec<u8>,
+    /// Disassembly; calls and returns carry a " ; CALL [depth:N]" or
+    /// " ; RETURN [depth:N]" suffix.
     pub insn_text: String,
     pub regs: String,
     pub mem_changes: Vec<MemChange>,
+    pub kind: InsnKind,
+    /// Call depth after this instruction executes.
+    pub depth: u64,
+}
+
+/// A signal delivered to the traced process while recording.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SignalEvent {
+    /// Step that was executing when the signal arrived.
+    pub step: u64,
+    pub signal: String,
+}
+
+/// Where a sliced trace came from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SliceInfo {
+    pub source: String,
+    /// First and last original step numbers included in the slice.
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Recording-wide information stored alongside the entries.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TraceMeta {
+    /// Program and arguments that were traced (empty when attached by PID).
+    pub command: Vec<String>,
+    pub pid: i32,
+    /// "x86_64" or "arm64".
+    pub arch: String,
+    /// Unix time (seconds) when recording started.
+    pub recorded_at: u64,
+    pub duration_ms: u64,
+    pub signals: Vec<SignalEvent>,
+    /// Known byte values before the first recorded step. Empty for a full
+    /// recording; filled in by `tdb slice` from the history that was cut off.
+    pub initial_memory: BTreeMap<u64, u8>,
+    pub slice: Option<SliceInfo>,
 }
 
 pub struct TraceDb {
     entries: Arc<RwLock<BTreeMap<u64, TraceEntry>>>,
+    index: Arc<RwLock<TraceIndex>>,
+    meta: Arc<RwLock<TraceMeta>>,
     path: String,
 }
 
@@ -38,12 +92,20 @@ impl TraceDb {
     pub fn new(path: &str) -> Result<Self, String> {
         Ok(Self {
             entries: Arc::new(RwLock::new(BTreeMap::new())),
+            index: Arc::new(RwLock::new(TraceIndex::default())),
+            meta: Arc::new(RwLock::new(TraceMeta::default())),
             path: path.to_string(),
         })
     }
 
     pub fn insert(&self, entry: TraceEntry) -> Result<(), String> {
-        self.entries.write().insert(entry.step, entry);
+        let mut entries = self.entries.write();
+        let mut index = self.index.write();
+        if let Some(old) = entries.get(&entry.step) {
+            index.remove(old);
+        }
+        index.add(&entry);
+        entries.insert(entry.step, entry);
         Ok(())
     }
 
@@ -51,6 +113,11 @@ impl TraceDb {
         self.entries.read().get(&step).cloned()
     }
 
+    /// Call depth after `step` executes, without copying the entry.
+    pub fn depth(&self, step: u64) -> Option<u64> {
+        self.entries.read().get(&step).map(|e| e.depth)
+    }
+
     pub fn get_range(&self, start: u64, end: u64) -> Vec<TraceEntry> {
         self.entries
             .read()
@@ -59,6 +126,38 @@ impl TraceDb {
             .collect()
     }
 
+    /// Visit the entries of `start..=end` in step order without copying them,
+    /// stopping at the first error. The read lock is held throughout, so `f`
+    /// must not write to this database.
+    pub fn try_for_each_in<E>(
+        &self,
+        start: u64,
+        end: u64,
+        f: impl FnMut(&TraceEntry) -> Result<(), E>,
+    ) -> Result<(), E> {
+        if start > end {
+            return Ok(());
+        }
+        self.entries
+            .read()
+            .range(start..=end)
+            .map(|(_, e)| e)
+            .try_for_each(f)
+    }
+
+    /// Visit the entries of `start..=end` in step order without copying them,
+    /// under the same lock as [`TraceDb::try_for_each_in`].
+    pub fn for_each_in(&self, start: u64, end: u64, f: impl FnMut(&TraceEntry)) {
+        if start > end {
+            return;
+        }
+        self.entries
+            .read()
+            .range(start..=end)
+            .map(|(_, e)| e)
+            .for_each(f)
+    }
+
     pub fn get_all(&self) -> Vec<TraceEntry> {
         self.entries.read().values().cloned().collect()
     }
@@ -67,21 +166,173 @@ impl TraceDb {
         self.entries.read().len() as u64
     }
 
-    pub fn save(&self) -> Result<(), String> {
+    /// First and last recorded step numbers.
+    pub fn step_bounds(&self) -> Option<(u64, u64)> {
         let entries = self.entries.read();
-        let data = bincode::serialize(&*entries).map_err(|e| format!("Serialize failed: {}", e))?;
+        let first = *entries.keys().next()?;
+        let last = *entries.keys().next_back()?;
+        Some((first, last))
+    }
+
+    /// Read access to the secondary indexes.
+    pub fn index(&self) -> RwLockReadGuard<'_, TraceIndex> {
+        self.index.read()
+    }
+
+    pub fn meta(&self) -> TraceMeta {
+        self.meta.read().clone()
+    }
+
+    pub fn set_meta(&self, meta: TraceMeta) {
+        *self.meta.write() = meta;
+    }
+
+    pub fn record_signal(&self, event: SignalEvent) {
+        self.meta.write().signals.push(event);
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        let data = format::encode(&self.meta.read(), &self.entries.read(), &self.index.read())?;
         std::fs::write(&self.path, data).map_err(|e| format!("Write failed: {}", e))?;
         Ok(())
     }
 
     pub fn load(path: &str) -> Result<Self, String> {
+        Self::load_versioned(path).map(|(db, _)| db)
+    }
+
+    /// Write the steps in `start..=end`, with the trace metadata, into the
+    /// trace tables of an SQLite database. Returns the number of steps.
+    pub fn save_sqlite(
+        &self,
+        conn: &mut rusqlite::Connection,
+        (start, end): (u64, u64),
+    ) -> Result<u64, String> {
+        let entries = self.entries.read();
+        // BTreeMap::range panics on an inverted range.
+        let range = (start <= end).then(|| entries.range(start..=end));
+        sqlite::write(
+            conn,
+            &self.meta.read(),
+            range.into_iter().flatten().map(|(_, e)| e),
+        )
+    }
+
+    /// Load a trace of any supported format version, upgrading it in memory,
+    /// and report which version the file was stored in. SQLite exports are
+    /// read back as the current version.
+    pub fn load_versioned(path: &str) -> Result<(Self, u32), String> {
+        if sqlite::is_sqlite(path) {
+            let (meta, entries) = sqlite::read(path)?;
+            let index = TraceIndex::build(entries.values());
+            return Ok((Self::from_parts(path, meta, entries, index), FORMAT_VERSION));
+        }
         let data = std::fs::read(path).map_err(|e| format!("Read failed: {}", e))?;
+        let scan = format::scan(&data)?;
+        if let Some(bad) = scan.corrupt.first() {
+            let steps = bad
+                .steps
+                .map(|(a, b)| format!(" in steps {}..{}", a, b))
+                .unwrap_or_default();
+            return Err(format!(
+                "Corrupt trace{} at offset {}: {} (run `tdb verify` for details)",
+                steps, bad.offset, bad.reason
+            ));
+        }
         let entries: BTreeMap<u64, TraceEntry> =
-            bincode::deserialize(&data).map_err(|e| format!("Deserialize failed: {}", e))?;
-        Ok(Self {
+            scan.entries.into_iter().map(|e| (e.step, e)).collect();
+        let index = scan
+            .index
+            .unwrap_or_else(|| TraceIndex::build(entries.values()));
+        let mut meta = scan.meta.unwrap_or_default();
+        if meta.arch.is_empty() {
+            meta.arch = entries
+                .values()
+                .next()
+                .and_then(|e| arch_from_regs(&e.regs))
+                .unwrap_or_default()
+                .to_string();
+        }
+        Ok((Self::from_parts(path, meta, entries, index), scan.version))
+    }
+
+    fn from_parts(
+        path: &str,
+        meta: TraceMeta,
+        entries: BTreeMap<u64, TraceEntry>,
+        index: TraceIndex,
+    ) -> Self {
+        Self {
             entries: Arc::new(RwLock::new(entries)),
+            index: Arc::new(RwLock::new(index)),
+            meta: Arc::new(RwLock::new(meta)),
             path: path.to_string(),
-        })
+        }
+    }
+}
+
+/// Guess the architecture from a step's register dump, for traces recorded
+/// before the arch was stored.
+fn arch_from_regs(regs: &str) -> Option<&'static str> {
+    let regs: serde_json::Value = serde_json::from_str(regs).ok()?;
+    if regs.get("rax").is_some() {
+        Some("x86_64")
+    } else if regs.get("x0").is_some() {
+        Some("arm64")
+    } else {
+        None
+    }
+}
+
+/// What `migrate` converted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub from_version: u32,
+    pub steps: u64,
+    pub calls: usize,
+    pub returns: usize,
+    pub arch: String,
+}
+
+/// Rewrite the trace at `src` in the current format at `dst`.
+pub fn migrate(src: &str, dst: &str) -> Result<Migration, String> {
+    let (old, from_version) = TraceDb::load_versioned(src)?;
+    let migration = {
+        let index = old.index();
+        Migration {
+            from_version,
+            steps: old.count(),
+            calls: index.steps(IndexKey::Kind(InsnKind::Call)).len(),
+            returns: index.steps(IndexKey::Kind(InsnKind::Return)).len(),
+            arch: old.meta().arch,
+        }
+    };
+    let new = TraceDb {
+        path: dst.to_string(),
+        ..old
+    };
+    new.save()?;
+    Ok(migration)
+}
+
+/// A test step running `insn` (encoded as an arm64 `nop`) with no register
+/// values or memory changes; tests override fields with struct update
+/// syntax.
+#[cfg(test)]
+pub(crate) fn entry(step: u64, pc: u64, insn: &str) -> TraceEntry {
+    TraceEntry {
+        step,
+        pc,
+        insn_bytes: vec![0x1f, 0x20, 0x03, 0xd5],
+        insn_text: insn.to_string(),
+        regs: "{}".to_string(),
+        mem_changes: vec![],
+        kind: InsnKind::classify(insn),
+        depth: 0,
     }
 }
 
@@ -98,6 +349,8 @@ mod tests {
             insn_text: insn.to_string(),
             regs: serde_json::json!({"x0": step, "sp": 0x7000}).to_string(),
             mem_changes: vec![],
+            kind: InsnKind::classify(insn),
+            depth: 0,
         }
     }
 
@@ -109,6 +362,8 @@ mod tests {
             insn_text: "str x0, [sp]".to_string(),
             regs: serde_json::json!({"x0": 42, "sp": 0x7000}).to_string(),
             mem_changes: changes,
+            kind: InsnKind::Store,
+            depth: 0,
         }
     }
 
@@ -164,6 +419,8 @@ mod tests {
                     new_val: 0x42,
                 },
             ],
+            kind: InsnKind::Call,
+            depth: 3,
         };
         db.insert(e.clone()).unwrap();
 
@@ -219,6 +476,19 @@ mod tests {
         assert_eq!(range[4].step, 7);
     }
 
+    #[test]
+    fn for_each_in_visits_range_in_order() {
+        let db = TraceDb::new(":memory:").unwrap();
+        for i in 0..10 {
+            db.insert(make_entry(i, 0x1000 + i * 4, "nop")).unwrap();
+        }
+        let mut steps = Vec::new();
+        db.for_each_in(3, 5, |e| steps.push(e.step));
+        assert_eq!(steps, vec![3, 4, 5]);
+        db.for_each_in(5, 3, |e| steps.push(e.step));
+        assert_eq!(steps.len(), 3);
+    }
+
     #[test]
     fn get_range_empty_when_no_match() {
         let db = TraceDb::new(":memory:").unwrap();
@@ -356,6 +626,218 @@ mod tests {
         );
     }
 
+    #[test]
+    fn load_checksum_failure_names_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flipped.tdb");
+        let path_str = path.to_str().unwrap();
+
+        let db = TraceDb::new(path_str).unwrap();
+        for i in 0..4 {
+            db.insert(make_entry(i, 0x1000 + i * 4, "nop")).unwrap();
+        }
+        db.save().unwrap();
+
+        // Flip a byte inside the "nop" text of the entries block
+        let mut data = std::fs::read(&path).unwrap();
+        let at = data.windows(3).position(|w| w == b"nop").unwrap();
+        data[at] = b'X';
+        std::fs::write(&path, data).unwrap();
+
+        let err = TraceDb::load(path_str).unwrap_err();
+        assert!(err.contains("steps 0..3"), "unexpected error: {}", err);
+        assert!(err.contains("checksum"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn load_unsupported_version_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("future.tdb");
+        let mut data = format::MAGIC.to_vec();
+        data.extend_from_slice(&99u32.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+
+        let err = TraceDb::load(path.to_str().unwrap()).unwrap_err();
+        assert!(err.contains("version 99"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn meta_persisted_across_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("meta.tdb");
+        let path_str = path.to_str().unwrap();
+
+        let db = TraceDb::new(path_str).unwrap();
+        db.set_meta(TraceMeta {
+            command: vec!["./a.out".into(), "-v".into()],
+            pid: 1234,
+            arch: "arm64".into(),
+            recorded_at: 1_700_000_000,
+            duration_ms: 250,
+            ..Default::default()
+        });
+        db.record_signal(SignalEvent {
+            step: 7,
+            signal: "SIGSEGV".into(),
+        });
+        db.save().unwrap();
+
+        let loaded = TraceDb::load(path_str).unwrap();
+        assert_eq!(loaded.meta(), db.meta());
+        assert_eq!(loaded.meta().signals[0].signal, "SIGSEGV");
+    }
+
+    #[test]
+    fn save_sqlite_inverted_range_writes_nothing() {
+        let db = TraceDb::new(":memory:").unwrap();
+        for i in 0..3 {
+            db.insert(make_entry(i, 0x1000 + i * 4, "nop")).unwrap();
+        }
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        assert_eq!(db.save_sqlite(&mut conn, (50, 2)).unwrap(), 0);
+    }
+
+    // ── secondary indexes ──
+
+    #[test]
+    fn index_tracks_inserts() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(make_entry(0, 0x1000, "bl #0x2000 ; CALL [depth:1]"))
+            .unwrap();
+        db.insert(make_entry(1, 0x2000, "nop")).unwrap();
+        db.insert(make_entry(2, 0x1000, "bl #0x2000 ; CALL [depth:1]"))
+            .unwrap();
+
+        let index = db.index();
+        assert_eq!(index.steps(IndexKey::Pc(0x1000)), &[0, 2]);
+        assert_eq!(index.steps(IndexKey::Kind(InsnKind::Call)), &[0, 2]);
+        assert_eq!(index.next_after(IndexKey::Pc(0x1000), 0), Some(2));
+        assert_eq!(index.prev_before(IndexKey::Pc(0x1000), 2), Some(0));
+    }
+
+    #[test]
+    fn index_follows_overwrite() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.insert(make_entry(0, 0x1000, "first")).unwrap();
+        db.insert(make_entry(0, 0x2000, "second")).unwrap();
+
+        assert!(db.index().steps(IndexKey::Pc(0x1000)).is_empty());
+        assert_eq!(db.index().steps(IndexKey::Pc(0x2000)), &[0]);
+    }
+
+    #[test]
+    fn index_persisted_across_save_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("indexed.tdb");
+        let path_str = path.to_str().unwrap();
+
+        let db = TraceDb::new(path_str).unwrap();
+        db.insert(make_entry(0, 0x1000, "nop")).unwrap();
+        db.insert(make_entry_with_mem(
+            1,
+            vec![MemChange {
+                addr: 0x7FF0,
+                old_val: 0,
+                new_val: 1,
+            }],
+        ))
+        .unwrap();
+        db.save().unwrap();
+
+        let loaded = TraceDb::load(path_str).unwrap();
+        assert_eq!(*loaded.index(), *db.index());
+        assert_eq!(loaded.index().steps(IndexKey::MemWrite(0x7FF0)), &[1]);
+        assert_eq!(loaded.index().steps(IndexKey::AnyMemWrite), &[1]);
+    }
+
+    #[test]
+    fn legacy_file_loads_and_rebuilds_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.tdb");
+
+        let entries = [make_entry(0, 0x1000, "nop"), make_entry(1, 0x1000, "nop")];
+        std::fs::write(&path, encode_legacy(&entries)).unwrap();
+
+        let loaded = TraceDb::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.count(), 2);
+        assert_eq!(loaded.index().steps(IndexKey::Pc(0x1000)), &[0, 1]);
+    }
+
+    // ── migration ──
+
+    fn annotated(step: u64, insn: &str, depth: u64) -> TraceEntry {
+        TraceEntry {
+            depth,
+            ..make_entry(step, 0x1000 + step * 4, insn)
+        }
+    }
+
+    /// main calls f, f calls g, g returns, f returns.
+    fn legacy_calls() -> Vec<TraceEntry> {
+        vec![
+            annotated(0, "bl #0x2000 ; CALL [depth:1]", 1),
+            annotated(1, "bl #0x3000 ; CALL [depth:2]", 2),
+            annotated(2, "mov x0, #1", 2),
+            annotated(3, "ret ; RETURN [depth:1]", 1),
+            annotated(4, "ret ; RETURN [depth:0]", 0),
+        ]
+    }
+
+    #[test]
+    fn legacy_file_recovers_kind_depth_and_arch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.tdb");
+        let entries = legacy_calls();
+        std::fs::write(&path, encode_legacy(&entries)).unwrap();
+
+        let (loaded, version) = TraceDb::load_versioned(path.to_str().unwrap()).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(loaded.get_all(), entries);
+        assert_eq!(loaded.meta().arch, "arm64");
+    }
+
+    #[test]
+    fn arch_from_regs_detects_both_architectures() {
+        assert_eq!(arch_from_regs(r#"{"rax":0,"rsp":1}"#), Some("x86_64"));
+        assert_eq!(arch_from_regs(r#"{"x0":0,"sp":1}"#), Some("arm64"));
+        assert_eq!(arch_from_regs("{}"), None);
+        assert_eq!(arch_from_regs("not json"), None);
+    }
+
+    #[test]
+    fn migrate_writes_current_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("old.tdb");
+        let dst = dir.path().join("new.tdb");
+        std::fs::write(&src, encode_legacy(&legacy_calls())).unwrap();
+
+        let m = migrate(src.to_str().unwrap(), dst.to_str().unwrap()).unwrap();
+        assert_eq!(
+            m,
+            Migration {
+                from_version: 1,
+                steps: 5,
+                calls: 2,
+                returns: 2,
+                arch: "arm64".to_string(),
+            }
+        );
+
+        let (migrated, version) = TraceDb::load_versioned(dst.to_str().unwrap()).unwrap();
+        assert_eq!(version, FORMAT_VERSION);
+        assert_eq!(migrated.get_all(), legacy_calls());
+        assert_eq!(migrated.meta().arch, "arm64");
+        assert_eq!(migrated.path(), dst.to_str().unwrap());
+    }
+
+    #[test]
+    fn migrate_missing_source_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let dst = dir.path().join("new.tdb");
+        assert!(migrate("/nonexistent/old.tdb", dst.to_str().unwrap()).is_err());
+        assert!(!dst.exists());
+    }
+
     #[test]
     fn large_db_insert_and_retrieve() {
         let db = TraceDb::new(":memory:").unwrap();
@@ -441,6 +923,8 @@ mod tests {
             insn_text: "nop".into(),
             regs: regs.to_string(),
             mem_changes: vec![],
+            kind: InsnKind::Other,
+            depth: 0,
         };
         let bytes = bincode::serialize(&e).unwrap();
         let back: TraceEntry = bincode::deserialize(&bytes).unwrap();
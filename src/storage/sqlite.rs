@@ -0,0 +1,292 @@
+//! SQLite copy of a trace, for running SQL over a recording.
+//!
+//! Steps, registers and memory writes each get their own table, so queries
+//! such as "every write to 0x16fdff000" or "where did x0 first become 0"
+//! are a plain `SELECT`. 64-bit values are stored as SQLite integers, which
+//! are signed: values at or above 2^63 read back negative in SQL but
+//! round-trip unchanged through `TraceDb`.
+
+use super::{InsnKind, MemChange, SignalEvent, SliceInfo, TraceEntry, TraceMeta};
+use rusqlite::{params, Connection};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// First bytes of every SQLite database file.
+const MAGIC: &[u8; 16] = b"SQLite format 3\0";
+
+/// Bumped when the tables change incompatibly.
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+CREATE TABLE steps (
+    step INTEGER PRIMARY KEY,
+    pc INTEGER NOT NULL,
+    insn TEXT NOT NULL,
+    insn_bytes BLOB NOT NULL,
+    kind TEXT NOT NULL,
+    depth INTEGER NOT NULL
+);
+CREATE TABLE registers (
+    step INTEGER NOT NULL REFERENCES steps(step),
+    name TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    PRIMARY KEY (step, name)
+) WITHOUT ROWID;
+CREATE TABLE memory_writes (
+    step INTEGER NOT NULL REFERENCES steps(step),
+    addr INTEGER NOT NULL,
+    old_value INTEGER NOT NULL,
+    new_value INTEGER NOT NULL
+);
+CREATE TABLE signals (step INTEGER NOT NULL, signal TEXT NOT NULL);
+CREATE TABLE initial_memory (addr INTEGER PRIMARY KEY, value INTEGER NOT NULL);
+";
+
+/// Created after the bulk insert, which is much faster than keeping them
+/// up to date row by row.
+const INDEXES: &str = "
+CREATE INDEX steps_pc ON steps(pc);
+CREATE INDEX steps_kind ON steps(kind);
+CREATE INDEX registers_name ON registers(name, value);
+CREATE INDEX memory_writes_step ON memory_writes(step);
+CREATE INDEX memory_writes_addr ON memory_writes(addr, step);
+";
+
+pub fn sql_err(e: rusqlite::Error) -> String {
+    format!("SQLite failed: {}", e)
+}
+
+/// Whether the file at `path` is an SQLite database.
+pub(super) fn is_sqlite(path: &str) -> bool {
+    let mut header = [0u8; 16];
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut header))
+        .is_ok_and(|_| &header == MAGIC)
+}
+
+/// Create the trace tables in `conn` and fill them with `meta` and
+/// `entries`. Returns the number of steps written.
+pub fn write<'a>(
+    conn: &mut Connection,
+    meta: &TraceMeta,
+    entries: impl IntoIterator<Item = &'a TraceEntry>,
+) -> Result<u64, String> {
+    let tx = conn.transaction().map_err(sql_err)?;
+    tx.execute_batch(SCHEMA).map_err(sql_err)?;
+    let mut written = 0;
+    {
+        let mut put_meta = tx
+            .prepare("INSERT INTO meta (key, value) VALUES (?1, ?2)")
+            .map_err(sql_err)?;
+        let mut rows = vec![
+            ("schema_version", SCHEMA_VERSION.to_string()),
+            ("arch", meta.arch.clone()),
+            ("command", json(&meta.command)),
+            ("pid", meta.pid.to_string()),
+            ("recorded_at", meta.recorded_at.to_string()),
+            ("duration_ms", meta.duration_ms.to_string()),
+        ];
+        if let Some(slice) = &meta.slice {
+            rows.push(("slice", json(slice)));
+        }
+        for (key, value) in rows {
+            put_meta.execute(params![key, value]).map_err(sql_err)?;
+        }
+
+        let mut put_signal = tx
+            .prepare("INSERT INTO signals (step, signal) VALUES (?1, ?2)")
+            .map_err(sql_err)?;
+        for s in &meta.signals {
+            put_signal
+                .execute(params![s.step as i64, s.signal])
+                .map_err(sql_err)?;
+        }
+        let mut put_initial = tx
+            .prepare("INSERT INTO initial_memory (addr, value) VALUES (?1, ?2)")
+            .map_err(sql_err)?;
+        for (&addr, &value) in &meta.initial_memory {
+            put_initial
+                .execute(params![addr as i64, value])
+                .map_err(sql_err)?;
+        }
+
+        let mut put_step = tx
+            .prepare(
+                "INSERT INTO steps (step, pc, insn, insn_bytes, kind, depth)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )
+            .map_err(sql_err)?;
+        let mut put_reg = tx
+            .prepare("INSERT INTO registers (step, name, value) VALUES (?1, ?2, ?3)")
+            .map_err(sql_err)?;
+        let mut put_write = tx
+            .prepare(
+                "INSERT INTO memory_writes (step, addr, old_value, new_value)
+                 VALUES (?1, ?2, ?3, ?4)",
+            )
+            .map_err(sql_err)?;
+        for entry in entries {
+            let step = entry.step as i64;
+            put_step
+                .execute(params![
+                    step,
+                    entry.pc as i64,
+                    entry.insn_text,
+                    entry.insn_bytes,
+                    entry.kind.name(),
+                    entry.depth as i64,
+                ])
+                .map_err(sql_err)?;
+            let regs: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_str(&entry.regs).unwrap_or_default();
+            for (name, value) in &regs {
+                if let Some(v) = value.as_u64() {
+                    put_reg
+                        .execute(params![step, name, v as i64])
+                        .map_err(sql_err)?;
+                }
+            }
+            for m in &entry.mem_changes {
+                put_write
+                    .execute(params![step, m.addr as i64, m.old_val, m.new_val])
+                    .map_err(sql_err)?;
+            }
+            written += 1;
+        }
+    }
+    tx.execute_batch(INDEXES).map_err(sql_err)?;
+    tx.commit().map_err(sql_err)?;
+    Ok(written)
+}
+
+fn json(value: &impl serde::Serialize) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+/// Read a database written by `write` back into trace metadata and entries.
+pub(super) fn read(path: &str) -> Result<(TraceMeta, BTreeMap<u64, TraceEntry>), String> {
+    let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(sql_err)?;
+
+    let mut meta_rows: BTreeMap<String, String> = BTreeMap::new();
+    let mut stmt = conn
+        .prepare("SELECT key, value FROM meta")
+        .map_err(|e| format!("Not a tdb SQLite export: {}", e))?;
+    let rows = stmt
+        .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+        .map_err(sql_err)?;
+    for row in rows {
+        let (key, value) = row.map_err(sql_err)?;
+        meta_rows.insert(key, value);
+    }
+    let version: u32 = meta_rows
+        .get("schema_version")
+        .and_then(|v| v.parse().ok())
+        .ok_or("Not a tdb SQLite export: no schema_version")?;
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "SQLite schema version {} is newer than this tdb supports ({})",
+            version, SCHEMA_VERSION
+        ));
+    }
+    let field = |key: &str| meta_rows.get(key).map(String::as_str).unwrap_or_default();
+    let mut meta = TraceMeta {
+        command: serde_json::from_str(field("command")).unwrap_or_default(),
+        pid: field("pid").parse().unwrap_or_default(),
+        arch: field("arch").to_string(),
+        recorded_at: field("recorded_at").parse().unwrap_or_default(),
+        duration_ms: field("duration_ms").parse().unwrap_or_default(),
+        slice: serde_json::from_str::<SliceInfo>(field("slice")).ok(),
+        ..Default::default()
+    };
+
+    let mut stmt = conn
+        .prepare("SELECT step, signal FROM signals ORDER BY rowid")
+        .map_err(sql_err)?;
+    meta.signals = stmt
+        .query_map([], |r| {
+            Ok(SignalEvent {
+                step: r.get::<_, i64>(0)? as u64,
+                signal: r.get(1)?,
+            })
+        })
+        .map_err(sql_err)?
+        .collect::<Result<_, _>>()
+        .map_err(sql_err)?;
+    let mut stmt = conn
+        .prepare("SELECT addr, value FROM initial_memory")
+        .map_err(sql_err)?;
+    meta.initial_memory = stmt
+        .query_map([], |r| Ok((r.get::<_, i64>(0)? as u64, r.get(1)?)))
+        .map_err(sql_err)?
+        .collect::<Result<_, _>>()
+        .map_err(sql_err)?;
+
+    let mut entries = BTreeMap::new();
+    let mut stmt = conn
+        .prepare("SELECT step, pc, insn, insn_bytes, kind, depth FROM steps")
+        .map_err(sql_err)?;
+    let rows = stmt
+        .query_map([], |r| {
+            let insn_text: String = r.get(2)?;
+            let kind: String = r.get(4)?;
+            Ok(TraceEntry {
+                step: r.get::<_, i64>(0)? as u64,
+                pc: r.get::<_, i64>(1)? as u64,
+                kind: InsnKind::from_name(&kind).unwrap_or_else(|| InsnKind::classify(&insn_text)),
+                insn_text,
+                insn_bytes: r.get(3)?,
+                regs: String::new(),
+                mem_changes: Vec::new(),
+                depth: r.get::<_, i64>(5)? as u64,
+            })
+        })
+        .map_err(sql_err)?;
+    for row in rows {
+        let entry = row.map_err(sql_err)?;
+        entries.insert(entry.step, entry);
+    }
+
+    // Both tables are read in step order, so registers are gathered per step
+    // and flushed when the step changes.
+    let mut stmt = conn
+        .prepare("SELECT step, name, value FROM registers ORDER BY step, name")
+        .map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    let mut regs = serde_json::Map::new();
+    let mut current = None;
+    let mut flush = |step: Option<u64>, regs: &mut serde_json::Map<_, _>| {
+        if let Some(entry) = step.and_then(|s| entries.get_mut(&s)) {
+            entry.regs = serde_json::Value::Object(std::mem::take(regs)).to_string();
+        }
+        regs.clear();
+    };
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let step = row.get::<_, i64>(0).map_err(sql_err)? as u64;
+        if current != Some(step) {
+            flush(current, &mut regs);
+            current = Some(step);
+        }
+        let name: String = row.get(1).map_err(sql_err)?;
+        let value = row.get::<_, i64>(2).map_err(sql_err)? as u64;
+        regs.insert(name, value.into());
+    }
+    flush(current, &mut regs);
+
+    let mut stmt = conn
+        .prepare("SELECT step, addr, old_value, new_value FROM memory_writes ORDER BY rowid")
+        .map_err(sql_err)?;
+    let mut rows = stmt.query([]).map_err(sql_err)?;
+    while let Some(row) = rows.next().map_err(sql_err)? {
+        let step = row.get::<_, i64>(0).map_err(sql_err)? as u64;
+        if let Some(entry) = entries.get_mut(&step) {
+            entry.mem_changes.push(MemChange {
+                addr: row.get::<_, i64>(1).map_err(sql_err)? as u64,
+                old_val: row.get(2).map_err(sql_err)?,
+                new_val: row.get(3).map_err(sql_err)?,
+            });
+        }
+    }
+    Ok((meta, entries))
+}
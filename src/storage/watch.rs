@@ -0,0 +1,581 @@
+//! Reverse watchpoints: every write to an address range, found through the
+//! memory-write index instead of a scan of the whole trace.
+//!
+//! Memory is sampled before a step runs, so a change recorded at step N was
+//! made by an earlier store. Each hit is attributed to that store, found by
+//! decoding the steps since the previous recorded change. Only the hits a
+//! lookup returns are decoded.
+
+use super::{IndexKey, MemChange, TraceDb, TraceEntry, TraceIndex, TraceMeta};
+use crate::deps::Decoder;
+use parking_lot::RwLockReadGuard;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+
+/// Longest range a watch may cover.
+pub const MAX_WATCH_LEN: u64 = 1 << 20;
+
+/// How far before a recorded change to look for the store that made it.
+const MAX_WRITER_DISTANCE: u64 = 4096;
+
+/// How a [`Condition`] compares the watched value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A test on the value of the watched range after a write, e.g. "== 0".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub op: CmpOp,
+    pub value: u64,
+}
+
+impl Condition {
+    pub fn matches(&self, v: u64) -> bool {
+        match self.op {
+            CmpOp::Eq => v == self.value,
+            CmpOp::Ne => v != self.value,
+            CmpOp::Lt => v < self.value,
+            CmpOp::Le => v <= self.value,
+            CmpOp::Gt => v > self.value,
+            CmpOp::Ge => v >= self.value,
+        }
+    }
+}
+
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    /// "0", "==0x10", "!=0", "<5", "<=5", ">5" or ">=5".
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (op, rest) = [
+            ("==", CmpOp::Eq),
+            ("!=", CmpOp::Ne),
+            ("<=", CmpOp::Le),
+            (">=", CmpOp::Ge),
+            ("<", CmpOp::Lt),
+            (">", CmpOp::Gt),
+            ("=", CmpOp::Eq),
+        ]
+        .iter()
+        .find_map(|&(p, op)| s.strip_prefix(p).map(|rest| (op, rest)))
+        .unwrap_or((CmpOp::Eq, s));
+        let value =
+            parse_number(rest.trim()).ok_or_else(|| format!("Invalid value condition '{}'", s))?;
+        Ok(Self { op, value })
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self.op {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        };
+        write!(f, "{} {:#x}", op, self.value)
+    }
+}
+
+/// The bytes `addr..addr + len`, optionally only matching writes that leave
+/// them holding a value that meets `condition`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    pub addr: u64,
+    pub len: u64,
+    pub condition: Option<Condition>,
+}
+
+impl Watch {
+    /// Parse a "0x"-prefixed (or decimal) address and an optional length,
+    /// which defaults to one byte.
+    pub fn parse(addr: &str, len: Option<&str>) -> Result<Self, String> {
+        let addr = parse_number(addr).ok_or_else(|| format!("Invalid address '{}'", addr))?;
+        let len = match len {
+            Some(len) => parse_number(len).ok_or_else(|| format!("Invalid length '{}'", len))?,
+            None => 1,
+        };
+        if len == 0 {
+            return Err("Watch length must be at least 1".to_string());
+        }
+        if len > MAX_WATCH_LEN {
+            return Err(format!("Watch length must be at most {:#x}", MAX_WATCH_LEN));
+        }
+        Ok(Self {
+            addr,
+            len,
+            condition: None,
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.addr.saturating_add(self.len)
+    }
+}
+
+impl std::fmt::Display for Watch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.len == 1 {
+            write!(f, "{:#x}", self.addr)?;
+        } else {
+            write!(f, "{:#x}..{:#x}", self.addr, self.end())?;
+        }
+        if let Some(cond) = &self.condition {
+            write!(f, " {}", cond)?;
+        }
+        Ok(())
+    }
+}
+
+/// One write to the watched range.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WatchHit {
+    /// The store that made the write, or `seen` when no decoded store
+    /// matches it.
+    pub step: u64,
+    /// First step whose memory sample shows the write.
+    pub seen: u64,
+    pub pc: u64,
+    pub insn: String,
+    /// The bytes of the range this step changed.
+    pub changes: Vec<MemChange>,
+    /// Little-endian value of the whole range before and after the write.
+    /// `None` for ranges wider than 8 bytes, or when some byte in the range
+    /// is never written and its value is not known.
+    pub old_value: Option<u64>,
+    pub new_value: Option<u64>,
+}
+
+impl TraceDb {
+    /// Every write to the watched range in `start..=end` whose result meets
+    /// the watch's condition, in step order. Writes whose value is unknown
+    /// never meet a condition.
+    pub fn watch(&self, watch: &Watch, (start, end): (u64, u64)) -> Vec<WatchHit> {
+        let lookup = Lookup::new(self, watch);
+        let seen = lookup.index.mem_write_steps(lookup.range());
+        let mut hits = Vec::new();
+        for (i, &step) in seen.iter().enumerate() {
+            let prev = i.checked_sub(1).map(|p| seen[p]);
+            // A hit's store comes after the previous recorded change.
+            if prev.is_some_and(|p| p > end) {
+                break;
+            }
+            if step < start {
+                continue;
+            }
+            if let Some(hit) = lookup.hit(step, prev) {
+                if (start..=end).contains(&hit.step) {
+                    hits.push(hit);
+                }
+            }
+        }
+        hits
+    }
+
+    /// The last matching write made before `step` runs: where the value
+    /// seen at `step` came from.
+    pub fn last_write_before(&self, watch: &Watch, step: u64) -> Option<WatchHit> {
+        let lookup = Lookup::new(self, watch);
+        // Only the first change recorded after `step` can have been stored
+        // before it; every later one has its store after that change.
+        if let Some(hit) = lookup
+            .next_seen(step)
+            .and_then(|seen| lookup.hit(seen, lookup.prev_seen(seen)))
+        {
+            if hit.visible_at(step) {
+                return Some(hit);
+            }
+        }
+        let mut seen = lookup.prev_seen(step.saturating_add(1));
+        while let Some(s) = seen {
+            let prev = lookup.prev_seen(s);
+            if let Some(hit) = lookup.hit(s, prev) {
+                return Some(hit);
+            }
+            seen = prev;
+        }
+        None
+    }
+
+    /// The first matching write not yet made when `step` runs, which may be
+    /// `step` itself.
+    pub fn next_write_after(&self, watch: &Watch, step: u64) -> Option<WatchHit> {
+        let lookup = Lookup::new(self, watch);
+        let mut seen = lookup.next_seen(step);
+        while let Some(s) = seen {
+            if let Some(hit) = lookup.hit(s, lookup.prev_seen(s)) {
+                if !hit.visible_at(step) {
+                    return Some(hit);
+                }
+            }
+            seen = lookup.next_seen(s);
+        }
+        None
+    }
+
+    /// Little-endian value of the `len` (at most 8) bytes at `addr` as seen
+    /// by `step`, if the trace knows every one of them.
+    pub fn read_memory(&self, addr: u64, len: u64, step: u64) -> Option<u64> {
+        let watch = Watch {
+            addr,
+            len,
+            condition: None,
+        };
+        match self.last_write_before(&watch, step) {
+            Some(hit) => hit.new_value,
+            // Nothing in the range was written before `step`.
+            None => Lookup::new(self, &watch).value_before(step),
+        }
+    }
+}
+
+/// Point lookups of single hits, through the per-address write index.
+struct Lookup<'a> {
+    watch: &'a Watch,
+    entries: RwLockReadGuard<'a, BTreeMap<u64, TraceEntry>>,
+    index: RwLockReadGuard<'a, TraceIndex>,
+    meta: RwLockReadGuard<'a, TraceMeta>,
+}
+
+impl<'a> Lookup<'a> {
+    fn new(db: &'a TraceDb, watch: &'a Watch) -> Self {
+        Self {
+            watch,
+            entries: db.entries.read(),
+            index: db.index.read(),
+            meta: db.meta.read(),
+        }
+    }
+
+    fn range(&self) -> Range<u64> {
+        self.watch.addr..self.watch.end()
+    }
+
+    /// Last step before `step` whose memory sample changed the range.
+    fn prev_seen(&self, step: u64) -> Option<u64> {
+        self.index.mem_write_before(self.range(), step)
+    }
+
+    /// First step after `step` whose memory sample changed the range.
+    fn next_seen(&self, step: u64) -> Option<u64> {
+        self.index.mem_write_after(self.range(), step)
+    }
+
+    /// Value of the range before `step` runs, without the changes `step`
+    /// records.
+    fn value_before(&self, step: u64) -> Option<u64> {
+        if self.watch.len > 8 {
+            return None;
+        }
+        let bytes = self
+            .range()
+            .filter_map(|a| Some((a, self.byte_before(a, step)?)))
+            .collect();
+        value(self.watch, &bytes)
+    }
+
+    /// The byte at `addr` as the sample at `step` shows it: the latest change
+    /// recorded before `step`, or else the slice's initial memory, or else
+    /// what the byte's first change overwrote.
+    fn byte_before(&self, addr: u64, step: u64) -> Option<u8> {
+        let key = IndexKey::MemWrite(addr);
+        match self.index.prev_before(key, step) {
+            Some(prev) => self
+                .entries
+                .get(&prev)?
+                .mem_changes
+                .iter()
+                .rev()
+                .find(|c| c.addr == addr)
+                .map(|c| c.new_val),
+            None => self.meta.initial_memory.get(&addr).copied().or_else(|| {
+                let first = *self.index.steps(key).first()?;
+                self.entries
+                    .get(&first)?
+                    .mem_changes
+                    .iter()
+                    .find(|c| c.addr == addr)
+                    .map(|c| c.old_val)
+            }),
+        }
+    }
+
+    /// The hit recorded at `seen`, if it meets the watch's condition. The
+    /// store that made it is looked for only after the previous recorded
+    /// change, `prev_seen`.
+    fn hit(&self, seen: u64, prev_seen: Option<u64>) -> Option<WatchHit> {
+        let e = self.entries.get(&seen)?;
+        let range = self.range();
+        let changes: Vec<MemChange> = e
+            .mem_changes
+            .iter()
+            .filter(|c| range.contains(&c.addr))
+            .cloned()
+            .collect();
+        let old_value = self.value_before(seen);
+        let new_value = self.value_before(seen.saturating_add(1));
+        if let Some(cond) = &self.watch.condition {
+            if !new_value.is_some_and(|v| cond.matches(v)) {
+                return None;
+            }
+        }
+
+        // The latest store to a changed byte since the previous change was
+        // recorded (that step's own store included). Without a decoder the
+        // hit stays at the step that recorded it.
+        let lowest = prev_seen.unwrap_or(seen.saturating_sub(MAX_WRITER_DISTANCE));
+        let writer = Decoder::new(&self.meta.arch).ok().and_then(|decoder| {
+            self.entries
+                .range(lowest..seen)
+                .rev()
+                .map(|(_, w)| w)
+                .find(|w| {
+                    decoder.decode(w).stores.iter().any(|&(addr, len)| {
+                        changes
+                            .iter()
+                            .any(|c| (addr..addr.saturating_add(len)).contains(&c.addr))
+                    })
+                })
+        });
+        let writer = writer.unwrap_or(e);
+        Some(WatchHit {
+            step: writer.step,
+            seen,
+            pc: writer.pc,
+            insn: writer.insn_text.clone(),
+            changes,
+            old_value,
+            new_value,
+        })
+    }
+}
+
+impl WatchHit {
+    /// Whether the write was made before `step` runs. A write with no
+    /// decoded store is only known to come before the step that recorded it.
+    fn visible_at(&self, step: u64) -> bool {
+        self.step < step || self.seen <= step
+    }
+}
+
+/// Little-endian value of the watched range, if it is at most 8 bytes and
+/// every byte is known.
+fn value(watch: &Watch, bytes: &HashMap<u64, u8>) -> Option<u64> {
+    if watch.len > 8 {
+        return None;
+    }
+    (watch.addr..watch.end())
+        .rev()
+        .try_fold(0u64, |acc, a| Some(acc << 8 | *bytes.get(&a)? as u64))
+}
+
+/// "0x"-prefixed hex or decimal.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{InsnKind, TraceMeta};
+
+    /// An arm64 step running `insn` with x1 = `x1`, whose memory sample
+    /// (taken before it runs) shows `writes` made by earlier steps.
+    fn step(step: u64, insn: &str, x1: u64, writes: &[(u64, u8, u8)]) -> TraceEntry {
+        let insn_bytes = match insn {
+            "strb w0, [x1]" => vec![0x20, 0x00, 0x00, 0x39],
+            "strh w0, [x1]" => vec![0x20, 0x00, 0x00, 0x79],
+            _ => vec![0x1f, 0x20, 0x03, 0xd5],
+        };
+        TraceEntry {
+            step,
+            pc: 0x1000 + step * 4,
+            insn_bytes,
+            insn_text: insn.to_string(),
+            regs: serde_json::json!({ "x1": x1 }).to_string(),
+            mem_changes: writes
+                .iter()
+                .map(|&(addr, old_val, new_val)| MemChange {
+                    addr,
+                    old_val,
+                    new_val,
+                })
+                .collect(),
+            kind: InsnKind::classify(insn),
+            depth: 0,
+        }
+    }
+
+    /// A 4-byte counter at 0x7000 goes 0 -> 1 -> 0x100 -> 0, written at
+    /// steps 0, 3 and 5 and seen at 1, 4 and 6, with an unrelated write to
+    /// 0x8000 in between.
+    fn trace() -> TraceDb {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".into(),
+            ..Default::default()
+        });
+        for e in [
+            step(0, "strb w0, [x1]", 0x7000, &[]),
+            step(1, "nop", 0, &[(0x7000, 0, 1)]),
+            step(2, "strb w0, [x1]", 0x8000, &[]),
+            step(3, "strh w0, [x1]", 0x7000, &[(0x8000, 0, 9)]),
+            step(4, "nop", 0, &[(0x7000, 1, 0), (0x7001, 0, 1)]),
+            step(5, "strb w0, [x1]", 0x7001, &[]),
+            step(6, "nop", 0, &[(0x7001, 1, 0)]),
+        ] {
+            db.insert(e).unwrap();
+        }
+        db
+    }
+
+    fn watch(addr: u64, len: u64) -> Watch {
+        Watch {
+            addr,
+            len,
+            condition: None,
+        }
+    }
+
+    #[test]
+    fn lists_every_write_to_the_range() {
+        let db = trace();
+        let hits = db.watch(&watch(0x7000, 4), (0, 6));
+        let steps: Vec<(u64, u64)> = hits.iter().map(|h| (h.step, h.seen)).collect();
+        assert_eq!(steps, vec![(0, 1), (3, 4), (5, 6)]);
+        assert_eq!(hits[1].insn, "strh w0, [x1]");
+        assert_eq!(hits[1].pc, 0x100c);
+        // Bytes 2 and 3 are never written, so the 4-byte value is unknown.
+        assert_eq!(hits[0].new_value, None);
+
+        let hits = db.watch(&watch(0x7000, 2), (0, 6));
+        let values: Vec<(Option<u64>, Option<u64>)> =
+            hits.iter().map(|h| (h.old_value, h.new_value)).collect();
+        assert_eq!(
+            values,
+            vec![
+                (Some(0), Some(1)),
+                (Some(1), Some(0x100)),
+                (Some(0x100), Some(0))
+            ]
+        );
+        assert_eq!(hits[1].changes.len(), 2);
+        assert!(db.watch(&watch(0x7000, 2), (2, 4)).len() == 1);
+    }
+
+    #[test]
+    fn last_and_next_write() {
+        let db = trace();
+        let w = watch(0x7001, 1);
+        // Step 4 sees the value step 3 stored.
+        assert_eq!(db.last_write_before(&w, 4).map(|h| h.step), Some(3));
+        assert_eq!(db.last_write_before(&w, 3).map(|h| h.step), None);
+        assert_eq!(db.next_write_after(&w, 3).map(|h| h.step), Some(3));
+        assert_eq!(db.next_write_after(&w, 4).map(|h| h.step), Some(5));
+        assert_eq!(db.next_write_after(&w, 6).map(|h| h.step), None);
+    }
+
+    #[test]
+    fn store_before_a_late_sample_is_visible() {
+        let db = TraceDb::new(":memory:").unwrap();
+        db.set_meta(TraceMeta {
+            arch: "arm64".into(),
+            ..Default::default()
+        });
+        for e in [
+            step(0, "strb w0, [x1]", 0x7000, &[]),
+            step(1, "nop", 0, &[]),
+            step(2, "nop", 0, &[]),
+            step(3, "nop", 0, &[(0x7000, 0, 7)]),
+        ] {
+            db.insert(e).unwrap();
+        }
+        let w = watch(0x7000, 1);
+        let hit = db.last_write_before(&w, 2).unwrap();
+        assert_eq!((hit.step, hit.seen), (0, 3));
+        assert_eq!(db.read_memory(0x7000, 1, 2), Some(7));
+        assert_eq!(db.read_memory(0x7000, 1, 0), Some(0));
+        assert_eq!(db.next_write_after(&w, 0).map(|h| h.step), Some(0));
+        assert_eq!(db.next_write_after(&w, 1), None);
+    }
+
+    #[test]
+    fn undecoded_writes_stay_where_seen() {
+        let db = trace();
+        db.set_meta(TraceMeta::default());
+        db.insert(step(7, "nop", 0, &[(0x9000, 0, 1)])).unwrap();
+        let hits = db.watch(&watch(0x9000, 1), (0, 7));
+        assert_eq!((hits[0].step, hits[0].seen), (7, 7));
+        assert!(db.last_write_before(&watch(0x9000, 1), 7).is_some());
+    }
+
+    #[test]
+    fn conditions_filter_on_new_value() {
+        let db = trace();
+        let mut w = watch(0x7000, 2);
+        w.condition = Some("0".parse().unwrap());
+        let steps: Vec<u64> = db.watch(&w, (0, 6)).iter().map(|h| h.step).collect();
+        assert_eq!(steps, vec![5]);
+        w.condition = Some(">=0x100".parse().unwrap());
+        assert_eq!(db.last_write_before(&w, 6).map(|h| h.step), Some(3));
+    }
+
+    #[test]
+    fn initial_memory_fills_unwritten_bytes() {
+        let db = trace();
+        db.set_meta(TraceMeta {
+            arch: "arm64".into(),
+            initial_memory: [(0x7002, 0), (0x7003, 0)].into(),
+            ..Default::default()
+        });
+        let hits = db.watch(&watch(0x7000, 4), (0, 6));
+        assert_eq!(hits[1].new_value, Some(0x100));
+    }
+
+    #[test]
+    fn read_memory_at_a_step() {
+        let db = trace();
+        // Before its first write, a byte holds that write's old value.
+        assert_eq!(db.read_memory(0x7000, 2, 0), Some(0));
+        assert_eq!(db.read_memory(0x7000, 2, 1), Some(1));
+        assert_eq!(db.read_memory(0x7000, 2, 3), Some(1));
+        assert_eq!(db.read_memory(0x7000, 2, 4), Some(0x100));
+        assert_eq!(db.read_memory(0x7000, 4, 4), None);
+        assert_eq!(db.read_memory(0x9000, 1, 4), None);
+    }
+
+    #[test]
+    fn parse_watch_and_condition() {
+        assert_eq!(Watch::parse("0x7000", Some("8")).unwrap(), watch(0x7000, 8));
+        assert_eq!(Watch::parse("4096", None).unwrap(), watch(0x1000, 1));
+        assert!(Watch::parse("0x7000", Some("0")).is_err());
+        assert!(Watch::parse("0x0", Some("0xffffffffffffffff")).is_err());
+        assert!(Watch::parse("sp", None).is_err());
+
+        let c: Condition = "!= 0x10".parse().unwrap();
+        assert_eq!(
+            c,
+            Condition {
+                op: CmpOp::Ne,
+                value: 0x10
+            }
+        );
+        assert!(c.matches(0) && !c.matches(0x10));
+        assert!("<>".parse::<Condition>().is_err());
+        assert_eq!(watch(0x7000, 4).to_string(), "0x7000..0x7004");
+    }
+}
@@ -0,0 +1,160 @@
+//! Source line information from DWARF debug info.
+
+use addr2line::gimli;
+use object::{Object, ObjectSection, SectionKind};
+use std::path::Path;
+
+/// A run of instructions belonging to one source line, at file (unslid)
+/// addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineRow {
+    pub addr: u64,
+    pub len: u64,
+    pub file: String,
+    pub line: u32,
+}
+
+/// Every line-table row of a binary's text, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    rows: Vec<LineRow>,
+}
+
+impl LineTable {
+    pub fn from_rows(mut rows: Vec<LineRow>) -> Self {
+        rows.sort_by_key(|r| r.addr);
+        Self { rows }
+    }
+
+    /// Read the line table for `binary`. On macOS the DWARF usually lives
+    /// in a `.dSYM` bundle next to the executable, which is tried first.
+    pub fn load(binary: &str) -> Result<Self, String> {
+        let path = dsym_path(binary)
+            .filter(|p| p.exists())
+            .unwrap_or_else(|| Path::new(binary).to_path_buf());
+        let data = std::fs::read(&path).map_err(|e| format!("Read failed: {}", e))?;
+        let file = object::File::parse(&*data)
+            .map_err(|e| format!("Parse {} failed: {}", path.display(), e))?;
+
+        let endian = if file.is_little_endian() {
+            gimli::RunTimeEndian::Little
+        } else {
+            gimli::RunTimeEndian::Big
+        };
+        let load_section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+            let data = file
+                .section_by_name(id.name())
+                .and_then(|s| s.data().ok())
+                .unwrap_or(&[]);
+            Ok(gimli::EndianSlice::new(data, endian))
+        };
+        let dwarf =
+            gimli::Dwarf::load(load_section).map_err(|e| format!("DWARF load failed: {}", e))?;
+        let ctx = addr2line::Context::from_dwarf(dwarf)
+            .map_err(|e| format!("DWARF parse failed: {}", e))?;
+
+        let mut rows = Vec::new();
+        for section in file.sections().filter(|s| s.kind() == SectionKind::Text) {
+            let (start, end) = (section.address(), section.address() + section.size());
+            let Ok(locations) = ctx.find_location_range(start, end) else {
+                continue;
+            };
+            for (addr, len, loc) in locations {
+                if let (Some(file), Some(line)) = (loc.file, loc.line) {
+                    rows.push(LineRow {
+                        addr,
+                        len,
+                        file: file.to_string(),
+                        line,
+                    });
+                }
+            }
+        }
+        if rows.is_empty() {
+            return Err(format!("No debug line info in {}", path.display()));
+        }
+        Ok(Self::from_rows(rows))
+    }
+
+    pub fn rows(&self) -> &[LineRow] {
+        &self.rows
+    }
+
+    /// The row covering file address `addr`.
+    pub fn lookup(&self, addr: u64) -> Option<&LineRow> {
+        let pos = self.rows.partition_point(|r| r.addr <= addr);
+        let row = &self.rows[pos.checked_sub(1)?];
+        (addr - row.addr < row.len.max(1)).then_some(row)
+    }
+}
+
+/// `foo.dSYM/Contents/Resources/DWARF/foo` for a binary `foo`.
+fn dsym_path(binary: &str) -> Option<std::path::PathBuf> {
+    let name = Path::new(binary).file_name()?;
+    let mut bundle = std::ffi::OsString::from(binary);
+    bundle.push(".dSYM");
+    Some(
+        Path::new(&bundle)
+            .join("Contents/Resources/DWARF")
+            .join(name),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolTable;
+
+    fn row(addr: u64, len: u64, line: u32) -> LineRow {
+        LineRow {
+            addr,
+            len,
+            file: "main.c".to_string(),
+            line,
+        }
+    }
+
+    #[test]
+    fn lookup_finds_covering_row() {
+        let table = LineTable::from_rows(vec![row(0x1010, 8, 12), row(0x1000, 0x10, 10)]);
+        assert_eq!(table.lookup(0x1000).unwrap().line, 10);
+        assert_eq!(table.lookup(0x100c).unwrap().line, 10);
+        assert_eq!(table.lookup(0x1014).unwrap().line, 12);
+        assert!(table.lookup(0x1018).is_none());
+        assert!(table.lookup(0xfff).is_none());
+    }
+
+    #[test]
+    fn dsym_path_layout() {
+        assert_eq!(
+            dsym_path("build/a.out").unwrap(),
+            Path::new("build/a.out.dSYM/Contents/Resources/DWARF/a.out")
+        );
+    }
+
+    #[test]
+    fn load_reads_own_debug_info() {
+        let exe = std::env::current_exe().unwrap();
+        let exe = exe.to_str().unwrap();
+        let table = LineTable::load(exe).unwrap();
+        let symbols = SymbolTable::load(exe).unwrap();
+        let this_fn = symbols
+            .symbols()
+            .find(|s| s.name.contains("load_reads_own_debug_info"))
+            .unwrap();
+        let row = table.lookup(this_fn.addr).unwrap();
+        assert!(
+            row.file.ends_with("lines.rs"),
+            "unexpected file {}",
+            row.file
+        );
+    }
+
+    #[test]
+    fn load_without_debug_info_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty");
+        std::fs::write(&path, b"").unwrap();
+        assert!(LineTable::load(path.to_str().unwrap()).is_err());
+    }
+}
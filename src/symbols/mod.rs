@@ -0,0 +1,306 @@
+mod lines;
+
+#[cfg(test)]
+pub(crate) use lines::LineRow;
+pub use lines::LineTable;
+
+use crate::storage::{IndexKey, InsnKind, TraceDb, TraceMeta, TraceStream};
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+use std::collections::{BTreeSet, HashMap};
+
+/// Slides are page-aligned, so a symbol and its runtime address share the
+/// low bits.
+const PAGE_MASK: u64 = 0xFFF;
+
+/// A function in the traced binary.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub addr: u64,
+    /// Zero when the binary does not record sizes; the symbol then extends
+    /// to the next one.
+    pub size: u64,
+    pub name: String,
+}
+
+/// Function symbols of one binary, sorted by address.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    /// Runtime load offset (ASLR slide) added to every symbol address.
+    slide: u64,
+    /// Binary the symbols were read from.
+    path: String,
+    /// Start and end of the mapped image, before the slide.
+    image: Option<(u64, u64)>,
+}
+
+impl SymbolTable {
+    pub fn from_symbols(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|s| s.addr);
+        symbols.dedup_by_key(|s| s.addr);
+        Self {
+            symbols,
+            ..Default::default()
+        }
+    }
+
+    /// Read the function symbols of a Mach-O or ELF executable.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Read failed: {}", e))?;
+        let file =
+            object::File::parse(&*data).map_err(|e| format!("Parse {} failed: {}", path, e))?;
+        let macho = matches!(file.format(), object::BinaryFormat::MachO);
+        let symbols = file
+            .symbols()
+            .filter(|s| s.kind() == SymbolKind::Text && s.is_definition() && s.address() != 0)
+            .filter_map(|s| {
+                let name = s.name().ok()?;
+                // Mach-O prefixes C symbols with an underscore.
+                let name = if macho {
+                    name.strip_prefix('_').unwrap_or(name)
+                } else {
+                    name
+                };
+                Some(Symbol {
+                    addr: s.address(),
+                    size: s.size(),
+                    name: name.to_string(),
+                })
+            })
+            .collect();
+        // Mach-O executables reserve a __PAGEZERO segment that is never mapped.
+        let image = file
+            .segments()
+            .filter(|s| s.size() != 0 && s.name() != Ok(Some("__PAGEZERO")))
+            .map(|s| (s.address(), s.address() + s.size()))
+            .reduce(|(a0, a1), (b0, b1)| (a0.min(b0), a1.max(b1)));
+        Ok(Self {
+            path: path.to_string(),
+            image,
+            ..Self::from_symbols(symbols)
+        })
+    }
+
+    /// Load the symbols of the program a trace recorded (or `binary`, when
+    /// given) and work out where it was loaded.
+    pub fn for_trace(db: &TraceDb, binary: Option<&str>) -> Result<Self, String> {
+        Self::for_program(&db.meta(), binary, || Ok(call_targets(db)))
+    }
+
+    /// [`SymbolTable::for_trace`] for a trace file that is streamed rather
+    /// than loaded; the call targets come from one more pass over it.
+    pub fn for_trace_file(
+        path: &str,
+        meta: &TraceMeta,
+        binary: Option<&str>,
+    ) -> Result<Self, String> {
+        Self::for_program(meta, binary, || {
+            let stream = TraceStream::open(path)?.ok_or("Trace file cannot be streamed")?;
+            streamed_call_targets(stream)
+        })
+    }
+
+    fn for_program(
+        meta: &TraceMeta,
+        binary: Option<&str>,
+        call_targets: impl FnOnce() -> Result<Vec<u64>, String>,
+    ) -> Result<Self, String> {
+        let path = binary
+            .or(meta.command.first().map(String::as_str))
+            .ok_or("Trace does not record its program; pass --binary")?;
+        let mut table = Self::load(path)?;
+        table.slide = table.infer_slide(&call_targets()?);
+        Ok(table)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn slide(&self) -> u64 {
+        self.slide
+    }
+
+    /// Runtime address range of the binary, if it was loaded from a file.
+    pub fn image_range(&self) -> Option<(u64, u64)> {
+        self.image
+            .map(|(start, end)| (start.wrapping_add(self.slide), end.wrapping_add(self.slide)))
+    }
+
+    /// Symbols at their runtime addresses.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        self.symbols.iter().map(|s| Symbol {
+            addr: s.addr.wrapping_add(self.slide),
+            ..s.clone()
+        })
+    }
+
+    /// The function containing runtime address `addr`, and the offset into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let addr = addr.wrapping_sub(self.slide);
+        let pos = self.symbols.partition_point(|s| s.addr <= addr);
+        let sym = &self.symbols[pos.checked_sub(1)?];
+        let offset = addr - sym.addr;
+        if sym.size != 0 && offset >= sym.size {
+            return None;
+        }
+        Some((&sym.name, offset))
+    }
+
+    /// "name" or "name+0x1c" for a runtime address.
+    pub fn describe(&self, addr: u64) -> Option<String> {
+        self.lookup(addr).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{}+0x{:x}", name, offset),
+        })
+    }
+
+    /// Pick the page-aligned slide that lands the most call targets exactly
+    /// on a function entry. Returns 0 when nothing matches (non-PIE binary
+    /// or a trace of a different program).
+    fn infer_slide(&self, targets: &[u64]) -> u64 {
+        let mut by_low_bits: HashMap<u64, Vec<u64>> = HashMap::new();
+        for s in &self.symbols {
+            by_low_bits
+                .entry(s.addr & PAGE_MASK)
+                .or_default()
+                .push(s.addr);
+        }
+        let mut votes: HashMap<u64, usize> = HashMap::new();
+        for &t in targets {
+            for &addr in by_low_bits.get(&(t & PAGE_MASK)).into_iter().flatten() {
+                *votes.entry(t.wrapping_sub(addr)).or_default() += 1;
+            }
+        }
+        votes
+            .into_iter()
+            .max_by_key(|&(slide, n)| (n, std::cmp::Reverse(slide)))
+            .map(|(slide, _)| slide)
+            .unwrap_or(0)
+    }
+}
+
+/// Unique PCs executed right after a call.
+fn call_targets(db: &TraceDb) -> Vec<u64> {
+    let calls = db.index().steps(IndexKey::Kind(InsnKind::Call)).to_vec();
+    let mut targets: Vec<u64> = calls
+        .into_iter()
+        .filter_map(|step| db.get(step + 1).map(|e| e.pc))
+        .collect();
+    targets.sort_unstable();
+    targets.dedup();
+    targets
+}
+
+/// [`call_targets`] of a streamed trace.
+fn streamed_call_targets(stream: TraceStream) -> Result<Vec<u64>, String> {
+    let mut targets = BTreeSet::new();
+    let mut last_call = None;
+    stream.try_for_each_in(0, u64::MAX, |e| {
+        if last_call.is_some_and(|call: u64| call + 1 == e.step) {
+            targets.insert(e.pc);
+        }
+        if e.kind == InsnKind::Call {
+            last_call = Some(e.step);
+        }
+        Ok(())
+    })?;
+    Ok(targets.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(addr: u64, size: u64, name: &str) -> Symbol {
+        Symbol {
+            addr,
+            size,
+            name: name.to_string(),
+        }
+    }
+
+    fn table() -> SymbolTable {
+        SymbolTable::from_symbols(vec![
+            sym(0x1_0000_3f00, 0, "main"),
+            sym(0x1_0000_3e00, 0x40, "add"),
+            sym(0x1_0000_3e80, 0x20, "mul"),
+        ])
+    }
+
+    #[test]
+    fn lookup_finds_containing_function() {
+        let t = table();
+        assert_eq!(t.lookup(0x1_0000_3e00), Some(("add", 0)));
+        assert_eq!(t.lookup(0x1_0000_3e3c), Some(("add", 0x3c)));
+        assert_eq!(t.lookup(0x1_0000_3e84), Some(("mul", 4)));
+        // Unsized symbols run to the end of the table.
+        assert_eq!(t.lookup(0x1_0000_4000), Some(("main", 0x100)));
+    }
+
+    #[test]
+    fn lookup_outside_functions_is_none() {
+        let t = table();
+        assert_eq!(t.lookup(0x1000), None);
+        // Between add's end and mul's start.
+        assert_eq!(t.lookup(0x1_0000_3e40), None);
+    }
+
+    #[test]
+    fn describe_formats_offsets() {
+        let t = table();
+        assert_eq!(t.describe(0x1_0000_3e80).as_deref(), Some("mul"));
+        assert_eq!(t.describe(0x1_0000_3e88).as_deref(), Some("mul+0x8"));
+        assert_eq!(t.describe(0x10), None);
+    }
+
+    #[test]
+    fn slide_inferred_from_call_targets() {
+        let mut t = table();
+        let slide = 0x4_5000;
+        let targets = [0x1_0000_3e00 + slide, 0x1_0000_3e80 + slide, 0x7fff_0123];
+        t.slide = t.infer_slide(&targets);
+        assert_eq!(t.slide(), slide);
+        assert_eq!(t.lookup(0x1_0000_3e84 + slide), Some(("mul", 4)));
+        assert_eq!(t.symbols().next().unwrap().addr, 0x1_0000_3e00 + slide);
+    }
+
+    #[test]
+    fn no_matching_targets_means_no_slide() {
+        let t = table();
+        assert_eq!(t.infer_slide(&[0x1234]), 0);
+        assert_eq!(t.infer_slide(&[]), 0);
+    }
+
+    #[test]
+    fn load_reads_own_executable() {
+        let exe = std::env::current_exe().unwrap();
+        let t = SymbolTable::load(exe.to_str().unwrap()).unwrap();
+        assert!(!t.is_empty());
+        assert!(t
+            .symbols()
+            .any(|s| s.name.contains("load_reads_own_executable")));
+        let (start, end) = t.image_range().unwrap();
+        assert!(t.symbols().all(|s| (start..end).contains(&s.addr)));
+    }
+
+    #[test]
+    fn load_rejects_non_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("not_a_binary");
+        std::fs::write(&path, b"hello").unwrap();
+        assert!(SymbolTable::load(path.to_str().unwrap()).is_err());
+        assert!(SymbolTable::load("/nonexistent/binary").is_err());
+    }
+
+    #[test]
+    fn for_trace_without_program_fails() {
+        let db = TraceDb::new(":memory:").unwrap();
+        let err = SymbolTable::for_trace(&db, None).unwrap_err();
+        assert!(err.contains("--binary"), "unexpected error: {}", err);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::storage::{MemChange, TraceDb, TraceEntry};
+use crate::storage::{InsnKind, MemChange, SignalEvent, TraceDb, TraceEntry};
 use crate::tracer::mach::MachTask;
 use capstone::prelude::*;
 use nix::sys::ptrace;
@@ -8,6 +8,12 @@ use nix::unistd::Pid;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Architecture name recorded in trace metadata.
+#[cfg(target_arch = "x86_64")]
+pub const ARCH: &str = "x86_64";
+#[cfg(target_arch = "aarch64")]
+pub const ARCH: &str = "arm64";
+
 /// Result of a single-step operation.
 pub enum StepResult {
     /// Successfully recorded one instruction.
@@ -24,7 +30,8 @@ pub struct Tracer {
     db: Arc<TraceDb>,
     cs: Capstone,
     step_count: u64,
-    memory_cache: HashMap<u64, Vec<u8>>,
+    /// Last observed value of every stack byte sampled so far.
+    shadow_memory: HashMap<u64, u8>,
     last_sp: u64,
     call_depth: u64,
     pending_signal: Option<Signal>,
@@ -57,7 +64,7 @@ impl Tracer {
             db,
             cs,
             step_count: 0,
-            memory_cache: HashMap::new(),
+            shadow_memory: HashMap::new(),
             last_sp: 0,
             call_depth: 0,
             pending_signal: None,
@@ -156,19 +163,21 @@ impl Tracer {
             let check_size: usize = 256;
             let stack_base = sp.saturating_sub(128);
             if let Ok(new_data) = self.task.read_memory(stack_base, check_size) {
-                if let Some(old_data) = self.memory_cache.get(&stack_base) {
-                    let len = new_data.len().min(old_data.len());
-                    for i in 0..len {
-                        if new_data[i] != old_data[i] {
+                // Compare byte-by-byte against the last value seen at each
+                // address, so old_val always matches the previous new_val
+                // even when the sampled window moves with SP.
+                for (i, &new_val) in new_data.iter().enumerate() {
+                    let addr = stack_base + i as u64;
+                    if let Some(old_val) = self.shadow_memory.insert(addr, new_val) {
+                        if old_val != new_val {
                             mem_changes.push(MemChange {
-                                addr: stack_base + i as u64,
-                                old_val: old_data[i],
-                                new_val: new_data[i],
+                                addr,
+                                old_val,
+                                new_val,
                             });
                         }
                     }
                 }
-                self.memory_cache.insert(stack_base, new_data);
             }
         }
 
@@ -205,9 +214,11 @@ impl Tracer {
             step: self.step_count,
             pc,
             insn_bytes,
+            kind: InsnKind::classify(&full_text),
             insn_text: full_text,
             regs: regs.to_string(),
             mem_changes,
+            depth: self.call_depth,
         };
 
         if let Err(e) = self.db.insert(entry.clone()) {
@@ -232,11 +243,21 @@ impl Tracer {
             Ok(WaitStatus::Stopped(_, sig)) => {
                 // Process received a different signal while stepping.
                 // Save it for re-delivery on the next step.
+                self.db.record_signal(SignalEvent {
+                    step: entry.step,
+                    signal: sig.as_str().to_string(),
+                });
                 self.pending_signal = Some(sig);
                 StepResult::Ok(entry)
             }
             Ok(WaitStatus::Exited(_, code)) => StepResult::ProcessExited(code),
-            Ok(WaitStatus::Signaled(_, _sig, _)) => StepResult::ProcessExited(-1),
+            Ok(WaitStatus::Signaled(_, sig, _)) => {
+                self.db.record_signal(SignalEvent {
+                    step: entry.step,
+                    signal: sig.as_str().to_string(),
+                });
+                StepResult::ProcessExited(-1)
+            }
             Ok(status) => StepResult::Error(format!("Unexpected wait status: {:?}", status)),
             Err(e) => StepResult::Error(format!("waitpid: {}", e)),
         }
@@ -256,7 +277,7 @@ impl Tracer {
     }
 }
 
-fn is_store_mnemonic(m: &str) -> bool {
+pub(crate) fn is_store_mnemonic(m: &str) -> bool {
     matches!(
         m,
         "str" | "stp" | "stur" | "stlr" | "stxr" | "strb" | "strh" | "push" | "pushq"
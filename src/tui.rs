@@ -1,23 +1,33 @@
+use crate::backtrace::{Backtrace, FrameSource, StackFrame};
+use crate::deps::{self, DepOptions};
+use crate::loops::Loops;
+use crate::query::Query;
 use crate::stats::TraceStats;
-use crate::storage::{TraceDb, TraceEntry};
+use crate::storage::{IndexKey, InsnKind, TraceDb, TraceEntry, TraceIndex};
+use crate::symbols::SymbolTable;
 use crossterm::{
     event::{self, Event, KeyCode, KeyEventKind},
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
     ExecutableCommand,
 };
 use ratatui::{prelude::*, widgets::*};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io::stdout;
 use std::time::Duration;
 
 enum Mode {
     Normal,
     Search(String),
+    /// Typing the register or memory range whose origin to jump to.
+    Origin(String),
     Help,
 }
 
 struct App {
+    db: TraceDb,
+    symbols: SymbolTable,
     trace: Vec<TraceEntry>,
+    index: TraceIndex,
     current: usize,
     list_state: ListState,
     prev_regs: Option<HashMap<String, serde_json::Value>>,
@@ -28,23 +38,31 @@ struct App {
     should_quit: bool,
     filtered_indices: Vec<usize>,
     trace_file: String,
+    /// Result of the last origin lookup or watch jump, shown in the footer.
+    message: Option<String>,
+    /// Address followed by the w / W keys.
+    watched: Option<u64>,
+    /// Call stack at the current step, computed when the Stack pane is drawn.
+    backtrace: Option<Backtrace>,
+    loops: Loops,
+    /// Timeline shows each loop run as one row.
+    summary: bool,
+    /// Runs (indexes into `loops.runs`) unfolded with Enter.
+    expanded: HashSet<usize>,
+    /// Trace position of each folded row's first step -> its run.
+    folded: HashMap<usize, usize>,
 }
 
 impl App {
-    fn new(db: &TraceDb, trace_file: &str) -> Self {
+    fn new(db: TraceDb, symbols: SymbolTable, trace_file: &str) -> Self {
         let trace = db.get_all();
-        let stats = TraceStats::analyze(db);
+        let index = db.index().clone();
+        let stats = TraceStats::analyze(&db, &symbols);
 
-        let arch = if !trace.is_empty() {
-            let regs: serde_json::Value = serde_json::from_str(&trace[0].regs).unwrap_or_default();
-            if regs.get("rax").is_some() {
-                "x86_64".to_string()
-            } else {
-                "arm64".to_string()
-            }
-        } else {
-            "unknown".to_string()
-        };
+        // Recorded traces store their arch; older ones get it guessed on load.
+        let arch = Some(db.meta().arch)
+            .filter(|a| !a.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
 
         let filtered_indices: Vec<usize> = (0..trace.len()).collect();
         let mut list_state = ListState::default();
@@ -59,8 +77,13 @@ impl App {
             None
         };
 
+        let loops = Loops::detect(&db, &symbols);
+
         Self {
+            db,
+            symbols,
             trace,
+            index,
             current: 0,
             list_state,
             prev_regs: None,
@@ -71,6 +94,13 @@ impl App {
             should_quit: false,
             filtered_indices,
             trace_file: trace_file.to_string(),
+            message: None,
+            watched: None,
+            backtrace: None,
+            loops,
+            summary: false,
+            expanded: HashSet::new(),
+            folded: HashMap::new(),
         }
     }
 
@@ -89,10 +119,86 @@ impl App {
             }
         }
         self.current = idx;
-        // Sync list selection
+        // Sync list selection; inside a folded loop, its row
         if let Some(pos) = self.filtered_indices.iter().position(|&i| i == idx) {
             self.list_state.select(Some(pos));
+        } else if self.summary {
+            let pos = self.filtered_indices.partition_point(|&i| i <= idx);
+            self.list_state.select(pos.checked_sub(1));
+        }
+    }
+
+    /// Move by timeline rows rather than steps, skipping folded loops.
+    fn move_row(&mut self, delta: i64) {
+        let Some(pos) = self.list_state.selected() else {
+            return;
+        };
+        let last = self.filtered_indices.len() as i64 - 1;
+        let pos = (pos as i64 + delta).clamp(0, last.max(0)) as usize;
+        if let Some(&idx) = self.filtered_indices.get(pos) {
+            self.select(idx);
+        }
+    }
+
+    /// Show every step again, or every row of the loop summary.
+    fn reset_filter(&mut self) {
+        self.folded.clear();
+        if self.summary {
+            let steps: Vec<u64> = self.trace.iter().map(|e| e.step).collect();
+            let rows = self.loops.timeline(&steps, &self.expanded);
+            self.filtered_indices = rows.iter().map(|r| r.index).collect();
+            self.folded = rows
+                .iter()
+                .filter_map(|r| Some((r.index, r.run?)))
+                .collect();
+        } else {
+            self.filtered_indices = (0..self.trace.len()).collect();
+        }
+        self.select(self.current);
+    }
+
+    fn toggle_summary(&mut self) {
+        self.summary = !self.summary;
+        self.reset_filter();
+        self.message = Some(if self.summary {
+            format!(
+                "{} loop runs folded (Enter expands, z shows every step)",
+                self.loops.runs.len()
+            )
+        } else {
+            "Showing every step".to_string()
+        });
+    }
+
+    /// Unfold the loop row under the cursor, or fold the innermost
+    /// unfolded run around the current step.
+    fn toggle_fold(&mut self) {
+        if !self.summary {
+            return;
         }
+        let row = self
+            .list_state
+            .selected()
+            .and_then(|pos| self.filtered_indices.get(pos));
+        if let Some(&run) = row.and_then(|i| self.folded.get(i)) {
+            self.expanded.insert(run);
+        } else {
+            let Some(step) = self.current_entry().map(|e| e.step) else {
+                return;
+            };
+            let innermost = self
+                .expanded
+                .iter()
+                .copied()
+                .filter(|&r| (self.loops.runs[r].start..=self.loops.runs[r].end).contains(&step))
+                .min_by_key(|&r| self.loops.runs[r].end - self.loops.runs[r].start);
+            let Some(run) = innermost else {
+                return;
+            };
+            self.expanded.remove(&run);
+            self.select_step(self.loops.runs[run].start);
+        }
+        self.reset_filter();
     }
 
     fn step(&mut self, delta: i64) {
@@ -103,52 +209,196 @@ impl App {
     }
 
     fn find_next(&mut self, what: &str) {
-        for i in (self.current + 1)..self.trace.len() {
-            if self.matches_filter(&self.trace[i], what) {
-                self.select(i);
-                return;
-            }
+        let Some(step) = self.current_entry().map(|e| e.step) else {
+            return;
+        };
+        let Some(key) = self.filter_key(what) else {
+            return;
+        };
+        if let Some(next) = self.index.next_after(key, step) {
+            self.select_step(next);
         }
     }
 
     fn find_prev(&mut self, what: &str) {
-        if self.current == 0 {
+        let Some(step) = self.current_entry().map(|e| e.step) else {
+            return;
+        };
+        let Some(key) = self.filter_key(what) else {
             return;
+        };
+        if let Some(prev) = self.index.prev_before(key, step) {
+            self.select_step(prev);
         }
-        for i in (0..self.current).rev() {
-            if self.matches_filter(&self.trace[i], what) {
-                self.select(i);
-                return;
+    }
+
+    /// Index key for a c / r / m jump, or None (with a footer message) for
+    /// anything else.
+    fn filter_key(&mut self, what: &str) -> Option<IndexKey> {
+        match what {
+            "call" => Some(IndexKey::Kind(InsnKind::Call)),
+            "ret" => Some(IndexKey::Kind(InsnKind::Return)),
+            "mem" => Some(IndexKey::AnyMemWrite),
+            _ => {
+                self.message = Some(format!("Unknown jump filter '{}'", what));
+                None
             }
         }
     }
 
-    fn matches_filter(&self, entry: &TraceEntry, what: &str) -> bool {
-        match what {
-            "call" => entry.insn_text.contains("CALL"),
-            "ret" => entry.insn_text.contains("RETURN"),
-            "mem" => !entry.mem_changes.is_empty(),
-            _ => false,
+    /// Jump to the previous (or next) write of the watched address. With
+    /// nothing watched yet, the first byte the current step changed is
+    /// watched from now on.
+    fn find_write(&mut self, forward: bool) {
+        let Some(entry) = self.current_entry() else {
+            return;
+        };
+        let step = entry.step;
+        let Some(addr) = self
+            .watched
+            .or_else(|| entry.mem_changes.first().map(|c| c.addr))
+        else {
+            self.message =
+                Some("Nothing to watch: search for a 0xaddr or pick a step that writes memory".into());
+            return;
+        };
+        self.watched = Some(addr);
+        let key = IndexKey::MemWrite(addr);
+        let found = if forward {
+            self.index.next_after(key, step)
+        } else {
+            self.index.prev_before(key, step)
+        };
+        self.message = Some(match found {
+            Some(s) => {
+                self.select_step(s);
+                format!("0x{:x} written at step {}", addr, s)
+            }
+            None => format!(
+                "No {} write to 0x{:x}",
+                if forward { "later" } else { "earlier" },
+                addr
+            ),
+        });
+    }
+
+    /// Call stack at the current step, computed the first time it is asked
+    /// for after the cursor moves.
+    fn stack(&mut self) -> &[StackFrame] {
+        let step = self.trace.get(self.current).map(|e| e.step);
+        if self.backtrace.as_ref().map(|b| b.step) != step {
+            self.backtrace = step.and_then(|s| Backtrace::at(&self.db, &self.symbols, s));
+        }
+        self.backtrace.as_ref().map_or(&[], |b| &b.frames)
+    }
+
+    /// Select the entry recorded at `step` (trace positions and step
+    /// numbers differ for sliced traces).
+    fn select_step(&mut self, step: u64) {
+        if let Ok(idx) = self.trace.binary_search_by_key(&step, |e| e.step) {
+            self.select(idx);
         }
     }
 
     fn apply_search(&mut self, query: &str) {
         let q = query.to_lowercase();
-        self.filtered_indices = if q.is_empty() {
-            (0..self.trace.len()).collect()
-        } else {
-            self.trace
+        if q.is_empty() {
+            self.reset_filter();
+            return;
+        }
+        self.folded.clear();
+        self.message = None;
+        let parsed = query.parse::<Query>();
+        let text_matches = |trace: &[TraceEntry]| -> Vec<usize> {
+            trace
                 .iter()
                 .enumerate()
                 .filter(|(_, e)| e.insn_text.to_lowercase().contains(&q))
                 .map(|(i, _)| i)
                 .collect()
         };
+        self.filtered_indices = if let Ok(expr) = &parsed {
+            match expr.matcher(&self.symbols, &self.arch) {
+                Ok(matcher) => {
+                    let trace = &self.trace;
+                    (0..trace.len())
+                        .filter(|&i| {
+                            let next = trace.get(i + 1).filter(|n| n.step == trace[i].step + 1);
+                            matcher.matches(&trace[i], next)
+                        })
+                        .collect()
+                }
+                Err(e) => {
+                    self.message = Some(e);
+                    Vec::new()
+                }
+            }
+        } else if let Some(addr) = parse_hex_addr(&q) {
+            self.watched = Some(addr);
+            // Addresses go through the indexes (every execution of that PC
+            // plus every write to that byte), along with the steps that name
+            // it in their operands.
+            let mut steps: Vec<u64> = self.index.steps(IndexKey::Pc(addr)).to_vec();
+            steps.extend_from_slice(self.index.steps(IndexKey::MemWrite(addr)));
+            let mut indices: Vec<usize> = steps
+                .iter()
+                .filter_map(|s| self.trace.binary_search_by_key(s, |e| e.step).ok())
+                .chain(text_matches(&self.trace))
+                .collect();
+            indices.sort_unstable();
+            indices.dedup();
+            indices
+        } else {
+            // Plain text is a substring search; text that was meant as an
+            // expression gets the parse error.
+            if looks_like_query(&q) {
+                self.message = parsed.err();
+            }
+            text_matches(&self.trace)
+        };
         if let Some(&first) = self.filtered_indices.first() {
             self.select(first);
         }
     }
 
+    /// Jump to the step that produced `query` (a register or memory range)
+    /// as it was before the current step, and narrow the timeline to the
+    /// steps its value was computed from.
+    fn jump_to_origin(&mut self, query: &str) {
+        let Some(step) = self.current_entry().map(|e| e.step) else {
+            return;
+        };
+        let result = deps::Target::parse(query.trim(), &self.arch).and_then(|target| {
+            deps::backward_slice(&self.trace, &self.arch, step, &target, &DepOptions::default())
+        });
+        let slice = match result {
+            Ok(slice) => slice,
+            Err(e) => {
+                self.message = Some(e);
+                return;
+            }
+        };
+        let Some(origin) = slice.origin() else {
+            self.message = Some(format!("{} was set before the trace started", slice.target));
+            return;
+        };
+        let mut steps: Vec<u64> = slice.nodes.iter().map(|n| n.step).collect();
+        steps.push(step);
+        steps.sort_unstable();
+        self.folded.clear();
+        self.filtered_indices = steps
+            .iter()
+            .filter_map(|s| self.trace.binary_search_by_key(s, |e| e.step).ok())
+            .collect();
+        self.select_step(origin);
+        self.message = Some(format!(
+            "{} <- step {}  ({} steps in slice, o Esc to show all)",
+            slice.target,
+            origin,
+            slice.nodes.len()
+        ));
+    }
+
     fn current_entry(&self) -> Option<&TraceEntry> {
         self.trace.get(self.current)
     }
@@ -163,6 +413,17 @@ impl App {
     }
 }
 
+/// Whether search text uses query operators rather than being plain text.
+fn looks_like_query(q: &str) -> bool {
+    q.contains(['=', '<', '>', '&', '|', '(', '!'])
+}
+
+/// Parse a `0x`-prefixed hex address typed into the search prompt.
+fn parse_hex_addr(q: &str) -> Option<u64> {
+    let hex = q.strip_prefix("0x")?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
 pub fn run(trace_file: &str) -> Result<(), String> {
     let db = TraceDb::load(trace_file).map_err(|e| format!("Failed to load trace: {}", e))?;
 
@@ -170,7 +431,9 @@ pub fn run(trace_file: &str) -> Result<(), String> {
         return Err("Trace file is empty".to_string());
     }
 
-    let mut app = App::new(&db, trace_file);
+    // No warnings here: they would be lost under the alternate screen.
+    let symbols = SymbolTable::for_trace(&db, None).unwrap_or_default();
+    let mut app = App::new(db, symbols, trace_file);
 
     enable_raw_mode().map_err(|e| e.to_string())?;
     stdout()
@@ -205,6 +468,7 @@ fn main_loop(
                 match &app.mode {
                     Mode::Normal => handle_normal_key(app, key.code),
                     Mode::Search(_) => handle_search_key(app, key.code),
+                    Mode::Origin(_) => handle_origin_key(app, key.code),
                     Mode::Help => {
                         app.mode = Mode::Normal;
                     }
@@ -219,10 +483,13 @@ fn main_loop(
 }
 
 fn handle_normal_key(app: &mut App, code: KeyCode) {
+    app.message = None;
     match code {
         KeyCode::Char('q') | KeyCode::Esc => app.should_quit = true,
         KeyCode::Char('h') | KeyCode::Left => app.step(-1),
         KeyCode::Char('l') | KeyCode::Right => app.step(1),
+        KeyCode::Char('j') | KeyCode::Down if app.summary => app.move_row(1),
+        KeyCode::Char('k') | KeyCode::Up if app.summary => app.move_row(-1),
         KeyCode::Char('j') | KeyCode::Down => app.step(1),
         KeyCode::Char('k') | KeyCode::Up => app.step(-1),
         KeyCode::Char('g') | KeyCode::Home => app.select(0),
@@ -235,7 +502,12 @@ fn handle_normal_key(app: &mut App, code: KeyCode) {
         KeyCode::Char('R') => app.find_prev("ret"),
         KeyCode::Char('m') => app.find_next("mem"),
         KeyCode::Char('M') => app.find_prev("mem"),
+        KeyCode::Char('w') => app.find_write(false),
+        KeyCode::Char('W') => app.find_write(true),
         KeyCode::Char('/') => app.mode = Mode::Search(String::new()),
+        KeyCode::Char('o') => app.mode = Mode::Origin(String::new()),
+        KeyCode::Char('z') => app.toggle_summary(),
+        KeyCode::Enter => app.toggle_fold(),
         KeyCode::Char('?') => app.mode = Mode::Help,
         KeyCode::PageDown => app.step(50),
         KeyCode::PageUp => app.step(-50),
@@ -247,7 +519,8 @@ fn handle_search_key(app: &mut App, code: KeyCode) {
     match code {
         KeyCode::Esc => {
             // Cancel search, restore full list
-            app.filtered_indices = (0..app.trace.len()).collect();
+            app.message = None;
+            app.reset_filter();
             app.mode = Mode::Normal;
         }
         KeyCode::Enter => {
@@ -271,6 +544,32 @@ fn handle_search_key(app: &mut App, code: KeyCode) {
     }
 }
 
+fn handle_origin_key(app: &mut App, code: KeyCode) {
+    match code {
+        KeyCode::Esc => {
+            // Cancel, and drop the filter of a previous lookup
+            app.reset_filter();
+            app.mode = Mode::Normal;
+        }
+        KeyCode::Enter => {
+            if let Mode::Origin(q) = std::mem::replace(&mut app.mode, Mode::Normal) {
+                app.jump_to_origin(&q);
+            }
+        }
+        KeyCode::Char(c) => {
+            if let Mode::Origin(ref mut q) = app.mode {
+                q.push(c);
+            }
+        }
+        KeyCode::Backspace => {
+            if let Mode::Origin(ref mut q) = app.mode {
+                q.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
 // ──────────────────────────── rendering ────────────────────────────
 
 fn ui(f: &mut Frame, app: &mut App) {
@@ -352,7 +651,7 @@ fn render_main(f: &mut Frame, app: &mut App, area: Rect) {
         .split(area);
 
     render_timeline(f, app, cols[0]);
-    render_detail(f, app, cols[1]);
+    render_middle(f, app, cols[1]);
     render_right(f, app, cols[2]);
 }
 
@@ -361,6 +660,15 @@ fn render_timeline(f: &mut Frame, app: &mut App, area: Rect) {
         .filtered_indices
         .iter()
         .map(|&i| {
+            if let Some(&run) = app.folded.get(&i) {
+                let run = &app.loops.runs[run];
+                let style = Style::default().fg(Color::Magenta);
+                return ListItem::new(Line::from(vec![
+                    Span::styled("+ ", style),
+                    Span::styled(format!("{:>6} ", run.start), style.fg(Color::DarkGray)),
+                    Span::styled(run.label(), style),
+                ]));
+            }
             let e = &app.trace[i];
             let is_call = e.insn_text.contains("CALL");
             let is_ret = e.insn_text.contains("RETURN");
@@ -407,13 +715,14 @@ fn render_timeline(f: &mut Frame, app: &mut App, area: Rect) {
         .collect();
 
     let title = format!(
-        " Timeline ({}{}) ",
+        " Timeline ({}{}){} ",
         app.filtered_indices.len(),
         if app.filtered_indices.len() != app.trace.len() {
             format!("/{}", app.trace.len())
         } else {
             String::new()
-        }
+        },
+        if app.folded.is_empty() { "" } else { "  loops folded" }
     );
 
     let list = List::new(items)
@@ -537,6 +846,54 @@ fn render_detail(f: &mut Frame, app: &App, area: Rect) {
     f.render_widget(detail, area);
 }
 
+fn render_middle(f: &mut Frame, app: &mut App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(15), Constraint::Min(3)])
+        .split(area);
+
+    render_detail(f, app, chunks[0]);
+    render_stack(f, app, chunks[1]);
+}
+
+fn render_stack(f: &mut Frame, app: &mut App, area: Rect) {
+    let frames = app.stack();
+    let lines: Vec<Line> = frames
+        .iter()
+        .map(|frame| {
+            let name = frame
+                .function
+                .clone()
+                .unwrap_or_else(|| format!("0x{:x}", frame.pc));
+            let from = match (frame.call_step, frame.source) {
+                (Some(step), _) => format!(" @{}", step),
+                (None, FrameSource::FramePointer) => " (fp)".to_string(),
+                (None, FrameSource::Call) => String::new(),
+            };
+            Line::from(vec![
+                Span::styled(
+                    format!(" #{:<2} ", frame.level),
+                    Style::default().fg(Color::DarkGray),
+                ),
+                Span::styled(name, Style::default().fg(Color::White)),
+                Span::styled(from, Style::default().fg(Color::DarkGray)),
+                Span::styled(
+                    format!("  sp 0x{:X}", frame.sp_low),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ])
+        })
+        .collect();
+
+    let block = Block::default()
+        .title(format!(" Stack ({}) ", frames.len()))
+        .borders(Borders::ALL)
+        .border_style(Style::default().fg(Color::DarkGray));
+
+    let widget = Paragraph::new(lines).block(block);
+    f.render_widget(widget, area);
+}
+
 fn render_right(f: &mut Frame, app: &App, area: Rect) {
     let chunks = Layout::default()
         .direction(Direction::Vertical)
@@ -632,8 +989,12 @@ fn render_memory(f: &mut Frame, app: &App, area: Rect) {
     }
 
     let count = entry.map(|e| e.mem_changes.len()).unwrap_or(0);
+    let title = match app.watched {
+        Some(addr) => format!(" Memory ({})  watching 0x{:X} ", count, addr),
+        None => format!(" Memory ({}) ", count),
+    };
     let block = Block::default()
-        .title(format!(" Memory ({}) ", count))
+        .title(title)
         .borders(Borders::ALL)
         .border_style(Style::default().fg(Color::DarkGray));
 
@@ -643,6 +1004,10 @@ fn render_memory(f: &mut Frame, app: &App, area: Rect) {
 
 fn render_footer(f: &mut Frame, app: &App, area: Rect) {
     let content = match &app.mode {
+        Mode::Normal if app.message.is_some() => Line::from(Span::styled(
+            format!(" {}", app.message.as_deref().unwrap_or_default()),
+            Style::default().fg(Color::Yellow),
+        )),
         Mode::Normal => Line::from(vec![
             Span::styled(" h", Style::default().fg(Color::Cyan)),
             Span::styled("/", Style::default().fg(Color::DarkGray)),
@@ -664,8 +1029,16 @@ fn render_footer(f: &mut Frame, app: &App, area: Rect) {
             Span::styled("/", Style::default().fg(Color::DarkGray)),
             Span::styled("M", Style::default().fg(Color::Cyan)),
             Span::styled(" mem  ", Style::default().fg(Color::DarkGray)),
+            Span::styled("w", Style::default().fg(Color::Cyan)),
+            Span::styled("/", Style::default().fg(Color::DarkGray)),
+            Span::styled("W", Style::default().fg(Color::Cyan)),
+            Span::styled(" watch  ", Style::default().fg(Color::DarkGray)),
             Span::styled("/", Style::default().fg(Color::Cyan)),
             Span::styled(" search  ", Style::default().fg(Color::DarkGray)),
+            Span::styled("o", Style::default().fg(Color::Cyan)),
+            Span::styled(" origin  ", Style::default().fg(Color::DarkGray)),
+            Span::styled("z", Style::default().fg(Color::Cyan)),
+            Span::styled(" loops  ", Style::default().fg(Color::DarkGray)),
             Span::styled("?", Style::default().fg(Color::Cyan)),
             Span::styled(" help  ", Style::default().fg(Color::DarkGray)),
             Span::styled("q", Style::default().fg(Color::Cyan)),
@@ -675,8 +1048,21 @@ fn render_footer(f: &mut Frame, app: &App, area: Rect) {
             Span::styled(" Search: ", Style::default().fg(Color::Yellow)),
             Span::styled(query, Style::default().fg(Color::White)),
             Span::styled("_", Style::default().fg(Color::White).slow_blink()),
+            match &app.message {
+                // Why the query doesn't parse
+                Some(e) => Span::styled(format!("  {}", e), Style::default().fg(Color::Red)),
+                None => Span::styled(
+                    "  [Enter] confirm  [Esc] cancel",
+                    Style::default().fg(Color::DarkGray),
+                ),
+            },
+        ]),
+        Mode::Origin(query) => Line::from(vec![
+            Span::styled(" Origin of: ", Style::default().fg(Color::Yellow)),
+            Span::styled(query, Style::default().fg(Color::White)),
+            Span::styled("_", Style::default().fg(Color::White).slow_blink()),
             Span::styled(
-                "  [Enter] confirm  [Esc] cancel",
+                "  register or 0xaddr[:len]  [Enter] jump  [Esc] cancel",
                 Style::default().fg(Color::DarkGray),
             ),
         ]),
@@ -744,11 +1130,34 @@ fn render_help(f: &mut Frame) {
             Span::styled("  m / M          ", Style::default().fg(Color::Yellow)),
             Span::raw("Next / prev memory change"),
         ]),
+        Line::from(vec![
+            Span::styled("  w / W          ", Style::default().fg(Color::Yellow)),
+            Span::raw("Prev / next write to the watched address"),
+        ]),
         Line::from(""),
         Line::from(vec![
             Span::styled("  /              ", Style::default().fg(Color::Cyan)),
             Span::raw("Search / filter instructions"),
         ]),
+        Line::from(vec![
+            Span::raw("                 "),
+            Span::styled(
+                "text, 0xaddr, or a query: pc in main && x0 == 0",
+                Style::default().fg(Color::DarkGray),
+            ),
+        ]),
+        Line::from(vec![
+            Span::styled("  o              ", Style::default().fg(Color::Cyan)),
+            Span::raw("Jump to where a register / memory value came from"),
+        ]),
+        Line::from(vec![
+            Span::styled("  z              ", Style::default().fg(Color::Magenta)),
+            Span::raw("Fold each loop run into one timeline row (j / k skip them)"),
+        ]),
+        Line::from(vec![
+            Span::styled("  Enter          ", Style::default().fg(Color::Magenta)),
+            Span::raw("Expand the loop row / fold the loop around the step"),
+        ]),
         Line::from(vec![
             Span::styled("  ?              ", Style::default().fg(Color::Cyan)),
             Span::raw("Show this help"),
@@ -0,0 +1,294 @@
+use crate::storage::{self, CorruptRange, TraceEntry, TraceIndex};
+use std::collections::HashMap;
+
+/// How many problems of each kind `print` lists before summarising.
+const MAX_LISTED: usize = 20;
+
+/// A memory change whose `old_val` disagrees with the last recorded value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemMismatch {
+    pub step: u64,
+    pub addr: u64,
+    pub expected: u8,
+    pub found: u8,
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    pub version: u32,
+    pub checksummed: bool,
+    pub steps: u64,
+    pub corrupt: Vec<CorruptRange>,
+    /// Inclusive ranges of step numbers missing between recorded steps.
+    pub gaps: Vec<(u64, u64)>,
+    pub duplicates: Vec<u64>,
+    pub mem_mismatches: Vec<MemMismatch>,
+    /// Whether the stored indexes match the entries (None if not stored,
+    /// or not comparable because blocks were lost).
+    pub index_ok: Option<bool>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt.is_empty()
+            && self.gaps.is_empty()
+            && self.duplicates.is_empty()
+            && self.mem_mismatches.is_empty()
+            && self.index_ok != Some(false)
+    }
+
+    pub fn print(&self) {
+        println!("  Trace Verification");
+        println!("  ------------------");
+        println!(
+            "  Format:           v{}{}",
+            self.version,
+            if self.checksummed {
+                " (checksummed)"
+            } else {
+                " (no checksums)"
+            }
+        );
+        println!("  Readable steps:   {}", self.steps);
+
+        println!("  Corrupt blocks:   {}", self.corrupt.len());
+        for c in self.corrupt.iter().take(MAX_LISTED) {
+            match c.steps {
+                Some((a, b)) => {
+                    println!("    steps {}..{} (offset {}): {}", a, b, c.offset, c.reason)
+                }
+                None => println!("    offset {}: {}", c.offset, c.reason),
+            }
+        }
+        print_more(self.corrupt.len());
+
+        println!("  Step gaps:        {}", self.gaps.len());
+        for (a, b) in self.gaps.iter().take(MAX_LISTED) {
+            println!("    missing {}..{}", a, b);
+        }
+        print_more(self.gaps.len());
+
+        println!("  Duplicate steps:  {}", self.duplicates.len());
+        for s in self.duplicates.iter().take(MAX_LISTED) {
+            println!("    step {}", s);
+        }
+        print_more(self.duplicates.len());
+
+        println!("  Memory mismatches: {}", self.mem_mismatches.len());
+        for m in self.mem_mismatches.iter().take(MAX_LISTED) {
+            println!(
+                "    step {} 0x{:x}: old_val {:02x}, last recorded {:02x}",
+                m.step, m.addr, m.found, m.expected
+            );
+        }
+        print_more(self.mem_mismatches.len());
+
+        let index = match self.index_ok {
+            Some(true) => "consistent",
+            Some(false) => "DOES NOT MATCH ENTRIES",
+            None => "not checked",
+        };
+        println!("  Indexes:          {}", index);
+        println!();
+        println!("  {}", if self.is_ok() { "OK" } else { "FAILED" });
+    }
+}
+
+fn print_more(total: usize) {
+    if total > MAX_LISTED {
+        println!("    ... and {} more", total - MAX_LISTED);
+    }
+}
+
+/// Check a trace file's checksums, step continuity and internal consistency.
+///
+/// Only fails if the file cannot be read or recognised at all; everything
+/// else is reported.
+pub fn verify_file(path: &str) -> Result<VerifyReport, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Read failed: {}", e))?;
+    let scan = storage::scan(&data)?;
+    let checksummed = scan.has_checksums();
+
+    let mut entries = scan.entries;
+    // Stable sort keeps file order among duplicates.
+    entries.sort_by_key(|e| e.step);
+    let initial_memory = scan
+        .meta
+        .map(|m| m.initial_memory)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+
+    let mut report = VerifyReport {
+        version: scan.version,
+        checksummed,
+        steps: entries.len() as u64,
+        ..Default::default()
+    };
+    check_continuity(&entries, &mut report);
+    report.mem_mismatches = check_memory(&entries, initial_memory);
+    if scan.corrupt.is_empty() {
+        report.index_ok = scan
+            .index
+            .map(|stored| stored == TraceIndex::build(&entries));
+    }
+    report.corrupt = scan.corrupt;
+    Ok(report)
+}
+
+/// Record gaps and duplicates in a step-sorted entry list.
+fn check_continuity(entries: &[TraceEntry], report: &mut VerifyReport) {
+    for pair in entries.windows(2) {
+        let (a, b) = (pair[0].step, pair[1].step);
+        if a == b {
+            if report.duplicates.last() != Some(&a) {
+                report.duplicates.push(a);
+            }
+        } else if b > a + 1 {
+            report.gaps.push((a + 1, b - 1));
+        }
+    }
+}
+
+/// Every `old_val` must equal the last `new_val` recorded at that address.
+/// The chain restarts after a gap, since the missing steps may have written.
+fn check_memory(entries: &[TraceEntry], mut shadow: HashMap<u64, u8>) -> Vec<MemMismatch> {
+    let mut mismatches = Vec::new();
+    let mut prev_step: Option<u64> = None;
+    for entry in entries {
+        if prev_step.is_some_and(|p| entry.step > p + 1) {
+            shadow.clear();
+        }
+        prev_step = Some(entry.step);
+        for c in &entry.mem_changes {
+            if let Some(expected) = shadow.insert(c.addr, c.new_val) {
+                if expected != c.old_val {
+                    mismatches.push(MemMismatch {
+                        step: entry.step,
+                        addr: c.addr,
+                        expected,
+                        found: c.old_val,
+                    });
+                }
+            }
+        }
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemChange, TraceDb};
+
+    fn entry(step: u64, changes: &[(u64, u8, u8)]) -> TraceEntry {
+        TraceEntry {
+            regs: r#"{"x0":0,"sp":4096}"#.to_string(),
+            mem_changes: changes
+                .iter()
+                .map(|&(addr, old_val, new_val)| MemChange {
+                    addr,
+                    old_val,
+                    new_val,
+                })
+                .collect(),
+            ..crate::storage::entry(step, 0x1000 + step * 4, "str x0, [sp]")
+        }
+    }
+
+    fn saved(dir: &tempfile::TempDir, entries: Vec<TraceEntry>) -> String {
+        let path = dir.path().join("t.tdb").to_str().unwrap().to_string();
+        let db = TraceDb::new(&path).unwrap();
+        for e in entries {
+            db.insert(e).unwrap();
+        }
+        db.save().unwrap();
+        path
+    }
+
+    #[test]
+    fn clean_trace_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = saved(
+            &dir,
+            vec![
+                entry(0, &[(0x7000, 0, 1)]),
+                entry(1, &[]),
+                entry(2, &[(0x7000, 1, 2)]),
+            ],
+        );
+        let report = verify_file(&path).unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.checksummed);
+        assert_eq!(report.steps, 3);
+        assert_eq!(report.index_ok, Some(true));
+    }
+
+    #[test]
+    fn gaps_and_duplicates_found() {
+        let entries = vec![entry(0, &[]), entry(1, &[]), entry(1, &[]), entry(5, &[])];
+        let mut report = VerifyReport::default();
+        check_continuity(&entries, &mut report);
+        assert_eq!(report.duplicates, vec![1]);
+        assert_eq!(report.gaps, vec![(2, 4)]);
+    }
+
+    #[test]
+    fn stale_old_val_is_a_mismatch() {
+        let entries = vec![entry(0, &[(0x7000, 0, 1)]), entry(1, &[(0x7000, 9, 2)])];
+        let m = check_memory(&entries, HashMap::new());
+        assert_eq!(
+            m,
+            vec![MemMismatch {
+                step: 1,
+                addr: 0x7000,
+                expected: 1,
+                found: 9,
+            }]
+        );
+    }
+
+    #[test]
+    fn initial_memory_seeds_the_chain() {
+        let entries = vec![entry(10, &[(0x7000, 5, 6)])];
+        let seeded = HashMap::from([(0x7000, 4)]);
+        assert_eq!(check_memory(&entries, seeded).len(), 1);
+        let seeded = HashMap::from([(0x7000, 5)]);
+        assert!(check_memory(&entries, seeded).is_empty());
+    }
+
+    #[test]
+    fn memory_chain_restarts_after_gap() {
+        let entries = vec![entry(0, &[(0x7000, 0, 1)]), entry(9, &[(0x7000, 7, 8)])];
+        assert!(check_memory(&entries, HashMap::new()).is_empty());
+    }
+
+    #[test]
+    fn corrupt_block_fails_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = saved(&dir, vec![entry(0, &[]), entry(1, &[])]);
+        let mut data = std::fs::read(&path).unwrap();
+        let at = data.windows(3).position(|w| w == b"str").unwrap();
+        data[at] = b'X';
+        std::fs::write(&path, data).unwrap();
+
+        let report = verify_file(&path).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt.len(), 1);
+        assert_eq!(report.corrupt[0].steps, Some((0, 1)));
+        assert_eq!(report.index_ok, None);
+    }
+
+    #[test]
+    fn legacy_trace_verifies_without_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.tdb");
+        let legacy = storage::encode_legacy(&[entry(0, &[]), entry(1, &[])]);
+        std::fs::write(&path, legacy).unwrap();
+
+        let report = verify_file(path.to_str().unwrap()).unwrap();
+        assert!(report.is_ok());
+        assert!(!report.checksummed);
+        assert_eq!(report.index_ok, None);
+    }
+}
@@ -124,6 +124,459 @@ fn stats_missing_args() {
     );
 }
 
+// ── `slice` without enough args ──
+
+#[test]
+fn slice_missing_args() {
+    let output = tdb().arg("slice").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("slice"),
+        "should show slice usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn slice_rejects_unknown_option() {
+    let output = tdb()
+        .args(["slice", "in.tdb", "out.tdb", "--bogus"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--bogus"),
+        "should name the bad option: {}",
+        stderr
+    );
+}
+
+#[test]
+fn slice_rejects_bad_step_without_panicking() {
+    let output = tdb()
+        .args(["slice", "in.tdb", "out.tdb", "--call", "12x"])
+        .output()
+        .expect("failed to run tdb");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid step '12x'") && !stderr.contains("panicked"),
+        "should report the bad step: {}",
+        stderr
+    );
+}
+
+// ── `verify` ──
+
+#[test]
+fn verify_missing_args() {
+    let output = tdb().arg("verify").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("verify"),
+        "should show verify usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn verify_garbage_file_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("garbage.tdb");
+    std::fs::write(&path, b"definitely not a trace").unwrap();
+    let output = tdb()
+        .arg("verify")
+        .arg(&path)
+        .output()
+        .expect("failed to run tdb");
+    assert!(
+        !output.status.success(),
+        "verify on garbage should exit non-zero"
+    );
+}
+
+// ── `migrate` ──
+
+#[test]
+fn migrate_missing_args() {
+    let output = tdb()
+        .arg("migrate")
+        .arg("old.tdb")
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("migrate"),
+        "should show migrate usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn migrate_garbage_file_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("garbage.tdb");
+    let dst = dir.path().join("new.tdb");
+    std::fs::write(&src, b"definitely not a trace").unwrap();
+    let output = tdb()
+        .arg("migrate")
+        .arg(&src)
+        .arg(&dst)
+        .output()
+        .expect("failed to run tdb");
+    assert!(
+        !output.status.success(),
+        "migrate on garbage should exit non-zero"
+    );
+    assert!(!dst.exists(), "nothing should be written on failure");
+}
+
+// ── `export` ──
+
+#[test]
+fn export_missing_args() {
+    let output = tdb().arg("export").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("export"),
+        "should show export usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn export_rejects_unknown_format() {
+    let output = tdb()
+        .args(["export", "--format", "xml", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("xml"),
+        "should name the bad format: {}",
+        stderr
+    );
+}
+
+#[test]
+fn export_rejects_inverted_step_range() {
+    let output = tdb()
+        .args(["export", "--format", "jsonl", "--steps", "50..10", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid step range '50..10'"),
+        "should reject the range: {}",
+        stderr
+    );
+}
+
+#[test]
+fn export_requires_format() {
+    let output = tdb()
+        .args(["export", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--format"),
+        "should ask for --format: {}",
+        stderr
+    );
+}
+
+#[test]
+fn export_sqlite_requires_output_file() {
+    let output = tdb()
+        .args(["export", "--format", "sqlite", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("-o"), "should ask for -o: {}", stderr);
+}
+
+// ── `coverage` ──
+
+#[test]
+fn coverage_missing_args() {
+    let output = tdb().arg("coverage").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("coverage"),
+        "should show coverage usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn coverage_rejects_unknown_format() {
+    let output = tdb()
+        .args(["coverage", "trace.tdb", "--format", "gcov"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("gcov"),
+        "should name the bad format: {}",
+        stderr
+    );
+}
+
+// ── `calls` ──
+
+#[test]
+fn calls_missing_args() {
+    let output = tdb().arg("calls").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("calls"),
+        "should show calls usage: {}",
+        stderr
+    );
+}
+
+// ── `diff` ──
+
+#[test]
+fn diff_missing_args() {
+    let output = tdb()
+        .args(["diff", "a.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("diff"),
+        "should show diff usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn diff_rejects_unknown_option() {
+    let output = tdb()
+        .args(["diff", "a.tdb", "b.tdb", "--fuzzy"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--fuzzy"),
+        "should name the bad option: {}",
+        stderr
+    );
+}
+
+// ── `slice-deps` ──
+
+#[test]
+fn slice_deps_missing_args() {
+    let output = tdb()
+        .args(["slice-deps", "trace.tdb", "5"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("slice-deps"),
+        "should show slice-deps usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn slice_deps_rejects_unknown_option() {
+    let output = tdb()
+        .args(["slice-deps", "trace.tdb", "5", "x0", "--forward"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--forward"),
+        "should name the bad option: {}",
+        stderr
+    );
+}
+
+// ── `watch` ──
+
+#[test]
+fn watch_missing_args() {
+    let output = tdb()
+        .args(["watch", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("watch"),
+        "should show watch usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn watch_rejects_bad_address() {
+    let output = tdb()
+        .args(["watch", "trace.tdb", "sp"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid address"),
+        "should reject the address: {}",
+        stderr
+    );
+}
+
+#[test]
+fn watch_rejects_bad_condition() {
+    let output = tdb()
+        .args(["watch", "trace.tdb", "0x7000", "8", "--value", "<>"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Invalid value condition"),
+        "should reject the condition: {}",
+        stderr
+    );
+}
+
+// ── `backtrace` ──
+
+#[test]
+fn backtrace_missing_args() {
+    let output = tdb()
+        .args(["backtrace", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("backtrace"),
+        "should show backtrace usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn backtrace_rejects_unknown_option() {
+    let output = tdb()
+        .args(["backtrace", "trace.tdb", "5", "--full"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--full"),
+        "should name the bad option: {}",
+        stderr
+    );
+}
+
+// ── `check` ──
+
+#[test]
+fn check_missing_args() {
+    let output = tdb().arg("check").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("check"),
+        "should show check usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn check_rejects_unknown_check() {
+    let output = tdb()
+        .args(["check", "trace.tdb", "--only", "heap"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unknown check 'heap'"),
+        "should name the bad check: {}",
+        stderr
+    );
+}
+
+// ── `query` ──
+
+#[test]
+fn query_requires_expression() {
+    let output = tdb()
+        .args(["query", "trace.tdb"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Usage"), "should print usage: {}", stderr);
+}
+
+#[test]
+fn query_reports_syntax_errors() {
+    let output = tdb()
+        .args(["query", "trace.tdb", "x0 == && pc"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Bad query: Unexpected '&&' at column 7"),
+        "should say what is wrong and where: {}",
+        stderr
+    );
+}
+
+// ── `cfg` ──
+
+#[test]
+fn cfg_missing_args() {
+    let output = tdb().arg("cfg").output().expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Usage") || stderr.contains("cfg"),
+        "should show cfg usage: {}",
+        stderr
+    );
+}
+
+#[test]
+fn cfg_rejects_unknown_format() {
+    let output = tdb()
+        .args(["cfg", "trace.tdb", "--format", "png"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("png"),
+        "should name the bad format: {}",
+        stderr
+    );
+}
+
 // ── `view` with nonexistent file ──
 
 #[test]
@@ -154,13 +607,116 @@ fn stats_nonexistent_file_fails() {
     );
 }
 
+#[test]
+fn stats_rejects_unknown_option() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--bogus"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--bogus"),
+        "should name the option: {}",
+        stderr
+    );
+}
+
+#[test]
+fn stats_folded_requires_path() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--folded"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn stats_branches_json_requires_path() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--branches-json"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--branches-json"),
+        "should name the option: {}",
+        stderr
+    );
+}
+
+#[test]
+fn stats_indirect_json_requires_path() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--indirect-json"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--indirect-json"),
+        "should name the option: {}",
+        stderr
+    );
+}
+
+#[test]
+fn stats_memory_json_requires_path() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--memory-json"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("--memory-json"),
+        "should name the option: {}",
+        stderr
+    );
+}
+
+#[test]
+fn stats_rejects_unknown_format() {
+    let output = tdb()
+        .args(["stats", "trace.tdb", "--format", "xml"])
+        .output()
+        .expect("failed to run tdb");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Unknown stats format 'xml'"),
+        "should name the bad format: {}",
+        stderr
+    );
+}
+
 // ── Usage text mentions all commands ──
 
 #[test]
 fn usage_lists_all_commands() {
     let output = tdb().output().expect("failed to run tdb");
     let stderr = String::from_utf8_lossy(&output.stderr);
-    for cmd in &["run", "trace", "view", "tui", "stats"] {
+    for cmd in &[
+        "run",
+        "trace",
+        "view",
+        "tui",
+        "stats",
+        "slice",
+        "verify",
+        "migrate",
+        "export",
+        "coverage",
+        "diff",
+        "calls",
+        "cfg",
+        "slice-deps",
+        "watch",
+        "backtrace",
+        "check",
+        "query",
+    ] {
         assert!(
             stderr.contains(cmd),
             "Usage should mention '{}' command. Got:\n{}",